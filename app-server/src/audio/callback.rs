@@ -2,11 +2,24 @@ use crossbeam_channel::{Receiver, Sender};
 use failure::Fail;
 
 use hero_studio_core::audio::{AudioInput, AudioOutput};
-use hero_studio_core::midi::buffer::EventIo;
+use hero_studio_core::config::SyncMode;
+use hero_studio_core::midi::buffer::{Endpoint, EventIo};
+use hero_studio_core::midi::capture::CapturedClip;
 use hero_studio_core::midi::io::{MidiInput, MidiOutput};
+use hero_studio_core::song::clips::{
+  drumbox::{ChainEntry, Hit, Kit, Pattern},
+  stepper::{Lane, Step},
+  tracker::TrackerClip,
+  Clip, ClipIndex,
+};
+use hero_studio_core::song::import::ImportedModule;
+use hero_studio_core::song::markers::{LocatorNumber, Markers};
+use hero_studio_core::song::session::{Scene, Slot};
 use hero_studio_core::studio::Studio;
+use hero_studio_core::time::BarsTime;
 
-use crate::midi::io::Protocol as MidiIoProtocol;
+use crate::midi::endpoints::EndpointId;
+use crate::midi::io::{PanicSender, Protocol as MidiIoProtocol};
 
 #[derive(Debug, Fail)]
 pub enum CallbackError {
@@ -22,6 +35,114 @@ impl std::fmt::Display for CallbackError {
 
 pub enum Protocol {
   Stop,
+
+  Panic,
+  SetSyncMode(SyncMode),
+
+  /// Release the notes of an output port that disappeared, and then close it
+  RemoveMidiOutput(EndpointId),
+
+  /// The markers are edited before, and replace the ones of the song
+  SetMarkers(Markers),
+  LocateMarker(usize),
+  LocateNextMarker,
+  LocatePreviousMarker,
+
+  SetLocator(LocatorNumber, BarsTime),
+  LoopFromLocators(LocatorNumber, LocatorNumber),
+  PunchFromLocators(LocatorNumber, LocatorNumber),
+
+  SelectTrack(Option<usize>),
+  /// The clip is built before from a copy of the captured input
+  AddCapturedClip(CapturedClip),
+  /// The clip for the next take is made before, so recording doesn't need to create it
+  ArmTrack {
+    track: usize,
+    take: Option<Clip>,
+  },
+
+  AddStepsClip {
+    track: usize,
+    clip: Clip,
+    lanes: Vec<Lane>,
+  },
+  SetStepsLane {
+    track: usize,
+    clip: ClipIndex,
+    lane: usize,
+    value: Lane,
+  },
+  RemoveStepsLane {
+    track: usize,
+    clip: ClipIndex,
+    lane: usize,
+  },
+  SetStep {
+    track: usize,
+    clip: ClipIndex,
+    lane: usize,
+    step: usize,
+    value: Step,
+  },
+
+  AddDrumClip {
+    track: usize,
+    clip: Clip,
+    kit: Kit,
+    patterns: Vec<Pattern>,
+  },
+  SetDrumKit {
+    track: usize,
+    clip: ClipIndex,
+    kit: Kit,
+  },
+  SetDrumPattern {
+    track: usize,
+    clip: ClipIndex,
+    pattern: usize,
+    value: Pattern,
+  },
+  SetDrumHit {
+    track: usize,
+    clip: ClipIndex,
+    pattern: usize,
+    instrument: usize,
+    step: usize,
+    hit: Option<Hit>,
+  },
+  SetDrumChain {
+    track: usize,
+    clip: ClipIndex,
+    chain: Vec<ChainEntry>,
+  },
+
+  /// The tracker clips are compiled before, so the audio thread only swaps them in
+  AddTrackerClip {
+    track: usize,
+    clip: TrackerClip,
+  },
+  SetTrackerClip {
+    track: usize,
+    clip: TrackerClip,
+  },
+
+  /// The module is imported before, so the audio thread only adds the track
+  ImportModule(ImportedModule),
+
+  SetSlot {
+    track: usize,
+    scene: usize,
+    slot: Option<Slot>,
+  },
+  AddScene(Scene),
+  LaunchSlot {
+    track: usize,
+    scene: usize,
+  },
+  LaunchScene(usize),
+  StopTrack(usize),
+  StopAllClips,
+  BackToArrangement,
 }
 
 struct ReceiverMidiInput {
@@ -45,11 +166,19 @@ impl MidiInput for ReceiverMidiInput {
 
 struct SenderMidiOutput {
   tx: Sender<MidiIoProtocol>,
+  panic_sender: PanicSender,
+}
+
+impl SenderMidiOutput {
+  fn new(tx: Sender<MidiIoProtocol>, panic_sender: PanicSender) -> Self {
+    SenderMidiOutput { tx, panic_sender }
+  }
 }
 
 impl SenderMidiOutput {
-  fn new(tx: Sender<MidiIoProtocol>) -> Self {
-    SenderMidiOutput { tx }
+  /// The port is closed after the events pushed before
+  fn close_endpoint(&mut self, id: EndpointId) {
+    drop(self.tx.send(MidiIoProtocol::CloseEndpoint(id)))
   }
 }
 
@@ -58,6 +187,10 @@ impl MidiOutput for SenderMidiOutput {
     let msg = MidiIoProtocol::EventOut(event);
     drop(self.tx.send(msg))
   }
+
+  fn panic(&mut self) {
+    self.panic_sender.send()
+  }
 }
 
 pub enum AudioCallbackResult {
@@ -70,6 +203,7 @@ pub struct AudioCallback {
   protocol_rx: Receiver<Protocol>,
   midi_input: ReceiverMidiInput,
   midi_output: SenderMidiOutput,
+  closing_endpoint: Option<EndpointId>,
 }
 
 impl AudioCallback {
@@ -78,12 +212,14 @@ impl AudioCallback {
     protocol_rx: Receiver<Protocol>,
     midi_out_tx: Sender<MidiIoProtocol>,
     midi_in_rx: Receiver<MidiIoProtocol>,
+    panic_sender: PanicSender,
   ) -> AudioCallback {
     AudioCallback {
       studio,
       protocol_rx,
       midi_input: ReceiverMidiInput::new(midi_in_rx),
-      midi_output: SenderMidiOutput::new(midi_out_tx),
+      midi_output: SenderMidiOutput::new(midi_out_tx, panic_sender),
+      closing_endpoint: None,
     }
   }
 
@@ -104,6 +240,10 @@ impl AudioCallback {
       &mut self.midi_output,
     );
 
+    if let Some(id) = self.closing_endpoint.take() {
+      self.midi_output.close_endpoint(id);
+    }
+
     Ok(result)
   }
 
@@ -116,7 +256,165 @@ impl AudioCallback {
 
   fn handle_message(&mut self, msg: Protocol) -> Result<AudioCallbackResult, CallbackError> {
     match msg {
-      Protocol::Stop => Ok(AudioCallbackResult::Stop),
+      Protocol::Stop => return Ok(AudioCallbackResult::Stop),
+
+      Protocol::Panic => {
+        self.studio.panic();
+      }
+      Protocol::SetSyncMode(mode) => {
+        self.studio.set_sync_mode(mode);
+      }
+
+      Protocol::RemoveMidiOutput(id) => {
+        self.studio.remove_endpoint(Endpoint::Id(id));
+        self.closing_endpoint = Some(id);
+      }
+
+      Protocol::SetMarkers(markers) => {
+        self.studio.song_mut().set_markers(markers);
+      }
+      Protocol::LocateMarker(index) => {
+        self.studio.locate_marker(index);
+      }
+      Protocol::LocateNextMarker => {
+        self.studio.locate_next_marker();
+      }
+      Protocol::LocatePreviousMarker => {
+        self.studio.locate_previous_marker();
+      }
+
+      Protocol::SetLocator(number, position) => {
+        self.studio.set_locator(number, position);
+      }
+      Protocol::LoopFromLocators(first, second) => {
+        self.studio.set_loop_from_locators(first, second);
+      }
+      Protocol::PunchFromLocators(first, second) => {
+        self.studio.set_punch_from_locators(first, second);
+      }
+
+      Protocol::SelectTrack(index) => {
+        self.studio.song_mut().select_track(index);
+      }
+      Protocol::AddCapturedClip(captured) => {
+        self.studio.add_captured_clip(captured);
+      }
+      Protocol::ArmTrack { track, take } => {
+        self.studio.song_mut().arm_track(track, take);
+      }
+
+      Protocol::AddStepsClip { track, clip, lanes } => {
+        self.studio.song_mut().add_steps_clip(track, clip, lanes);
+      }
+      Protocol::SetStepsLane {
+        track,
+        clip,
+        lane,
+        value,
+      } => {
+        if let Some(steps_clip) = self.studio.song_mut().get_steps_clip_mut(track, clip) {
+          steps_clip.set_lane(lane, value);
+        }
+      }
+      Protocol::RemoveStepsLane { track, clip, lane } => {
+        if let Some(steps_clip) = self.studio.song_mut().get_steps_clip_mut(track, clip) {
+          steps_clip.remove_lane(lane);
+        }
+      }
+      Protocol::SetStep {
+        track,
+        clip,
+        lane,
+        step,
+        value,
+      } => {
+        if let Some(steps_clip) = self.studio.song_mut().get_steps_clip_mut(track, clip) {
+          steps_clip.set_step(lane, step, value);
+        }
+      }
+
+      Protocol::AddDrumClip {
+        track,
+        clip,
+        kit,
+        patterns,
+      } => {
+        self.studio.song_mut().add_drum_clip(track, clip, kit, patterns);
+      }
+      Protocol::SetDrumKit { track, clip, kit } => {
+        if let Some(drum_clip) = self.studio.song_mut().get_drum_clip_mut(track, clip) {
+          drum_clip.set_kit(kit);
+        }
+      }
+      Protocol::SetDrumPattern {
+        track,
+        clip,
+        pattern,
+        value,
+      } => {
+        if let Some(drum_clip) = self.studio.song_mut().get_drum_clip_mut(track, clip) {
+          drum_clip.set_pattern(pattern, value);
+        }
+      }
+      Protocol::SetDrumHit {
+        track,
+        clip,
+        pattern,
+        instrument,
+        step,
+        hit,
+      } => {
+        if let Some(drum_clip) = self.studio.song_mut().get_drum_clip_mut(track, clip) {
+          drum_clip.set_hit(pattern, instrument, step, hit);
+        }
+      }
+      Protocol::SetDrumChain { track, clip, chain } => {
+        if let Some(drum_clip) = self.studio.song_mut().get_drum_clip_mut(track, clip) {
+          drum_clip.set_chain(chain);
+        }
+      }
+
+      Protocol::AddTrackerClip { track, clip } => {
+        self.studio.song_mut().add_tracker_clip(track, clip);
+      }
+      Protocol::SetTrackerClip { track, clip } => {
+        self.studio.song_mut().replace_tracker_clip(track, clip);
+      }
+
+      Protocol::ImportModule(imported) => {
+        self.studio.import_module(imported);
+      }
+
+      Protocol::SetSlot { track, scene, slot } => {
+        self.studio.song_mut().set_slot(track, scene, slot);
+      }
+      Protocol::AddScene(scene) => {
+        self.studio.song_mut().get_session_mut().add_scene(scene);
+      }
+      Protocol::LaunchSlot { track, scene } => {
+        self
+          .studio
+          .song_mut()
+          .get_session_mut()
+          .launch_slot(track, scene);
+      }
+      Protocol::LaunchScene(scene) => {
+        self.studio.song_mut().get_session_mut().launch_scene(scene);
+      }
+      Protocol::StopTrack(track) => {
+        self.studio.song_mut().get_session_mut().stop_track(track);
+      }
+      Protocol::StopAllClips => {
+        self.studio.song_mut().get_session_mut().stop_all();
+      }
+      Protocol::BackToArrangement => {
+        self
+          .studio
+          .song_mut()
+          .get_session_mut()
+          .back_to_arrangement();
+      }
     }
+    Ok(AudioCallbackResult::Continue)
   }
 }
@@ -0,0 +1,554 @@
+use std::collections::HashMap;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use failure::Fail;
+use serde_derive::Deserialize;
+
+use hero_studio_core::config::SyncMode;
+use hero_studio_core::midi::capture::MidiCapture;
+use hero_studio_core::song::clips::{
+  drumbox::{ChainEntry, Hit, Kit, Pattern, MAX_INSTRUMENTS, MAX_STEPS},
+  stepper::{Lane, Step},
+  tracker::{Tracker, TrackerClip},
+  Clip, ClipId, ClipIndex,
+};
+use hero_studio_core::song::import::Module;
+use hero_studio_core::song::markers::{LocatorNumber, Marker, Markers};
+use hero_studio_core::song::session::{Scene, Slot, MAX_FOLLOW_LOOPS, MAX_SCENES};
+use hero_studio_core::song::source::audio::AudioDataSource;
+use hero_studio_core::time::{BarsTime, Signature, TicksTime};
+
+use crate::audio::callback::Protocol as AudioProtocol;
+use crate::midi::io::Protocol as MidiIoProtocol;
+
+#[derive(Debug, Fail)]
+pub enum CommandError {
+  #[fail(display = "Invalid command: {}", cause)]
+  InvalidFormat { cause: String },
+
+  #[fail(display = "Out of range: {}", cause)]
+  OutOfRange { cause: String },
+
+  #[fail(display = "Unknown tracker clip {} in the track {}", clip, track)]
+  UnknownTrackerClip { track: usize, clip: ClipId },
+
+  #[fail(display = "Nothing was played to capture")]
+  NothingCaptured,
+
+  #[fail(display = "Failed to import the module {}: {}", path, cause)]
+  Import { path: String, cause: String },
+}
+
+pub type CommandResult<T> = Result<T, CommandError>;
+
+/// Commands sent by the clients of the server, as JSON objects with the name of the command in `command`,
+/// like `{"command": "LocateMarker", "index": 2}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command")]
+pub enum Command {
+  /// Silence all the MIDI outputs
+  Panic,
+  /// Follow the MIDI clock or timecode of the input, or the internal clock, like `{"command": "SetSyncMode", "mode": "midi_clock"}`
+  SetSyncMode {
+    mode: SyncMode,
+  },
+
+  AddMarker(Marker),
+  RemoveMarker {
+    index: usize,
+  },
+  LocateMarker {
+    index: usize,
+  },
+  LocateNextMarker,
+  LocatePreviousMarker,
+
+  /// The position is shown like `2.1.1.0`, counting the bars, beats and sixteenths from 1
+  SetLocator {
+    number: LocatorNumber,
+    position: BarsTime,
+  },
+  LoopFromLocators {
+    first: LocatorNumber,
+    second: LocatorNumber,
+  },
+  PunchFromLocators {
+    first: LocatorNumber,
+    second: LocatorNumber,
+  },
+
+  UpdateMidiEndpoints,
+
+  /// Track where the MIDI input is captured
+  SelectTrack {
+    track: Option<usize>,
+  },
+  /// Turn the last phrase played on the MIDI input into a clip of the selected track
+  CaptureMidi,
+  /// Arm a track to record the MIDI input while the transport records.
+  /// Arming again prepares the clip for another new take once the previous one was used.
+  ArmTrack {
+    track: usize,
+    rec: bool,
+  },
+
+  /// The resolution of the lanes is a note value like `{"note_value": 8, "notes": 3, "space": 2}`
+  AddStepsClip {
+    track: usize,
+    clip: Clip,
+    lanes: Vec<Lane>,
+  },
+  SetStepsLane {
+    track: usize,
+    clip: ClipIndex,
+    lane: usize,
+    value: Lane,
+  },
+  RemoveStepsLane {
+    track: usize,
+    clip: ClipIndex,
+    lane: usize,
+  },
+  /// The fields missing from the step keep their default value
+  SetStep {
+    track: usize,
+    clip: ClipIndex,
+    lane: usize,
+    step: usize,
+    value: Step,
+  },
+
+  AddDrumClip {
+    track: usize,
+    clip: Clip,
+    kit: Kit,
+    patterns: Vec<Pattern>,
+  },
+  SetDrumKit {
+    track: usize,
+    clip: ClipIndex,
+    kit: Kit,
+  },
+  SetDrumPattern {
+    track: usize,
+    clip: ClipIndex,
+    pattern: usize,
+    value: Pattern,
+  },
+  SetDrumHit {
+    track: usize,
+    clip: ClipIndex,
+    pattern: usize,
+    instrument: usize,
+    step: usize,
+    hit: Option<Hit>,
+  },
+  SetDrumChain {
+    track: usize,
+    clip: ClipIndex,
+    chain: Vec<ChainEntry>,
+  },
+
+  /// The tracker is the text of a tracker file.
+  /// The tracker clips are edited with the uuid of the clip they were added with.
+  AddTrackerClip {
+    track: usize,
+    clip: Clip,
+    tracker: String,
+  },
+  SetTracker {
+    track: usize,
+    clip: ClipId,
+    tracker: String,
+  },
+  /// The cell is shown like `C#4 01 7F A0F`
+  SetTrackerCell {
+    track: usize,
+    clip: ClipId,
+    pattern: usize,
+    line: usize,
+    column: usize,
+    cell: String,
+  },
+  InsertTrackerCell {
+    track: usize,
+    clip: ClipId,
+    pattern: usize,
+    line: usize,
+    column: usize,
+  },
+  DeleteTrackerCell {
+    track: usize,
+    clip: ClipId,
+    pattern: usize,
+    line: usize,
+    column: usize,
+  },
+  SetTrackerSequence {
+    track: usize,
+    clip: ClipId,
+    sequence: Vec<usize>,
+  },
+
+  /// Add a ProTracker or FastTracker module file as a new track
+  ImportModule {
+    path: String,
+  },
+
+  SetSlot {
+    track: usize,
+    scene: usize,
+    slot: Option<Slot>,
+  },
+  AddScene(Scene),
+  LaunchSlot {
+    track: usize,
+    scene: usize,
+  },
+  LaunchScene {
+    scene: usize,
+  },
+  StopTrack {
+    track: usize,
+  },
+  StopAllClips,
+  BackToArrangement,
+}
+
+/// Copies of the parts of the song that are edited here, and replace the ones of the audio thread.
+/// The tracker clips are compiled here too, the captured input is turned into clips
+/// and the samples of the imports are added to the audio source of the song.
+pub struct SongCopy {
+  tracker_clips: HashMap<(usize, ClipId), TrackerClip>,
+  markers: Markers,
+  midi_capture: Arc<RwLock<MidiCapture>>,
+  audio_source: Arc<RwLock<AudioDataSource>>,
+}
+
+impl SongCopy {
+  pub fn new(
+    midi_capture: Arc<RwLock<MidiCapture>>,
+    audio_source: Arc<RwLock<AudioDataSource>>,
+  ) -> SongCopy {
+    SongCopy {
+      tracker_clips: HashMap::new(),
+      markers: Markers::new(),
+      midi_capture,
+      audio_source,
+    }
+  }
+
+  fn add_tracker_clip(&mut self, track: usize, tracker_clip: TrackerClip) {
+    let key = (track, tracker_clip.get_clip().uuid);
+    self.tracker_clips.insert(key, tracker_clip);
+  }
+
+  fn edit_tracker_clip<F>(
+    &mut self,
+    track: usize,
+    clip: ClipId,
+    edit: F,
+  ) -> CommandResult<AudioProtocol>
+  where
+    F: FnOnce(&mut TrackerClip),
+  {
+    let tracker_clip = self
+      .tracker_clips
+      .get_mut(&(track, clip))
+      .ok_or(CommandError::UnknownTrackerClip { track, clip })?;
+    edit(tracker_clip);
+    Ok(AudioProtocol::SetTrackerClip {
+      track,
+      clip: tracker_clip.clone(),
+    })
+  }
+}
+
+/// The thread that handles a command
+pub enum Target {
+  Audio(AudioProtocol),
+  Midi(MidiIoProtocol),
+}
+
+impl Command {
+  pub fn decode(data: &[u8]) -> CommandResult<Command> {
+    serde_json::from_slice(data).map_err(invalid_format)
+  }
+
+  /// The message for the thread handling the command.
+  /// Anything expensive to build is done here and not in the audio thread.
+  pub fn into_target(self, song: &mut SongCopy) -> CommandResult<Target> {
+    let protocol = match self {
+      Command::Panic => AudioProtocol::Panic,
+      Command::SetSyncMode { mode } => AudioProtocol::SetSyncMode(mode),
+
+      Command::AddMarker(marker) => {
+        song.markers.add(marker);
+        AudioProtocol::SetMarkers(song.markers.clone())
+      }
+      Command::RemoveMarker { index } => {
+        check_index("marker", index, song.markers.len())?;
+        song.markers.remove(index);
+        AudioProtocol::SetMarkers(song.markers.clone())
+      }
+      Command::LocateMarker { index } => AudioProtocol::LocateMarker(index),
+      Command::LocateNextMarker => AudioProtocol::LocateNextMarker,
+      Command::LocatePreviousMarker => AudioProtocol::LocatePreviousMarker,
+
+      Command::SetLocator { number, position } => AudioProtocol::SetLocator(number, position),
+      Command::LoopFromLocators { first, second } => AudioProtocol::LoopFromLocators(first, second),
+      Command::PunchFromLocators { first, second } => {
+        AudioProtocol::PunchFromLocators(first, second)
+      }
+
+      Command::UpdateMidiEndpoints => return Ok(Target::Midi(MidiIoProtocol::UpdateEndpoints)),
+
+      Command::SelectTrack { track } => AudioProtocol::SelectTrack(track),
+      Command::CaptureMidi => {
+        // the audio thread only waits for the copy of the events
+        let phrase = song
+          .midi_capture
+          .read()
+          .unwrap_or_else(PoisonError::into_inner)
+          .copy_last_phrase();
+        let captured = phrase.into_clip().ok_or(CommandError::NothingCaptured)?;
+        AudioProtocol::AddCapturedClip(captured)
+      }
+      Command::ArmTrack { track, rec } => {
+        let take = Clip {
+          uuid: 0,
+          name: "Recording".to_string(),
+          signature: Signature::new(4, 4),
+          start: TicksTime::zero(),
+          length: TicksTime::zero(),
+        };
+        AudioProtocol::ArmTrack {
+          track,
+          take: Some(take).filter(|_| rec),
+        }
+      }
+
+      Command::AddStepsClip { track, clip, lanes } => {
+        AudioProtocol::AddStepsClip { track, clip, lanes }
+      }
+      Command::SetStepsLane {
+        track,
+        clip,
+        lane,
+        value,
+      } => AudioProtocol::SetStepsLane {
+        track,
+        clip,
+        lane,
+        value,
+      },
+      Command::RemoveStepsLane { track, clip, lane } => {
+        AudioProtocol::RemoveStepsLane { track, clip, lane }
+      }
+      Command::SetStep {
+        track,
+        clip,
+        lane,
+        step,
+        value,
+      } => AudioProtocol::SetStep {
+        track,
+        clip,
+        lane,
+        step,
+        value,
+      },
+
+      Command::AddDrumClip {
+        track,
+        clip,
+        kit,
+        patterns,
+      } => {
+        check_kit(&kit)?;
+        patterns.iter().try_for_each(check_pattern)?;
+        AudioProtocol::AddDrumClip {
+          track,
+          clip,
+          kit,
+          patterns,
+        }
+      }
+      Command::SetDrumKit { track, clip, kit } => {
+        check_kit(&kit)?;
+        AudioProtocol::SetDrumKit { track, clip, kit }
+      }
+      Command::SetDrumPattern {
+        track,
+        clip,
+        pattern,
+        value,
+      } => {
+        check_pattern(&value)?;
+        AudioProtocol::SetDrumPattern {
+          track,
+          clip,
+          pattern,
+          value,
+        }
+      }
+      Command::SetDrumHit {
+        track,
+        clip,
+        pattern,
+        instrument,
+        step,
+        hit,
+      } => {
+        check_index("instrument", instrument, MAX_INSTRUMENTS)?;
+        check_index("step", step, MAX_STEPS)?;
+        AudioProtocol::SetDrumHit {
+          track,
+          clip,
+          pattern,
+          instrument,
+          step,
+          hit,
+        }
+      }
+      Command::SetDrumChain { track, clip, chain } => {
+        AudioProtocol::SetDrumChain { track, clip, chain }
+      }
+
+      Command::AddTrackerClip {
+        track,
+        clip,
+        tracker,
+      } => {
+        let tracker_clip = TrackerClip::new(clip, parse_tracker(&tracker)?);
+        song.add_tracker_clip(track, tracker_clip.clone());
+        AudioProtocol::AddTrackerClip {
+          track,
+          clip: tracker_clip,
+        }
+      }
+      Command::SetTracker {
+        track,
+        clip,
+        tracker,
+      } => {
+        let tracker = parse_tracker(&tracker)?;
+        song.edit_tracker_clip(track, clip, |tracker_clip| {
+          tracker_clip.set_tracker(tracker)
+        })?
+      }
+      Command::SetTrackerCell {
+        track,
+        clip,
+        pattern,
+        line,
+        column,
+        cell,
+      } => {
+        let cell = cell.parse().map_err(invalid_format)?;
+        song.edit_tracker_clip(track, clip, |tracker_clip| {
+          tracker_clip.set_cell(pattern, line, column, cell)
+        })?
+      }
+      Command::InsertTrackerCell {
+        track,
+        clip,
+        pattern,
+        line,
+        column,
+      } => song.edit_tracker_clip(track, clip, |tracker_clip| {
+        tracker_clip.insert_cell(pattern, line, column)
+      })?,
+      Command::DeleteTrackerCell {
+        track,
+        clip,
+        pattern,
+        line,
+        column,
+      } => song.edit_tracker_clip(track, clip, |tracker_clip| {
+        tracker_clip.delete_cell(pattern, line, column)
+      })?,
+      Command::SetTrackerSequence {
+        track,
+        clip,
+        sequence,
+      } => song.edit_tracker_clip(track, clip, |tracker_clip| {
+        tracker_clip.set_sequence(sequence)
+      })?,
+
+      Command::ImportModule { path } => {
+        let module = Module::open(&path).map_err(|err| CommandError::Import {
+          path: path.clone(),
+          cause: err.to_string(),
+        })?;
+        AudioProtocol::ImportModule(module.import(&song.audio_source))
+      }
+
+      Command::SetSlot { track, scene, slot } => {
+        check_index("scene", scene, MAX_SCENES)?;
+        if let Some(slot) = slot.as_ref() {
+          check_count(
+            "follow loops",
+            slot.follow_loops as usize,
+            MAX_FOLLOW_LOOPS as usize,
+          )?;
+        }
+        AudioProtocol::SetSlot { track, scene, slot }
+      }
+      Command::AddScene(scene) => AudioProtocol::AddScene(scene),
+      Command::LaunchSlot { track, scene } => AudioProtocol::LaunchSlot { track, scene },
+      Command::LaunchScene { scene } => AudioProtocol::LaunchScene(scene),
+      Command::StopTrack { track } => AudioProtocol::StopTrack(track),
+      Command::StopAllClips => AudioProtocol::StopAllClips,
+      Command::BackToArrangement => AudioProtocol::BackToArrangement,
+    };
+    Ok(Target::Audio(protocol))
+  }
+}
+
+fn invalid_format<E>(err: E) -> CommandError
+where
+  E: ToString,
+{
+  CommandError::InvalidFormat {
+    cause: err.to_string(),
+  }
+}
+
+/// The indices and sizes from the clients are limited, so the audio thread doesn't allocate too much
+fn check_index(name: &str, index: usize, count: usize) -> CommandResult<()> {
+  if index < count {
+    Ok(())
+  } else {
+    Err(CommandError::OutOfRange {
+      cause: format!("The {} {} is not below {}", name, index, count),
+    })
+  }
+}
+
+fn check_count(name: &str, count: usize, max: usize) -> CommandResult<()> {
+  if count <= max {
+    Ok(())
+  } else {
+    Err(CommandError::OutOfRange {
+      cause: format!("{} {} are more than {}", count, name, max),
+    })
+  }
+}
+
+fn check_kit(kit: &Kit) -> CommandResult<()> {
+  check_count("instruments", kit.instruments.len(), MAX_INSTRUMENTS)
+}
+
+fn check_pattern(pattern: &Pattern) -> CommandResult<()> {
+  check_count("steps", pattern.num_steps, MAX_STEPS)?;
+  check_count("rows", pattern.rows.len(), MAX_INSTRUMENTS)?;
+  for row in pattern.rows.iter() {
+    check_count("steps", row.len(), MAX_STEPS)?;
+  }
+  Ok(())
+}
+
+fn parse_tracker(content: &str) -> CommandResult<Tracker> {
+  Tracker::from_toml(content).map_err(invalid_format)
+}
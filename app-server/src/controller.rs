@@ -1,11 +1,17 @@
+use std::sync::{Arc, RwLock};
 use std::thread;
 use std::thread::JoinHandle;
 
 use crossbeam_channel::{Receiver, Sender};
 use failure::Fail;
-use log::{debug, info};
+use log::{debug, error, info};
+
+use hero_studio_core::midi::capture::MidiCapture;
+use hero_studio_core::song::source::audio::AudioDataSource;
 
 use crate::audio::callback::Protocol as AudioProtocol;
+use crate::commands::{Command, SongCopy, Target};
+use crate::midi::endpoints::EndpointId;
 use crate::midi::io::Protocol as MidiOutputProtocol;
 use crate::server::Message as ServerMessage;
 
@@ -24,16 +30,29 @@ pub enum Protocol {
   ServerInput(ServerMessage),
 
   MidiInitialised,
+
+  /// An output port disappeared, and it is closed once its notes are released
+  MidiOutputRemoved(EndpointId),
 }
 
 struct ControllerThread {
   audio_tx: Sender<AudioProtocol>,
   midi_tx: Sender<MidiOutputProtocol>,
+  song: SongCopy,
 }
 
 impl ControllerThread {
-  fn new(audio_tx: Sender<AudioProtocol>, midi_tx: Sender<MidiOutputProtocol>) -> ControllerThread {
-    ControllerThread { audio_tx, midi_tx }
+  fn new(
+    audio_tx: Sender<AudioProtocol>,
+    midi_tx: Sender<MidiOutputProtocol>,
+    midi_capture: Arc<RwLock<MidiCapture>>,
+    audio_source: Arc<RwLock<AudioDataSource>>,
+  ) -> ControllerThread {
+    ControllerThread {
+      audio_tx,
+      midi_tx,
+      song: SongCopy::new(midi_capture, audio_source),
+    }
   }
 
   pub fn handle_messages(&mut self, protocol_rx: Receiver<Protocol>) {
@@ -45,11 +64,25 @@ impl ControllerThread {
           break;
         }
 
+        Protocol::ServerInput(ServerMessage::Incoming { data, port }) => {
+          let target =
+            Command::decode(&data).and_then(|command| command.into_target(&mut self.song));
+          match target {
+            Ok(Target::Audio(protocol)) => drop(self.audio_tx.send(protocol)),
+            Ok(Target::Midi(protocol)) => drop(self.midi_tx.send(protocol)),
+            Err(err) => error!("Failed to handle a command from {}: {}", port, err),
+          }
+        }
+
         Protocol::ServerInput(message) => {
           debug!("Received {:#?}", message);
         }
 
         Protocol::MidiInitialised => {}
+
+        Protocol::MidiOutputRemoved(id) => {
+          drop(self.audio_tx.send(AudioProtocol::RemoveMidiOutput(id)));
+        }
       }
     }
   }
@@ -73,12 +106,17 @@ impl Controller {
     protocol_rx: Receiver<Protocol>,
     audio_tx: Sender<AudioProtocol>,
     midi_tx: Sender<MidiOutputProtocol>,
+    midi_capture: Arc<RwLock<MidiCapture>>,
+    audio_source: Arc<RwLock<AudioDataSource>>,
   ) -> Result<Controller, ControllerError> {
     info!("Starting Controller ...");
 
     thread::Builder::new()
       .name("controller".into())
-      .spawn(move || ControllerThread::new(audio_tx, midi_tx).handle_messages(protocol_rx))
+      .spawn(move || {
+        ControllerThread::new(audio_tx, midi_tx, midi_capture, audio_source)
+          .handle_messages(protocol_rx)
+      })
       .map_err(|err| ControllerError::Start {
         cause: err.to_string(),
       })
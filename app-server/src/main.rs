@@ -13,12 +13,14 @@ mod config;
 use crate::config::Config as AppConfig;
 
 mod midi;
-use crate::midi::io::{MidiIo, Protocol as MidiOutputProtocol};
+use crate::midi::io::{MidiIo, PanicSender, Protocol as MidiOutputProtocol};
 
 mod audio;
 use crate::audio::callback::{AudioCallback, Protocol as AudioProtocol};
 use crate::audio::drivers::portaudio::{PortAudioDriver, PortAudioStream};
 
+mod commands;
+
 mod controller;
 use crate::controller::{Controller, Protocol as ControllerProtocol};
 
@@ -72,17 +74,22 @@ fn main() -> Result<(), Error> {
   )?;
 
   let studio = init_studio(studio_config)?;
+  let midi_capture = studio.get_midi_capture().clone();
+  let audio_source = studio.song().get_audio_source().clone();
 
   let (_, mut stream) = init_audio(studio,
                                    audio_rx.clone(),
                                    midi_out_tx.clone(),
-                                   midi_in_rx.clone())?;
+                                   midi_in_rx.clone(),
+                                   midi_output.panic_sender())?;
 
   let controller = Controller::new(
     ctrl_tx.clone(),
     ctrl_rx.clone(),
     audio_tx.clone(),
     midi_out_tx.clone(),
+    midi_capture,
+    audio_source,
   )?;
 
   let server = init_server(
@@ -166,13 +173,14 @@ fn init_audio(
   audio_rx: Receiver<AudioProtocol>,
   midi_out_tx: Sender<MidiOutputProtocol>,
   midi_in_rx: Receiver<MidiOutputProtocol>,
+  panic_sender: PanicSender,
 ) -> Result<(Rc<PortAudioDriver>, PortAudioStream), Error> {
   info!("Initialising audio ...");
 
   let audio_config = &studio.config().audio.clone();
 
   let driver = PortAudioDriver::new().map(Rc::new)?;
-  let audio_callback = AudioCallback::new(studio, audio_rx, midi_out_tx, midi_in_rx);
+  let audio_callback = AudioCallback::new(studio, audio_rx, midi_out_tx, midi_in_rx, panic_sender);
   let mut stream = PortAudioStream::new(driver.clone(), audio_config, audio_callback)?;
   stream.start()?;
 
@@ -1,4 +1,6 @@
 use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
 
@@ -11,6 +13,7 @@ use hero_studio_core::time::ClockTime;
 
 use hero_studio_core::config::{Audio as AudioConfig, Midi as MidiConfig};
 use hero_studio_core::midi::buffer::{Buffer, Endpoint, EventIo};
+use hero_studio_core::midi::panic;
 
 use crate::controller::Protocol as StudioProtocol;
 use crate::midi::drivers::{MidiDriver, MidiDrivers, MidiOutput as MidiOutputPort, MidiInput as MidiInputPort};
@@ -34,13 +37,41 @@ pub enum Protocol {
   EventOut(EventIo),
 
   EventIn(EventIo),
+
+  Panic,
+
+  /// Look for the ports that were added or removed
+  UpdateEndpoints,
+
+  /// Close an output port once the studio released its notes
+  CloseEndpoint(EndpointId),
+}
+
+/// Requests a panic to the MIDI IO thread.
+/// The events sent before the request that are still in the queue are dropped.
+#[derive(Clone)]
+pub struct PanicSender {
+  pending: Arc<AtomicUsize>,
+  tx: Sender<Protocol>,
+}
+
+impl PanicSender {
+  pub fn send(&self) {
+    self.pending.fetch_add(1, Ordering::SeqCst);
+    drop(self.tx.send(Protocol::Panic));
+  }
 }
 
 pub struct MidiIoThread {
-  _driver: Box<dyn MidiDriver>,
+  config: MidiConfig,
+  driver: Box<dyn MidiDriver>,
   endpoints_out: Endpoints<MidiOutputPort>,
-  _endpoints_in: Endpoints<MidiInputPort>,
+  endpoints_in: Endpoints<MidiInputPort>,
+  midi_in_tx: Sender<Protocol>,
+  studio_tx: Sender<StudioProtocol>,
   buffer: Option<Buffer>,
+  pending_panics: Arc<AtomicUsize>,
+  panic_note_offs: bool,
   _rta_priority: Option<RealTimeAudioPriority>,
 }
 
@@ -50,8 +81,9 @@ impl MidiIoThread {
     audio_config: &AudioConfig,
     midi_in_tx: Sender<Protocol>,
     studio_tx: Sender<StudioProtocol>,
+    pending_panics: Arc<AtomicUsize>,
   ) -> MidiIoThread {
-    let (driver, endpoints_out, endpoints_in) = Self::init_endpoints(config, midi_in_tx);
+    let (driver, endpoints_out, endpoints_in) = Self::init_endpoints(config, midi_in_tx.clone());
 
     drop(studio_tx.send(StudioProtocol::MidiInitialised));
 
@@ -59,10 +91,15 @@ impl MidiIoThread {
       RealTimeAudioPriority::promote(audio_config.sample_rate, audio_config.frames.into()).ok();
 
     MidiIoThread {
-      _driver: driver,
+      config: config.clone(),
+      driver,
       endpoints_out,
-      _endpoints_in: endpoints_in,
+      endpoints_in,
+      midi_in_tx,
+      studio_tx,
       buffer: Some(Buffer::with_capacity(1)),
+      pending_panics,
+      panic_note_offs: config.panic.note_offs,
       _rta_priority,
     }
   }
@@ -73,7 +110,26 @@ impl MidiIoThread {
     for message in protocol_rx.iter() {
       match message {
         Protocol::EventOut(event) => {
-          self.send_event(event);
+          // the events queued before a panic are not sent
+          if self.pending_panics.load(Ordering::SeqCst) == 0 {
+            self.send_event(event);
+          }
+        }
+
+        Protocol::Panic => {
+          self.send_panic();
+          self.pending_panics.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        Protocol::UpdateEndpoints => {
+          self.update_endpoints();
+        }
+
+        Protocol::CloseEndpoint(id) => {
+          let ids = std::iter::once(id).collect();
+          self
+            .endpoints_out
+            .remove(ids, |name, id| debug!("(-) {} [{}]", name, id));
         }
 
         Protocol::Stop => {
@@ -116,16 +172,49 @@ impl MidiIoThread {
     self.buffer = Some(buffer);
   }
 
+  /// Silence every channel of all the output endpoints
+  fn send_panic(&mut self) {
+    info!("Sending MIDI panic ...");
+
+    let mut buffer = self.buffer.take().unwrap();
+
+    for endpoint in self.endpoints_out.iter_mut() {
+      for message in panic::messages(self.panic_note_offs) {
+        buffer.reset().push(ClockTime::zero(), message);
+        endpoint.send(ClockTime::zero(), &buffer);
+      }
+    }
+
+    self.buffer = Some(buffer);
+  }
+
+  /// The output ports that disappeared are closed after the studio releases the notes sent to them
+  fn update_endpoints(&mut self) {
+    let removed = Self::update_endpoints_out(
+      &self.config,
+      self.driver.as_ref(),
+      &mut self.endpoints_out,
+    );
+    for id in removed {
+      drop(self.studio_tx.send(StudioProtocol::MidiOutputRemoved(id)));
+    }
+    Self::update_endpoints_in(
+      &self.config,
+      self.driver.as_ref(),
+      self.midi_in_tx.clone(),
+      &mut self.endpoints_in,
+    );
+  }
+
   // TODO This logic should go into another thread that will scan ports regularly and report back to this one
+  /// Open the new output ports, and return the ones that are not there anymore
   fn update_endpoints_out(
     _config: &MidiConfig,
     driver: &MidiDriver,
     endpoints_out: &mut Endpoints<MidiOutputPort>,
-  ) {
+  ) -> HashSet<EndpointId> {
     let mut unvisited: HashSet<EndpointId> = endpoints_out.ids().cloned().collect();
 
-    // TODO send the updates to the studio worker
-
     debug!("Updating output endpoints:");
     for destination in driver.destinations() {
       let name = destination.name();
@@ -139,7 +228,7 @@ impl MidiIoThread {
         error!("Error opening MIDI output port: {}", name);
       }
     }
-    endpoints_out.remove(unvisited, |name, id| debug!("(-) {} [{}]", name, id));
+    unvisited
   }
 
   fn update_endpoints_in(
@@ -208,6 +297,7 @@ impl MidiIoThread {
 pub struct MidiIo {
   handler: JoinHandle<()>,
   midi_out_tx: Sender<Protocol>,
+  pending_panics: Arc<AtomicUsize>,
 }
 
 impl MidiIo {
@@ -229,12 +319,20 @@ impl MidiIo {
 
     let cloned_config = config.clone();
     let cloned_audio_config = audio_config.clone();
+    let pending_panics = Arc::new(AtomicUsize::new(0));
+    let thread_pending_panics = pending_panics.clone();
 
     thread::Builder::new()
       .name("midi-io".into())
       .spawn(move || {
-        MidiIoThread::new(&cloned_config, &cloned_audio_config, midi_in_tx, studio_tx)
-          .handle_messages(midi_out_rx)
+        MidiIoThread::new(
+          &cloned_config,
+          &cloned_audio_config,
+          midi_in_tx,
+          studio_tx,
+          thread_pending_panics,
+        )
+        .handle_messages(midi_out_rx)
       })
       .map_err(|err| MidiIoError::Start {
         cause: err.to_string(),
@@ -242,9 +340,17 @@ impl MidiIo {
       .map(|handler| MidiIo {
         handler,
         midi_out_tx,
+        pending_panics,
       })
   }
 
+  pub fn panic_sender(&self) -> PanicSender {
+    PanicSender {
+      pending: self.pending_panics.clone(),
+      tx: self.midi_out_tx.clone(),
+    }
+  }
+
   pub fn stop(self) -> Result<(), MidiIoError> {
     info!("Stopping MIDI IO thread ...");
 
@@ -1,4 +1,5 @@
 pub mod buffer;
+pub mod wav;
 pub use buffer::{new_buffer_pool, Buffer};
 
 use crate::time::ClockTime;
@@ -0,0 +1,263 @@
+use std::fs::File;
+use std::io::Read;
+
+use failure::Fail;
+
+use crate::time::SampleRate;
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_IEEE_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+#[derive(Debug, Fail)]
+pub enum WavError {
+  #[fail(display = "Failed to read the WAV file: {}", cause)]
+  Io { cause: String },
+
+  #[fail(display = "Invalid WAV file: {}", text)]
+  InvalidFormat { text: String },
+
+  #[fail(display = "Unsupported WAV format: {}", text)]
+  Unsupported { text: String },
+}
+
+pub type WavResult<T> = Result<T, WavError>;
+
+/// Audio read from a WAV file, with the channels mixed down to a single one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wav {
+  sample_rate: SampleRate,
+  samples: Vec<f32>,
+}
+
+impl Wav {
+  pub fn open(path: &str) -> WavResult<Wav> {
+    let mut data = Vec::new();
+    File::open(path)
+      .and_then(|mut file| file.read_to_end(&mut data))
+      .map_err(|err| WavError::Io {
+        cause: err.to_string(),
+      })?;
+    Wav::decode(&data)
+  }
+
+  /// Decode the integer PCM formats from 8 to 32 bits, and the 32 bits floating point format
+  pub fn decode(data: &[u8]) -> WavResult<Wav> {
+    let invalid_format = |text: &str| WavError::InvalidFormat {
+      text: text.to_string(),
+    };
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+      return Err(invalid_format("missing the RIFF header"));
+    }
+
+    let mut format = None;
+    let mut samples = None;
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+      let id = &data[offset..offset + 4];
+      let size = read_u32(&data[offset + 4..]) as usize;
+      let start = offset + 8;
+      let end = start
+        .checked_add(size)
+        .filter(|end| *end <= data.len())
+        .ok_or_else(|| invalid_format("truncated chunk"))?;
+      match id {
+        b"fmt " => format = Some(Format::decode(&data[start..end])?),
+        b"data" => samples = Some(&data[start..end]),
+        _ => {}
+      }
+      // chunks are aligned to two bytes
+      offset = end + size % 2;
+    }
+
+    let format = format.ok_or_else(|| invalid_format("missing the fmt chunk"))?;
+    let samples = samples.ok_or_else(|| invalid_format("missing the data chunk"))?;
+    Ok(Wav {
+      sample_rate: format.sample_rate,
+      samples: format.decode_samples(samples),
+    })
+  }
+
+  pub fn get_sample_rate(&self) -> SampleRate {
+    self.sample_rate
+  }
+
+  pub fn get_samples(&self) -> &[f32] {
+    self.samples.as_slice()
+  }
+
+  /// Samples converted to another sample rate with linear interpolation
+  pub fn resample(&self, sample_rate: SampleRate) -> Vec<f32> {
+    if sample_rate == self.sample_rate || self.samples.is_empty() {
+      return self.samples.clone();
+    }
+    let ratio = f64::from(self.sample_rate) / f64::from(sample_rate);
+    let length = (self.samples.len() as f64 / ratio).round() as usize;
+    let last = self.samples.len() - 1;
+    (0..length)
+      .map(|index| {
+        let position = index as f64 * ratio;
+        let left = (position.floor() as usize).min(last);
+        let right = (left + 1).min(last);
+        let fraction = (position - left as f64) as f32;
+        self.samples[left] + (self.samples[right] - self.samples[left]) * fraction
+      })
+      .collect()
+  }
+}
+
+struct Format {
+  encoding: u16,
+  channels: usize,
+  sample_rate: SampleRate,
+  bits_per_sample: u16,
+}
+
+impl Format {
+  fn decode(data: &[u8]) -> WavResult<Format> {
+    if data.len() < 16 {
+      return Err(WavError::InvalidFormat {
+        text: "truncated fmt chunk".to_string(),
+      });
+    }
+    let mut encoding = read_u16(&data[0..]);
+    if encoding == FORMAT_EXTENSIBLE && data.len() >= 26 {
+      // the format is the first two bytes of the sub-format GUID
+      encoding = read_u16(&data[24..]);
+    }
+    let format = Format {
+      encoding,
+      channels: usize::from(read_u16(&data[2..])),
+      sample_rate: read_u32(&data[4..]),
+      bits_per_sample: read_u16(&data[14..]),
+    };
+    let supported = match format.encoding {
+      FORMAT_PCM => [8, 16, 24, 32].contains(&format.bits_per_sample),
+      FORMAT_IEEE_FLOAT => format.bits_per_sample == 32,
+      _ => false,
+    };
+    if supported && format.channels > 0 && format.sample_rate > 0 {
+      Ok(format)
+    } else {
+      Err(WavError::Unsupported {
+        text: format!(
+          "encoding {}, {} channels, {} bits",
+          format.encoding, format.channels, format.bits_per_sample
+        ),
+      })
+    }
+  }
+
+  fn decode_samples(&self, data: &[u8]) -> Vec<f32> {
+    let sample_size = usize::from(self.bits_per_sample / 8);
+    let frame_size = sample_size * self.channels;
+    data
+      .chunks_exact(frame_size)
+      .map(|frame| {
+        let sum: f32 = frame
+          .chunks_exact(sample_size)
+          .map(|sample| self.decode_sample(sample))
+          .sum();
+        sum / self.channels as f32
+      })
+      .collect()
+  }
+
+  fn decode_sample(&self, data: &[u8]) -> f32 {
+    match (self.encoding, self.bits_per_sample) {
+      (FORMAT_IEEE_FLOAT, _) => f32::from_bits(read_u32(data)),
+      // 8 bits samples are unsigned
+      (_, 8) => (f32::from(data[0]) - 128.0) / 128.0,
+      (_, 16) => f32::from(read_u16(data) as i16) / 32_768.0,
+      (_, 24) => {
+        let value = i32::from(data[0]) | i32::from(data[1]) << 8 | i32::from(data[2] as i8) << 16;
+        value as f32 / 8_388_608.0
+      }
+      _ => read_u32(data) as i32 as f32 / 2_147_483_648.0,
+    }
+  }
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+  u16::from_le_bytes([data[0], data[1]])
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+  u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{Wav, WavError};
+
+  fn wav_file(encoding: u16, channels: u16, bits: u16, samples: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(b"RIFF");
+    data.extend_from_slice(&(36 + samples.len() as u32).to_le_bytes());
+    data.extend_from_slice(b"WAVE");
+    data.extend_from_slice(b"fmt ");
+    data.extend_from_slice(&16u32.to_le_bytes());
+    data.extend_from_slice(&encoding.to_le_bytes());
+    data.extend_from_slice(&channels.to_le_bytes());
+    data.extend_from_slice(&22050u32.to_le_bytes());
+    let block_align = channels * bits / 8;
+    data.extend_from_slice(&(22050 * u32::from(block_align)).to_le_bytes());
+    data.extend_from_slice(&block_align.to_le_bytes());
+    data.extend_from_slice(&bits.to_le_bytes());
+    data.extend_from_slice(b"data");
+    data.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+    data.extend_from_slice(samples);
+    data
+  }
+
+  #[test]
+  pub fn decode_pcm() {
+    let samples = [0x00, 0x40, 0x00, 0xc0, 0xff, 0x7f, 0x00, 0x80];
+    let wav = Wav::decode(&wav_file(1, 1, 16, &samples)).unwrap();
+    assert_eq!(wav.get_sample_rate(), 22050);
+    assert_eq!(wav.get_samples(), &[0.5, -0.5, 32767.0 / 32768.0, -1.0]);
+
+    let samples = [0x00, 0x00, 0x40, 0x00, 0x00, 0xc0];
+    let wav = Wav::decode(&wav_file(1, 1, 24, &samples)).unwrap();
+    assert_eq!(wav.get_samples(), &[0.5, -0.5]);
+
+    let wav = Wav::decode(&wav_file(1, 1, 8, &[0xc0, 0x40])).unwrap();
+    assert_eq!(wav.get_samples(), &[0.5, -0.5]);
+  }
+
+  #[test]
+  pub fn decode_float_stereo_mixed_down() {
+    let mut samples = Vec::new();
+    for value in [0.5f32, 0.25, -1.0, 0.0].iter() {
+      samples.extend_from_slice(&value.to_bits().to_le_bytes());
+    }
+    let wav = Wav::decode(&wav_file(3, 2, 32, &samples)).unwrap();
+    assert_eq!(wav.get_samples(), &[0.375, -0.5]);
+  }
+
+  #[test]
+  pub fn decode_errors() {
+    match Wav::decode(b"RIFF0000AVI ") {
+      Err(WavError::InvalidFormat { .. }) => {}
+      result => panic!("Unexpected result: {:?}", result),
+    }
+    match Wav::decode(&wav_file(2, 1, 4, &[0, 0])) {
+      Err(WavError::Unsupported { .. }) => {}
+      result => panic!("Unexpected result: {:?}", result),
+    }
+    let mut truncated = wav_file(1, 1, 16, &[0, 0, 0, 0]);
+    truncated.truncate(truncated.len() - 2);
+    match Wav::decode(&truncated) {
+      Err(WavError::InvalidFormat { .. }) => {}
+      result => panic!("Unexpected result: {:?}", result),
+    }
+  }
+
+  #[test]
+  pub fn resample() {
+    let wav = Wav::decode(&wav_file(1, 1, 16, &[0x00, 0x00, 0x00, 0x40])).unwrap();
+    assert_eq!(wav.resample(22050), vec![0.0, 0.5]);
+    assert_eq!(wav.resample(44100), vec![0.0, 0.25, 0.5, 0.5]);
+  }
+}
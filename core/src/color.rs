@@ -1,3 +1,6 @@
+use serde_derive::Deserialize;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Color(String);
 
 impl Color {
@@ -30,5 +33,4 @@ mod test {
     let color = Color::from_rgb(10, 20, 30);
     assert_eq!(color.get_value(), "rgb(10,20,30)");
   }
-
 }
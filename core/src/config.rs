@@ -9,6 +9,8 @@ use std::io::Read;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 
+use crate::time::smpte::{FrameRate, SmpteResult, SmpteTime};
+
 pub type ConfigLock = Arc<RwLock<Config>>;
 
 #[serde(default)]
@@ -16,7 +18,17 @@ pub type ConfigLock = Arc<RwLock<Config>>;
 pub struct Config {
   pub audio: Audio,
   pub midi: Midi,
+  pub transport: Transport,
+  pub chase: Chase,
+  pub record: Record,
+  pub capture: Capture,
+  pub session: Session,
   pub metronome: Metronome,
+  pub midi_clock: MidiClock,
+  pub smpte: Smpte,
+  pub mtc: Mtc,
+  pub mmc: Mmc,
+  pub sync: Synchronization,
 }
 
 impl Default for Config {
@@ -24,7 +36,17 @@ impl Default for Config {
     Config {
       audio: Audio::default(),
       midi: Midi::default(),
+      transport: Transport::default(),
+      chase: Chase::default(),
+      record: Record::default(),
+      capture: Capture::default(),
+      session: Session::default(),
       metronome: Metronome::default(),
+      midi_clock: MidiClock::default(),
+      smpte: Smpte::default(),
+      mtc: Mtc::default(),
+      mmc: Mmc::default(),
+      sync: Synchronization::default(),
     }
   }
 }
@@ -34,6 +56,7 @@ impl FromStr for Config {
 
   fn from_str(content: &str) -> Result<Self, Self::Err> {
     let config: Config = toml::from_str(content)?;
+    config.smpte.offset()?;
     Ok(config)
   }
 }
@@ -48,6 +71,7 @@ impl Config {
     let mut file = File::open(path_str)?;
     file.read_to_string(&mut content)?;
     let config: Config = toml::from_str(&content)?;
+    config.smpte.offset()?;
     Ok(config)
   }
 }
@@ -89,6 +113,7 @@ pub struct Midi {
   pub default_input: MidiPort,
   pub default_output: MidiPort,
   pub virtual_ports: Vec<MidiVirtualPort>,
+  pub panic: MidiPanic,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -117,17 +142,143 @@ impl Default for Midi {
       default_input: MidiPort::All,
       default_output: MidiPort::SystemDefault,
       virtual_ports: Vec::new(),
+      panic: MidiPanic::default(),
+    }
+  }
+}
+
+/// How to silence all the outputs
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MidiPanic {
+  /// Send a note off for every key too, for the synths that ignore the all notes off controller
+  pub note_offs: bool,
+  /// Input message that triggers the panic
+  pub trigger: Option<MidiTrigger>,
+}
+
+/// Input message on any channel, unless one is given
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MidiTrigger {
+  #[serde(rename = "note")]
+  Note { channel: Option<u8>, key: u8 },
+  #[serde(rename = "controller")]
+  Controller { channel: Option<u8>, controller: u8 },
+}
+
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Transport {
+  pub count_in_bars: u16,
+  pub pre_roll_bars: u16,
+}
+
+/// What to send when the song starts playing from the middle of the notes and controls
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Chase {
+  /// Start the notes that began before the position
+  pub notes: bool,
+  /// Send the last controller, program and pitch bend values before the position
+  pub controls: bool,
+}
+
+impl Default for Chase {
+  fn default() -> Chase {
+    Chase {
+      notes: false,
+      controls: true,
     }
   }
 }
 
+/// How the MIDI input is recorded into the armed tracks
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Record {
+  /// Note value of the grid for the input quantize, like 16 for sixteenths, or 0 to keep the notes as played
+  pub quantize: u16,
+  /// From 0.0 (as played) to 1.0 (exactly at the grid)
+  pub quantize_strength: f64,
+}
+
+impl Default for Record {
+  fn default() -> Record {
+    Record {
+      quantize: 0,
+      quantize_strength: 1.0,
+    }
+  }
+}
+
+/// Background capture of the MIDI input, to turn what was played into a clip afterwards
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Capture {
+  pub enabled: bool,
+  /// Minutes of input kept
+  pub minutes: u32,
+  /// Events kept at most, the oldest ones are dropped first
+  pub max_events: usize,
+  /// Seconds of silence that separate the captured phrase from what was played before
+  pub gap_seconds: f64,
+}
+
+impl Default for Capture {
+  fn default() -> Capture {
+    Capture {
+      enabled: true,
+      minutes: 5,
+      max_events: 64 * 1024,
+      gap_seconds: 4.0,
+    }
+  }
+}
+
+/// Clips launched from the session grid
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Session {
+  pub launch_quantize: LaunchQuantize,
+}
+
+impl Default for Session {
+  fn default() -> Session {
+    Session {
+      launch_quantize: LaunchQuantize::Bar,
+    }
+  }
+}
+
+/// Where the launched clips start
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LaunchQuantize {
+  /// As soon as possible
+  #[serde(rename = "none")]
+  None,
+  /// At the next beat
+  #[serde(rename = "beat")]
+  Beat,
+  /// At the next bar
+  #[serde(rename = "bar")]
+  Bar,
+}
+
 #[serde(default)]
 #[derive(Deserialize, Debug, Clone)]
 pub struct Metronome {
   pub enabled: bool,
+  /// Click only during the count-in, even when enabled
+  pub count_in_only: bool,
+  pub midi: bool,
   pub port: MidiPort,
   pub bar_note: MetronomeNote,
   pub beat_note: MetronomeNote,
+  pub subdivision_note: MetronomeNote,
+  pub subdivision: MetronomeSubdivision,
+  /// Patterns for specific signatures, the other ones accent the first beat and click the rest
+  pub patterns: Vec<MetronomePattern>,
+  pub audio: MetronomeAudio,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -157,6 +308,8 @@ impl Default for Metronome {
   fn default() -> Metronome {
     Metronome {
       enabled: true,
+      count_in_only: false,
+      midi: true,
       port: MidiPort::SystemDefault,
       bar_note: MetronomeNote {
         channel: 0,
@@ -170,6 +323,195 @@ impl Default for Metronome {
         velocity: 120,
         duration: 16,
       },
+      subdivision_note: MetronomeNote {
+        channel: 0,
+        key: 77,
+        velocity: 80,
+        duration: 32,
+      },
+      subdivision: MetronomeSubdivision::None,
+      patterns: Vec::new(),
+      audio: MetronomeAudio::default(),
+    }
+  }
+}
+
+/// Clicks between the beats
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MetronomeSubdivision {
+  #[serde(rename = "none")]
+  None,
+  #[serde(rename = "8th")]
+  Eighths,
+  #[serde(rename = "16th")]
+  Sixteenths,
+  #[serde(rename = "triplet")]
+  Triplets,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MetronomeStep {
+  #[serde(rename = "accent")]
+  Accent,
+  #[serde(rename = "normal")]
+  Normal,
+  #[serde(rename = "silent")]
+  Silent,
+}
+
+/// Clicks for the bars of a signature, given either by the step of every beat
+/// or by the groups of beats of an odd meter, like 2+2+3 for 7/8
+#[derive(Deserialize, Debug, Clone)]
+pub struct MetronomePattern {
+  pub num_beats: u8,
+  pub note_value: u8,
+  #[serde(default)]
+  pub beats: Vec<MetronomeStep>,
+  #[serde(default)]
+  pub groups: Vec<u8>,
+}
+
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct MetronomeAudio {
+  pub enabled: bool,
+  pub volume: f32,
+  /// Volume of the accented clicks relative to the beat clicks
+  pub accent: f32,
+  /// Volume of the subdivision clicks relative to the beat clicks
+  pub subdivision: f32,
+  /// Output channels for the left and right sides of the click
+  pub channels: [usize; 2],
+  /// WAV files for the clicks, instead of the built-in ones
+  pub bar_sample: Option<String>,
+  pub beat_sample: Option<String>,
+}
+
+impl Default for MetronomeAudio {
+  fn default() -> MetronomeAudio {
+    MetronomeAudio {
+      enabled: false,
+      volume: 0.5,
+      accent: 1.0,
+      subdivision: 0.5,
+      channels: [0, 1],
+      bar_sample: None,
+      beat_sample: None,
+    }
+  }
+}
+
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct MidiClock {
+  pub enabled: bool,
+  pub ports: Vec<MidiPort>,
+}
+
+impl Default for MidiClock {
+  fn default() -> MidiClock {
+    MidiClock {
+      enabled: false,
+      ports: vec![MidiPort::SystemDefault],
+    }
+  }
+}
+
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Smpte {
+  pub frame_rate: SmpteFrameRate,
+  pub offset: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum SmpteFrameRate {
+  #[serde(rename = "24")]
+  Fps24,
+  #[serde(rename = "25")]
+  Fps25,
+  #[serde(rename = "29.97df")]
+  Fps2997Drop,
+  #[serde(rename = "30")]
+  Fps30,
+}
+
+impl Default for Smpte {
+  fn default() -> Smpte {
+    Smpte {
+      frame_rate: SmpteFrameRate::Fps25,
+      offset: "00:00:00:00".to_string(),
+    }
+  }
+}
+
+impl Smpte {
+  /// Timecode at the start of the song
+  pub fn offset(&self) -> SmpteResult<SmpteTime> {
+    SmpteTime::parse(&self.offset, FrameRate::from(self.frame_rate))
+  }
+}
+
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Mtc {
+  pub enabled: bool,
+  pub ports: Vec<MidiPort>,
+  pub input: MidiPort,
+}
+
+impl Default for Mtc {
+  fn default() -> Mtc {
+    Mtc {
+      enabled: false,
+      ports: vec![MidiPort::SystemDefault],
+      input: MidiPort::All,
+    }
+  }
+}
+
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Mmc {
+  pub receive: bool,
+  pub input: MidiPort,
+  pub device_id: u8,
+  pub send: bool,
+  pub ports: Vec<MidiPort>,
+}
+
+impl Default for Mmc {
+  fn default() -> Mmc {
+    Mmc {
+      receive: false,
+      input: MidiPort::All,
+      device_id: 0x7f,
+      send: false,
+      ports: vec![MidiPort::SystemDefault],
+    }
+  }
+}
+
+#[serde(default)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct Synchronization {
+  pub mode: SyncMode,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub enum SyncMode {
+  #[serde(rename = "internal")]
+  Internal,
+  #[serde(rename = "midi_clock")]
+  MidiClock,
+  #[serde(rename = "mtc")]
+  Mtc,
+}
+
+impl Default for Synchronization {
+  fn default() -> Synchronization {
+    Synchronization {
+      mode: SyncMode::Internal,
     }
   }
 }
@@ -11,5 +11,9 @@ pub mod midi;
 pub mod pool;
 pub mod song;
 pub mod studio;
+pub mod sync;
 pub mod time;
 pub mod transport;
+
+#[cfg(test)]
+mod testing;
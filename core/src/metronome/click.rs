@@ -0,0 +1,257 @@
+use std::f32::consts::PI;
+
+use log::warn;
+
+use crate::audio::wav::{Wav, WavResult};
+use crate::audio::AudioOutput;
+use crate::config::MetronomeAudio as MetronomeAudioConfig;
+use crate::time::{ClockTime, SampleRate};
+
+use super::pattern::ClickLevel;
+
+const MAX_SCHEDULED_CLICKS: usize = 64;
+const MAX_VOICES: usize = 16;
+
+const BAR_FREQUENCY: f32 = 1760.0;
+const BEAT_FREQUENCY: f32 = 1320.0;
+const CLICK_SECONDS: f32 = 0.05;
+const CLICK_DECAY_SECONDS: f32 = 0.008;
+
+/// Mono samples of a click at the studio sample rate
+#[derive(Debug, Clone)]
+pub struct ClickSound {
+  samples: Vec<f32>,
+}
+
+impl ClickSound {
+  /// A short sine with an exponential decay
+  pub fn synthesized(frequency: f32, sample_rate: SampleRate) -> ClickSound {
+    let sample_rate = sample_rate as f32;
+    let length = (CLICK_SECONDS * sample_rate) as usize;
+    let samples = (0..length)
+      .map(|index| {
+        let time = index as f32 / sample_rate;
+        (2.0 * PI * frequency * time).sin() * (-time / CLICK_DECAY_SECONDS).exp()
+      })
+      .collect();
+    ClickSound { samples }
+  }
+
+  pub fn from_wav(path: &str, sample_rate: SampleRate) -> WavResult<ClickSound> {
+    let wav = Wav::open(path)?;
+    Ok(ClickSound {
+      samples: wav.resample(sample_rate),
+    })
+  }
+
+  pub fn get_samples(&self) -> &[f32] {
+    self.samples.as_slice()
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+  level: ClickLevel,
+  delay: usize,
+  position: usize,
+}
+
+/// Mixes the clicks into the audio output at the sample where they are due,
+/// so they don't drift relative to the audio as the MIDI clicks do
+pub struct AudioClick {
+  sample_rate: SampleRate,
+  bar_sound: ClickSound,
+  beat_sound: ClickSound,
+  volume: f32,
+  accent: f32,
+  subdivision: f32,
+  channels: [usize; 2],
+  scheduled: Vec<(ClockTime, ClickLevel)>,
+  voices: Vec<Voice>,
+}
+
+impl AudioClick {
+  /// The built-in clicks are used when the WAV files can not be loaded, and the error is logged
+  pub fn new(config: &MetronomeAudioConfig, sample_rate: SampleRate) -> AudioClick {
+    let sound = |path: &Option<String>, frequency: f32| {
+      path
+        .as_ref()
+        .and_then(|path| match ClickSound::from_wav(path, sample_rate) {
+          Ok(sound) => Some(sound),
+          Err(err) => {
+            warn!("Failed to load the metronome click {}: {}", path, err);
+            None
+          }
+        })
+        .unwrap_or_else(|| ClickSound::synthesized(frequency, sample_rate))
+    };
+    AudioClick {
+      sample_rate,
+      bar_sound: sound(&config.bar_sample, BAR_FREQUENCY),
+      beat_sound: sound(&config.beat_sample, BEAT_FREQUENCY),
+      volume: config.volume.max(0.0),
+      accent: config.accent.max(0.0),
+      subdivision: config.subdivision.max(0.0),
+      channels: config.channels,
+      scheduled: Vec::with_capacity(MAX_SCHEDULED_CLICKS),
+      voices: Vec::with_capacity(MAX_VOICES),
+    }
+  }
+
+  pub fn set_volume(&mut self, volume: f32) {
+    self.volume = volume.max(0.0);
+  }
+
+  pub fn get_volume(&self) -> f32 {
+    self.volume
+  }
+
+  /// Schedule a click for the next buffer, where the accented ones use the bar sound
+  pub fn schedule(&mut self, time: ClockTime, level: ClickLevel) {
+    if self.scheduled.len() < MAX_SCHEDULED_CLICKS {
+      self.scheduled.push((time, level));
+    }
+  }
+
+  /// Start the clicks scheduled for this buffer and add the sounding ones to the output
+  pub fn render(&mut self, audio_output: &mut AudioOutput, frames: usize) {
+    let buffer_time = audio_output.time;
+    let sample_rate = self.sample_rate;
+    for (time, level) in self.scheduled.drain(..) {
+      let delay = if time > buffer_time {
+        (time - buffer_time).to_samples(sample_rate) as usize
+      } else {
+        0
+      };
+      if self.voices.len() == MAX_VOICES {
+        self.voices.remove(0);
+      }
+      self.voices.push(Voice {
+        level,
+        delay,
+        position: 0,
+      });
+    }
+
+    let num_channels = audio_output.channels;
+    let frames = frames.min(audio_output.buffer.len() / num_channels.max(1));
+    for voice in self.voices.iter_mut() {
+      let (samples, gain) = match voice.level {
+        ClickLevel::Accent => (self.bar_sound.get_samples(), self.volume * self.accent),
+        ClickLevel::Normal => (self.beat_sound.get_samples(), self.volume),
+        ClickLevel::Subdivision => (
+          self.beat_sound.get_samples(),
+          self.volume * self.subdivision,
+        ),
+      };
+      let start = voice.delay.min(frames);
+      let length = (frames - start).min(samples.len() - voice.position);
+      for frame in start..start + length {
+        let value = samples[voice.position] * gain;
+        for channel in self
+          .channels
+          .iter()
+          .filter(|channel| **channel < num_channels)
+        {
+          audio_output.buffer[frame * num_channels + channel] += value;
+        }
+        voice.position += 1;
+      }
+      voice.delay -= start;
+    }
+
+    let bar_length = self.bar_sound.get_samples().len();
+    let beat_length = self.beat_sound.get_samples().len();
+    self.voices.retain(|voice| {
+      let length = match voice.level {
+        ClickLevel::Accent => bar_length,
+        ClickLevel::Normal | ClickLevel::Subdivision => beat_length,
+      };
+      voice.position < length
+    });
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{AudioClick, ClickSound};
+  use crate::audio::AudioOutput;
+  use crate::config::MetronomeAudio as MetronomeAudioConfig;
+  use crate::metronome::pattern::ClickLevel;
+  use crate::time::ClockTime;
+
+  const SAMPLE_RATE: u32 = 44100;
+
+  #[test]
+  pub fn synthesized_click() {
+    let sound = ClickSound::synthesized(1000.0, SAMPLE_RATE);
+    let samples = sound.get_samples();
+    assert_eq!(samples.len(), 2205);
+    assert_eq!(samples[0], 0.0);
+    assert!(samples.iter().all(|sample| sample.abs() <= 1.0));
+    assert!(samples[samples.len() - 1].abs() < 0.001);
+  }
+
+  #[test]
+  pub fn clicks_at_their_sample() {
+    let config = MetronomeAudioConfig {
+      volume: 0.5,
+      accent: 2.0,
+      channels: [1, 2],
+      ..MetronomeAudioConfig::default()
+    };
+    let mut click = AudioClick::new(&config, SAMPLE_RATE);
+    let bar_samples = click.bar_sound.get_samples().to_vec();
+    let beat_samples = click.beat_sound.get_samples().to_vec();
+
+    let frames = 2048;
+    let channels = 4;
+    let start = ClockTime::from_seconds(1.0);
+    click.schedule(
+      start + ClockTime::from_samples(100, SAMPLE_RATE),
+      ClickLevel::Accent,
+    );
+    click.schedule(
+      start + ClockTime::from_samples(2000, SAMPLE_RATE),
+      ClickLevel::Normal,
+    );
+
+    let mut buffer = vec![0.0; frames * channels];
+    click.render(&mut AudioOutput::new(start, channels, &mut buffer), frames);
+    for frame in 0..frames {
+      let expected = if frame < 100 {
+        0.0
+      } else if frame < 2000 {
+        bar_samples[frame - 100]
+      } else {
+        bar_samples[frame - 100] + beat_samples[frame - 2000] * 0.5
+      };
+      assert_eq!(buffer[frame * channels], 0.0);
+      assert_eq!(buffer[frame * channels + 1], expected);
+      assert_eq!(buffer[frame * channels + 2], expected);
+      assert_eq!(buffer[frame * channels + 3], 0.0);
+    }
+
+    // the clicks continue in the next buffer until they finish
+    let mut buffer = vec![0.0; frames * channels];
+    let next = start + ClockTime::from_samples(frames as u32, SAMPLE_RATE);
+    click.render(&mut AudioOutput::new(next, channels, &mut buffer), frames);
+    assert_eq!(
+      buffer[1],
+      bar_samples[frames - 100] + beat_samples[frames - 2000] * 0.5
+    );
+    assert_eq!(click.voices.len(), 1);
+
+    let mut buffer = vec![0.0; frames * channels];
+    let next = next + ClockTime::from_samples(frames as u32, SAMPLE_RATE);
+    click.render(&mut AudioOutput::new(next, channels, &mut buffer), frames);
+    let beat_end = beat_samples.len() - (2 * frames - 2000);
+    assert_eq!(
+      buffer[(beat_end - 1) * channels + 1],
+      beat_samples[beat_samples.len() - 1] * 0.5
+    );
+    assert_eq!(buffer[beat_end * channels + 1], 0.0);
+    assert_eq!(click.voices.len(), 0);
+  }
+}
@@ -0,0 +1,402 @@
+pub mod click;
+pub mod pattern;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::audio::AudioOutput;
+use crate::config::{Metronome as MetronomeConfig, MetronomeNote};
+use crate::midi;
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::io::MidiOutput;
+use crate::time::{ticks::TICKS_RESOLUTION, ClockTime, SampleRate, Signature, Tempo, TicksTime};
+use crate::transport::{Segment, Transport};
+
+use self::click::AudioClick;
+use self::pattern::{BarPattern, ClickLevel};
+
+pub struct Metronome {
+  config: MetronomeConfig,
+  enabled: bool,
+  count_in_only: bool,
+  midi_enabled: bool,
+  audio_enabled: bool,
+  endpoint: Endpoint,
+  audio_click: AudioClick,
+  pattern: BarPattern,
+}
+
+impl Metronome {
+  pub fn new(config: MetronomeConfig, sample_rate: SampleRate) -> Metronome {
+    let enabled = config.enabled;
+    let midi_enabled = config.midi;
+    let audio_enabled = config.audio.enabled;
+    let endpoint = Endpoint::from(&config.port);
+    let audio_click = AudioClick::new(&config.audio, sample_rate);
+    let pattern = BarPattern::new(Signature::new(4, 4), &config);
+
+    Metronome {
+      enabled,
+      count_in_only: config.count_in_only,
+      midi_enabled,
+      audio_enabled,
+      endpoint,
+      audio_click,
+      pattern,
+      config,
+    }
+  }
+
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+
+  /// Click only during the count-in, even when enabled
+  pub fn set_count_in_only(&mut self, count_in_only: bool) {
+    self.count_in_only = count_in_only;
+  }
+
+  pub fn is_count_in_only(&self) -> bool {
+    self.count_in_only
+  }
+
+  /// Send the clicks as MIDI notes to the metronome port
+  pub fn set_midi_enabled(&mut self, enabled: bool) {
+    self.midi_enabled = enabled;
+  }
+
+  pub fn is_midi_enabled(&self) -> bool {
+    self.midi_enabled
+  }
+
+  /// Mix the clicks into the audio output
+  pub fn set_audio_enabled(&mut self, enabled: bool) {
+    self.audio_enabled = enabled;
+  }
+
+  pub fn is_audio_enabled(&self) -> bool {
+    self.audio_enabled
+  }
+
+  pub fn set_audio_volume(&mut self, volume: f32) {
+    self.audio_click.set_volume(volume);
+  }
+
+  pub fn get_audio_volume(&self) -> f32 {
+    self.audio_click.get_volume()
+  }
+
+  pub fn endpoint(&self) -> Endpoint {
+    self.endpoint
+  }
+
+  /// Click the pattern of the bars of the segment.
+  /// The count-in always clicks, even when the metronome is disabled.
+  pub fn process_segment<MidiOut>(&mut self, segment: &Segment, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
+    let clicking = segment.is_count_in() || (self.enabled && !self.count_in_only);
+    if !clicking {
+      return;
+    }
+
+    let signature = segment.signature;
+    let tempo = segment.tempo;
+    if self.pattern.get_signature() != signature {
+      self.pattern.update(signature, &self.config);
+    }
+
+    // the signature doesn't change within a segment, so the bars are relative to the bar start
+    let bar_duration = self.pattern.get_duration();
+    if bar_duration == TicksTime::zero() {
+      return;
+    }
+    let num_bars = (segment.start_position - segment.bar_start_position) / bar_duration;
+    let mut bar_position = segment.bar_start_position + num_bars * bar_duration;
+
+    while bar_position < segment.end_position {
+      for (offset, level) in self.pattern.get_clicks().iter() {
+        let position = bar_position + *offset;
+        if position < segment.start_position {
+          continue;
+        } else if position >= segment.end_position {
+          break;
+        }
+
+        let note_time = segment.master_clock_at(position);
+        if self.midi_enabled {
+          let note = match level {
+            ClickLevel::Accent => &self.config.bar_note,
+            ClickLevel::Normal => &self.config.beat_note,
+            ClickLevel::Subdivision => &self.config.subdivision_note,
+          };
+          Self::push_note(
+            midi_output,
+            self.endpoint,
+            note_time,
+            note,
+            signature,
+            tempo,
+          );
+        }
+        if self.audio_enabled {
+          self.audio_click.schedule(note_time, *level);
+        }
+      }
+      bar_position += bar_duration;
+    }
+  }
+
+  /// Add the audio clicks of the processed segments to the output.
+  /// It has to be called for every buffer, even when stopped, so the clicks already started can finish.
+  pub fn process_audio(&mut self, audio_output: &mut AudioOutput, frames: usize) {
+    self.audio_click.render(audio_output, frames);
+  }
+
+  fn push_note<MidiOut>(
+    midi_output: &mut MidiOut,
+    endpoint: Endpoint,
+    start_time: ClockTime,
+    note: &MetronomeNote,
+    signature: Signature,
+    tempo: Tempo,
+  ) where
+    MidiOut: MidiOutput,
+  {
+    // TODO duration_ticks only needs to be calculated once per note
+    let duration_ticks = TicksTime::new(16 * TICKS_RESOLUTION / u64::from(note.duration));
+    let duration_time = duration_ticks.to_clock(signature, tempo);
+    let end_time = start_time + duration_time;
+
+    midi_output.push(EventIo::new(
+      start_time,
+      endpoint,
+      midi::Message::NoteOn {
+        channel: note.channel,
+        key: note.key,
+        velocity: note.velocity,
+      },
+    ));
+
+    midi_output.push(EventIo::new(
+      end_time,
+      endpoint,
+      midi::Message::NoteOff {
+        channel: note.channel,
+        key: note.key,
+        velocity: note.velocity,
+      },
+    ));
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::Metronome;
+  use crate::audio::AudioOutput;
+  use crate::config::{
+    Metronome as MetronomeConfig, MetronomeAudio as MetronomeAudioConfig, MetronomePattern,
+  };
+  use crate::midi::Message;
+  use crate::testing::VecMidiOutput;
+  use crate::time::{clock, BarsTime, ClockTime, Signature};
+  use crate::transport::Transport;
+
+  const SAMPLE_RATE: u32 = 44100;
+
+  #[test]
+  pub fn metronome_follows_signature_changes() {
+    let config = MetronomeConfig::default();
+    let bar_key = config.bar_note.key;
+    let mut metronome = Metronome::new(config, SAMPLE_RATE);
+
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_signature_change(1, Signature::new(7, 8));
+    transport.set_signature_change(2, Signature::new(3, 4));
+
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    while master_clock < ClockTime::from_seconds(6.9) {
+      let mut segments = transport.segments_iterator(master_clock, samples);
+      while let Some(segment) = segments.next(&transport) {
+        metronome.process_segment(&segment, &mut midi_output);
+      }
+      transport.update_from_segments(&segments);
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+
+    let notes: Vec<(bool, u64)> = midi_output
+      .0
+      .iter()
+      .filter_map(|event| match event.message {
+        Message::NoteOn { key, .. } => Some((key == bar_key, event.timestamp.units())),
+        _ => None,
+      })
+      .collect();
+
+    // the tempo is expressed in beats of the signature, so every beat takes half a second at 120 bpm
+    let mut expected = Vec::new();
+    let mut time = 0.0;
+    for num_beats in &[4, 7, 3] {
+      for beat in 0..*num_beats {
+        expected.push((beat == 0, time));
+        time += 0.5;
+      }
+    }
+
+    assert_eq!(notes.len(), expected.len());
+    for ((is_bar, units), (expected_is_bar, seconds)) in notes.iter().zip(expected.iter()) {
+      assert_eq!(is_bar, expected_is_bar);
+      let expected_units = ClockTime::from_seconds(*seconds).units();
+      let diff = units.abs_diff(expected_units);
+      assert!(diff < clock::UNITS_PER_MILLI / 1000);
+    }
+  }
+
+  #[test]
+  pub fn count_in_clicks_when_disabled() {
+    let config = MetronomeConfig::default();
+    let bar_key = config.bar_note.key;
+    let mut metronome = Metronome::new(config, SAMPLE_RATE);
+    metronome.set_enabled(false);
+
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_count_in_bars(1);
+    transport.play_with_pre_roll(false);
+
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    while master_clock < ClockTime::from_seconds(4.0) {
+      let mut segments = transport.segments_iterator(master_clock, samples);
+      while let Some(segment) = segments.next(&transport) {
+        metronome.process_segment(&segment, &mut midi_output);
+      }
+      transport.update_from_segments(&segments);
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+
+    // the four beats of the count-in, and nothing once the song plays
+    let notes: Vec<(bool, u64)> = midi_output
+      .0
+      .iter()
+      .filter_map(|event| match event.message {
+        Message::NoteOn { key, .. } => Some((key == bar_key, event.timestamp.units())),
+        _ => None,
+      })
+      .collect();
+    assert_eq!(notes.len(), 4);
+    for (beat, (is_bar, units)) in notes.iter().enumerate() {
+      assert_eq!(*is_bar, beat == 0);
+      let expected_units = ClockTime::from_seconds(beat as f64 * 0.5).units();
+      assert!(units.abs_diff(expected_units) < clock::UNITS_PER_MILLI / 1000);
+    }
+  }
+
+  #[test]
+  pub fn audio_click_without_midi() {
+    let config = MetronomeConfig {
+      midi: false,
+      audio: MetronomeAudioConfig {
+        enabled: true,
+        ..MetronomeAudioConfig::default()
+      },
+      ..MetronomeConfig::default()
+    };
+    let mut metronome = Metronome::new(config, SAMPLE_RATE);
+
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.play(false);
+
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let samples = 512;
+    let channels = 2;
+    let mut buffer = vec![0.0; samples as usize * channels];
+    let mut segments = transport.segments_iterator(ClockTime::zero(), samples);
+    while let Some(segment) = segments.next(&transport) {
+      metronome.process_segment(&segment, &mut midi_output);
+    }
+    let mut audio_output = AudioOutput::new(ClockTime::zero(), channels, &mut buffer);
+    metronome.process_audio(&mut audio_output, samples as usize);
+
+    assert!(midi_output.0.is_empty());
+    assert!(buffer.iter().any(|sample| *sample != 0.0));
+    assert_eq!(buffer[0], buffer[1]);
+  }
+
+  #[test]
+  pub fn patterns_follow_signature_changes() {
+    let config = MetronomeConfig {
+      patterns: vec![MetronomePattern {
+        num_beats: 7,
+        note_value: 8,
+        beats: Vec::new(),
+        groups: vec![2, 2, 3],
+      }],
+      ..MetronomeConfig::default()
+    };
+    let bar_key = config.bar_note.key;
+    let mut metronome = Metronome::new(config, SAMPLE_RATE);
+
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_signature_change(1, Signature::new(7, 8));
+
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    while master_clock < ClockTime::from_seconds(5.4) {
+      let mut segments = transport.segments_iterator(master_clock, samples);
+      while let Some(segment) = segments.next(&transport) {
+        metronome.process_segment(&segment, &mut midi_output);
+      }
+      transport.update_from_segments(&segments);
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+
+    let notes: Vec<(bool, u64)> = midi_output
+      .0
+      .iter()
+      .filter_map(|event| match event.message {
+        Message::NoteOn { key, .. } => Some((key == bar_key, event.timestamp.units())),
+        _ => None,
+      })
+      .collect();
+
+    // four beats of 4/4 and then only the start of the groups of 7/8
+    let expected = [
+      (true, 0.0),
+      (false, 0.5),
+      (false, 1.0),
+      (false, 1.5),
+      (true, 2.0),
+      (false, 3.0),
+      (false, 4.0),
+    ];
+    assert_eq!(notes.len(), expected.len());
+    for ((is_bar, units), (expected_is_bar, seconds)) in notes.iter().zip(expected.iter()) {
+      assert_eq!(is_bar, expected_is_bar);
+      let expected_units = ClockTime::from_seconds(*seconds).units();
+      assert!(units.abs_diff(expected_units) < clock::UNITS_PER_MILLI / 1000);
+    }
+
+    // nothing clicks outside of the count-in
+    metronome.set_count_in_only(true);
+    transport.set_position(BarsTime::new(0, 0, 0, 0));
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let mut segments = transport.segments_iterator(master_clock, samples);
+    while let Some(segment) = segments.next(&transport) {
+      metronome.process_segment(&segment, &mut midi_output);
+    }
+    assert!(midi_output.0.is_empty());
+  }
+}
@@ -0,0 +1,225 @@
+use crate::config::{
+  Metronome as MetronomeConfig, MetronomePattern, MetronomeStep, MetronomeSubdivision,
+};
+use crate::time::{BarsTime, Signature, TicksTime};
+
+const MAX_CLICKS_PER_BAR: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClickLevel {
+  Accent,
+  Normal,
+  Subdivision,
+}
+
+/// Clicks of a bar relative to its start, for the signature it was built for
+#[derive(Debug, Clone)]
+pub struct BarPattern {
+  signature: Signature,
+  duration: TicksTime,
+  clicks: Vec<(TicksTime, ClickLevel)>,
+}
+
+impl BarPattern {
+  pub fn new(signature: Signature, config: &MetronomeConfig) -> BarPattern {
+    let mut pattern = BarPattern {
+      signature,
+      duration: TicksTime::zero(),
+      clicks: Vec::with_capacity(MAX_CLICKS_PER_BAR),
+    };
+    pattern.update(signature, config);
+    pattern
+  }
+
+  pub fn get_signature(&self) -> Signature {
+    self.signature
+  }
+
+  pub fn get_duration(&self) -> TicksTime {
+    self.duration
+  }
+
+  pub fn get_clicks(&self) -> &[(TicksTime, ClickLevel)] {
+    self.clicks.as_slice()
+  }
+
+  /// Build the clicks for another signature.
+  /// It is called from the audio thread when the signature changes, so it reuses the memory of the clicks.
+  pub fn update(&mut self, signature: Signature, config: &MetronomeConfig) {
+    self.signature = signature;
+    self.duration = BarsTime::from_bars(1).to_ticks(signature);
+    self.clicks.clear();
+
+    let num_beats = u64::from(signature.get_num_beats());
+    if num_beats == 0 {
+      return;
+    }
+    let beat_duration = u64::from(self.duration) / num_beats;
+    let note_value = u64::from(signature.get_note_value());
+    let clicks_per_beat = match config.subdivision {
+      MetronomeSubdivision::None => 1,
+      MetronomeSubdivision::Eighths => (8 / note_value).max(1),
+      MetronomeSubdivision::Sixteenths => (16 / note_value).max(1),
+      MetronomeSubdivision::Triplets => 3,
+    };
+    let subdivision_duration = beat_duration / clicks_per_beat;
+
+    let pattern = config.patterns.iter().find(|pattern| {
+      u64::from(pattern.num_beats) == num_beats && u64::from(pattern.note_value) == note_value
+    });
+    for beat in 0..num_beats {
+      let level = match Self::beat_step(pattern, beat) {
+        MetronomeStep::Accent => ClickLevel::Accent,
+        MetronomeStep::Normal => ClickLevel::Normal,
+        // the subdivisions of a silent beat are silent too
+        MetronomeStep::Silent => continue,
+      };
+      let beat_position = beat * beat_duration;
+      for subdivision in 0..clicks_per_beat {
+        if self.clicks.len() == MAX_CLICKS_PER_BAR {
+          return;
+        }
+        let position = TicksTime::new(beat_position + subdivision * subdivision_duration);
+        let level = if subdivision == 0 {
+          level
+        } else {
+          ClickLevel::Subdivision
+        };
+        self.clicks.push((position, level));
+      }
+    }
+  }
+
+  /// The step of a beat given by the pattern. Without pattern, or when the groups don't add up
+  /// to the number of beats, the first beat is accented and the rest are normal.
+  fn beat_step(pattern: Option<&MetronomePattern>, beat: u64) -> MetronomeStep {
+    let default_step = if beat == 0 {
+      MetronomeStep::Accent
+    } else {
+      MetronomeStep::Normal
+    };
+    match pattern {
+      Some(pattern) if !pattern.beats.is_empty() => pattern
+        .beats
+        .get(beat as usize)
+        .cloned()
+        .unwrap_or(MetronomeStep::Normal),
+      Some(pattern)
+        if pattern
+          .groups
+          .iter()
+          .map(|group| u64::from(*group))
+          .sum::<u64>()
+          == u64::from(pattern.num_beats) =>
+      {
+        let mut group_start = 0;
+        for group in pattern.groups.iter() {
+          if beat == group_start {
+            return default_step;
+          }
+          group_start += u64::from(*group);
+        }
+        MetronomeStep::Silent
+      }
+      _ => default_step,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{BarPattern, ClickLevel};
+  use crate::config::{
+    Metronome as MetronomeConfig, MetronomePattern, MetronomeStep, MetronomeSubdivision,
+  };
+  use crate::time::{ticks::TICKS_RESOLUTION, Signature, TicksTime};
+
+  fn levels(pattern: &BarPattern) -> Vec<(u64, ClickLevel)> {
+    pattern
+      .get_clicks()
+      .iter()
+      .map(|(position, level)| (u64::from(*position), *level))
+      .collect()
+  }
+
+  #[test]
+  pub fn default_pattern() {
+    let config = MetronomeConfig::default();
+    let pattern = BarPattern::new(Signature::new(3, 4), &config);
+    assert_eq!(
+      pattern.get_duration(),
+      TicksTime::new(12 * TICKS_RESOLUTION)
+    );
+    assert_eq!(
+      levels(&pattern),
+      vec![
+        (0, ClickLevel::Accent),
+        (4 * TICKS_RESOLUTION, ClickLevel::Normal),
+        (8 * TICKS_RESOLUTION, ClickLevel::Normal),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn beats_and_subdivisions() {
+    let config = MetronomeConfig {
+      subdivision: MetronomeSubdivision::Triplets,
+      patterns: vec![MetronomePattern {
+        num_beats: 2,
+        note_value: 4,
+        beats: vec![MetronomeStep::Normal, MetronomeStep::Silent],
+        groups: Vec::new(),
+      }],
+      ..MetronomeConfig::default()
+    };
+    let pattern = BarPattern::new(Signature::new(2, 4), &config);
+    let third = 4 * TICKS_RESOLUTION / 3;
+    assert_eq!(
+      levels(&pattern),
+      vec![
+        (0, ClickLevel::Normal),
+        (third, ClickLevel::Subdivision),
+        (2 * third, ClickLevel::Subdivision),
+      ]
+    );
+
+    // eighths in a signature of eighths don't add any click
+    let config = MetronomeConfig {
+      subdivision: MetronomeSubdivision::Eighths,
+      ..MetronomeConfig::default()
+    };
+    let pattern = BarPattern::new(Signature::new(2, 8), &config);
+    assert_eq!(pattern.get_clicks().len(), 2);
+    let pattern = BarPattern::new(Signature::new(2, 4), &config);
+    assert_eq!(pattern.get_clicks().len(), 4);
+  }
+
+  #[test]
+  pub fn odd_meter_groups() {
+    let mut config = MetronomeConfig {
+      patterns: vec![MetronomePattern {
+        num_beats: 7,
+        note_value: 8,
+        beats: Vec::new(),
+        groups: vec![2, 2, 3],
+      }],
+      ..MetronomeConfig::default()
+    };
+    let mut pattern = BarPattern::new(Signature::new(7, 8), &config);
+    let eighth = 2 * TICKS_RESOLUTION;
+    assert_eq!(
+      levels(&pattern),
+      vec![
+        (0, ClickLevel::Accent),
+        (2 * eighth, ClickLevel::Normal),
+        (4 * eighth, ClickLevel::Normal),
+      ]
+    );
+
+    // groups that don't add up to the signature are ignored
+    config.patterns[0].groups = vec![3, 3];
+    pattern.update(Signature::new(7, 8), &config);
+    assert_eq!(pattern.get_clicks().len(), 7);
+  }
+}
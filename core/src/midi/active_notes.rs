@@ -0,0 +1,219 @@
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::io::MidiOutput;
+use crate::midi::types::{U4, U7};
+use crate::midi::Message;
+use crate::time::ClockTime;
+
+const MAX_ACTIVE_NOTES: usize = 1024;
+
+/// Notes sent to the outputs that didn't end yet, by endpoint and channel,
+/// so they can be released when nothing else would end them
+pub struct ActiveNotes {
+  notes: Vec<(Endpoint, U4, U7)>,
+}
+
+impl Default for ActiveNotes {
+  fn default() -> Self {
+    ActiveNotes {
+      notes: Vec::with_capacity(MAX_ACTIVE_NOTES),
+    }
+  }
+}
+
+impl ActiveNotes {
+  pub fn new() -> ActiveNotes {
+    ActiveNotes::default()
+  }
+
+  pub fn len(&self) -> usize {
+    self.notes.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.notes.is_empty()
+  }
+
+  /// Update the notes from an event sent to the outputs
+  pub fn track(&mut self, event: &EventIo) {
+    let endpoint = event.endpoint;
+    match event.message {
+      Message::NoteOn {
+        channel,
+        key,
+        velocity,
+      } if velocity > 0 => self.add((endpoint, channel, key)),
+      // a note on without velocity is a note off
+      Message::NoteOn { channel, key, .. } | Message::NoteOff { channel, key, .. } => {
+        let note = (endpoint, channel, key);
+        if let Some(index) = self.notes.iter().position(|active| *active == note) {
+          self.notes.swap_remove(index);
+        }
+      }
+      Message::AllNotesOff { channel } | Message::AllSoundOff { channel } => {
+        self.notes.retain(|(active_endpoint, active_channel, _)| {
+          *active_endpoint != endpoint || *active_channel != channel
+        })
+      }
+      _ => {}
+    }
+  }
+
+  /// Send a note off for every active note
+  pub fn release_all<MidiOut>(&mut self, time: ClockTime, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
+    self.release(time, midi_output, |_| true);
+  }
+
+  /// Send a note off for the active notes of an endpoint, like when it is removed
+  pub fn release_endpoint<MidiOut>(
+    &mut self,
+    endpoint: Endpoint,
+    time: ClockTime,
+    midi_output: &mut MidiOut,
+  ) where
+    MidiOut: MidiOutput,
+  {
+    self.release(time, midi_output, |active_endpoint| {
+      active_endpoint == endpoint
+    });
+  }
+
+  /// Forget the active notes, like when the outputs were silenced
+  pub fn clear(&mut self) {
+    self.notes.clear();
+  }
+
+  fn add(&mut self, note: (Endpoint, U4, U7)) {
+    if self.notes.len() < MAX_ACTIVE_NOTES {
+      self.notes.push(note);
+    }
+  }
+
+  fn release<MidiOut, Predicate>(
+    &mut self,
+    time: ClockTime,
+    midi_output: &mut MidiOut,
+    predicate: Predicate,
+  ) where
+    MidiOut: MidiOutput,
+    Predicate: Fn(Endpoint) -> bool,
+  {
+    self.notes.retain(|(endpoint, channel, key)| {
+      let release = predicate(*endpoint);
+      if release {
+        midi_output.push(EventIo::new(
+          time,
+          *endpoint,
+          Message::NoteOff {
+            channel: *channel,
+            key: *key,
+            velocity: 0,
+          },
+        ));
+      }
+      !release
+    });
+  }
+}
+
+/// Output that keeps track of the notes sent through it
+pub struct TrackedOutput<'a, MidiOut>
+where
+  MidiOut: MidiOutput,
+{
+  active_notes: &'a mut ActiveNotes,
+  midi_output: &'a mut MidiOut,
+}
+
+impl<'a, MidiOut> TrackedOutput<'a, MidiOut>
+where
+  MidiOut: MidiOutput,
+{
+  pub fn new(active_notes: &'a mut ActiveNotes, midi_output: &'a mut MidiOut) -> Self {
+    TrackedOutput {
+      active_notes,
+      midi_output,
+    }
+  }
+}
+
+impl<'a, MidiOut> MidiOutput for TrackedOutput<'a, MidiOut>
+where
+  MidiOut: MidiOutput,
+{
+  fn push(&mut self, event: EventIo) {
+    self.active_notes.track(&event);
+    self.midi_output.push(event);
+  }
+
+  fn panic(&mut self) {
+    self.active_notes.clear();
+    self.midi_output.panic();
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{ActiveNotes, TrackedOutput};
+  use crate::midi::buffer::{Endpoint, EventIo};
+  use crate::midi::io::MidiOutput;
+  use crate::midi::Message;
+  use crate::testing::VecMidiOutput;
+  use crate::time::ClockTime;
+
+  fn note_on(endpoint: Endpoint, channel: u8, key: u8, velocity: u8) -> EventIo {
+    EventIo::new(
+      ClockTime::zero(),
+      endpoint,
+      Message::NoteOn {
+        channel,
+        key,
+        velocity,
+      },
+    )
+  }
+
+  #[test]
+  pub fn track_notes_through_the_output() {
+    let mut active_notes = ActiveNotes::new();
+    let mut midi_output = VecMidiOutput(Vec::new());
+    {
+      let mut output = TrackedOutput::new(&mut active_notes, &mut midi_output);
+      output.push(note_on(Endpoint::Default, 0, 60, 100));
+      output.push(note_on(Endpoint::Default, 0, 62, 100));
+      output.push(note_on(Endpoint::Id(1), 0, 60, 100));
+      output.push(note_on(Endpoint::Id(1), 1, 64, 100));
+      output.push(note_on(Endpoint::Default, 0, 62, 0));
+      output.push(EventIo::new(
+        ClockTime::zero(),
+        Endpoint::Id(1),
+        Message::AllNotesOff { channel: 1 },
+      ));
+    }
+    assert_eq!(midi_output.0.len(), 6);
+    assert_eq!(active_notes.len(), 2);
+
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let time = ClockTime::from_seconds(1.0);
+    active_notes.release_endpoint(Endpoint::Id(1), time, &mut midi_output);
+    assert_eq!(midi_output.0.len(), 1);
+    assert_eq!(midi_output.0[0].endpoint, Endpoint::Id(1));
+    assert_eq!(midi_output.0[0].timestamp, time);
+    assert_eq!(
+      midi_output.0[0].message,
+      Message::NoteOff {
+        channel: 0,
+        key: 60,
+        velocity: 0,
+      }
+    );
+
+    active_notes.release_all(time, &mut midi_output);
+    assert_eq!(midi_output.0.len(), 2);
+    assert_eq!(midi_output.0[1].endpoint, Endpoint::Default);
+    assert!(active_notes.is_empty());
+  }
+}
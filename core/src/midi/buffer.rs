@@ -1,3 +1,4 @@
+use crate::config::MidiPort;
 use crate::midi::messages::Message;
 use crate::pool::Pool;
 use crate::time::ClockTime;
@@ -67,7 +68,7 @@ pub fn new_buffer_pool(pool_capacity: usize, buffer_capacity: usize) -> Pool<Buf
   Pool::new(pool_capacity, allocator, reset)
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Endpoint {
   None,
   Default,
@@ -75,6 +76,30 @@ pub enum Endpoint {
   Id(usize),
 }
 
+impl Endpoint {
+  /// Whether an event received from an endpoint comes from this one when used as an input
+  pub fn accepts(&self, endpoint: Endpoint) -> bool {
+    match (self, endpoint) {
+      (Endpoint::None, _) => false,
+      (Endpoint::Id(input), Endpoint::Id(id)) => *input == id,
+      (Endpoint::Id(_), _) => false,
+      _ => true,
+    }
+  }
+}
+
+impl From<&MidiPort> for Endpoint {
+  fn from(port: &MidiPort) -> Endpoint {
+    // TODO Select the endpoint from the configuration when update events are received
+    match port {
+      MidiPort::None => Endpoint::None,
+      MidiPort::SystemDefault => Endpoint::Default,
+      MidiPort::All => Endpoint::All,
+      MidiPort::ByName(_name) => Endpoint::None, // TODO
+    }
+  }
+}
+
 pub struct BufferIo {
   pub endpoint: Endpoint,
   pub buffer: Option<Box<Buffer>>,
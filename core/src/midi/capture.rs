@@ -0,0 +1,496 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use crate::config::Capture as CaptureConfig;
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::types::{U4, U7};
+use crate::midi::Message;
+use crate::song::clips::Clip;
+use crate::song::source::notes::{Note, NotesClip as NotesSourceClip};
+use crate::time::{clock, BarsTime, ClockTime, Signature, SignatureMap, Tempo, TicksTime};
+
+/// The tempo estimated from the notes played is within an octave, so it is not mistaken for its double or half
+const MIN_ESTIMATED_BPM: f64 = 80.0;
+const MAX_ESTIMATED_BPM: f64 = 160.0;
+const ESTIMATED_BPM_STEP: f64 = 0.5;
+
+/// Song position with the bar it falls in, so the captured notes are placed without the transport
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CapturedPosition {
+  pub ticks: TicksTime,
+  pub bar_start: TicksTime,
+  pub signature: Signature,
+}
+
+impl CapturedPosition {
+  pub fn new(ticks: TicksTime, signature_map: &SignatureMap) -> CapturedPosition {
+    let bar_start = signature_map.bar_start(ticks);
+    CapturedPosition {
+      ticks,
+      bar_start,
+      signature: signature_map.signature_at(bar_start),
+    }
+  }
+}
+
+/// Where the song was when the input was last processed, to place what was played while stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CapturedSongTime {
+  pub playing: bool,
+  pub position: CapturedPosition,
+  pub tempo: Tempo,
+}
+
+/// Input event received while the transport was playing or stopped
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedEvent {
+  pub timestamp: ClockTime,
+  /// Song position when it was received while playing
+  pub position: Option<CapturedPosition>,
+  pub endpoint: Endpoint,
+  pub message: Message,
+}
+
+/// Note played on the input, from the note on until the note off, or the last event for the notes still held
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CapturedNote {
+  pub key: U7,
+  pub velocity: U7,
+  pub start: ClockTime,
+  pub end: ClockTime,
+  pub start_position: Option<CapturedPosition>,
+  pub end_position: Option<CapturedPosition>,
+}
+
+/// Copy of the last phrase played and where the song was, to build its clip away from the audio thread
+#[derive(Debug, Clone)]
+pub struct CapturedPhrase {
+  events: Vec<CapturedEvent>,
+  song_time: CapturedSongTime,
+}
+
+/// Clip built from a captured phrase, with the tempo estimated when it was played while stopped
+pub struct CapturedClip {
+  pub clip: Clip,
+  pub notes: NotesSourceClip,
+  pub tempo: Option<Tempo>,
+}
+
+/// Ring buffer that keeps the last minutes of the MIDI input, even while the transport is stopped.
+/// Only the channel voice messages are kept, as they don't need any memory on their own.
+pub struct MidiCapture {
+  enabled: bool,
+  events: VecDeque<CapturedEvent>,
+  max_events: usize,
+  max_age: ClockTime,
+  gap: ClockTime,
+  song_time: CapturedSongTime,
+}
+
+impl MidiCapture {
+  pub fn new(config: &CaptureConfig) -> MidiCapture {
+    MidiCapture {
+      enabled: config.enabled,
+      events: VecDeque::with_capacity(config.max_events),
+      max_events: config.max_events,
+      max_age: ClockTime::from_seconds(f64::from(config.minutes) * 60.0),
+      gap: ClockTime::from_seconds(config.gap_seconds),
+      song_time: CapturedSongTime {
+        playing: false,
+        position: CapturedPosition::new(
+          TicksTime::zero(),
+          &SignatureMap::new(Signature::new(4, 4)),
+        ),
+        tempo: Tempo::from_bpm(120.0),
+      },
+    }
+  }
+
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+
+  pub fn len(&self) -> usize {
+    self.events.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.events.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.events.clear();
+  }
+
+  /// Keep an input event, dropping the ones older than the minutes kept or the oldest one when full
+  pub fn push(&mut self, event: &EventIo, position: Option<CapturedPosition>) {
+    if !self.enabled || self.max_events == 0 || !is_captured(&event.message) {
+      return;
+    }
+    while self.events.front().is_some_and(|oldest| {
+      self.events.len() == self.max_events || oldest.timestamp + self.max_age < event.timestamp
+    }) {
+      self.events.pop_front();
+    }
+    self.events.push_back(CapturedEvent {
+      timestamp: event.timestamp,
+      position,
+      endpoint: event.endpoint,
+      message: event.message.clone(),
+    });
+  }
+
+  pub fn set_song_time(&mut self, song_time: CapturedSongTime) {
+    self.song_time = song_time;
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &CapturedEvent> {
+    self.events.iter()
+  }
+
+  /// The events received from a time on, like for an activity monitor
+  pub fn events_since(&self, time: ClockTime) -> impl Iterator<Item = &CapturedEvent> {
+    let start = self
+      .events
+      .iter()
+      .rposition(|event| event.timestamp < time)
+      .map_or(0, |index| index + 1);
+    self.events.range(start..)
+  }
+
+  /// The events of the last phrase, which starts after the last silence longer than the gap
+  pub fn last_phrase(&self) -> impl Iterator<Item = &CapturedEvent> {
+    let start = (1..self.events.len())
+      .rev()
+      .find(|index| self.events[index - 1].timestamp + self.gap < self.events[*index].timestamp)
+      .unwrap_or(0);
+    self.events.range(start..)
+  }
+
+  /// Copy the last phrase, to turn it into a clip without holding the capture
+  pub fn copy_last_phrase(&self) -> CapturedPhrase {
+    CapturedPhrase {
+      events: self.last_phrase().cloned().collect(),
+      song_time: self.song_time,
+    }
+  }
+}
+
+impl CapturedPhrase {
+  /// The notes of the phrase sorted by their start
+  pub fn notes(&self) -> Vec<CapturedNote> {
+    let mut notes = Vec::new();
+    let mut held: Vec<(Endpoint, U4, CapturedNote)> = Vec::new();
+    let mut last: Option<&CapturedEvent> = None;
+    for event in self.events.iter() {
+      last = Some(event);
+      match event.message {
+        Message::NoteOn {
+          channel,
+          key,
+          velocity,
+        } if velocity > 0 => {
+          let note = CapturedNote {
+            key,
+            velocity,
+            start: event.timestamp,
+            end: event.timestamp,
+            start_position: event.position,
+            end_position: event.position,
+          };
+          held.push((event.endpoint, channel, note));
+        }
+        // a note on without velocity is a note off
+        Message::NoteOn { channel, key, .. } | Message::NoteOff { channel, key, .. } => {
+          let index = held.iter().position(|(endpoint, held_channel, note)| {
+            *endpoint == event.endpoint && *held_channel == channel && note.key == key
+          });
+          if let Some(index) = index {
+            let (_, _, mut note) = held.remove(index);
+            note.end = event.timestamp;
+            note.end_position = event.position;
+            notes.push(note);
+          }
+        }
+        _ => {}
+      }
+    }
+    if let Some(last) = last {
+      for (_, _, mut note) in held.drain(..) {
+        note.end = last.timestamp;
+        note.end_position = last.position;
+        notes.push(note);
+      }
+    }
+    notes.sort_by_key(|note| note.start.units());
+    notes
+  }
+
+  /// Turn the notes into a clip that starts at the bar of the first one.
+  /// The notes played while stopped start from the bar of the position,
+  /// and the tempo is estimated from them when the transport was still stopped.
+  pub fn into_clip(self) -> Option<CapturedClip> {
+    let notes = self.notes();
+    let first = notes.first()?;
+
+    let played = notes
+      .iter()
+      .all(|note| note.start_position.is_some() && note.end_position.is_some());
+    let estimated_tempo = if !played && !self.song_time.playing {
+      estimate_tempo(&notes)
+    } else {
+      None
+    };
+    let tempo = estimated_tempo.unwrap_or(self.song_time.tempo);
+
+    let first_position = match notes
+      .iter()
+      .filter_map(|note| note.start_position)
+      .min_by_key(|position| position.ticks)
+    {
+      Some(position) if played => position,
+      _ => self.song_time.position,
+    };
+    let start = first_position.bar_start;
+    let signature = first_position.signature;
+
+    let mut notes_clip = NotesSourceClip::new();
+    let mut end = start;
+    for note in notes.iter() {
+      let (note_start, note_end) = match (note.start_position, note.end_position) {
+        (Some(note_start), Some(note_end)) if played => (note_start.ticks, note_end.ticks),
+        _ => {
+          let position = |time: ClockTime| {
+            first_position.ticks + (time - first.start).to_ticks(signature, tempo)
+          };
+          (position(note.start), position(note.end))
+        }
+      };
+      let velocity = f64::from(note.velocity) / 127.0;
+      let length = (note_end - note_start).max(TicksTime::new(1));
+      notes_clip.add_note(Note::new(note.key, velocity, note_start - start, length));
+      end = end.max(note_start + length);
+    }
+
+    let bar = u64::from(BarsTime::from_bars(1).to_ticks(signature));
+    let num_bars = u64::from(end - start).div_ceil(bar);
+    let clip = Clip {
+      uuid: 0,
+      name: "Capture".to_string(),
+      signature,
+      start,
+      length: TicksTime::new(num_bars.max(1) * bar),
+    };
+    Some(CapturedClip {
+      clip,
+      notes: notes_clip,
+      tempo: estimated_tempo,
+    })
+  }
+}
+
+fn is_captured(message: &Message) -> bool {
+  matches!(
+    message,
+    Message::NoteOff { .. }
+      | Message::NoteOn { .. }
+      | Message::PolyphonicKeyPressure { .. }
+      | Message::ControlChange { .. }
+      | Message::ProgramChange { .. }
+      | Message::ChannelPressure { .. }
+      | Message::PitchBend { .. }
+  )
+}
+
+/// Tempo that fits the starts of the notes best in a grid of eighths from the first note.
+/// At least three different starts are needed.
+pub fn estimate_tempo(notes: &[CapturedNote]) -> Option<Tempo> {
+  let first = notes
+    .iter()
+    .map(|note| note.start)
+    .min_by_key(|start| start.units())?;
+  let mut onsets: Vec<f64> = notes
+    .iter()
+    .map(|note| (note.start - first).units() as f64 / clock::UNITS_PER_SECOND as f64)
+    .collect();
+  onsets.sort_by(|a, b| a.partial_cmp(b).unwrap());
+  onsets.dedup_by(|a, b| (*a - *b).abs() < 0.001);
+  if onsets.len() < 3 {
+    return None;
+  }
+
+  let num_steps = ((MAX_ESTIMATED_BPM - MIN_ESTIMATED_BPM) / ESTIMATED_BPM_STEP) as usize;
+  (0..num_steps)
+    .map(|step| MIN_ESTIMATED_BPM + step as f64 * ESTIMATED_BPM_STEP)
+    .map(|bpm| {
+      let eighth = 30.0 / bpm;
+      let score: f64 = onsets
+        .iter()
+        .map(|onset| (2.0 * PI * onset / eighth).cos())
+        .sum();
+      (bpm, score)
+    })
+    .fold(None, |best: Option<(f64, f64)>, (bpm, score)| match best {
+      Some((_, best_score)) if best_score >= score => best,
+      _ => Some((bpm, score)),
+    })
+    .map(|(bpm, _)| Tempo::from_bpm(bpm))
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{estimate_tempo, CapturedPosition, MidiCapture};
+  use crate::config::Capture as CaptureConfig;
+  use crate::midi::buffer::{Endpoint, EventIo};
+  use crate::midi::Message;
+  use crate::song::source::notes::Note;
+  use crate::time::{
+    ticks::TICKS_RESOLUTION, ClockTime, Signature, SignatureMap, Tempo, TicksTime,
+  };
+
+  fn event(seconds: f64, message: Message) -> EventIo {
+    EventIo::new(ClockTime::from_seconds(seconds), Endpoint::Id(0), message)
+  }
+
+  fn note_on(key: u8) -> Message {
+    Message::NoteOn {
+      channel: 0,
+      key,
+      velocity: 100,
+    }
+  }
+
+  fn note_off(key: u8) -> Message {
+    Message::NoteOff {
+      channel: 0,
+      key,
+      velocity: 0,
+    }
+  }
+
+  #[test]
+  pub fn keep_the_last_events() {
+    let config = CaptureConfig {
+      minutes: 1,
+      max_events: 4,
+      ..CaptureConfig::default()
+    };
+    let mut capture = MidiCapture::new(&config);
+    capture.push(&event(0.0, note_on(60)), None);
+    capture.push(&event(1.0, Message::TimingClock), None);
+    capture.push(&event(30.0, note_off(60)), None);
+    let signature_map = SignatureMap::new(Signature::new(4, 4));
+    let position = CapturedPosition::new(TicksTime::new(10), &signature_map);
+    capture.push(&event(61.0, note_on(62)), Some(position));
+    assert_eq!(capture.len(), 2);
+    assert_eq!(capture.iter().next().unwrap().message, note_off(60));
+
+    for seconds in 0..4 {
+      capture.push(&event(62.0 + f64::from(seconds), note_on(64)), None);
+    }
+    assert_eq!(capture.len(), 4);
+    assert_eq!(
+      capture.events_since(ClockTime::from_seconds(64.5)).count(),
+      1
+    );
+  }
+
+  #[test]
+  pub fn notes_of_the_last_phrase() {
+    let mut capture = MidiCapture::new(&CaptureConfig::default());
+    for message in [
+      event(0.0, note_on(48)),
+      event(0.5, note_off(48)),
+      event(10.0, note_on(60)),
+      event(10.5, note_on(64)),
+      event(11.0, note_off(60)),
+      event(12.0, note_on(67)),
+      event(12.5, note_off(64)),
+    ]
+    .iter()
+    {
+      capture.push(message, None);
+    }
+    let notes = capture.copy_last_phrase().notes();
+    let notes: Vec<(u8, f64, f64)> = notes
+      .iter()
+      .map(|note| {
+        (
+          note.key,
+          note.start.units() as f64 / 1e9,
+          note.end.units() as f64 / 1e9,
+        )
+      })
+      .collect();
+    assert_eq!(
+      notes,
+      vec![(60, 10.0, 11.0), (64, 10.5, 12.5), (67, 12.0, 12.5)]
+    );
+  }
+
+  #[test]
+  pub fn tempo_from_the_notes() {
+    let mut capture = MidiCapture::new(&CaptureConfig::default());
+    // eighths and quarters at 100 bpm, played a bit loose
+    let eighth = 0.3;
+    let starts = [0.0, 1.0, 2.0, 4.0, 5.0, 6.0, 8.0, 10.0, 11.0, 12.0];
+    let jitter = [
+      0.0, 0.01, -0.008, 0.004, -0.01, 0.006, 0.0, -0.004, 0.008, -0.006,
+    ];
+    for (start, jitter) in starts.iter().zip(jitter.iter()) {
+      let seconds = 20.0 + start * eighth + jitter;
+      capture.push(&event(seconds, note_on(60)), None);
+      capture.push(&event(seconds + 0.1, note_off(60)), None);
+    }
+    let notes = capture.copy_last_phrase().notes();
+    assert_eq!(estimate_tempo(&notes), Some(Tempo::from_bpm(100.0)));
+    assert_eq!(estimate_tempo(&notes[0..2]), None);
+  }
+
+  #[test]
+  pub fn place_the_notes_played() {
+    let mut capture = MidiCapture::new(&CaptureConfig::default());
+    let signature_map = SignatureMap::new(Signature::new(4, 4));
+    let bar = 16 * TICKS_RESOLUTION;
+    let beat = 4 * TICKS_RESOLUTION;
+    let mut push = |seconds: f64, message: Message, ticks: u64| {
+      let position = CapturedPosition::new(TicksTime::new(ticks), &signature_map);
+      capture.push(&event(seconds, message), Some(position));
+    };
+    push(10.0, note_on(60), 2 * bar + beat);
+    push(10.5, note_off(60), 2 * bar + 2 * beat);
+    push(11.0, note_on(62), 2 * bar + 3 * beat);
+    push(11.5, note_off(62), 3 * bar);
+
+    let captured = capture.copy_last_phrase().into_clip().unwrap();
+    assert_eq!(captured.tempo, None);
+    assert_eq!(captured.clip.start, TicksTime::new(2 * bar));
+    assert_eq!(captured.clip.length, TicksTime::new(bar));
+    let notes: Vec<Note> = captured
+      .notes
+      .notes_range(TicksTime::zero(), TicksTime::new(u64::MAX))
+      .collect();
+    assert_eq!(
+      notes,
+      vec![
+        Note::new(
+          60,
+          100.0 / 127.0,
+          TicksTime::new(beat),
+          TicksTime::new(beat)
+        ),
+        Note::new(
+          62,
+          100.0 / 127.0,
+          TicksTime::new(3 * beat),
+          TicksTime::new(beat)
+        ),
+      ]
+    );
+  }
+}
@@ -1,4 +1,5 @@
 use crate::midi::messages::Message;
+use crate::midi::mmc::MmcCommand;
 use crate::midi::types::{U14, U4, U7};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -220,7 +221,22 @@ impl<'a> Decoder<'a> {
       self.sysex_decoding = false;
       let data = self.sysex_data.to_owned();
       self.sysex_data = Vec::new();
-      Some(DecodedMessage::SysEx { data })
+      match data.as_slice() {
+        [0x7f, _device, 0x01, 0x01, hours, minutes, seconds, frames] => Some(
+          Message::MTCFullFrame {
+            smpte_type: (hours >> 5) & 0x03,
+            hours: hours & 0x1f,
+            minutes: *minutes,
+            seconds: *seconds,
+            frames: *frames,
+          }
+          .into(),
+        ),
+        _ => match MmcCommand::sysex_decode(&data) {
+          Some((device_id, command)) => Some(Message::MachineControl { device_id, command }.into()),
+          None => Some(DecodedMessage::SysEx { data }),
+        },
+      }
     } else {
       Some(self.unknown(self.pos))
     }
@@ -461,6 +477,54 @@ mod tests {
     assert_eq!(dec.next(), None);
   }
 
+  #[test]
+  fn decode_mtc_full_frame() {
+    let data = &vec![
+      0b1111_0000u8,
+      0x7f,
+      0x10,
+      0x01,
+      0x01,
+      0b111_0001,
+      2,
+      3,
+      4,
+      0b1111_0111,
+    ];
+    let mut dec = Decoder::new(data);
+    assert_eq!(
+      dec.next(),
+      Some(
+        Message::MTCFullFrame {
+          smpte_type: 3,
+          hours: 17,
+          minutes: 2,
+          seconds: 3,
+          frames: 4,
+        }
+        .into()
+      )
+    );
+    assert_eq!(dec.next(), None);
+  }
+
+  #[test]
+  fn decode_machine_control() {
+    let data = &vec![0b1111_0000u8, 0x7f, 0x10, 0x06, 0x01, 0b1111_0111];
+    let mut dec = Decoder::new(data);
+    assert_eq!(
+      dec.next(),
+      Some(
+        Message::MachineControl {
+          device_id: 0x10,
+          command: MmcCommand::Stop,
+        }
+        .into()
+      )
+    );
+    assert_eq!(dec.next(), None);
+  }
+
   #[test]
   fn decode_song_position_pointer() {
     let data = &vec![
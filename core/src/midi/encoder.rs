@@ -62,6 +62,8 @@ impl Encoder {
       Message::Stop => 1,
       Message::ActiveSensing => 1,
       Message::SystemReset => 1,
+      Message::MTCFullFrame { .. } => 10,
+      Message::MachineControl { command, .. } => command.sysex_data_size() + 2,
     }
   }
 
@@ -144,6 +146,30 @@ impl Encoder {
       Message::Stop => out[0] = 0b1111_1100,
       Message::ActiveSensing => out[0] = 0b1111_1110,
       Message::SystemReset => out[0] = 0b1111_1111,
+      Message::MTCFullFrame {
+        smpte_type,
+        hours,
+        minutes,
+        seconds,
+        frames,
+      } => out[..10].copy_from_slice(&[
+        0b1111_0000,
+        0x7f,
+        0x7f,
+        0x01,
+        0x01,
+        ((smpte_type & 0x03) << 5) | (hours & 0x1f),
+        u7(minutes),
+        u7(seconds),
+        u7(frames),
+        0b1111_0111,
+      ]),
+      Message::MachineControl { device_id, command } => {
+        let size = command.sysex_data_size();
+        out[0] = 0b1111_0000;
+        command.sysex_encode(*device_id, &mut out[1..=size]);
+        out[size + 1] = 0b1111_0111;
+      }
     }
   }
 
@@ -162,6 +188,7 @@ impl Encoder {
 mod test {
 
   use super::*;
+  use crate::midi::mmc::MmcCommand;
 
   #[test]
   pub fn test_u3() {
@@ -360,6 +387,42 @@ mod test {
     )
   }
 
+  #[test]
+  pub fn mtc_full_frame() {
+    assert_encoding(
+      &Message::MTCFullFrame {
+        smpte_type: 1,
+        hours: 10,
+        minutes: 20,
+        seconds: 30,
+        frames: 24,
+      },
+      vec![
+        0b1111_0000,
+        0x7f,
+        0x7f,
+        0x01,
+        0x01,
+        0b010_1010,
+        20,
+        30,
+        24,
+        0b1111_0111,
+      ],
+    )
+  }
+
+  #[test]
+  pub fn machine_control() {
+    assert_encoding(
+      &Message::MachineControl {
+        device_id: 0x7f,
+        command: MmcCommand::Play,
+      },
+      vec![0b1111_0000, 0x7f, 0x7f, 0x06, 0x02, 0b1111_0111],
+    )
+  }
+
   #[test]
   pub fn song_position_pointer() {
     assert_encoding(
@@ -8,4 +8,7 @@ pub trait MidiInput {
 
 pub trait MidiOutput {
   fn push(&mut self, event: EventIo);
+
+  /// Silence all the outputs, dropping the events pushed before that were not sent yet
+  fn panic(&mut self) {}
 }
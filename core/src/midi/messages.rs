@@ -1,3 +1,4 @@
+use crate::midi::mmc::MmcCommand;
 use crate::midi::types::{U14, U3, U4, U7};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -110,4 +111,20 @@ pub enum Message {
   /// Reset all receivers in the system to power-up status. This should be used sparingly,
   /// preferably under manual control. In particular, it should not be sent on power-up.
   SystemReset,
+
+  // --- Universal Real Time System Exclusive Messages
+  /// MIDI Time Code Full Frame.
+  /// Sent to locate the receivers instead of a series of quarter frames,
+  /// the SMPTE type shares the byte with the hours like in the quarter frames:
+  /// 0 24 fps, 1 25 fps, 2 30 fps drop frame, 3 30 fps
+  MTCFullFrame {
+    smpte_type: U3,
+    hours: U7,
+    minutes: U7,
+    seconds: U7,
+    frames: U7,
+  },
+
+  /// MIDI Machine Control command for a device, or for all of them with the device ID 0x7F
+  MachineControl { device_id: U7, command: MmcCommand },
 }
@@ -0,0 +1,182 @@
+use super::types::{U3, U7};
+
+/// Device ID that addresses every device
+pub const ALL_DEVICES: U7 = 0x7f;
+
+const UNIVERSAL_REAL_TIME: u8 = 0x7f;
+const MMC_COMMAND: u8 = 0x06;
+
+const LOCATE: u8 = 0x44;
+const LOCATE_TARGET: u8 = 0x01;
+const LOCATE_DATA_SIZE: u8 = 6;
+
+/// MIDI Machine Control commands, sent as Universal Real Time System Exclusive messages:
+/// F0 7F <device id> 06 <command> [<data>] F7
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MmcCommand {
+  Stop,
+  Play,
+
+  /// Play once the device has finished locating
+  DeferredPlay,
+
+  FastForward,
+  Rewind,
+
+  /// Start recording, playing first when stopped
+  RecordStrobe,
+
+  /// Stop recording but keep playing
+  RecordExit,
+
+  RecordPause,
+  Pause,
+  Reset,
+
+  /// Locate to the target timecode, the SMPTE type shares the byte with the hours like in the MTC
+  Locate {
+    smpte_type: U3,
+    hours: U7,
+    minutes: U7,
+    seconds: U7,
+    frames: U7,
+    subframes: U7,
+  },
+}
+
+impl MmcCommand {
+  fn code(&self) -> u8 {
+    match self {
+      MmcCommand::Stop => 0x01,
+      MmcCommand::Play => 0x02,
+      MmcCommand::DeferredPlay => 0x03,
+      MmcCommand::FastForward => 0x04,
+      MmcCommand::Rewind => 0x05,
+      MmcCommand::RecordStrobe => 0x06,
+      MmcCommand::RecordExit => 0x07,
+      MmcCommand::RecordPause => 0x08,
+      MmcCommand::Pause => 0x09,
+      MmcCommand::Reset => 0x0d,
+      MmcCommand::Locate { .. } => LOCATE,
+    }
+  }
+
+  fn from_code(code: u8) -> Option<MmcCommand> {
+    match code {
+      0x01 => Some(MmcCommand::Stop),
+      0x02 => Some(MmcCommand::Play),
+      0x03 => Some(MmcCommand::DeferredPlay),
+      0x04 => Some(MmcCommand::FastForward),
+      0x05 => Some(MmcCommand::Rewind),
+      0x06 => Some(MmcCommand::RecordStrobe),
+      0x07 => Some(MmcCommand::RecordExit),
+      0x08 => Some(MmcCommand::RecordPause),
+      0x09 => Some(MmcCommand::Pause),
+      0x0d => Some(MmcCommand::Reset),
+      _ => None,
+    }
+  }
+
+  /// Size of the System Exclusive data, without the start and end bytes
+  pub fn sysex_data_size(&self) -> usize {
+    match self {
+      MmcCommand::Locate { .. } => 5 + LOCATE_DATA_SIZE as usize,
+      _ => 4,
+    }
+  }
+
+  /// Encode the System Exclusive data, without the start and end bytes
+  pub fn sysex_encode(&self, device_id: U7, out: &mut [u8]) {
+    out[..4].copy_from_slice(&[
+      UNIVERSAL_REAL_TIME,
+      device_id & 0x7f,
+      MMC_COMMAND,
+      self.code(),
+    ]);
+    if let MmcCommand::Locate {
+      smpte_type,
+      hours,
+      minutes,
+      seconds,
+      frames,
+      subframes,
+    } = *self
+    {
+      out[4..11].copy_from_slice(&[
+        LOCATE_DATA_SIZE,
+        LOCATE_TARGET,
+        ((smpte_type & 0x03) << 5) | (hours & 0x1f),
+        minutes & 0x7f,
+        seconds & 0x7f,
+        frames & 0x7f,
+        subframes & 0x7f,
+      ]);
+    }
+  }
+
+  /// Decode the device ID and the command from the System Exclusive data, without the start and end bytes.
+  /// Other System Exclusive messages and unsupported commands are ignored.
+  pub fn sysex_decode(data: &[U7]) -> Option<(U7, MmcCommand)> {
+    match data {
+      [UNIVERSAL_REAL_TIME, device_id, MMC_COMMAND, LOCATE, LOCATE_DATA_SIZE, LOCATE_TARGET, hours, minutes, seconds, frames, subframes] =>
+      {
+        let command = MmcCommand::Locate {
+          smpte_type: (hours >> 5) & 0x03,
+          hours: hours & 0x1f,
+          minutes: *minutes,
+          seconds: *seconds,
+          frames: *frames,
+          subframes: *subframes,
+        };
+        Some((*device_id, command))
+      }
+      [UNIVERSAL_REAL_TIME, device_id, MMC_COMMAND, code] => {
+        MmcCommand::from_code(*code).map(|command| (*device_id, command))
+      }
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::MmcCommand;
+
+  fn assert_sysex(command: MmcCommand, device_id: u8, expected: Vec<u8>) {
+    let mut data = vec![0u8; command.sysex_data_size()];
+    command.sysex_encode(device_id, data.as_mut_slice());
+    assert_eq!(data, expected);
+    assert_eq!(MmcCommand::sysex_decode(&data), Some((device_id, command)));
+  }
+
+  #[test]
+  pub fn commands() {
+    assert_sysex(MmcCommand::Stop, 0x7f, vec![0x7f, 0x7f, 0x06, 0x01]);
+    assert_sysex(MmcCommand::Play, 0x10, vec![0x7f, 0x10, 0x06, 0x02]);
+    assert_sysex(MmcCommand::DeferredPlay, 0, vec![0x7f, 0, 0x06, 0x03]);
+    assert_sysex(MmcCommand::RecordStrobe, 1, vec![0x7f, 1, 0x06, 0x06]);
+    assert_sysex(MmcCommand::RecordExit, 1, vec![0x7f, 1, 0x06, 0x07]);
+  }
+
+  #[test]
+  pub fn locate() {
+    let command = MmcCommand::Locate {
+      smpte_type: 1,
+      hours: 1,
+      minutes: 2,
+      seconds: 3,
+      frames: 4,
+      subframes: 5,
+    };
+    let expected = vec![0x7f, 0x7f, 0x06, 0x44, 0x06, 0x01, 0b010_0001, 2, 3, 4, 5];
+    assert_sysex(command, 0x7f, expected);
+  }
+
+  #[test]
+  pub fn ignore_other_sysex() {
+    assert_eq!(MmcCommand::sysex_decode(&[0x7f, 0x7f, 0x06, 0x7e]), None);
+    assert_eq!(MmcCommand::sysex_decode(&[0x7f, 0x7f, 0x01, 0x01]), None);
+    assert_eq!(MmcCommand::sysex_decode(&[0x43, 0x10, 0x06, 0x02]), None);
+  }
+}
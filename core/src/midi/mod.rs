@@ -1,7 +1,11 @@
 //pub mod bus;
+pub mod active_notes;
+pub mod capture;
 pub mod decoder;
 pub mod encoder;
 pub mod messages;
+pub mod mmc;
+pub mod panic;
 pub use messages::Message;
 pub mod buffer;
 pub use buffer::{new_buffer_io_vec_pool, new_buffer_pool, Buffer, BufferIo, BufferIoVec, EventIo};
@@ -0,0 +1,118 @@
+use std::iter;
+
+use crate::config::MidiTrigger;
+use crate::midi::types::{U4, U7};
+use crate::midi::Message;
+
+pub const NUM_CHANNELS: U4 = 16;
+pub const NUM_KEYS: U7 = 128;
+
+/// Messages that silence every channel of an output: all notes off, all sound off and
+/// reset all controllers, followed by a note off for every key when asked to
+pub fn messages(note_offs: bool) -> impl Iterator<Item = Message> {
+  let num_keys = if note_offs { NUM_KEYS } else { 0 };
+  (0..NUM_CHANNELS).flat_map(move |channel| {
+    iter::once(Message::AllNotesOff { channel })
+      .chain(iter::once(Message::AllSoundOff { channel }))
+      .chain(iter::once(Message::ResetAllControllers { channel }))
+      .chain((0..num_keys).map(move |key| Message::NoteOff {
+        channel,
+        key,
+        velocity: 0,
+      }))
+  })
+}
+
+/// Whether an input message triggers the panic, a note on or a controller that is not zero
+pub fn is_trigger(trigger: MidiTrigger, message: &Message) -> bool {
+  let matches_channel =
+    |expected: Option<U4>, channel: U4| expected.is_none_or(|expected| expected == channel);
+  match (trigger, message) {
+    (
+      MidiTrigger::Note {
+        channel: expected_channel,
+        key: expected_key,
+      },
+      Message::NoteOn {
+        channel,
+        key,
+        velocity,
+      },
+    ) => *velocity > 0 && *key == expected_key && matches_channel(expected_channel, *channel),
+    (
+      MidiTrigger::Controller {
+        channel: expected_channel,
+        controller: expected_controller,
+      },
+      Message::ControlChange {
+        channel,
+        controller,
+        value,
+      },
+    ) => {
+      *value > 0
+        && *controller == expected_controller
+        && matches_channel(expected_channel, *channel)
+    }
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{is_trigger, messages};
+  use crate::config::MidiTrigger;
+  use crate::midi::Message;
+
+  #[test]
+  pub fn panic_messages() {
+    let all: Vec<Message> = messages(false).collect();
+    assert_eq!(all.len(), 16 * 3);
+    assert_eq!(all[0], Message::AllNotesOff { channel: 0 });
+    assert_eq!(all[1], Message::AllSoundOff { channel: 0 });
+    assert_eq!(all[2], Message::ResetAllControllers { channel: 0 });
+    assert_eq!(all[47], Message::ResetAllControllers { channel: 15 });
+
+    let all: Vec<Message> = messages(true).collect();
+    assert_eq!(all.len(), 16 * (3 + 128));
+    assert_eq!(
+      all[3 + 127],
+      Message::NoteOff {
+        channel: 0,
+        key: 127,
+        velocity: 0,
+      }
+    );
+  }
+
+  #[test]
+  pub fn triggers() {
+    let note = MidiTrigger::Note {
+      channel: None,
+      key: 0,
+    };
+    let note_on = |channel, key, velocity| Message::NoteOn {
+      channel,
+      key,
+      velocity,
+    };
+    assert!(is_trigger(note, &note_on(3, 0, 100)));
+    assert!(!is_trigger(note, &note_on(3, 0, 0)));
+    assert!(!is_trigger(note, &note_on(3, 1, 100)));
+
+    let controller = MidiTrigger::Controller {
+      channel: Some(15),
+      controller: 119,
+    };
+    let control_change = |channel, value| Message::ControlChange {
+      channel,
+      controller: 119,
+      value,
+    };
+    assert!(is_trigger(controller, &control_change(15, 127)));
+    assert!(!is_trigger(controller, &control_change(15, 0)));
+    assert!(!is_trigger(controller, &control_change(0, 127)));
+    assert!(!is_trigger(controller, &note_on(15, 119, 127)));
+  }
+}
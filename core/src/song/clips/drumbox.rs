@@ -0,0 +1,514 @@
+use std::iter;
+
+use serde_derive::Deserialize;
+
+use crate::midi::types::{U4, U7};
+use crate::song::{
+  clips::{stepper::deserialize_step_length, Clip},
+  source::notes::NoteEvent,
+};
+use crate::time::{ticks::TICKS_RESOLUTION, TicksTime};
+
+/// Most instruments of a kit that can be sent by the clients
+pub const MAX_INSTRUMENTS: usize = 128;
+
+/// Most steps of a pattern that can be sent by the clients
+pub const MAX_STEPS: usize = 128;
+
+/// Instrument of a kit, played with a key and optionally on its own channel instead of the one of the track
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct KitInstrument {
+  pub name: String,
+  pub key: U7,
+  pub channel: Option<U4>,
+}
+
+impl KitInstrument {
+  pub fn new<T>(name: T, key: U7) -> KitInstrument
+  where
+    T: Into<String>,
+  {
+    KitInstrument {
+      name: name.into(),
+      key,
+      channel: None,
+    }
+  }
+}
+
+/// Maps the rows of the patterns to the notes of a drum machine
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Kit {
+  pub name: String,
+  pub instruments: Vec<KitInstrument>,
+}
+
+impl Kit {
+  pub fn new<T>(name: T, instruments: Vec<KitInstrument>) -> Kit
+  where
+    T: Into<String>,
+  {
+    Kit {
+      name: name.into(),
+      instruments,
+    }
+  }
+
+  /// The most common instruments of the General MIDI percussion map
+  pub fn general_midi() -> Kit {
+    Kit::new(
+      "General MIDI",
+      vec![
+        KitInstrument::new("Kick", 36),
+        KitInstrument::new("Rim", 37),
+        KitInstrument::new("Snare", 38),
+        KitInstrument::new("Clap", 39),
+        KitInstrument::new("Closed Hat", 42),
+        KitInstrument::new("Low Tom", 45),
+        KitInstrument::new("Open Hat", 46),
+        KitInstrument::new("Mid Tom", 47),
+        KitInstrument::new("Crash", 49),
+        KitInstrument::new("High Tom", 50),
+        KitInstrument::new("Ride", 51),
+        KitInstrument::new("Cowbell", 56),
+      ],
+    )
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Hit {
+  pub velocity: f64,
+  /// Adds the accent of the pattern to the velocity
+  pub accent: bool,
+  /// Plays a softer grace note on the step and the hit a bit later, see [`DrumClip::set_flam`]
+  pub flam: bool,
+}
+
+impl Hit {
+  pub fn new(velocity: f64) -> Hit {
+    Hit {
+      velocity,
+      accent: false,
+      flam: false,
+    }
+  }
+
+  pub fn accented(velocity: f64) -> Hit {
+    Hit {
+      accent: true,
+      ..Hit::new(velocity)
+    }
+  }
+
+  pub fn flam(velocity: f64) -> Hit {
+    Hit {
+      flam: true,
+      ..Hit::new(velocity)
+    }
+  }
+}
+
+/// Rows of hits for every instrument of the kit, all of them with the same steps
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Pattern {
+  pub name: String,
+  pub num_steps: usize,
+  /// Length of every step, see [`step_length`](super::stepper::step_length)
+  #[serde(deserialize_with = "deserialize_step_length")]
+  pub resolution: TicksTime,
+  /// Velocity added to the accented hits
+  pub accent: f64,
+  pub rows: Vec<Vec<Option<Hit>>>,
+}
+
+impl Pattern {
+  pub fn new<T>(name: T, num_steps: usize, resolution: TicksTime) -> Pattern
+  where
+    T: Into<String>,
+  {
+    Pattern {
+      name: name.into(),
+      num_steps,
+      resolution,
+      accent: 0.25,
+      rows: Vec::new(),
+    }
+  }
+
+  pub fn length(&self) -> TicksTime {
+    TicksTime::new(u64::from(self.resolution) * self.num_steps as u64)
+  }
+
+  /// Set the hit of an instrument at a step, adding the missing rows and steps
+  pub fn set_hit(&mut self, instrument: usize, step: usize, hit: Option<Hit>) {
+    if step >= self.num_steps {
+      return;
+    }
+    if self.rows.len() <= instrument {
+      self.rows.resize_with(instrument + 1, Vec::new);
+    }
+    let row = &mut self.rows[instrument];
+    if row.len() <= step {
+      row.resize(step + 1, None);
+    }
+    row[step] = hit;
+  }
+
+  pub fn get_hit(&self, instrument: usize, step: usize) -> Option<&Hit> {
+    self
+      .rows
+      .get(instrument)
+      .and_then(|row| row.get(step))
+      .and_then(|hit| hit.as_ref())
+      .filter(|_| step < self.num_steps)
+  }
+}
+
+/// Entry of the song-mode sequence, playing a pattern a number of times
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ChainEntry {
+  pub pattern: usize,
+  pub repeats: u32,
+}
+
+impl ChainEntry {
+  pub fn new(pattern: usize, repeats: u32) -> ChainEntry {
+    ChainEntry { pattern, repeats }
+  }
+}
+
+/// Drum machine clip, with patterns of hits for the instruments of a kit.
+/// The patterns play one after the other following the chain, which repeats until the end of the clip.
+/// Without a chain the first pattern repeats.
+pub struct DrumClip {
+  clip: Clip,
+
+  kit: Kit,
+  patterns: Vec<Pattern>,
+  chain: Vec<ChainEntry>,
+  flam: TicksTime,
+  flam_velocity: f64,
+
+  /// Entries of the chain that play with their start within one cycle over the chain
+  entries: Vec<(ChainEntry, TicksTime)>,
+  chain_length: TicksTime,
+}
+
+impl DrumClip {
+  pub fn new(clip: Clip, kit: Kit, patterns: Vec<Pattern>) -> DrumClip {
+    let mut drum_clip = DrumClip {
+      clip,
+      kit,
+      patterns,
+      chain: Vec::new(),
+      flam: TicksTime::new(TICKS_RESOLUTION / 6),
+      flam_velocity: 0.6,
+      entries: Vec::new(),
+      chain_length: TicksTime::zero(),
+    };
+    drum_clip.update_entries();
+    drum_clip
+  }
+
+  pub fn get_clip(&self) -> &Clip {
+    &self.clip
+  }
+
+  pub fn set_kit(&mut self, kit: Kit) {
+    self.kit = kit;
+  }
+
+  pub fn get_kit(&self) -> &Kit {
+    &self.kit
+  }
+
+  /// Add a pattern and return its index
+  pub fn add_pattern(&mut self, pattern: Pattern) -> usize {
+    self.patterns.push(pattern);
+    self.update_entries();
+    self.patterns.len() - 1
+  }
+
+  /// Replace the pattern at an index, or add it when the index is the number of patterns
+  pub fn set_pattern(&mut self, index: usize, pattern: Pattern) {
+    if index < self.patterns.len() {
+      self.patterns[index] = pattern;
+    } else if index == self.patterns.len() {
+      self.patterns.push(pattern);
+    }
+    self.update_entries();
+  }
+
+  pub fn get_patterns(&self) -> &[Pattern] {
+    self.patterns.as_slice()
+  }
+
+  /// The hits for instruments out of the kit are ignored
+  pub fn set_hit(&mut self, pattern: usize, instrument: usize, step: usize, hit: Option<Hit>) {
+    if instrument >= self.kit.instruments.len() {
+      return;
+    }
+    if let Some(pattern) = self.patterns.get_mut(pattern) {
+      pattern.set_hit(instrument, step, hit);
+    }
+  }
+
+  /// The entries for patterns that don't exist are skipped
+  pub fn set_chain(&mut self, chain: Vec<ChainEntry>) {
+    self.chain = chain;
+    self.update_entries();
+  }
+
+  pub fn get_chain(&self) -> &[ChainEntry] {
+    self.chain.as_slice()
+  }
+
+  /// The grace note of a flam plays on the step, and the hit a bit later with its velocity
+  pub fn set_flam(&mut self, offset: TicksTime, velocity: f64) {
+    self.flam = offset;
+    self.flam_velocity = velocity.clamp(0.0, 1.0);
+  }
+
+  pub fn get_flam(&self) -> (TicksTime, f64) {
+    (self.flam, self.flam_velocity)
+  }
+
+  /// Note events of the hits over a range of ticks from the start of the clip, with the channel of the instrument.
+  /// The notes last half a step, and the events of every instrument are in order within a pass of a pattern.
+  pub fn play_events_range<'a>(
+    &'a self,
+    start: TicksTime,
+    end: TicksTime,
+  ) -> impl Iterator<Item = (TicksTime, Option<U4>, NoteEvent)> + 'a {
+    let chain_length = u64::from(self.chain_length);
+    // the notes of the last pass of a cycle might end in the next one
+    let cycles = u64::from(start)
+      .checked_div(chain_length)
+      .map(|first| first.saturating_sub(1)..=u64::from(end) / chain_length);
+    cycles
+      .into_iter()
+      .flatten()
+      .flat_map(move |cycle| {
+        self.entries.iter().flat_map(move |(entry, entry_start)| {
+          let pattern = &self.patterns[entry.pattern];
+          let length = u64::from(pattern.length());
+          let entry_start = cycle * chain_length + u64::from(*entry_start);
+          // the passes that start before the end and whose notes might end after the start
+          let tail = length + u64::from(pattern.resolution);
+          let first = u64::from(start)
+            .checked_sub(entry_start + tail)
+            .map_or(0, |before| before / length + 1);
+          let last = u64::from(end).saturating_sub(entry_start).div_ceil(length);
+          (first..last.min(u64::from(entry.repeats)))
+            .map(move |pass| (pattern, entry_start + pass * length))
+        })
+      })
+      .flat_map(move |(pattern, pass_start)| {
+        self
+          .kit
+          .instruments
+          .iter()
+          .zip(pattern.rows.iter())
+          .flat_map(move |(instrument, row)| {
+            row
+              .iter()
+              .take(pattern.num_steps)
+              .enumerate()
+              .filter_map(|(step, hit)| hit.map(|hit| (step, hit)))
+              .flat_map(move |(step, hit)| {
+                let position = pass_start + u64::from(pattern.resolution) * step as u64;
+                self.hit_events(instrument, pattern, position, hit)
+              })
+          })
+      })
+      .filter(move |(position, _, _)| start <= *position && *position < end)
+  }
+
+  fn hit_events(
+    &self,
+    instrument: &KitInstrument,
+    pattern: &Pattern,
+    position: u64,
+    hit: Hit,
+  ) -> impl Iterator<Item = (TicksTime, Option<U4>, NoteEvent)> {
+    let resolution = u64::from(pattern.resolution);
+    let velocity = if hit.accent {
+      hit.velocity + pattern.accent
+    } else {
+      hit.velocity
+    }
+    .clamp(0.0, 1.0);
+    let flam = u64::from(self.flam).min(resolution / 2);
+    let grace = if hit.flam && flam > 0 {
+      Some((position, (flam / 2).max(1), velocity * self.flam_velocity))
+    } else {
+      None
+    };
+    let main_start = if grace.is_some() {
+      position + flam
+    } else {
+      position
+    };
+    let main_length = (resolution / 2)
+      .saturating_sub(main_start - position)
+      .max(1);
+    let key = instrument.key;
+    let channel = instrument.channel;
+    grace
+      .into_iter()
+      .chain(iter::once((main_start, main_length, velocity)))
+      .flat_map(move |(note_start, length, velocity)| {
+        let note_start = TicksTime::new(note_start);
+        let note_end = TicksTime::new(u64::from(note_start) + length);
+        iter::once((
+          note_start,
+          channel,
+          NoteEvent::NoteStart {
+            key,
+            velocity,
+            end: note_end,
+          },
+        ))
+        .chain(iter::once((
+          note_end,
+          channel,
+          NoteEvent::NoteEnd {
+            key,
+            velocity,
+            start: note_start,
+          },
+        )))
+      })
+  }
+
+  /// The patterns without length are skipped, the others start after the repeats of the ones before
+  fn update_entries(&mut self) {
+    let default_chain = [ChainEntry::new(0, 1)];
+    let chain = if self.chain.is_empty() {
+      &default_chain[..]
+    } else {
+      self.chain.as_slice()
+    };
+    self.entries.clear();
+    let mut position = 0u64;
+    for entry in chain.iter() {
+      let length = self
+        .patterns
+        .get(entry.pattern)
+        .map_or(0, |pattern| u64::from(pattern.length()));
+      if length > 0 && entry.repeats > 0 {
+        self.entries.push((*entry, TicksTime::new(position)));
+        position = position.saturating_add(length.saturating_mul(u64::from(entry.repeats)));
+      }
+    }
+    self.chain_length = TicksTime::new(position);
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{ChainEntry, DrumClip, Hit, Kit, KitInstrument, Pattern};
+  use crate::song::{clips::stepper::deserialize_step_length, source::notes::NoteEvent};
+  use crate::testing::clip;
+  use crate::time::{ticks::TICKS_RESOLUTION, TicksTime};
+
+  const SIXTEENTH: u64 = TICKS_RESOLUTION;
+
+  fn kit() -> Kit {
+    let mut snare = KitInstrument::new("Snare", 38);
+    snare.channel = Some(9);
+    Kit::new("kit", vec![KitInstrument::new("Kick", 36), snare])
+  }
+
+  /// The note starts as (key, channel, velocity, start) in sixteenths
+  fn note_starts(clip: &DrumClip, start: u64, end: u64) -> Vec<(u8, Option<u8>, f64, f64)> {
+    clip
+      .play_events_range(TicksTime::new(start), TicksTime::new(end))
+      .filter_map(|(position, channel, event)| match event {
+        NoteEvent::NoteStart { key, velocity, .. } => Some((
+          key,
+          channel,
+          (velocity * 100.0).round() / 100.0,
+          u64::from(position) as f64 / SIXTEENTH as f64,
+        )),
+        NoteEvent::NoteEnd { .. } => None,
+      })
+      .collect()
+  }
+
+  #[test]
+  pub fn accents_and_flams() {
+    let mut pattern = Pattern::new("A", 4, TicksTime::new(SIXTEENTH));
+    pattern.set_hit(0, 0, Some(Hit::accented(0.5)));
+    pattern.set_hit(1, 2, Some(Hit::flam(0.5)));
+    pattern.set_hit(1, 4, Some(Hit::new(1.0)));
+    let mut drums = DrumClip::new(clip(1, 0, 4), kit(), vec![pattern]);
+    assert_eq!(drums.get_patterns()[0].get_hit(1, 4), None);
+    drums.set_hit(0, 2, 0, Some(Hit::new(1.0)));
+    assert_eq!(drums.get_patterns()[0].rows.len(), 2);
+
+    assert_eq!(
+      note_starts(&drums, 0, SIXTEENTH * 5),
+      vec![
+        (36, None, 0.75, 0.0),
+        (38, Some(9), 0.3, 2.0),
+        (38, Some(9), 0.5, 2.0 + 1.0 / 6.0),
+        (36, None, 0.75, 4.0),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn patterns_chained_in_song_mode() {
+    let mut first = Pattern::new("A", 4, TicksTime::new(SIXTEENTH));
+    first.set_hit(0, 0, Some(Hit::new(1.0)));
+    let mut second = Pattern::new("B", 2, TicksTime::new(SIXTEENTH * 2));
+    second.set_hit(1, 1, Some(Hit::new(1.0)));
+    let mut drums = DrumClip::new(clip(1, 0, 4), kit(), vec![first, second]);
+    drums.set_chain(vec![
+      ChainEntry::new(0, 2),
+      ChainEntry::new(1, 1),
+      ChainEntry::new(7, 1),
+    ]);
+
+    let starts: Vec<(u8, f64)> = note_starts(&drums, 0, SIXTEENTH * 24)
+      .iter()
+      .map(|(key, _, _, start)| (*key, *start))
+      .collect();
+    assert_eq!(
+      starts,
+      vec![
+        (36, 0.0),
+        (36, 4.0),
+        (38, 10.0),
+        (36, 12.0),
+        (36, 16.0),
+        (38, 22.0),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn long_chains() {
+    let mut first = Pattern::new("A", 4, TicksTime::new(SIXTEENTH));
+    first.set_hit(0, 1, Some(Hit::new(1.0)));
+    let mut second = Pattern::new("B", 4, TicksTime::new(SIXTEENTH));
+    second.set_hit(1, 0, Some(Hit::new(1.0)));
+    let mut drums = DrumClip::new(clip(1, 0, 4), kit(), vec![first, second]);
+    let repeats = u32::MAX;
+    drums.set_chain(vec![ChainEntry::new(0, repeats), ChainEntry::new(1, 1)]);
+
+    let second_start = SIXTEENTH * 4 * u64::from(repeats);
+    let starts: Vec<(u8, f64)> = note_starts(
+      &drums,
+      second_start - SIXTEENTH * 4,
+      second_start + SIXTEENTH * 8,
+    )
+    .iter()
+    .map(|(key, _, _, start)| (*key, *start - (second_start / SIXTEENTH) as f64))
+    .collect();
+    assert_eq!(starts, vec![(36, -3.0), (38, 0.0), (36, 5.0)]);
+  }
+}
@@ -1,17 +1,24 @@
 pub mod audio;
+pub mod drumbox;
 pub mod pianoroll;
 pub mod stepper;
+pub mod tracker;
 
-use crate::time::{Signature, TicksTime};
+use serde_derive::Deserialize;
+
+use crate::time::{ticks::deserialize_ticks, Signature, TicksTime};
 
 pub type ClipId = u64;
 
 pub type ClipIndex = usize;
 
+#[derive(Debug, Clone, Deserialize)]
 pub struct Clip {
   pub uuid: ClipId,
   pub name: String,
   pub signature: Signature,
+  #[serde(deserialize_with = "deserialize_ticks")]
   pub start: TicksTime,
+  #[serde(deserialize_with = "deserialize_ticks")]
   pub length: TicksTime,
 }
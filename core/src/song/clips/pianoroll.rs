@@ -10,8 +10,40 @@ pub struct Notes {
   id: ClipId,
 }
 
+impl Notes {
+  pub fn new(source: Arc<RwLock<NotesSource>>, id: ClipId) -> Notes {
+    Notes { source, id }
+  }
+
+  pub fn get_source(&self) -> &Arc<RwLock<NotesSource>> {
+    &self.source
+  }
+
+  pub fn get_id(&self) -> ClipId {
+    self.id
+  }
+}
+
 pub struct NotesClip {
   clip: Clip,
 
   notes: Notes,
 }
+
+impl NotesClip {
+  pub fn new(clip: Clip, notes: Notes) -> NotesClip {
+    NotesClip { clip, notes }
+  }
+
+  pub fn get_clip(&self) -> &Clip {
+    &self.clip
+  }
+
+  pub fn get_clip_mut(&mut self) -> &mut Clip {
+    &mut self.clip
+  }
+
+  pub fn get_notes(&self) -> &Notes {
+    &self.notes
+  }
+}
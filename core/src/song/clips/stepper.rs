@@ -1 +1,412 @@
+use std::iter;
 
+use serde::{de::Error, Deserialize, Deserializer};
+use serde_derive::Deserialize;
+
+use crate::midi::types::U7;
+use crate::song::{clips::Clip, random, source::notes::NoteEvent};
+use crate::time::{ticks::TICKS_RESOLUTION, TicksTime};
+
+pub const MAX_RATCHET: u8 = 16;
+
+pub const MAX_NOTE_VALUE: u64 = 256;
+pub const MAX_TUPLET_NOTES: u64 = 16;
+
+/// Shortest step that can be sent by the clients, a 256th note
+pub const MIN_STEP_LENGTH: u64 = TICKS_RESOLUTION / 16;
+
+/// Length of a step for a note value (4 for quarters, 16 for sixteenths, ...) played as a tuplet
+/// of a number of notes in the space of others, like 3 in the space of 2 for triplets, or 1 in the space of 1
+pub fn step_length(note_value: u64, notes: u64, space: u64) -> TicksTime {
+  TicksTime::new(TICKS_RESOLUTION * 16 * space / (note_value.max(1) * notes.max(1)))
+}
+
+/// Note value of the steps as sent by the clients, like `{"note_value": 8, "notes": 3, "space": 2}`
+#[serde(default)]
+#[derive(Deserialize)]
+struct StepValue {
+  note_value: u64,
+  notes: u64,
+  space: u64,
+}
+
+impl Default for StepValue {
+  fn default() -> Self {
+    StepValue {
+      note_value: 16,
+      notes: 1,
+      space: 1,
+    }
+  }
+}
+
+/// Decode the length of a step from its note value, rejecting the ones too short for playing them
+pub fn deserialize_step_length<'de, D>(deserializer: D) -> Result<TicksTime, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let StepValue {
+    note_value,
+    notes,
+    space,
+  } = StepValue::deserialize(deserializer)?;
+  let in_range = |value: u64, max: u64| (1..=max).contains(&value);
+  if !in_range(note_value, MAX_NOTE_VALUE)
+    || !in_range(notes, MAX_TUPLET_NOTES)
+    || !in_range(space, MAX_TUPLET_NOTES)
+  {
+    return Err(D::Error::custom(format!(
+      "Wrong note value for the steps: {} notes of 1/{} in the space of {}",
+      notes, note_value, space
+    )));
+  }
+  let length = step_length(note_value, notes, space);
+  if u64::from(length) < MIN_STEP_LENGTH {
+    return Err(D::Error::custom(format!(
+      "Steps shorter than a 1/{} note",
+      MAX_NOTE_VALUE
+    )));
+  }
+  Ok(length)
+}
+
+#[serde(default)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Step {
+  pub active: bool,
+  pub velocity: f64,
+  /// Part of the step that the note lasts, or of every repeat when ratcheting
+  pub gate: f64,
+  /// Chance for the step to play, from 0 to 1
+  pub probability: f64,
+  /// Number of times the note is repeated within the step
+  pub ratchet: u8,
+  /// Micro-offset as a part of the step, from -0.5 to 0.5
+  pub offset: f64,
+}
+
+impl Default for Step {
+  fn default() -> Self {
+    Step {
+      active: false,
+      velocity: 1.0,
+      gate: 0.5,
+      probability: 1.0,
+      ratchet: 1,
+      offset: 0.0,
+    }
+  }
+}
+
+impl Step {
+  pub fn on(velocity: f64) -> Step {
+    Step {
+      active: true,
+      velocity,
+      ..Step::default()
+    }
+  }
+
+  fn offset_ticks(&self, length: u64) -> i64 {
+    (self.offset.clamp(-0.5, 0.5) * length as f64).round() as i64
+  }
+}
+
+/// Row of steps playing the same key. Every lane repeats its own number of steps,
+/// so lanes with different lengths or resolutions run against each other (polymeter).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Lane {
+  pub key: U7,
+  /// Length of every step, see [`step_length`]
+  #[serde(deserialize_with = "deserialize_step_length")]
+  pub resolution: TicksTime,
+  pub steps: Vec<Step>,
+  pub mute: bool,
+}
+
+impl Lane {
+  pub fn new(key: U7, resolution: TicksTime, num_steps: usize) -> Lane {
+    Lane {
+      key,
+      resolution,
+      steps: vec![Step::default(); num_steps],
+      mute: false,
+    }
+  }
+
+  /// Length of a whole pass over the steps
+  pub fn length(&self) -> TicksTime {
+    TicksTime::new(u64::from(self.resolution) * self.steps.len() as u64)
+  }
+
+  /// Position of a step from the start of the clip, with its micro-offset
+  fn step_position(&self, number: u64) -> u64 {
+    let resolution = u64::from(self.resolution);
+    let step = &self.steps[(number % self.steps.len() as u64) as usize];
+    let position = (number * resolution) as i64 + step.offset_ticks(resolution);
+    position.max(0) as u64
+  }
+}
+
+/// Step sequencer clip with lanes of steps that generate the notes while playing
+pub struct StepsClip {
+  clip: Clip,
+
+  lanes: Vec<Lane>,
+  seed: u64,
+}
+
+impl StepsClip {
+  pub fn new(clip: Clip, lanes: Vec<Lane>) -> StepsClip {
+    let seed = clip.uuid;
+    StepsClip { clip, lanes, seed }
+  }
+
+  pub fn get_clip(&self) -> &Clip {
+    &self.clip
+  }
+
+  /// Add a lane and return its index
+  pub fn add_lane(&mut self, lane: Lane) -> usize {
+    self.lanes.push(lane);
+    self.lanes.len() - 1
+  }
+
+  /// Replace the lane at an index, or add it when the index is the number of lanes
+  pub fn set_lane(&mut self, index: usize, lane: Lane) {
+    if index < self.lanes.len() {
+      self.lanes[index] = lane;
+    } else if index == self.lanes.len() {
+      self.lanes.push(lane);
+    }
+  }
+
+  pub fn remove_lane(&mut self, index: usize) -> Option<Lane> {
+    if index < self.lanes.len() {
+      Some(self.lanes.remove(index))
+    } else {
+      None
+    }
+  }
+
+  pub fn get_lanes(&self) -> &[Lane] {
+    self.lanes.as_slice()
+  }
+
+  pub fn set_step(&mut self, lane: usize, index: usize, step: Step) {
+    if let Some(current) = self
+      .lanes
+      .get_mut(lane)
+      .and_then(|lane| lane.steps.get_mut(index))
+    {
+      *current = step;
+    }
+  }
+
+  pub fn get_step(&self, lane: usize, index: usize) -> Option<&Step> {
+    self.lanes.get(lane).and_then(|lane| lane.steps.get(index))
+  }
+
+  /// The probabilities are decided from the seed and the number of the step since the start of the clip,
+  /// so the passes over a lane play differently, but playing the clip again plays it the same way
+  pub fn set_seed(&mut self, seed: u64) {
+    self.seed = seed;
+  }
+
+  pub fn get_seed(&self) -> u64 {
+    self.seed
+  }
+
+  /// Note events generated by the steps over a range of ticks from the start of the clip.
+  /// The events of every lane are in order, one lane after the other.
+  pub fn play_events_range<'a>(
+    &'a self,
+    start: TicksTime,
+    end: TicksTime,
+  ) -> impl Iterator<Item = (TicksTime, NoteEvent)> + 'a {
+    self
+      .lanes
+      .iter()
+      .enumerate()
+      .filter(|(_, lane)| {
+        !lane.mute && !lane.steps.is_empty() && lane.resolution > TicksTime::zero()
+      })
+      .flat_map(move |(lane_index, lane)| {
+        let resolution = u64::from(lane.resolution);
+        // the notes of a step might start half a step before it and end half a step after it
+        let first = (u64::from(start) / resolution).saturating_sub(1);
+        let last = u64::from(end) / resolution + 1;
+        (first..=last).flat_map(move |number| self.step_events(lane_index, lane, number))
+      })
+      .filter(move |(position, _)| start <= *position && *position < end)
+  }
+
+  /// Events of the notes of a step, the repeats of a ratchet share the time until the next step
+  fn step_events<'a>(
+    &'a self,
+    lane_index: usize,
+    lane: &'a Lane,
+    number: u64,
+  ) -> impl Iterator<Item = (TicksTime, NoteEvent)> + 'a {
+    let step = &lane.steps[(number % lane.steps.len() as u64) as usize];
+    let plays = step.active
+      && (step.probability >= 1.0 || self.chance(lane_index, number) < step.probability);
+    let ratchet = if plays {
+      u64::from(step.ratchet.clamp(1, MAX_RATCHET))
+    } else {
+      0
+    };
+    let position = lane.step_position(number);
+    let span = lane.step_position(number + 1).saturating_sub(position);
+    let gate = step.gate.clamp(0.0, 1.0);
+    let key = lane.key;
+    let velocity = step.velocity.clamp(0.0, 1.0);
+    (0..ratchet).flat_map(move |repeat| {
+      let note_start = position + span * repeat / ratchet;
+      let note_length = ((span / ratchet) as f64 * gate).round().max(1.0) as u64;
+      let note_start = TicksTime::new(note_start);
+      let note_end = TicksTime::new(u64::from(note_start) + note_length);
+      iter::once((
+        note_start,
+        NoteEvent::NoteStart {
+          key,
+          velocity,
+          end: note_end,
+        },
+      ))
+      .chain(iter::once((
+        note_end,
+        NoteEvent::NoteEnd {
+          key,
+          velocity,
+          start: note_start,
+        },
+      )))
+    })
+  }
+
+  /// Number between 0 and 1 that only depends on the seed, the lane and the step number
+  fn chance(&self, lane_index: usize, number: u64) -> f64 {
+    let mut state = self.seed ^ (lane_index as u64).rotate_left(48) ^ number;
+    let value = random::splitmix64(&mut state);
+    (value >> 11) as f64 / (1u64 << 53) as f64
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{step_length, Lane, Step, StepsClip};
+  use crate::song::source::notes::NoteEvent;
+  use crate::testing::clip;
+  use crate::time::{ticks::TICKS_RESOLUTION, TicksTime};
+
+  const SIXTEENTH: u64 = TICKS_RESOLUTION;
+
+  /// The note starts as (key, velocity, start, end) in sixteenths
+  fn note_starts(clip: &StepsClip, start: u64, end: u64) -> Vec<(u8, f64, f64, f64)> {
+    clip
+      .play_events_range(TicksTime::new(start), TicksTime::new(end))
+      .filter_map(|(position, event)| match event {
+        NoteEvent::NoteStart { key, velocity, end } => Some((
+          key,
+          velocity,
+          u64::from(position) as f64 / SIXTEENTH as f64,
+          u64::from(end) as f64 / SIXTEENTH as f64,
+        )),
+        NoteEvent::NoteEnd { .. } => None,
+      })
+      .collect()
+  }
+
+  fn assert_notes(notes: &[(u8, f64, f64, f64)], expected: &[(u8, f64, f64, f64)]) {
+    assert_eq!(notes.len(), expected.len());
+    for (note, expected_note) in notes.iter().zip(expected.iter()) {
+      assert_eq!(note.0, expected_note.0);
+      assert_eq!(note.1, expected_note.1);
+      assert!((note.2 - expected_note.2).abs() < 1e-6);
+      assert!((note.3 - expected_note.3).abs() < 1e-6);
+    }
+  }
+
+  #[test]
+  pub fn lengths_of_the_steps() {
+    assert_eq!(step_length(16, 1, 1), TicksTime::new(SIXTEENTH));
+    assert_eq!(step_length(8, 3, 2), TicksTime::new(SIXTEENTH * 4 / 3));
+    assert_eq!(step_length(16, 5, 4), TicksTime::new(SIXTEENTH * 4 / 5));
+    assert_eq!(step_length(4, 7, 4), TicksTime::new(SIXTEENTH * 16 / 7));
+  }
+
+  #[test]
+  pub fn lanes_with_their_own_length() {
+    let mut kick = Lane::new(36, step_length(16, 1, 1), 3);
+    kick.steps[0] = Step::on(1.0);
+    let mut hat = Lane::new(42, step_length(8, 3, 2), 2);
+    hat.steps[1] = Step {
+      ratchet: 2,
+      gate: 1.0,
+      offset: 0.25,
+      ..Step::on(0.5)
+    };
+    let steps = StepsClip::new(clip(1, 0, 1), vec![kick, hat]);
+
+    let notes = note_starts(&steps, 0, SIXTEENTH * 8);
+    assert_notes(
+      &notes,
+      &[
+        (36, 1.0, 0.0, 0.5),
+        (36, 1.0, 3.0, 3.5),
+        (36, 1.0, 6.0, 6.5),
+        (42, 0.5, 5.0 / 3.0, 13.0 / 6.0),
+        (42, 0.5, 13.0 / 6.0, 8.0 / 3.0),
+        (42, 0.5, 13.0 / 3.0, 29.0 / 6.0),
+        (42, 0.5, 29.0 / 6.0, 16.0 / 3.0),
+        (42, 0.5, 7.0, 7.5),
+        (42, 0.5, 7.5, 8.0),
+      ],
+    );
+
+    // the notes that started before the range only end in it
+    let notes = note_starts(&steps, SIXTEENTH * 6 + SIXTEENTH / 4, SIXTEENTH * 7);
+    assert!(notes.is_empty());
+  }
+
+  #[test]
+  pub fn probabilities_from_the_seed() {
+    let mut lane = Lane::new(38, step_length(16, 1, 1), 1);
+    lane.steps[0] = Step {
+      probability: 0.25,
+      ..Step::on(1.0)
+    };
+    let mut steps = StepsClip::new(clip(1, 0, 1), vec![lane]);
+    let num_steps = 4000;
+    let played = note_starts(&steps, 0, SIXTEENTH * num_steps);
+    assert!(played.len() > 900 && played.len() < 1100);
+    assert_eq!(played, note_starts(&steps, 0, SIXTEENTH * num_steps));
+
+    steps.set_seed(2);
+    assert_ne!(played, note_starts(&steps, 0, SIXTEENTH * num_steps));
+
+    steps.set_step(0, 0, Step::on(1.0));
+    assert_eq!(note_starts(&steps, 0, SIXTEENTH * 16).len(), 16);
+  }
+
+  #[test]
+  pub fn decode_the_note_value_of_the_steps() {
+    let lane = |resolution: &str| {
+      let content = format!(
+        "key = 36\nmute = false\nsteps = []\nresolution = {}",
+        resolution
+      );
+      toml::from_str::<Lane>(&content).map(|lane| lane.resolution)
+    };
+    assert_eq!(
+      lane("{ note_value = 8, notes = 3, space = 2 }").unwrap(),
+      step_length(8, 3, 2)
+    );
+    assert_eq!(lane("{ note_value = 32 }").unwrap(), step_length(32, 1, 1));
+    assert!(lane("{ note_value = 0 }").is_err());
+    assert!(lane("{ note_value = 256, notes = 3, space = 2 }").is_err());
+    assert!(lane("{ note_value = 4, notes = 1, space = 100000000000 }").is_err());
+  }
+}
@@ -0,0 +1,913 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use failure::Fail;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::midi::types::{U4, U7};
+use crate::song::{
+  clips::Clip,
+  source::notes::{ControlEvent, NoteEvent},
+};
+use crate::time::{ticks::TICKS_RESOLUTION, TicksTime};
+
+pub const MAX_KEY: U7 = 119;
+
+const NOTE_NAMES: [&str; 12] = [
+  "C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-",
+];
+const COLUMNS_SEPARATOR: &str = " | ";
+
+/// Most ticks a line is divided in, the effects run once per tick
+pub const MAX_TICKS_PER_LINE: u32 = 32;
+
+const PITCH_BEND_CENTER: i32 = 8192;
+/// Pitch bend for every unit of the slides, a 64th of a semitone with the usual range of two semitones
+const PITCH_BEND_PER_SLIDE: i32 = 64;
+const VOLUME_CONTROLLER: U7 = 7;
+const DEFAULT_VOLUME: i32 = 100;
+
+#[derive(Debug, Fail)]
+pub enum TrackerError {
+  #[fail(display = "Failed to access the tracker file: {}", cause)]
+  Io { cause: String },
+
+  #[fail(display = "Invalid tracker file: {}", cause)]
+  InvalidFormat { cause: String },
+
+  #[fail(display = "Invalid tracker cell: {}", text)]
+  InvalidCell { text: String },
+}
+
+pub type TrackerResult<T> = Result<T, TrackerError>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellNote {
+  On(U7),
+  Off,
+}
+
+/// Effect commands of a cell, written like the ones of the classic trackers.
+/// They apply on every tick of the line but the first, except the delay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Effect {
+  /// 1xx: bend the pitch up
+  SlideUp(u8),
+  /// 2xx: bend the pitch down
+  SlideDown(u8),
+  /// Axy: raise the volume of the channel by x or lower it by y
+  VolumeSlide { up: u8, down: u8 },
+  /// E9x: play the note again every x ticks
+  Retrigger(u8),
+  /// ECx: end the note after x ticks
+  NoteCut(u8),
+  /// EDx: start the note after x ticks
+  Delay(u8),
+}
+
+/// Note, instrument, volume and effect of a line of a column, shown like `C#4 01 7F A0F`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Cell {
+  pub note: Option<CellNote>,
+  /// Number of the instrument, starting from 1
+  pub instrument: Option<u8>,
+  /// Velocity of the note
+  pub volume: Option<U7>,
+  pub effect: Option<Effect>,
+}
+
+impl Cell {
+  pub fn note(key: U7, instrument: u8) -> Cell {
+    Cell {
+      note: Some(CellNote::On(key.min(MAX_KEY))),
+      instrument: Some(instrument),
+      ..Cell::default()
+    }
+  }
+
+  pub fn off() -> Cell {
+    Cell {
+      note: Some(CellNote::Off),
+      ..Cell::default()
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    *self == Cell::default()
+  }
+}
+
+impl FromStr for Cell {
+  type Err = TrackerError;
+
+  fn from_str(text: &str) -> TrackerResult<Cell> {
+    let invalid_cell = || TrackerError::InvalidCell {
+      text: text.to_string(),
+    };
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    if parts.len() != 4 {
+      return Err(invalid_cell());
+    }
+    let hex = |part: &str| u8::from_str_radix(part, 16).map_err(|_| invalid_cell());
+    let note = match parts[0] {
+      "---" => None,
+      "===" => Some(CellNote::Off),
+      note if note.len() == 3 => {
+        let semitone = NOTE_NAMES
+          .iter()
+          .position(|name| note.starts_with(name))
+          .ok_or_else(invalid_cell)?;
+        let octave = note[2..].parse::<u8>().map_err(|_| invalid_cell())?;
+        Some(CellNote::On(octave * 12 + semitone as u8))
+      }
+      _ => return Err(invalid_cell()),
+    };
+    let instrument = match parts[1] {
+      ".." => None,
+      instrument => Some(hex(instrument)?),
+    };
+    let volume = match parts[2] {
+      ".." => None,
+      volume => Some(hex(volume)?.min(127)),
+    };
+    let effect = match parts[3] {
+      "..." => None,
+      effect if effect.len() == 3 && effect.is_char_boundary(1) => {
+        let command = &effect[0..1];
+        let value = hex(&effect[1..])?;
+        let (x, y) = (value >> 4, value & 0x0f);
+        match (command, x) {
+          ("1", _) => Some(Effect::SlideUp(value)),
+          ("2", _) => Some(Effect::SlideDown(value)),
+          ("A", _) => Some(Effect::VolumeSlide { up: x, down: y }),
+          ("E", 0x9) => Some(Effect::Retrigger(y)),
+          ("E", 0xC) => Some(Effect::NoteCut(y)),
+          ("E", 0xD) => Some(Effect::Delay(y)),
+          _ => return Err(invalid_cell()),
+        }
+      }
+      _ => return Err(invalid_cell()),
+    };
+    Ok(Cell {
+      note,
+      instrument,
+      volume,
+      effect,
+    })
+  }
+}
+
+impl fmt::Display for Cell {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.note {
+      Some(CellNote::On(key)) => {
+        let key = key.min(MAX_KEY);
+        write!(f, "{}{}", NOTE_NAMES[usize::from(key % 12)], key / 12)?
+      }
+      Some(CellNote::Off) => write!(f, "===")?,
+      None => write!(f, "---")?,
+    }
+    match self.instrument {
+      Some(instrument) => write!(f, " {:02X}", instrument)?,
+      None => write!(f, " ..")?,
+    }
+    match self.volume {
+      Some(volume) => write!(f, " {:02X}", volume)?,
+      None => write!(f, " ..")?,
+    }
+    match self.effect {
+      Some(Effect::SlideUp(value)) => write!(f, " 1{:02X}", value),
+      Some(Effect::SlideDown(value)) => write!(f, " 2{:02X}", value),
+      Some(Effect::VolumeSlide { up, down }) => write!(f, " A{:X}{:X}", up & 0x0f, down & 0x0f),
+      Some(Effect::Retrigger(ticks)) => write!(f, " E9{:X}", ticks & 0x0f),
+      Some(Effect::NoteCut(ticks)) => write!(f, " EC{:X}", ticks & 0x0f),
+      Some(Effect::Delay(ticks)) => write!(f, " ED{:X}", ticks & 0x0f),
+      None => write!(f, " ..."),
+    }
+  }
+}
+
+/// Instrument of the tracker, played on its own channel or on the one of the track
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackerInstrument {
+  pub name: String,
+  pub channel: Option<U4>,
+}
+
+impl TrackerInstrument {
+  pub fn new<T>(name: T, channel: Option<U4>) -> TrackerInstrument
+  where
+    T: Into<String>,
+  {
+    TrackerInstrument {
+      name: name.into(),
+      channel,
+    }
+  }
+}
+
+/// Lines of cells for a number of columns
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackerPattern {
+  pub name: String,
+  num_columns: usize,
+  lines: Vec<Vec<Cell>>,
+}
+
+impl TrackerPattern {
+  pub fn new<T>(name: T, num_lines: usize, num_columns: usize) -> TrackerPattern
+  where
+    T: Into<String>,
+  {
+    TrackerPattern {
+      name: name.into(),
+      num_columns,
+      lines: vec![vec![Cell::default(); num_columns]; num_lines],
+    }
+  }
+
+  pub fn num_lines(&self) -> usize {
+    self.lines.len()
+  }
+
+  pub fn num_columns(&self) -> usize {
+    self.num_columns
+  }
+
+  pub fn get_cell(&self, line: usize, column: usize) -> Option<&Cell> {
+    self.lines.get(line).and_then(|cells| cells.get(column))
+  }
+
+  pub fn set_cell(&mut self, line: usize, column: usize, cell: Cell) {
+    if let Some(current) = self
+      .lines
+      .get_mut(line)
+      .and_then(|cells| cells.get_mut(column))
+    {
+      *current = cell;
+    }
+  }
+
+  /// Insert an empty cell pushing the next ones of the column down, the last one is lost
+  pub fn insert_cell(&mut self, line: usize, column: usize) {
+    if line < self.lines.len() && column < self.num_columns {
+      for index in (line + 1..self.lines.len()).rev() {
+        self.lines[index][column] = self.lines[index - 1][column];
+      }
+      self.lines[line][column] = Cell::default();
+    }
+  }
+
+  /// Remove a cell pulling the next ones of the column up
+  pub fn delete_cell(&mut self, line: usize, column: usize) {
+    if line < self.lines.len() && column < self.num_columns {
+      let last = self.lines.len() - 1;
+      for index in line..last {
+        self.lines[index][column] = self.lines[index + 1][column];
+      }
+      self.lines[last][column] = Cell::default();
+    }
+  }
+
+  pub fn set_num_lines(&mut self, num_lines: usize) {
+    let num_columns = self.num_columns;
+    self
+      .lines
+      .resize_with(num_lines, || vec![Cell::default(); num_columns]);
+  }
+
+  pub fn set_num_columns(&mut self, num_columns: usize) {
+    self.num_columns = num_columns;
+    for cells in self.lines.iter_mut() {
+      cells.resize(num_columns, Cell::default());
+    }
+  }
+
+  fn from_lines<T>(name: T, lines: &[String]) -> TrackerResult<TrackerPattern>
+  where
+    T: Into<String>,
+  {
+    let lines = lines
+      .iter()
+      .map(|line| {
+        line
+          .split(COLUMNS_SEPARATOR.trim())
+          .map(|text| text.parse::<Cell>())
+          .collect::<TrackerResult<Vec<Cell>>>()
+      })
+      .collect::<TrackerResult<Vec<Vec<Cell>>>>()?;
+    let num_columns = lines.first().map_or(0, |cells| cells.len());
+    if lines.iter().any(|cells| cells.len() != num_columns) {
+      return Err(TrackerError::InvalidFormat {
+        cause: "the lines of a pattern have a different number of columns".to_string(),
+      });
+    }
+    Ok(TrackerPattern {
+      name: name.into(),
+      num_columns,
+      lines,
+    })
+  }
+
+  fn to_lines(&self) -> Vec<String> {
+    self
+      .lines
+      .iter()
+      .map(|cells| {
+        cells
+          .iter()
+          .map(|cell| cell.to_string())
+          .collect::<Vec<String>>()
+          .join(COLUMNS_SEPARATOR)
+      })
+      .collect()
+  }
+}
+
+/// Patterns of a tracker song, played in the order of the sequence.
+/// The lines last a part of a beat, and are divided in ticks for the effects.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tracker {
+  pub lines_per_beat: u32,
+  pub ticks_per_line: u32,
+  pub instruments: Vec<TrackerInstrument>,
+  pub patterns: Vec<TrackerPattern>,
+  /// Indices of the patterns to play, the ones that don't exist are skipped
+  pub sequence: Vec<usize>,
+}
+
+impl Default for Tracker {
+  fn default() -> Self {
+    Tracker {
+      lines_per_beat: 4,
+      ticks_per_line: 6,
+      instruments: Vec::new(),
+      patterns: Vec::new(),
+      sequence: Vec::new(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TrackerFile {
+  lines_per_beat: u32,
+  ticks_per_line: u32,
+  sequence: Vec<usize>,
+  instruments: Vec<TrackerInstrument>,
+  patterns: Vec<TrackerPatternFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TrackerPatternFile {
+  name: String,
+  lines: Vec<String>,
+}
+
+impl Tracker {
+  pub fn new() -> Tracker {
+    Tracker::default()
+  }
+
+  /// Length of a line, a beat being a quarter note
+  pub fn line_length(&self) -> TicksTime {
+    TicksTime::new(TICKS_RESOLUTION * 4 / u64::from(self.lines_per_beat.max(1)))
+  }
+
+  /// The file keeps every line of a pattern as text, with the cells of the columns separated by `|`
+  pub fn from_toml(content: &str) -> TrackerResult<Tracker> {
+    let file: TrackerFile = toml::from_str(content).map_err(|err| TrackerError::InvalidFormat {
+      cause: err.to_string(),
+    })?;
+    let patterns = file
+      .patterns
+      .iter()
+      .map(|pattern| TrackerPattern::from_lines(pattern.name.as_str(), &pattern.lines))
+      .collect::<TrackerResult<Vec<TrackerPattern>>>()?;
+    Ok(Tracker {
+      lines_per_beat: file.lines_per_beat,
+      ticks_per_line: file.ticks_per_line.clamp(1, MAX_TICKS_PER_LINE),
+      instruments: file.instruments,
+      patterns,
+      sequence: file.sequence,
+    })
+  }
+
+  pub fn to_toml(&self) -> TrackerResult<String> {
+    let file = TrackerFile {
+      lines_per_beat: self.lines_per_beat,
+      ticks_per_line: self.ticks_per_line,
+      sequence: self.sequence.clone(),
+      instruments: self.instruments.clone(),
+      patterns: self
+        .patterns
+        .iter()
+        .map(|pattern| TrackerPatternFile {
+          name: pattern.name.clone(),
+          lines: pattern.to_lines(),
+        })
+        .collect(),
+    };
+    toml::to_string(&file).map_err(|err| TrackerError::InvalidFormat {
+      cause: err.to_string(),
+    })
+  }
+
+  pub fn open(path: &str) -> TrackerResult<Tracker> {
+    let mut content = String::new();
+    File::open(path)
+      .and_then(|mut file| file.read_to_string(&mut content))
+      .map_err(|err| TrackerError::Io {
+        cause: err.to_string(),
+      })?;
+    Tracker::from_toml(&content)
+  }
+
+  pub fn save(&self, path: &str) -> TrackerResult<()> {
+    let content = self.to_toml()?;
+    File::create(path)
+      .and_then(|mut file| file.write_all(content.as_bytes()))
+      .map_err(|err| TrackerError::Io {
+        cause: err.to_string(),
+      })
+  }
+}
+
+/// Event compiled from the cells, a note or a control for the channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackerEvent {
+  Note(NoteEvent),
+  Control(ControlEvent),
+}
+
+type CompiledEvent = (TicksTime, Option<U4>, TrackerEvent);
+
+/// Clip that plays a tracker song, repeating its sequence until the end of the clip.
+/// The cells are compiled into events every time they change, so playing only needs to look them up.
+#[derive(Clone)]
+pub struct TrackerClip {
+  clip: Clip,
+
+  tracker: Tracker,
+  events: Vec<CompiledEvent>,
+  length: TicksTime,
+}
+
+impl TrackerClip {
+  pub fn new(clip: Clip, tracker: Tracker) -> TrackerClip {
+    let mut tracker_clip = TrackerClip {
+      clip,
+      tracker,
+      events: Vec::new(),
+      length: TicksTime::zero(),
+    };
+    tracker_clip.compile();
+    tracker_clip
+  }
+
+  pub fn get_clip(&self) -> &Clip {
+    &self.clip
+  }
+
+  pub fn set_tracker(&mut self, tracker: Tracker) {
+    self.tracker = tracker;
+    self.compile();
+  }
+
+  pub fn get_tracker(&self) -> &Tracker {
+    &self.tracker
+  }
+
+  pub fn set_cell(&mut self, pattern: usize, line: usize, column: usize, cell: Cell) {
+    if let Some(pattern) = self.tracker.patterns.get_mut(pattern) {
+      pattern.set_cell(line, column, cell);
+      self.compile();
+    }
+  }
+
+  /// Insert an empty cell in a column of a pattern, see [`TrackerPattern::insert_cell`]
+  pub fn insert_cell(&mut self, pattern: usize, line: usize, column: usize) {
+    if let Some(pattern) = self.tracker.patterns.get_mut(pattern) {
+      pattern.insert_cell(line, column);
+      self.compile();
+    }
+  }
+
+  /// Remove a cell from a column of a pattern, see [`TrackerPattern::delete_cell`]
+  pub fn delete_cell(&mut self, pattern: usize, line: usize, column: usize) {
+    if let Some(pattern) = self.tracker.patterns.get_mut(pattern) {
+      pattern.delete_cell(line, column);
+      self.compile();
+    }
+  }
+
+  pub fn set_sequence(&mut self, sequence: Vec<usize>) {
+    self.tracker.sequence = sequence;
+    self.compile();
+  }
+
+  /// Length of one pass over the sequence
+  pub fn length(&self) -> TicksTime {
+    self.length
+  }
+
+  /// Events over a range of ticks from the start of the clip, with the channel of the instrument playing them
+  pub fn play_events_range<'a>(
+    &'a self,
+    start: TicksTime,
+    end: TicksTime,
+  ) -> impl Iterator<Item = CompiledEvent> + 'a {
+    let length = u64::from(self.length);
+    // the notes still playing at the end of the sequence end at the start of the next pass
+    let passes = u64::from(start)
+      .checked_div(length)
+      .map(|first| first.saturating_sub(1)..=u64::from(end) / length);
+    passes.into_iter().flatten().flat_map(move |pass| {
+      let offset = pass * length;
+      let pass_start = TicksTime::new(u64::from(start).saturating_sub(offset));
+      let pass_end = TicksTime::new(u64::from(end).saturating_sub(offset));
+      let first = self
+        .events
+        .partition_point(|(position, _, _)| *position < pass_start);
+      let last = self
+        .events
+        .partition_point(|(position, _, _)| *position < pass_end);
+      self.events[first..last]
+        .iter()
+        .map(move |(position, channel, event)| {
+          let offset = TicksTime::new(offset);
+          let event = match *event {
+            TrackerEvent::Note(NoteEvent::NoteStart { key, velocity, end }) => {
+              TrackerEvent::Note(NoteEvent::NoteStart {
+                key,
+                velocity,
+                end: end + offset,
+              })
+            }
+            TrackerEvent::Note(NoteEvent::NoteEnd {
+              key,
+              velocity,
+              start,
+            }) => TrackerEvent::Note(NoteEvent::NoteEnd {
+              key,
+              velocity,
+              start: start + offset,
+            }),
+            event => event,
+          };
+          (*position + offset, *channel, event)
+        })
+    })
+  }
+
+  fn compile(&mut self) {
+    self.events.clear();
+    let tracker = &self.tracker;
+    let line_length = u64::from(tracker.line_length());
+    let ticks_per_line = u64::from(tracker.ticks_per_line.clamp(1, MAX_TICKS_PER_LINE));
+    let patterns: Vec<&TrackerPattern> = tracker
+      .sequence
+      .iter()
+      .filter_map(|index| tracker.patterns.get(*index))
+      .collect();
+    let num_columns = patterns
+      .iter()
+      .map(|pattern| pattern.num_columns)
+      .max()
+      .unwrap_or(0);
+    let num_lines: usize = patterns.iter().map(|pattern| pattern.num_lines()).sum();
+    let length = num_lines as u64 * line_length;
+    for column in 0..num_columns {
+      let mut state = ColumnState::new(line_length, ticks_per_line);
+      let lines = patterns.iter().flat_map(|pattern| {
+        pattern
+          .lines
+          .iter()
+          .map(move |cells| cells.get(column).cloned().unwrap_or_default())
+      });
+      for (line, cell) in lines.enumerate() {
+        let line_start = line as u64 * line_length;
+        state.play(&cell, line_start, &tracker.instruments, &mut self.events);
+      }
+      state.end_note(length, &mut self.events);
+    }
+    self.events.sort_by_key(|(position, _, _)| *position);
+    self.length = TicksTime::new(length);
+  }
+}
+
+/// Note playing in a column, with the index of its start event to set its end
+struct ColumnNote {
+  key: U7,
+  velocity: f64,
+  channel: Option<U4>,
+  start: u64,
+  event_index: usize,
+}
+
+/// What the previous lines of a column left for the next ones
+struct ColumnState {
+  line_length: u64,
+  ticks_per_line: u64,
+  note: Option<ColumnNote>,
+  instrument: Option<u8>,
+  bend: i32,
+  volume: i32,
+}
+
+impl ColumnState {
+  fn new(line_length: u64, ticks_per_line: u64) -> ColumnState {
+    ColumnState {
+      line_length,
+      ticks_per_line,
+      note: None,
+      instrument: None,
+      bend: 0,
+      volume: DEFAULT_VOLUME,
+    }
+  }
+
+  fn play(
+    &mut self,
+    cell: &Cell,
+    line_start: u64,
+    instruments: &[TrackerInstrument],
+    events: &mut Vec<CompiledEvent>,
+  ) {
+    let tick_length = self.line_length / self.ticks_per_line;
+    let tick_position = |tick: u64| line_start + tick * tick_length;
+    if cell.instrument.is_some() {
+      self.instrument = cell.instrument;
+    }
+    let channel = self
+      .instrument
+      .and_then(|number| instruments.get(usize::from(number).wrapping_sub(1)))
+      .and_then(|instrument| instrument.channel);
+
+    let note_position = match cell.effect {
+      Some(Effect::Delay(ticks)) => tick_position(u64::from(ticks).min(self.ticks_per_line - 1)),
+      _ => line_start,
+    };
+    match cell.note {
+      Some(CellNote::On(key)) => {
+        self.end_note(note_position, events);
+        if self.bend != 0 {
+          self.bend = 0;
+          let value = PITCH_BEND_CENTER as u16;
+          let event = TrackerEvent::Control(ControlEvent::PitchBend { value });
+          events.push((TicksTime::new(note_position), channel, event));
+        }
+        let velocity = cell.volume.map_or(1.0, |volume| f64::from(volume) / 127.0);
+        self.start_note(key, velocity, channel, note_position, events);
+      }
+      Some(CellNote::Off) => self.end_note(note_position, events),
+      None => {}
+    }
+
+    let channel = self.note.as_ref().map_or(channel, |note| note.channel);
+    let ticks = 1..self.ticks_per_line;
+    match cell.effect {
+      Some(Effect::SlideUp(amount)) | Some(Effect::SlideDown(amount)) => {
+        let sign = if let Some(Effect::SlideUp(_)) = cell.effect {
+          1
+        } else {
+          -1
+        };
+        for tick in ticks {
+          self.bend = (self.bend + sign * i32::from(amount) * PITCH_BEND_PER_SLIDE)
+            .clamp(-PITCH_BEND_CENTER, PITCH_BEND_CENTER - 1);
+          let value = (PITCH_BEND_CENTER + self.bend) as u16;
+          let event = TrackerEvent::Control(ControlEvent::PitchBend { value });
+          events.push((TicksTime::new(tick_position(tick)), channel, event));
+        }
+      }
+      Some(Effect::VolumeSlide { up, down }) => {
+        for tick in ticks {
+          self.volume = (self.volume + 2 * (i32::from(up) - i32::from(down))).clamp(0, 127);
+          let event = TrackerEvent::Control(ControlEvent::Controller {
+            controller: VOLUME_CONTROLLER,
+            value: self.volume as U7,
+          });
+          events.push((TicksTime::new(tick_position(tick)), channel, event));
+        }
+      }
+      Some(Effect::Retrigger(interval)) if interval > 0 => {
+        let ticks = u64::from(interval)..self.ticks_per_line;
+        for tick in ticks.step_by(usize::from(interval)) {
+          if let Some(note) = self.note.take() {
+            let position = tick_position(tick);
+            let (key, velocity, channel) = (note.key, note.velocity, note.channel);
+            self.note = Some(note);
+            self.end_note(position, events);
+            self.start_note(key, velocity, channel, position, events);
+          }
+        }
+      }
+      Some(Effect::NoteCut(tick)) if u64::from(tick) < self.ticks_per_line => {
+        self.end_note(tick_position(u64::from(tick)), events);
+      }
+      _ => {}
+    }
+  }
+
+  fn start_note(
+    &mut self,
+    key: U7,
+    velocity: f64,
+    channel: Option<U4>,
+    position: u64,
+    events: &mut Vec<CompiledEvent>,
+  ) {
+    let event = TrackerEvent::Note(NoteEvent::NoteStart {
+      key,
+      velocity,
+      end: TicksTime::new(position),
+    });
+    events.push((TicksTime::new(position), channel, event));
+    self.note = Some(ColumnNote {
+      key,
+      velocity,
+      channel,
+      start: position,
+      event_index: events.len() - 1,
+    });
+  }
+
+  fn end_note(&mut self, position: u64, events: &mut Vec<CompiledEvent>) {
+    if let Some(note) = self.note.take() {
+      let end = TicksTime::new(position);
+      if let (_, _, TrackerEvent::Note(NoteEvent::NoteStart { end: note_end, .. })) =
+        &mut events[note.event_index]
+      {
+        *note_end = end;
+      }
+      let event = TrackerEvent::Note(NoteEvent::NoteEnd {
+        key: note.key,
+        velocity: note.velocity,
+        start: TicksTime::new(note.start),
+      });
+      events.push((end, note.channel, event));
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{
+    Cell, CellNote, Effect, Tracker, TrackerClip, TrackerEvent, TrackerInstrument, TrackerPattern,
+    MAX_TICKS_PER_LINE,
+  };
+  use crate::song::source::notes::{ControlEvent, NoteEvent};
+  use crate::testing::clip;
+  use crate::time::{ticks::TICKS_RESOLUTION, TicksTime};
+
+  const SIXTEENTH: u64 = TICKS_RESOLUTION;
+
+  #[test]
+  pub fn cells_as_text() {
+    let cell = Cell {
+      note: Some(CellNote::On(49)),
+      instrument: Some(1),
+      volume: Some(0x7f),
+      effect: Some(Effect::VolumeSlide { up: 0, down: 0xf }),
+    };
+    assert_eq!(cell.to_string(), "C#4 01 7F A0F");
+    assert_eq!("C#4 01 7F A0F".parse::<Cell>().unwrap(), cell);
+    assert_eq!("--- .. .. ...".parse::<Cell>().unwrap(), Cell::default());
+    assert_eq!(
+      "=== .. .. ED3".parse::<Cell>().unwrap().to_string(),
+      "=== .. .. ED3"
+    );
+    assert!("H-4 01 7F ...".parse::<Cell>().is_err());
+    assert!("C-4 01 7F E1".parse::<Cell>().is_err());
+  }
+
+  #[test]
+  pub fn save_and_open_the_patterns() {
+    let mut pattern = TrackerPattern::new("intro", 4, 2);
+    pattern.set_cell(0, 0, Cell::note(48, 1));
+    pattern.set_cell(2, 1, Cell::off());
+    pattern.insert_cell(0, 0);
+    let tracker = Tracker {
+      instruments: vec![
+        TrackerInstrument::new("bass", Some(1)),
+        TrackerInstrument::new("lead", None),
+      ],
+      patterns: vec![pattern],
+      sequence: vec![0, 0],
+      ..Tracker::default()
+    };
+
+    let content = tracker.to_toml().unwrap();
+    assert!(content.contains("\"--- .. .. ... | --- .. .. ...\""));
+    assert!(content.contains("\"C-4 01 .. ... | --- .. .. ...\""));
+    assert_eq!(Tracker::from_toml(&content).unwrap(), tracker);
+
+    let tracker = Tracker {
+      ticks_per_line: u32::MAX,
+      ..tracker
+    };
+    let content = tracker.to_toml().unwrap();
+    let tracker = Tracker::from_toml(&content).unwrap();
+    assert_eq!(tracker.ticks_per_line, MAX_TICKS_PER_LINE);
+  }
+
+  #[test]
+  pub fn compile_the_effects() {
+    let mut pattern = TrackerPattern::new("A", 4, 1);
+    pattern.set_cell(
+      0,
+      0,
+      Cell {
+        effect: Some(Effect::Retrigger(3)),
+        ..Cell::note(60, 1)
+      },
+    );
+    pattern.set_cell(
+      1,
+      0,
+      Cell {
+        effect: Some(Effect::SlideUp(2)),
+        ..Cell::default()
+      },
+    );
+    pattern.set_cell(
+      2,
+      0,
+      Cell {
+        volume: Some(0x40),
+        effect: Some(Effect::Delay(3)),
+        ..Cell::note(62, 2)
+      },
+    );
+    pattern.set_cell(3, 0, Cell::off());
+    let tracker = Tracker {
+      instruments: vec![
+        TrackerInstrument::new("lead", None),
+        TrackerInstrument::new("pad", Some(3)),
+      ],
+      patterns: vec![pattern],
+      sequence: vec![0],
+      ..Tracker::default()
+    };
+    let tracker_clip = TrackerClip::new(clip(1, 0, 4), tracker);
+    assert_eq!(tracker_clip.length(), TicksTime::new(SIXTEENTH * 4));
+
+    let tick = SIXTEENTH / 6;
+    let events: Vec<(u64, Option<u8>, TrackerEvent)> = tracker_clip
+      .play_events_range(TicksTime::zero(), TicksTime::new(SIXTEENTH * 4 + 1))
+      .map(|(position, channel, event)| (u64::from(position), channel, event))
+      .collect();
+    let note_start = |key, velocity, end| {
+      TrackerEvent::Note(NoteEvent::NoteStart {
+        key,
+        velocity,
+        end: TicksTime::new(end),
+      })
+    };
+    let note_end = |key, velocity, start| {
+      TrackerEvent::Note(NoteEvent::NoteEnd {
+        key,
+        velocity,
+        start: TicksTime::new(start),
+      })
+    };
+    let bend = |value| TrackerEvent::Control(ControlEvent::PitchBend { value });
+    let mut expected = vec![
+      (0, None, note_start(60, 1.0, 3 * tick)),
+      (3 * tick, None, note_end(60, 1.0, 0)),
+      (
+        3 * tick,
+        None,
+        note_start(60, 1.0, 2 * SIXTEENTH + 3 * tick),
+      ),
+    ];
+    for tick_number in 1..6 {
+      let value = 8192 + 128 * tick_number as u16;
+      expected.push((SIXTEENTH + tick_number * tick, None, bend(value)));
+    }
+    let velocity = f64::from(0x40) / 127.0;
+    expected.extend(vec![
+      (2 * SIXTEENTH + 3 * tick, None, note_end(60, 1.0, 3 * tick)),
+      (2 * SIXTEENTH + 3 * tick, Some(3), bend(8192)),
+      (
+        2 * SIXTEENTH + 3 * tick,
+        Some(3),
+        note_start(62, velocity, 3 * SIXTEENTH),
+      ),
+      (
+        3 * SIXTEENTH,
+        Some(3),
+        note_end(62, velocity, 2 * SIXTEENTH + 3 * tick),
+      ),
+      (
+        4 * SIXTEENTH,
+        None,
+        note_start(60, 1.0, 4 * SIXTEENTH + 3 * tick),
+      ),
+    ]);
+    assert_eq!(events, expected);
+  }
+}
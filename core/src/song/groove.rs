@@ -0,0 +1,261 @@
+use crate::song::source::notes::{Note, NotesClip};
+use crate::time::TicksTime;
+
+/// Timing offset in ticks and velocity factor for the notes around a grid line
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GrooveStep {
+  offset: i64,
+  velocity: f64,
+}
+
+impl GrooveStep {
+  pub fn new(offset: i64, velocity: f64) -> GrooveStep {
+    GrooveStep { offset, velocity }
+  }
+
+  pub fn straight() -> GrooveStep {
+    GrooveStep::new(0, 1.0)
+  }
+
+  pub fn get_offset(&self) -> i64 {
+    self.offset
+  }
+
+  pub fn get_velocity(&self) -> f64 {
+    self.velocity
+  }
+}
+
+/// A groove template moves every grid line by the offset of its step, repeating the steps along the song.
+/// The positions between two grid lines are stretched between the moved lines,
+/// so the order of the events never changes.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Groove {
+  grid: TicksTime,
+  steps: Vec<GrooveStep>,
+}
+
+impl Groove {
+  /// The offsets are limited to half the grid so the grid lines never cross each other
+  pub fn new(grid: TicksTime, steps: Vec<GrooveStep>) -> Groove {
+    let max_offset = (u64::from(grid) / 2) as i64;
+    let steps = if steps.is_empty() {
+      vec![GrooveStep::straight()]
+    } else {
+      steps
+        .into_iter()
+        .map(|step| {
+          let offset = step.offset.clamp(-max_offset, max_offset);
+          GrooveStep::new(offset, step.velocity.max(0.0))
+        })
+        .collect()
+    };
+    Groove { grid, steps }
+  }
+
+  /// Grid without any offset nor velocity change, useful to quantize
+  pub fn straight(grid: TicksTime) -> Groove {
+    Groove::new(grid, vec![GrooveStep::straight()])
+  }
+
+  /// Swing delays every second grid line. The percentage is the part of a pair of grid lines
+  /// taken by the first one, from 50% (straight) to 75% (dotted), where 66.67% swings as triplets.
+  pub fn swing(grid: TicksTime, percentage: f64) -> Groove {
+    let percentage = percentage.clamp(50.0, 75.0);
+    let offset = (u64::from(grid) as f64 * (2.0 * percentage - 100.0) / 100.0).round() as i64;
+    Groove::new(
+      grid,
+      vec![GrooveStep::straight(), GrooveStep::new(offset, 1.0)],
+    )
+  }
+
+  /// Extract a groove template from the average timing and velocity of the notes around every grid line.
+  /// The velocities are relative to the loudest step, and steps without notes are left straight.
+  pub fn extract(clip: &NotesClip, grid: TicksTime, num_steps: usize) -> Groove {
+    let num_steps = num_steps.max(1);
+    let mut offsets = vec![0i64; num_steps];
+    let mut velocities = vec![0.0; num_steps];
+    let mut counts = vec![0u32; num_steps];
+    let groove = Groove::straight(grid);
+    for note in clip.notes_range(TicksTime::zero(), TicksTime::new(u64::MAX)) {
+      let line = groove.nearest_line(note.get_start());
+      let step = (line % num_steps as u64) as usize;
+      offsets[step] += u64::from(note.get_start()) as i64 - (line * u64::from(grid)) as i64;
+      velocities[step] += note.get_velocity();
+      counts[step] += 1;
+    }
+
+    let average_velocity = |step: usize| velocities[step] / f64::from(counts[step]);
+    let max_velocity = (0..num_steps)
+      .filter(|step| counts[*step] > 0)
+      .map(average_velocity)
+      .fold(0.0, f64::max);
+    let steps = (0..num_steps)
+      .map(|step| {
+        if counts[step] > 0 && max_velocity > 0.0 {
+          GrooveStep::new(
+            offsets[step] / i64::from(counts[step]),
+            average_velocity(step) / max_velocity,
+          )
+        } else {
+          GrooveStep::straight()
+        }
+      })
+      .collect();
+    Groove::new(grid, steps)
+  }
+
+  pub fn get_grid(&self) -> TicksTime {
+    self.grid
+  }
+
+  pub fn get_steps(&self) -> &[GrooveStep] {
+    self.steps.as_slice()
+  }
+
+  /// Maximum distance between a position and its position with the groove applied
+  pub fn max_offset(&self) -> TicksTime {
+    TicksTime::new(u64::from(self.grid) / 2)
+  }
+
+  /// The position with the groove applied
+  pub fn apply(&self, position: TicksTime) -> TicksTime {
+    let grid = u64::from(self.grid);
+    if grid == 0 {
+      return position;
+    }
+    let position = u64::from(position);
+    let line = position / grid;
+    let start = self.line_position(line);
+    let end = self.line_position(line + 1);
+    let fraction = i128::from(position % grid);
+    let grooved = start + (end - start) * fraction / i128::from(grid);
+    TicksTime::new(grooved.max(0) as u64)
+  }
+
+  /// Velocity factor for a note at a position, given by the step of the nearest grid line
+  pub fn velocity_at(&self, position: TicksTime) -> f64 {
+    self.step(self.nearest_line(position)).velocity
+  }
+
+  /// Move the note towards the nearest grid line with the groove applied.
+  /// The strength goes from 0.0 (unchanged) to 1.0 (exactly at the grooved grid line).
+  pub fn quantize(&self, note: &Note, strength: f64) -> Note {
+    let strength = strength.clamp(0.0, 1.0);
+    let start = u64::from(note.get_start()) as i64;
+    let line = self.nearest_line(note.get_start());
+    let target = self.line_position(line) as i64;
+    let start = start + ((target - start) as f64 * strength).round() as i64;
+    let velocity_factor = 1.0 + (self.step(line).velocity - 1.0) * strength;
+    let velocity = (note.get_velocity() * velocity_factor).min(1.0);
+    Note::new(
+      note.get_key(),
+      velocity,
+      TicksTime::new(start.max(0) as u64),
+      note.get_length(),
+    )
+  }
+
+  fn nearest_line(&self, position: TicksTime) -> u64 {
+    let grid = u64::from(self.grid);
+    (u64::from(position) + grid / 2)
+      .checked_div(grid)
+      .unwrap_or(0)
+  }
+
+  fn step(&self, line: u64) -> &GrooveStep {
+    &self.steps[(line % self.steps.len() as u64) as usize]
+  }
+
+  fn line_position(&self, line: u64) -> i128 {
+    i128::from(line) * i128::from(u64::from(self.grid)) + i128::from(self.step(line).offset)
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{Groove, GrooveStep};
+  use crate::song::source::notes::{Note, NotesClip};
+  use crate::time::{ticks::TICKS_RESOLUTION, TicksTime};
+
+  const SIXTEENTH: u64 = TICKS_RESOLUTION;
+
+  fn ticks(sixteenths: f64) -> TicksTime {
+    TicksTime::new((sixteenths * SIXTEENTH as f64).round() as u64)
+  }
+
+  #[test]
+  pub fn swing_as_triplets() {
+    let groove = Groove::swing(TicksTime::new(SIXTEENTH), 200.0 / 3.0);
+
+    // every second sixteenth is delayed to the last eighth triplet
+    assert_eq!(groove.apply(ticks(0.0)), ticks(0.0));
+    assert_eq!(groove.apply(ticks(1.0)), TicksTime::new(SIXTEENTH * 4 / 3));
+    assert_eq!(groove.apply(ticks(2.0)), ticks(2.0));
+    assert_eq!(groove.apply(ticks(3.0)), TicksTime::new(SIXTEENTH * 10 / 3));
+
+    // positions between the grid lines are stretched
+    assert_eq!(groove.apply(ticks(0.5)), TicksTime::new(SIXTEENTH * 2 / 3));
+    assert_eq!(groove.apply(ticks(1.5)), TicksTime::new(SIXTEENTH * 5 / 3));
+  }
+
+  #[test]
+  pub fn offsets_limited_to_half_the_grid() {
+    let grid = TicksTime::new(SIXTEENTH);
+    let groove = Groove::new(
+      grid,
+      vec![
+        GrooveStep::new(-(SIXTEENTH as i64), 1.0),
+        GrooveStep::new(SIXTEENTH as i64, 1.0),
+      ],
+    );
+    assert_eq!(groove.get_steps()[0].get_offset(), -(SIXTEENTH as i64 / 2));
+    assert_eq!(groove.get_steps()[1].get_offset(), SIXTEENTH as i64 / 2);
+    assert_eq!(groove.apply(ticks(0.0)), ticks(0.0));
+    assert!(groove.apply(ticks(1.5)) <= groove.apply(ticks(1.9)));
+    assert!(groove.apply(ticks(1.9)) <= groove.apply(ticks(2.0)));
+  }
+
+  #[test]
+  pub fn quantize_with_strength() {
+    let groove = Groove::new(
+      TicksTime::new(SIXTEENTH),
+      vec![
+        GrooveStep::straight(),
+        GrooveStep::new(SIXTEENTH as i64 / 4, 0.5),
+      ],
+    );
+    let note = Note::new(60, 0.8, ticks(0.9), ticks(1.0));
+
+    assert_eq!(
+      groove.quantize(&note, 1.0),
+      Note::new(60, 0.4, ticks(1.25), ticks(1.0))
+    );
+    let half = groove.quantize(&note, 0.5);
+    assert_eq!(half.get_start(), ticks(1.075));
+    assert!((half.get_velocity() - 0.6).abs() < 1e-9);
+    assert_eq!(groove.quantize(&note, 0.0), note);
+  }
+
+  #[test]
+  pub fn extract_from_clip() {
+    let mut clip = NotesClip::new();
+    for beat in 0..4 {
+      let start = f64::from(beat) * 2.0;
+      clip.add_note(Note::new(36, 1.0, ticks(start), ticks(0.5)));
+      clip.add_note(Note::new(42, 0.5, ticks(start + 1.2), ticks(0.5)));
+      clip.add_note(Note::new(42, 0.5, ticks(start + 1.4), ticks(0.5)));
+    }
+
+    let groove = Groove::extract(&clip, TicksTime::new(SIXTEENTH), 2);
+
+    assert_eq!(
+      groove.get_steps(),
+      &[
+        GrooveStep::straight(),
+        GrooveStep::new(u64::from(ticks(0.3)) as i64, 0.5)
+      ]
+    );
+  }
+}
@@ -0,0 +1,393 @@
+use crate::song::{
+  clips::tracker::{CellNote, TrackerPattern, MAX_KEY},
+  sampler::{SampleZone, SamplerInstrument},
+  source::audio::Sample,
+};
+
+use super::{
+  byte, bytes, dword_le, instrument_channel, module_cell, module_volume, text, word_le,
+  ImportError, ImportResult, Module, ModulePattern, BPM, MAX_VOLUME, MODULE_ROOT_KEY,
+  MODULE_SAMPLE_RATE, TICKS_PER_LINE,
+};
+
+const ID_TEXT: &[u8] = b"Extended Module: ";
+const HEADER_SIZE_OFFSET: usize = 60;
+const NUM_ORDERS: usize = 256;
+const NOTE_OFF: u8 = 97;
+const NUM_KEYMAP_NOTES: usize = 96;
+const SIXTEEN_BITS: u8 = 0x10;
+const MAX_CHANNELS: usize = 32;
+const MAX_LINES: usize = 256;
+const MAX_TICKS_PER_LINE: u32 = 31;
+const MIN_BPM: u32 = 32;
+const MAX_BPM: u32 = 255;
+
+pub fn is_fasttracker(data: &[u8]) -> bool {
+  data.starts_with(ID_TEXT)
+}
+
+/// Note of a line, from C-0
+fn note(value: u8) -> Option<CellNote> {
+  match value {
+    1..=96 => Some(CellNote::On((value - 1).min(MAX_KEY))),
+    NOTE_OFF => Some(CellNote::Off),
+    _ => None,
+  }
+}
+
+/// Volume of the volume column, that has other commands besides it
+fn volume(value: u8) -> Option<u8> {
+  match value {
+    0x10..=0x50 => Some(module_volume(value - 0x10)),
+    _ => None,
+  }
+}
+
+/// Decode a FastTracker 2 module, the patterns and instruments follow the header one after the other
+pub fn decode(data: &[u8]) -> ImportResult<Module> {
+  if !is_fasttracker(data) {
+    return Err(ImportError::Unsupported {
+      cause: "Missing the FastTracker id text".to_string(),
+    });
+  }
+  let name = text(data, 17, 20)?;
+  let header_size = dword_le(data, HEADER_SIZE_OFFSET)? as usize;
+  let song_length = usize::from(word_le(data, 64)?).min(NUM_ORDERS);
+  let num_channels = usize::from(word_le(data, 68)?);
+  if num_channels == 0 || num_channels > MAX_CHANNELS {
+    return Err(ImportError::InvalidFormat {
+      cause: format!("Wrong number of channels: {}", num_channels),
+    });
+  }
+  let num_patterns = usize::from(word_le(data, 70)?);
+  let num_instruments = usize::from(word_le(data, 72)?);
+  // the speed and tempo out of their range are left for the default ones
+  let ticks_per_line = u32::from(word_le(data, 76)?);
+  let ticks_per_line = match ticks_per_line {
+    1..=MAX_TICKS_PER_LINE => ticks_per_line,
+    _ => TICKS_PER_LINE,
+  };
+  let bpm = u32::from(word_le(data, 78)?);
+  let bpm = match bpm {
+    MIN_BPM..=MAX_BPM => bpm,
+    _ => BPM,
+  };
+  let orders: Vec<usize> = bytes(data, 80, song_length)?
+    .iter()
+    .map(|order| usize::from(*order))
+    .collect();
+
+  let mut offset = HEADER_SIZE_OFFSET + header_size;
+  let mut patterns = Vec::with_capacity(num_patterns);
+  for index in 0..num_patterns {
+    let pattern_header_size = dword_le(data, offset)? as usize;
+    let num_lines = usize::from(word_le(data, offset + 5)?);
+    if num_lines == 0 || num_lines > MAX_LINES {
+      return Err(ImportError::InvalidFormat {
+        cause: format!(
+          "Wrong number of lines for the pattern {}: {}",
+          index, num_lines
+        ),
+      });
+    }
+    let packed_size = usize::from(word_le(data, offset + 7)?);
+    let packed = bytes(data, offset + pattern_header_size, packed_size)?;
+    offset += pattern_header_size + packed_size;
+    patterns.push(decode_pattern(index, packed, num_lines, num_channels)?);
+  }
+
+  let mut instruments = Vec::with_capacity(num_instruments);
+  let mut samples = Vec::new();
+  for index in 0..num_instruments {
+    let instrument_size = dword_le(data, offset)? as usize;
+    let instrument_name = text(data, offset + 4, 22)?;
+    let num_samples = usize::from(word_le(data, offset + 27)?);
+    let mut instrument = SamplerInstrument::new(instrument_name, instrument_channel(index));
+    if num_samples == 0 {
+      instruments.push(instrument);
+      offset += instrument_size;
+      continue;
+    }
+
+    let sample_header_size = dword_le(data, offset + 29)? as usize;
+    let keymap = bytes(data, offset + 33, NUM_KEYMAP_NOTES)?;
+    let first_sample = samples.len();
+    let mut key = 0;
+    while key < NUM_KEYMAP_NOTES {
+      let sample = keymap[key];
+      let low_key = key;
+      while key < NUM_KEYMAP_NOTES && keymap[key] == sample {
+        key += 1;
+      }
+      if usize::from(sample) < num_samples {
+        let sample = (first_sample + usize::from(sample)) as u64;
+        instrument
+          .zones
+          .push(SampleZone::new(low_key as u8, (key - 1) as u8, sample));
+      }
+    }
+    instruments.push(instrument);
+
+    // the headers of the samples come before their data
+    offset += instrument_size;
+    let mut headers = Vec::with_capacity(num_samples);
+    for _ in 0..num_samples {
+      headers.push(bytes(data, offset, sample_header_size.max(40))?);
+      offset += sample_header_size;
+    }
+    for header in headers {
+      let length = dword_le(header, 0)? as usize;
+      let sample_data = bytes(data, offset, length)?;
+      offset += length;
+      samples.push(decode_sample(header, sample_data)?);
+    }
+  }
+
+  Ok(Module {
+    name,
+    num_channels,
+    ticks_per_line,
+    bpm,
+    orders,
+    patterns,
+    instruments,
+    samples,
+  })
+}
+
+/// Every cell starts with the note unless its first byte has the highest bit set,
+/// then its bits tell which of the note, instrument, volume, effect and parameter follow
+fn decode_pattern(
+  index: usize,
+  packed: &[u8],
+  num_lines: usize,
+  num_channels: usize,
+) -> ImportResult<ModulePattern> {
+  let mut pattern = TrackerPattern::new(format!("Pattern {}", index), num_lines, num_channels);
+  let mut speed_changes = Vec::new();
+  let mut offset = 0;
+  // an empty pattern has no data at all
+  if !packed.is_empty() {
+    for line in 0..num_lines {
+      for column in 0..num_channels {
+        let first = byte(packed, offset)?;
+        let flags = if first & 0x80 != 0 {
+          offset += 1;
+          first
+        } else {
+          0x1f
+        };
+        let mut values = [0u8; 5];
+        for (bit, value) in values.iter_mut().enumerate() {
+          if flags & (1 << bit) != 0 {
+            *value = byte(packed, offset)?;
+            offset += 1;
+          }
+        }
+        let [note_value, instrument, volume_value, effect, param] = values;
+        let (cell, speed_change) = module_cell(
+          note(note_value),
+          instrument,
+          volume(volume_value),
+          effect,
+          param,
+        );
+        pattern.set_cell(line, column, cell);
+        if let Some(speed_change) = speed_change {
+          speed_changes.push((line, speed_change));
+        }
+      }
+    }
+  }
+  Ok(ModulePattern {
+    pattern,
+    speed_changes,
+  })
+}
+
+/// The data keeps the differences between the values, in 8 or 16 bits
+fn decode_sample(header: &[u8], sample_data: &[u8]) -> ImportResult<Sample> {
+  let loop_start = dword_le(header, 4)? as usize;
+  let loop_length = dword_le(header, 8)? as usize;
+  let volume = byte(header, 12)?.min(MAX_VOLUME);
+  let fine_tune = byte(header, 13)? as i8;
+  let sample_type = byte(header, 14)?;
+  let relative_note = byte(header, 16)? as i8;
+  let name = text(header, 18, 22)?;
+
+  let (data, frame_size) = if sample_type & SIXTEEN_BITS != 0 {
+    let mut value = 0i16;
+    let data = sample_data
+      .chunks_exact(2)
+      .map(|delta| {
+        value = value.wrapping_add(i16::from_le_bytes([delta[0], delta[1]]));
+        f32::from(value) / 32768.0
+      })
+      .collect::<Vec<f32>>();
+    (data, 2)
+  } else {
+    let mut value = 0i8;
+    let data = sample_data
+      .iter()
+      .map(|delta| {
+        value = value.wrapping_add(*delta as i8);
+        f32::from(value) / 128.0
+      })
+      .collect::<Vec<f32>>();
+    (data, 1)
+  };
+
+  let mut sample = Sample::new(name, MODULE_SAMPLE_RATE, data);
+  let root_key = i32::from(MODULE_ROOT_KEY) - i32::from(relative_note);
+  sample.root_key = root_key.clamp(0, i32::from(MAX_KEY)) as u8;
+  sample.fine_tune = f64::from(fine_tune) / 128.0;
+  sample.volume = f64::from(volume) / f64::from(MAX_VOLUME);
+  let (loop_start, loop_length) = (loop_start / frame_size, loop_length / frame_size);
+  if sample_type & 0x03 != 0 && loop_length > 0 && loop_start < sample.data.len() {
+    let loop_length = loop_length.min(sample.data.len() - loop_start);
+    sample.loop_range = Some((loop_start, loop_length));
+  }
+  Ok(sample)
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::super::{Module, SpeedChange};
+  use crate::song::clips::tracker::{Cell, Effect};
+
+  /// Header of a module with one pattern and one instrument
+  fn header(num_channels: u16, ticks_per_line: u16, bpm: u16) -> Vec<u8> {
+    let mut data = b"Extended Module: song".to_vec();
+    data.resize(60, 0);
+    let header = [
+      &276u32.to_le_bytes()[..],
+      &2u16.to_le_bytes(), // song length
+      &0u16.to_le_bytes(), // restart
+      &num_channels.to_le_bytes(),
+      &1u16.to_le_bytes(), // patterns
+      &1u16.to_le_bytes(), // instruments
+      &1u16.to_le_bytes(), // linear frequencies
+      &ticks_per_line.to_le_bytes(),
+      &bpm.to_le_bytes(),
+    ]
+    .concat();
+    data.extend_from_slice(&header);
+    data.extend_from_slice(&[0u8; 256]);
+    data
+  }
+
+  fn pattern(data: &mut Vec<u8>, num_lines: u16, packed: &[u8]) {
+    data.extend_from_slice(&9u32.to_le_bytes());
+    data.push(0);
+    data.extend_from_slice(&num_lines.to_le_bytes());
+    data.extend_from_slice(&(packed.len() as u16).to_le_bytes());
+    data.extend_from_slice(packed);
+  }
+
+  #[test]
+  pub fn decode_a_fasttracker_module() {
+    let mut data = header(2, 4, 140);
+
+    // a note with all its fields, a note off with only its note, an empty cell and a bpm change
+    let packed = [
+      0x31, 0x02, 0x30, 0x0a, 0x1f, 0x81, 0x61, 0x80, 0x98, 0x0f, 0x30,
+    ];
+    pattern(&mut data, 2, &packed);
+
+    // an instrument with two samples, the second one for the keys from C-5
+    let mut instrument = 263u32.to_le_bytes().to_vec();
+    instrument.extend_from_slice(b"lead");
+    instrument.resize(27, 0);
+    instrument.extend_from_slice(&2u16.to_le_bytes());
+    instrument.extend_from_slice(&40u32.to_le_bytes());
+    let mut keymap = [0u8; 96];
+    keymap[60..].iter_mut().for_each(|sample| *sample = 1);
+    instrument.extend_from_slice(&keymap);
+    instrument.resize(263, 0);
+    data.extend_from_slice(&instrument);
+
+    let sample_header = |length: u32, sample_type: u8, relative_note: i8, name: &[u8]| {
+      let mut header = length.to_le_bytes().to_vec();
+      header.extend_from_slice(&2u32.to_le_bytes());
+      header.extend_from_slice(&2u32.to_le_bytes());
+      header.extend_from_slice(&[48, 0xf0, sample_type, 0x80, relative_note as u8, 0]);
+      header.extend_from_slice(name);
+      header.resize(40, 0);
+      header
+    };
+    data.extend_from_slice(&sample_header(4, 0x01, 12, b"low"));
+    data.extend_from_slice(&sample_header(6, 0x10, -1, b"high"));
+    data.extend_from_slice(&[0x10, 0x10, 0xe0, 0xf0]);
+    for delta in [16384i16, -8192, -16384].iter() {
+      data.extend_from_slice(&delta.to_le_bytes());
+    }
+
+    let module = Module::from_bytes(&data).unwrap();
+    assert_eq!(module.name, "song");
+    assert_eq!(module.num_channels, 2);
+    assert_eq!((module.ticks_per_line, module.bpm), (4, 140));
+    assert_eq!(module.orders, vec![0, 0]);
+
+    let pattern = &module.patterns[0];
+    assert_eq!(
+      pattern.pattern.get_cell(0, 0),
+      Some(&Cell {
+        volume: Some(63),
+        effect: Some(Effect::VolumeSlide { up: 1, down: 15 }),
+        ..Cell::note(48, 2)
+      })
+    );
+    assert_eq!(pattern.pattern.get_cell(0, 1), Some(&Cell::off()));
+    assert_eq!(pattern.pattern.get_cell(1, 0), Some(&Cell::default()));
+    assert_eq!(pattern.speed_changes, vec![(1, SpeedChange::Bpm(0x30))]);
+
+    let zones: Vec<(u8, u8, u64)> = module.instruments[0]
+      .zones
+      .iter()
+      .map(|zone| (zone.low_key, zone.high_key, zone.sample))
+      .collect();
+    assert_eq!(zones, vec![(0, 59, 0), (60, 95, 1)]);
+
+    let low = &module.samples[0];
+    assert_eq!(low.data, vec![0.125, 0.25, 0.0, -0.125]);
+    assert_eq!(
+      (low.root_key, low.fine_tune, low.volume),
+      (36, -0.125, 0.75)
+    );
+    assert_eq!(low.loop_range, Some((2, 2)));
+    let high = &module.samples[1];
+    assert_eq!(high.name, "high");
+    assert_eq!(high.data, vec![0.5, 0.25, -0.25]);
+    assert_eq!(high.root_key, 49);
+    assert_eq!(high.loop_range, None);
+  }
+
+  #[test]
+  pub fn reject_the_wrong_sizes() {
+    for num_channels in [0, 33].iter() {
+      let mut data = header(*num_channels, 4, 140);
+      pattern(&mut data, 64, &[]);
+      let error = Module::from_bytes(&data).unwrap_err().to_string();
+      assert!(error.contains("channels"), "{}", error);
+    }
+    for num_lines in [0, 257, 65535].iter() {
+      let mut data = header(4, 4, 140);
+      pattern(&mut data, *num_lines, &[]);
+      let error = Module::from_bytes(&data).unwrap_err().to_string();
+      assert!(error.contains("lines"), "{}", error);
+    }
+  }
+
+  #[test]
+  pub fn default_tempo_out_of_range() {
+    for (ticks_per_line, bpm) in [(0, 0), (32, 31), (65535, 256)].iter() {
+      let mut data = header(4, *ticks_per_line, *bpm);
+      pattern(&mut data, 64, &[]);
+      data.extend_from_slice(&29u32.to_le_bytes());
+      data.resize(data.len() + 25, 0);
+      let module = Module::from_bytes(&data).unwrap();
+      assert_eq!((module.ticks_per_line, module.bpm), (6, 125));
+    }
+  }
+}
@@ -0,0 +1,380 @@
+pub mod fasttracker;
+pub mod protracker;
+
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use failure::Fail;
+
+use crate::color::Color;
+use crate::midi::types::{U4, U7};
+use crate::song::{
+  clips::{
+    tracker::{Cell, CellNote, Effect, Tracker, TrackerClip, TrackerInstrument, TrackerPattern},
+    Clip, ClipIndex,
+  },
+  sampler::{Sampler, SamplerInstrument},
+  source::audio::{AudioDataSource, Sample},
+  track::{instrument::InstrumentTrack, Track, TrackMedia},
+};
+use crate::time::{Signature, Tempo, TicksTime};
+
+/// Sample rate of the samples for their root key, as played by the Amiga for the middle C
+pub const MODULE_SAMPLE_RATE: u32 = 8363;
+
+/// Key of the middle C of the modules, `C-4` like shown by FastTracker
+pub const MODULE_ROOT_KEY: U7 = 48;
+
+const MAX_VOLUME: u8 = 64;
+const TICKS_PER_LINE: u32 = 6;
+const BPM: u32 = 125;
+
+#[derive(Debug, Fail)]
+pub enum ImportError {
+  #[fail(display = "Failed to access the module file: {}", cause)]
+  Io { cause: String },
+
+  #[fail(display = "Invalid module file: {}", cause)]
+  InvalidFormat { cause: String },
+
+  #[fail(display = "Unsupported module file: {}", cause)]
+  Unsupported { cause: String },
+}
+
+pub type ImportResult<T> = Result<T, ImportError>;
+
+/// Change of the speed of the song from a line of a pattern
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpeedChange {
+  TicksPerLine(u32),
+  Bpm(u32),
+}
+
+/// Pattern of a module with the speed changes of its lines, as the tracker clips can't play them
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModulePattern {
+  pub pattern: TrackerPattern,
+  pub speed_changes: Vec<(usize, SpeedChange)>,
+}
+
+/// Song of a ProTracker (.mod) or FastTracker (.xm) file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+  pub name: String,
+  pub num_channels: usize,
+  pub ticks_per_line: u32,
+  pub bpm: u32,
+  /// Indices of the patterns in the order they are played
+  pub orders: Vec<usize>,
+  pub patterns: Vec<ModulePattern>,
+  /// The zones of the instruments have the indices of the samples of the module
+  pub instruments: Vec<SamplerInstrument>,
+  pub samples: Vec<Sample>,
+}
+
+/// Track made from a module, to be added to a song with the tempo of the module
+pub struct ImportedModule {
+  pub track: Track,
+  pub clips: Vec<ClipIndex>,
+  /// Tempo from the start of the song and the positions where it changes
+  pub tempo_changes: Vec<(TicksTime, Tempo)>,
+}
+
+impl Module {
+  /// The format is found from the content of the file
+  pub fn from_bytes(data: &[u8]) -> ImportResult<Module> {
+    if fasttracker::is_fasttracker(data) {
+      fasttracker::decode(data)
+    } else if protracker::is_protracker(data) {
+      protracker::decode(data)
+    } else {
+      Err(ImportError::Unsupported {
+        cause: "Unknown format".to_string(),
+      })
+    }
+  }
+
+  pub fn open(path: &str) -> ImportResult<Module> {
+    let mut data = Vec::new();
+    File::open(path)
+      .and_then(|mut file| file.read_to_end(&mut data))
+      .map_err(|err| ImportError::Io {
+        cause: err.to_string(),
+      })?;
+    Module::from_bytes(&data)
+  }
+
+  /// Tempo of a quarter note, for four lines per beat
+  pub fn tempo(ticks_per_line: u32, bpm: u32) -> Tempo {
+    Tempo::from_bpm(f64::from(bpm) * f64::from(TICKS_PER_LINE) / f64::from(ticks_per_line.max(1)))
+  }
+
+  /// Make an instrument track that plays the orders with a tracker clip for every one of them,
+  /// one after the other from the start of the song, and a sampler with the samples of the module.
+  /// The samples are added to the audio source of the song.
+  /// The pattern breaks and position jumps are not followed.
+  pub fn import(self, source: &Arc<RwLock<AudioDataSource>>) -> ImportedModule {
+    let first_id = {
+      let mut source = source.write().unwrap_or_else(PoisonError::into_inner);
+      let first_id = source.next_free_id();
+      for (index, sample) in self.samples.into_iter().enumerate() {
+        source.add_sample(first_id + index as u64, sample);
+      }
+      first_id
+    };
+
+    let mut sampler = Sampler::new(source.clone());
+    for instrument in self.instruments.iter() {
+      let mut instrument = instrument.clone();
+      for zone in instrument.zones.iter_mut() {
+        zone.sample += first_id;
+      }
+      sampler.add_instrument(instrument);
+    }
+    let mut instrument_track = InstrumentTrack::new();
+    instrument_track.set_sampler(Some(sampler));
+    let mut track = Track::new(
+      self.name.as_str(),
+      Color::new("orange".into()),
+      TrackMedia::Instrument(instrument_track),
+    );
+
+    let instruments: Vec<TrackerInstrument> = self
+      .instruments
+      .iter()
+      .map(|instrument| TrackerInstrument::new(instrument.name.as_str(), Some(instrument.channel)))
+      .collect();
+    let line_length = Tracker::new().line_length();
+    let (mut ticks_per_line, mut bpm) = (self.ticks_per_line, self.bpm);
+    let mut tempo_changes = vec![(TicksTime::zero(), Module::tempo(ticks_per_line, bpm))];
+    let mut position = TicksTime::zero();
+    let mut clips = Vec::new();
+    let patterns = &self.patterns;
+    for pattern in self.orders.iter().filter_map(|index| patterns.get(*index)) {
+      // the clip keeps the ticks per line from its first line
+      let mut clip_ticks_per_line = ticks_per_line;
+      for (line, change) in pattern.speed_changes.iter() {
+        match *change {
+          SpeedChange::TicksPerLine(value) => ticks_per_line = value,
+          SpeedChange::Bpm(value) => bpm = value,
+        }
+        if *line == 0 {
+          clip_ticks_per_line = ticks_per_line;
+        }
+        let tempo = Module::tempo(ticks_per_line, bpm);
+        let line_position = position + TicksTime::new(u64::from(line_length) * *line as u64);
+        match tempo_changes.last_mut() {
+          Some((last_position, last_tempo)) if *last_position == line_position => {
+            *last_tempo = tempo
+          }
+          Some((_, last_tempo)) if *last_tempo == tempo => {}
+          _ => tempo_changes.push((line_position, tempo)),
+        }
+      }
+
+      let tracker = Tracker {
+        ticks_per_line: clip_ticks_per_line,
+        instruments: instruments.clone(),
+        patterns: vec![pattern.pattern.clone()],
+        sequence: vec![0],
+        ..Tracker::new()
+      };
+      let length = TicksTime::new(u64::from(line_length) * pattern.pattern.num_lines() as u64);
+      let clip = Clip {
+        uuid: 0,
+        name: pattern.pattern.name.clone(),
+        signature: Signature::new(4, 4),
+        start: position,
+        length,
+      };
+      if let Some(clip_index) = track.add_tracker_clip(TrackerClip::new(clip, tracker)) {
+        clips.push(clip_index);
+      }
+      position += length;
+    }
+
+    ImportedModule {
+      track,
+      clips,
+      tempo_changes,
+    }
+  }
+}
+
+/// The instruments are played on the channels one after the other, sharing them after the 16th
+fn instrument_channel(index: usize) -> U4 {
+  (index % 16) as U4
+}
+
+/// Cell for a line of a channel, from the note, instrument and volume and the effect command
+fn module_cell(
+  note: Option<CellNote>,
+  instrument: u8,
+  volume: Option<U7>,
+  effect: u8,
+  param: u8,
+) -> (Cell, Option<SpeedChange>) {
+  let mut cell = Cell {
+    note,
+    instrument: Some(instrument).filter(|instrument| *instrument > 0),
+    volume,
+    effect: None,
+  };
+  let mut speed_change = None;
+  match (effect, param >> 4, param & 0x0f) {
+    (0x1, _, _) if param > 0 => cell.effect = Some(Effect::SlideUp(param)),
+    (0x2, _, _) if param > 0 => cell.effect = Some(Effect::SlideDown(param)),
+    (0xa, up, down) if param > 0 => cell.effect = Some(Effect::VolumeSlide { up, down }),
+    (0xc, _, _) => cell.volume = Some(module_volume(param)),
+    (0xe, 0x9, ticks) => cell.effect = Some(Effect::Retrigger(ticks)),
+    (0xe, 0xc, ticks) => cell.effect = Some(Effect::NoteCut(ticks)),
+    (0xe, 0xd, ticks) => cell.effect = Some(Effect::Delay(ticks)),
+    (0xf, _, _) if param > 0 && param < 0x20 => {
+      speed_change = Some(SpeedChange::TicksPerLine(u32::from(param)))
+    }
+    (0xf, _, _) if param >= 0x20 => speed_change = Some(SpeedChange::Bpm(u32::from(param))),
+    _ => {}
+  }
+  (cell, speed_change)
+}
+
+/// Velocity for a volume of a module, from 0 to 64
+fn module_volume(volume: u8) -> U7 {
+  (u32::from(volume.min(MAX_VOLUME)) * 127 / u32::from(MAX_VOLUME)) as U7
+}
+
+fn truncated() -> ImportError {
+  ImportError::InvalidFormat {
+    cause: "Truncated file".to_string(),
+  }
+}
+
+fn bytes(data: &[u8], offset: usize, length: usize) -> ImportResult<&[u8]> {
+  data
+    .get(offset..offset.saturating_add(length))
+    .ok_or_else(truncated)
+}
+
+fn byte(data: &[u8], offset: usize) -> ImportResult<u8> {
+  data.get(offset).copied().ok_or_else(truncated)
+}
+
+fn word_be(data: &[u8], offset: usize) -> ImportResult<u16> {
+  bytes(data, offset, 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn word_le(data: &[u8], offset: usize) -> ImportResult<u16> {
+  bytes(data, offset, 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn dword_le(data: &[u8], offset: usize) -> ImportResult<u32> {
+  bytes(data, offset, 4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Text padded with zeros or spaces
+fn text(data: &[u8], offset: usize, length: usize) -> ImportResult<String> {
+  let bytes = bytes(data, offset, length)?;
+  let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(length);
+  let text: String = bytes[0..end]
+    .iter()
+    .map(|byte| match *byte {
+      0x20..=0x7e => char::from(*byte),
+      _ => ' ',
+    })
+    .collect();
+  Ok(text.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod test {
+
+  use std::sync::{Arc, RwLock};
+
+  use super::{Module, ModulePattern, SpeedChange, MODULE_SAMPLE_RATE};
+  use crate::song::{
+    clips::tracker::{Cell, TrackerPattern},
+    sampler::{SampleZone, SamplerInstrument},
+    source::audio::{AudioDataSource, Sample},
+    track::TrackMedia,
+  };
+  use crate::time::{ticks::TICKS_RESOLUTION, Tempo, TicksTime};
+
+  fn pattern(name: &str, speed_changes: Vec<(usize, SpeedChange)>) -> ModulePattern {
+    ModulePattern {
+      pattern: TrackerPattern::new(name, 64, 4),
+      speed_changes,
+    }
+  }
+
+  #[test]
+  pub fn import_the_orders_and_samples() {
+    let mut instrument = SamplerInstrument::new("beep", 0);
+    instrument.zones.push(SampleZone::new(0, 119, 0));
+    let mut module = Module {
+      name: "module".to_string(),
+      num_channels: 4,
+      ticks_per_line: 6,
+      bpm: 125,
+      orders: vec![0, 1, 7, 0],
+      patterns: vec![
+        pattern("intro", vec![]),
+        pattern(
+          "verse",
+          vec![
+            (0, SpeedChange::TicksPerLine(3)),
+            (16, SpeedChange::Bpm(150)),
+          ],
+        ),
+      ],
+      instruments: vec![instrument],
+      samples: vec![Sample::new("beep", MODULE_SAMPLE_RATE, vec![0.0, 0.5])],
+    };
+    module.patterns[1].pattern.set_cell(1, 0, Cell::note(50, 1));
+
+    let source = Arc::new(RwLock::new(AudioDataSource::new()));
+    let mut imported = module.import(&source);
+    assert_eq!(imported.clips, vec![0, 1, 2]);
+    let pattern_length = TicksTime::new(64 * TICKS_RESOLUTION);
+    assert_eq!(
+      imported.tempo_changes,
+      vec![
+        (TicksTime::zero(), Tempo::from_bpm(125.0)),
+        (pattern_length, Tempo::from_bpm(250.0)),
+        (
+          pattern_length + TicksTime::new(16 * TICKS_RESOLUTION),
+          Tempo::from_bpm(300.0)
+        ),
+      ]
+    );
+
+    let track = &mut imported.track;
+    let starts: Vec<TicksTime> = (0..3)
+      .map(|index| track.get_clip(index).unwrap().start)
+      .collect();
+    assert_eq!(
+      starts,
+      vec![
+        TicksTime::zero(),
+        pattern_length,
+        pattern_length + pattern_length
+      ]
+    );
+    let tracker = track.get_tracker_clip_mut(1).unwrap().get_tracker();
+    assert_eq!(tracker.ticks_per_line, 3);
+    assert_eq!(tracker.patterns[0].get_cell(1, 0), Some(&Cell::note(50, 1)));
+    let tracker = track.get_tracker_clip_mut(2).unwrap().get_tracker();
+    assert_eq!(tracker.patterns[0].name, "intro");
+
+    let sampler = match &track.media {
+      TrackMedia::Instrument(instrument_track) => instrument_track.get_sampler().unwrap(),
+      _ => panic!("Not an instrument track"),
+    };
+    let zone = sampler.zone_for(0, 60).unwrap();
+    assert!(Arc::ptr_eq(sampler.get_source(), &source));
+    let source = source.read().unwrap();
+    let sample = source.get_sample(zone.sample).unwrap();
+    assert_eq!(sample.name, "beep");
+    assert_eq!(sample.playback_rate(60) / sample.playback_rate(48), 2.0);
+  }
+}
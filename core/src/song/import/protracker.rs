@@ -0,0 +1,241 @@
+use crate::song::{
+  clips::tracker::{CellNote, TrackerPattern, MAX_KEY},
+  sampler::{SampleZone, SamplerInstrument},
+  source::audio::Sample,
+};
+
+use super::{
+  byte, bytes, instrument_channel, module_cell, text, word_be, ImportError, ImportResult, Module,
+  ModulePattern, BPM, MAX_VOLUME, MODULE_ROOT_KEY, MODULE_SAMPLE_RATE, TICKS_PER_LINE,
+};
+
+const NUM_SAMPLES: usize = 31;
+const SAMPLE_HEADER_LENGTH: usize = 30;
+const SONG_LENGTH_OFFSET: usize = 950;
+const ORDERS_OFFSET: usize = 952;
+const NUM_ORDERS: usize = 128;
+const SIGNATURE_OFFSET: usize = 1080;
+const PATTERNS_OFFSET: usize = 1084;
+const NUM_LINES: usize = 64;
+
+/// Period of the middle C, that plays the samples at their sample rate
+const MIDDLE_C_PERIOD: f64 = 428.0;
+
+/// Number of channels for the signature of the files with 31 samples
+fn num_channels(signature: &[u8]) -> Option<usize> {
+  match signature {
+    b"M.K." | b"M!K!" | b"M&K!" | b"FLT4" | b"4CHN" | b"N.T." => Some(4),
+    b"FLT8" | b"OCTA" | b"CD81" => Some(8),
+    [digit, b'C', b'H', b'N'] if digit.is_ascii_digit() => Some(usize::from(digit - b'0')),
+    [tens, units, b'C', b'H'] if tens.is_ascii_digit() && units.is_ascii_digit() => {
+      Some(usize::from(tens - b'0') * 10 + usize::from(units - b'0'))
+    }
+    _ => None,
+  }
+  .filter(|channels| *channels > 0)
+}
+
+pub fn is_protracker(data: &[u8]) -> bool {
+  bytes(data, SIGNATURE_OFFSET, 4)
+    .ok()
+    .and_then(num_channels)
+    .is_some()
+}
+
+/// Key for the period of an Amiga note
+fn period_key(period: u16) -> Option<CellNote> {
+  if period == 0 {
+    return None;
+  }
+  let semitones = (12.0 * (MIDDLE_C_PERIOD / f64::from(period)).log2()).round() as i32;
+  let key = (i32::from(MODULE_ROOT_KEY) + semitones).clamp(0, i32::from(MAX_KEY));
+  Some(CellNote::On(key as u8))
+}
+
+/// Decode a ProTracker module with 31 samples, the old ones with 15 samples have no signature
+pub fn decode(data: &[u8]) -> ImportResult<Module> {
+  let num_channels = bytes(data, SIGNATURE_OFFSET, 4)
+    .ok()
+    .and_then(num_channels)
+    .ok_or_else(|| ImportError::Unsupported {
+      cause: "Missing the ProTracker signature".to_string(),
+    })?;
+  let name = text(data, 0, 20)?;
+
+  let song_length = usize::from(byte(data, SONG_LENGTH_OFFSET)?).min(NUM_ORDERS);
+  let all_orders = bytes(data, ORDERS_OFFSET, NUM_ORDERS)?;
+  let orders: Vec<usize> = all_orders[0..song_length]
+    .iter()
+    .map(|order| usize::from(*order))
+    .collect();
+  // the patterns that are not in the song are stored too
+  let num_patterns = all_orders
+    .iter()
+    .map(|order| usize::from(*order) + 1)
+    .max()
+    .unwrap_or(0);
+
+  let line_size = num_channels * 4;
+  let mut patterns = Vec::with_capacity(num_patterns);
+  for index in 0..num_patterns {
+    let pattern_data = bytes(
+      data,
+      PATTERNS_OFFSET + index * NUM_LINES * line_size,
+      NUM_LINES * line_size,
+    )?;
+    let mut pattern = TrackerPattern::new(format!("Pattern {}", index), NUM_LINES, num_channels);
+    let mut speed_changes = Vec::new();
+    for (line, line_data) in pattern_data.chunks(line_size).enumerate() {
+      for (column, cell_data) in line_data.chunks(4).enumerate() {
+        let instrument = (cell_data[0] & 0xf0) | (cell_data[2] >> 4);
+        let period = (u16::from(cell_data[0] & 0x0f) << 8) | u16::from(cell_data[1]);
+        let (cell, speed_change) = module_cell(
+          period_key(period),
+          instrument,
+          None,
+          cell_data[2] & 0x0f,
+          cell_data[3],
+        );
+        pattern.set_cell(line, column, cell);
+        if let Some(speed_change) = speed_change {
+          speed_changes.push((line, speed_change));
+        }
+      }
+    }
+    patterns.push(ModulePattern {
+      pattern,
+      speed_changes,
+    });
+  }
+
+  let mut samples = Vec::with_capacity(NUM_SAMPLES);
+  let mut instruments = Vec::new();
+  let mut offset = PATTERNS_OFFSET + num_patterns * NUM_LINES * line_size;
+  for index in 0..NUM_SAMPLES {
+    let header = bytes(
+      data,
+      20 + index * SAMPLE_HEADER_LENGTH,
+      SAMPLE_HEADER_LENGTH,
+    )?;
+    let sample_name = text(header, 0, 22)?;
+    let length = usize::from(word_be(header, 22)?) * 2;
+    let fine_tune = ((header[24] & 0x0f) as i8) << 4 >> 4;
+    let volume = header[25].min(MAX_VOLUME);
+    let loop_start = usize::from(word_be(header, 26)?) * 2;
+    let loop_length = usize::from(word_be(header, 28)?) * 2;
+
+    // the samples at the end of many files are cut short
+    let end = (offset + length).min(data.len());
+    let sample_data = data
+      .get(offset..end)
+      .unwrap_or(&[])
+      .iter()
+      .map(|value| f32::from(*value as i8) / 128.0)
+      .collect::<Vec<f32>>();
+    offset += length;
+
+    let mut sample = Sample::new(sample_name.as_str(), MODULE_SAMPLE_RATE, sample_data);
+    sample.root_key = MODULE_ROOT_KEY;
+    sample.fine_tune = f64::from(fine_tune) / 8.0;
+    sample.volume = f64::from(volume) / f64::from(MAX_VOLUME);
+    if loop_length > 2 && loop_start < sample.data.len() {
+      let loop_length = loop_length.min(sample.data.len() - loop_start);
+      sample.loop_range = Some((loop_start, loop_length));
+    }
+    let mut instrument = SamplerInstrument::new(sample_name, instrument_channel(index));
+    if length > 0 {
+      instrument
+        .zones
+        .push(SampleZone::new(0, MAX_KEY, samples.len() as u64));
+    }
+    instruments.push(instrument);
+    samples.push(sample);
+  }
+
+  Ok(Module {
+    name,
+    num_channels,
+    ticks_per_line: TICKS_PER_LINE,
+    bpm: BPM,
+    orders,
+    patterns,
+    instruments,
+    samples,
+  })
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::super::{Module, SpeedChange};
+  use crate::song::clips::tracker::{Cell, CellNote, Effect};
+
+  #[test]
+  pub fn decode_a_protracker_module() {
+    let mut data = vec![0u8; 1084 + 2 * 64 * 16 + 8];
+    data[0..5].copy_from_slice(b"tune\0");
+    // first sample, 4 words long, finetune -1, volume 32 and a loop of the last 2 words
+    data[20..24].copy_from_slice(b"beep");
+    data[42..44].copy_from_slice(&4u16.to_be_bytes());
+    data[44] = 0x0f;
+    data[45] = 32;
+    data[46..48].copy_from_slice(&2u16.to_be_bytes());
+    data[48..50].copy_from_slice(&2u16.to_be_bytes());
+    data[950] = 3;
+    data[952..955].copy_from_slice(&[1, 0, 1]);
+    data[1080..1084].copy_from_slice(b"M.K.");
+
+    let pattern = |index: usize| 1084 + index * 64 * 16;
+    let cell =
+      |pattern_offset: usize, line: usize, column: usize| pattern_offset + line * 16 + column * 4;
+    // C-4 of the first sample with the volume at 32, then the G-4 sliding up and the speed at 3
+    let offset = cell(pattern(0), 0, 0);
+    data[offset..offset + 4].copy_from_slice(&[0x01, 0xac, 0x1c, 0x20]);
+    let offset = cell(pattern(0), 2, 3);
+    data[offset..offset + 4].copy_from_slice(&[0x01, 0x1d, 0x01, 0x08]);
+    let offset = cell(pattern(1), 63, 1);
+    data[offset..offset + 4].copy_from_slice(&[0x00, 0x00, 0x0f, 0x03]);
+    let samples = pattern(2);
+    data[samples..samples + 8].copy_from_slice(&[0, 64, 127, 128, 192, 255, 0, 0]);
+
+    let module = Module::from_bytes(&data).unwrap();
+    assert_eq!(module.name, "tune");
+    assert_eq!(module.num_channels, 4);
+    assert_eq!(module.orders, vec![1, 0, 1]);
+    assert_eq!(module.patterns.len(), 2);
+
+    let pattern = &module.patterns[0].pattern;
+    assert_eq!(
+      pattern.get_cell(0, 0),
+      Some(&Cell {
+        volume: Some(63),
+        ..Cell::note(48, 1)
+      })
+    );
+    assert_eq!(
+      pattern.get_cell(2, 3),
+      Some(&Cell {
+        note: Some(CellNote::On(55)),
+        effect: Some(Effect::SlideUp(8)),
+        ..Cell::default()
+      })
+    );
+    assert_eq!(
+      module.patterns[1].speed_changes,
+      vec![(63, SpeedChange::TicksPerLine(3))]
+    );
+
+    assert_eq!(module.samples.len(), 31);
+    let sample = &module.samples[0];
+    assert_eq!(sample.name, "beep");
+    assert_eq!(
+      sample.data,
+      vec![0.0, 0.5, 127.0 / 128.0, -1.0, -0.5, -1.0 / 128.0, 0.0, 0.0]
+    );
+    assert_eq!(sample.fine_tune, -0.125);
+    assert_eq!(sample.volume, 0.5);
+    assert_eq!(sample.loop_range, Some((4, 4)));
+    assert_eq!(module.instruments[0].zone_for_key(60).unwrap().sample, 0);
+    assert!(module.instruments[1].zones.is_empty());
+  }
+}
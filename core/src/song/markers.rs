@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+
+use serde_derive::Deserialize;
+
+use crate::color::Color;
+use crate::time::{ticks::deserialize_ticks, TicksTime};
+
+pub type LocatorNumber = u8;
+
+/// Named position in the song, like the start of a section
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Marker {
+  name: String,
+  color: Color,
+  #[serde(deserialize_with = "deserialize_ticks")]
+  position: TicksTime,
+}
+
+impl Marker {
+  pub fn new<T>(name: T, color: Color, position: TicksTime) -> Marker
+  where
+    T: Into<String>,
+  {
+    Marker {
+      name: name.into(),
+      color,
+      position,
+    }
+  }
+
+  pub fn get_name(&self) -> &str {
+    self.name.as_str()
+  }
+
+  pub fn get_color(&self) -> &Color {
+    &self.color
+  }
+
+  pub fn get_position(&self) -> TicksTime {
+    self.position
+  }
+}
+
+/// Markers sorted by position, where the markers at the same position keep the order they were added
+#[derive(Debug, Clone, Default)]
+pub struct Markers {
+  markers: Vec<Marker>,
+}
+
+impl Markers {
+  pub fn new() -> Markers {
+    Markers {
+      markers: Vec::new(),
+    }
+  }
+
+  /// Add a marker and return its index
+  pub fn add(&mut self, marker: Marker) -> usize {
+    let index = self
+      .markers
+      .iter()
+      .position(|other| other.position > marker.position)
+      .unwrap_or(self.markers.len());
+    self.markers.insert(index, marker);
+    index
+  }
+
+  pub fn remove(&mut self, index: usize) -> Option<Marker> {
+    if index < self.markers.len() {
+      Some(self.markers.remove(index))
+    } else {
+      None
+    }
+  }
+
+  pub fn get(&self, index: usize) -> Option<&Marker> {
+    self.markers.get(index)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &Marker> {
+    self.markers.iter()
+  }
+
+  pub fn len(&self) -> usize {
+    self.markers.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.markers.is_empty()
+  }
+
+  /// The first marker after a position
+  pub fn next_after(&self, position: TicksTime) -> Option<&Marker> {
+    self
+      .markers
+      .iter()
+      .find(|marker| marker.position > position)
+  }
+
+  /// The last marker before a position
+  pub fn previous_before(&self, position: TicksTime) -> Option<&Marker> {
+    self
+      .markers
+      .iter()
+      .rev()
+      .find(|marker| marker.position < position)
+  }
+}
+
+/// Numbered positions used to set the loop and punch ranges
+#[derive(Debug, Clone, Default)]
+pub struct Locators {
+  positions: BTreeMap<LocatorNumber, TicksTime>,
+}
+
+impl Locators {
+  pub fn new() -> Locators {
+    Locators {
+      positions: BTreeMap::new(),
+    }
+  }
+
+  pub fn set(&mut self, number: LocatorNumber, position: TicksTime) {
+    self.positions.insert(number, position);
+  }
+
+  pub fn get(&self, number: LocatorNumber) -> Option<TicksTime> {
+    self.positions.get(&number).cloned()
+  }
+
+  pub fn remove(&mut self, number: LocatorNumber) -> Option<TicksTime> {
+    self.positions.remove(&number)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (LocatorNumber, TicksTime)> + '_ {
+    self
+      .positions
+      .iter()
+      .map(|(number, position)| (*number, *position))
+  }
+
+  /// Start and end of the range between two locators in any order, when both are set and different
+  pub fn range(
+    &self,
+    first: LocatorNumber,
+    second: LocatorNumber,
+  ) -> Option<(TicksTime, TicksTime)> {
+    match (self.get(first), self.get(second)) {
+      (Some(first), Some(second)) if first < second => Some((first, second)),
+      (Some(first), Some(second)) if second < first => Some((second, first)),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{Locators, Marker, Markers};
+  use crate::color::Color;
+  use crate::time::TicksTime;
+
+  fn marker(name: &str, position: u64) -> Marker {
+    Marker::new(name, Color::new("red".into()), TicksTime::new(position))
+  }
+
+  #[test]
+  pub fn markers_sorted_by_position() {
+    let mut markers = Markers::new();
+    assert_eq!(markers.add(marker("chorus", 200)), 0);
+    assert_eq!(markers.add(marker("intro", 0)), 0);
+    assert_eq!(markers.add(marker("verse", 100)), 1);
+    assert_eq!(markers.add(marker("break", 100)), 2);
+
+    let names: Vec<&str> = markers.iter().map(|marker| marker.get_name()).collect();
+    assert_eq!(names, vec!["intro", "verse", "break", "chorus"]);
+
+    assert_eq!(markers.remove(1), Some(marker("verse", 100)));
+    assert_eq!(markers.remove(3), None);
+    assert_eq!(markers.len(), 3);
+  }
+
+  #[test]
+  pub fn next_and_previous_markers() {
+    let mut markers = Markers::new();
+    markers.add(marker("intro", 0));
+    markers.add(marker("verse", 100));
+    markers.add(marker("chorus", 200));
+
+    assert_eq!(markers.next_after(TicksTime::new(0)), markers.get(1));
+    assert_eq!(markers.next_after(TicksTime::new(150)), markers.get(2));
+    assert_eq!(markers.next_after(TicksTime::new(200)), None);
+
+    assert_eq!(markers.previous_before(TicksTime::new(200)), markers.get(1));
+    assert_eq!(markers.previous_before(TicksTime::new(150)), markers.get(1));
+    assert_eq!(markers.previous_before(TicksTime::new(0)), None);
+  }
+
+  #[test]
+  pub fn locators_range() {
+    let mut locators = Locators::new();
+    locators.set(1, TicksTime::new(300));
+    locators.set(2, TicksTime::new(100));
+    locators.set(3, TicksTime::new(100));
+
+    assert_eq!(
+      locators.range(1, 2),
+      Some((TicksTime::new(100), TicksTime::new(300)))
+    );
+    assert_eq!(
+      locators.range(2, 1),
+      Some((TicksTime::new(100), TicksTime::new(300)))
+    );
+    assert_eq!(locators.range(2, 3), None);
+    assert_eq!(locators.range(1, 4), None);
+
+    assert_eq!(locators.remove(1), Some(TicksTime::new(300)));
+    assert_eq!(locators.get(1), None);
+  }
+}
@@ -1,28 +1,66 @@
 pub mod clips;
+pub mod groove;
+pub mod import;
 pub mod io;
+pub mod markers;
+pub mod random;
+pub mod sampler;
+pub mod session;
 pub mod source;
 pub mod track;
 
-use crate::config::Config;
+use std::sync::{Arc, RwLock};
+
+use crate::audio::AudioOutput;
+use crate::config::{Chase as ChaseConfig, Config};
 use crate::metronome::Metronome;
-use crate::time::{BarsTime, SampleRate, Signature, TicksTime};
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::io::MidiOutput;
+use crate::time::{BarsTime, ClockTime, SampleRate, Signature, TicksTime};
 use crate::transport::{Segment, Transport};
 
-use self::track::{Track, TrackMedia};
+use self::clips::{
+  drumbox::{DrumClip, Kit, Pattern},
+  pianoroll::Notes,
+  stepper::{Lane, StepsClip},
+  tracker::TrackerClip,
+  Clip, ClipIndex,
+};
+use self::markers::{Locators, Markers};
+use self::session::{Session, Slot};
+use self::source::audio::AudioDataSource;
+use self::source::notes::{NotesClip as NotesSourceClip, NotesSource};
+use self::track::{record::InputQuantize, Track, TrackMedia};
 
 pub struct Song {
   name: String,
   tracks: Vec<Track>,
+  markers: Markers,
+  locators: Locators,
+  session: Session,
+  chase: ChaseConfig,
+  notes_source: Arc<RwLock<NotesSource>>,
+  audio_source: Arc<RwLock<AudioDataSource>>,
+  input_quantize: Option<InputQuantize>,
+  selected_track: Option<usize>,
 }
 
 impl Song {
-  pub fn new<T>(name: T, _config: &Config) -> Song
+  pub fn new<T>(name: T, config: &Config) -> Song
   where
     T: Into<String>,
   {
     Song {
       name: name.into(),
       tracks: Vec::new(),
+      markers: Markers::new(),
+      locators: Locators::new(),
+      session: Session::new(&config.session),
+      chase: config.chase,
+      notes_source: Arc::new(RwLock::new(NotesSource::new())),
+      audio_source: Arc::new(RwLock::new(AudioDataSource::new())),
+      input_quantize: InputQuantize::from_config(&config.record),
+      selected_track: None,
     }
   }
 
@@ -37,7 +75,218 @@ impl Song {
     self.name.as_str()
   }
 
-  pub fn process_segment(&mut self, _segment: &Segment) {
+  pub fn get_markers(&self) -> &Markers {
+    &self.markers
+  }
+
+  pub fn get_markers_mut(&mut self) -> &mut Markers {
+    &mut self.markers
+  }
+
+  /// Replace all the markers, like with the ones edited away from the audio thread
+  pub fn set_markers(&mut self, markers: Markers) {
+    self.markers = markers;
+  }
+
+  pub fn get_locators(&self) -> &Locators {
+    &self.locators
+  }
+
+  pub fn get_locators_mut(&mut self) -> &mut Locators {
+    &mut self.locators
+  }
+
+  pub fn get_session(&self) -> &Session {
+    &self.session
+  }
+
+  pub fn get_session_mut(&mut self) -> &mut Session {
+    &mut self.session
+  }
+
+  /// Put a clip into the slot of a track of the session, unless the track doesn't exist
+  pub fn set_slot(&mut self, track_index: usize, scene: usize, slot: Option<Slot>) {
+    if track_index < self.tracks.len() {
+      self.session.set_slot(track_index, scene, slot);
+    }
+  }
+
+  /// What to chase when the song starts playing from the middle of the notes and controls
+  pub fn set_chase(&mut self, chase: ChaseConfig) {
+    self.chase = chase;
+  }
+
+  pub fn get_chase(&self) -> &ChaseConfig {
+    &self.chase
+  }
+
+  /// Source for the notes of the clips recorded into the tracks
+  pub fn get_notes_source(&self) -> &Arc<RwLock<NotesSource>> {
+    &self.notes_source
+  }
+
+  /// Source for the samples played by the instruments and the audio clips
+  pub fn get_audio_source(&self) -> &Arc<RwLock<AudioDataSource>> {
+    &self.audio_source
+  }
+
+  pub fn set_input_quantize(&mut self, quantize: Option<InputQuantize>) {
+    self.input_quantize = quantize;
+  }
+
+  pub fn get_input_quantize(&self) -> Option<&InputQuantize> {
+    self.input_quantize.as_ref()
+  }
+
+  /// Add a track and return its index
+  pub fn add_track(&mut self, track: Track) -> usize {
+    self.tracks.push(track);
+    self.tracks.len() - 1
+  }
+
+  pub fn get_track(&self, index: usize) -> Option<&Track> {
+    self.tracks.get(index)
+  }
+
+  pub fn get_track_mut(&mut self, index: usize) -> Option<&mut Track> {
+    self.tracks.get_mut(index)
+  }
+
+  pub fn select_track(&mut self, index: Option<usize>) {
+    self.selected_track = index.filter(|index| *index < self.tracks.len());
+  }
+
+  pub fn get_selected_track(&self) -> Option<usize> {
+    self.selected_track
+  }
+
+  /// Add a clip to a track with its notes kept in the song source, and return its index.
+  /// The uuid of the clip is replaced by the id of the notes in the source.
+  /// Nothing is added when the track can't have notes or the source is being used.
+  pub fn add_notes_clip(
+    &mut self,
+    track_index: usize,
+    mut clip: Clip,
+    notes: NotesSourceClip,
+  ) -> Option<ClipIndex> {
+    let track = self
+      .tracks
+      .get_mut(track_index)
+      .filter(|track| track.has_notes())?;
+    let mut source = self.notes_source.try_write().ok()?;
+    let id = source.next_free_id();
+    source.add_clip(id, notes);
+    clip.uuid = id;
+    track.add_notes_clip(clip, Notes::new(self.notes_source.clone(), id))
+  }
+
+  /// Arm a track for recording with the clip for its next new take, or disarm it without one.
+  /// The clip is kept empty in the track with its notes in the source, until a take needs it.
+  pub fn arm_track(&mut self, track_index: usize, take: Option<Clip>) {
+    let track = match self.tracks.get_mut(track_index) {
+      Some(track) => track,
+      None => return,
+    };
+    track.rec = take.is_some();
+    let mut clip = match take {
+      Some(clip) if track.has_notes() => clip,
+      _ => return,
+    };
+    if track.get_new_take().is_some() {
+      return;
+    }
+    if let Ok(mut source) = self.notes_source.try_write() {
+      let id = source.next_free_id();
+      source.add_clip(id, NotesSourceClip::new());
+      clip.uuid = id;
+      track.prepare_take(clip, Notes::new(self.notes_source.clone(), id));
+    }
+  }
+
+  /// Add a step sequencer clip to a track and return its index, unless the track can't have notes
+  pub fn add_steps_clip(
+    &mut self,
+    track_index: usize,
+    clip: Clip,
+    lanes: Vec<Lane>,
+  ) -> Option<ClipIndex> {
+    self
+      .tracks
+      .get_mut(track_index)
+      .and_then(|track| track.add_steps_clip(clip, lanes))
+  }
+
+  pub fn get_steps_clip_mut(
+    &mut self,
+    track_index: usize,
+    clip_index: ClipIndex,
+  ) -> Option<&mut StepsClip> {
+    self
+      .tracks
+      .get_mut(track_index)
+      .and_then(|track| track.get_steps_clip_mut(clip_index))
+  }
+
+  /// Add a drum machine clip to a track and return its index, unless the track can't have notes
+  pub fn add_drum_clip(
+    &mut self,
+    track_index: usize,
+    clip: Clip,
+    kit: Kit,
+    patterns: Vec<Pattern>,
+  ) -> Option<ClipIndex> {
+    self
+      .tracks
+      .get_mut(track_index)
+      .and_then(|track| track.add_drum_clip(clip, kit, patterns))
+  }
+
+  pub fn get_drum_clip_mut(
+    &mut self,
+    track_index: usize,
+    clip_index: ClipIndex,
+  ) -> Option<&mut DrumClip> {
+    self
+      .tracks
+      .get_mut(track_index)
+      .and_then(|track| track.get_drum_clip_mut(clip_index))
+  }
+
+  /// Add a compiled tracker clip to a track and return its index, unless the track can't have notes
+  pub fn add_tracker_clip(
+    &mut self,
+    track_index: usize,
+    tracker_clip: TrackerClip,
+  ) -> Option<ClipIndex> {
+    self
+      .tracks
+      .get_mut(track_index)
+      .and_then(|track| track.add_tracker_clip(tracker_clip))
+  }
+
+  /// Swap a tracker clip of a track for a compiled one with the same uuid
+  pub fn replace_tracker_clip(&mut self, track_index: usize, tracker_clip: TrackerClip) -> bool {
+    self
+      .tracks
+      .get_mut(track_index)
+      .is_some_and(|track| track.replace_tracker_clip(tracker_clip))
+  }
+
+  pub fn get_tracker_clip_mut(
+    &mut self,
+    track_index: usize,
+    clip_index: ClipIndex,
+  ) -> Option<&mut TrackerClip> {
+    self
+      .tracks
+      .get_mut(track_index)
+      .and_then(|track| track.get_tracker_clip_mut(clip_index))
+  }
+
+  pub fn process_segment<MidiOut>(&mut self, segment: &Segment, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
     // println!(
     //   "=> Segment T [{:06?}, {:06?}) <{:06?}> C [{:010?}, {:010?}) <{:010?}> @ PT {:06?} PC {:010?}",
     //   u64::from(segment.start_ticks),
@@ -50,15 +299,62 @@ impl Song {
     //   segment.play_time.units()
     // );
 
+    for (index, track) in self.tracks.iter_mut().enumerate() {
+      self
+        .session
+        .process_track(index, track, segment, &self.chase, midi_output);
+    }
+  }
+
+  /// Record the input events of the segment into the armed tracks
+  pub fn record_segment(&mut self, segment: &Segment, events: &[EventIo]) {
+    let quantize = self.input_quantize.as_ref();
+    for track in self.tracks.iter_mut() {
+      track.record_segment(segment, events, quantize);
+    }
+  }
+
+  /// Finish the recordings in progress
+  pub fn finish_recording(&mut self) {
+    let quantize = self.input_quantize.as_ref();
+    for track in self.tracks.iter_mut() {
+      track.finish_recording(quantize);
+    }
+  }
+
+  /// Release the notes still playing
+  pub fn stop<MidiOut>(&mut self, time: ClockTime, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
+    for track in self.tracks.iter_mut() {
+      track.stop(time, midi_output);
+    }
+  }
+
+  /// Forget the notes still playing, the outputs were silenced
+  pub fn forget_notes(&mut self) {
+    for track in self.tracks.iter_mut() {
+      track.forget_notes();
+    }
+  }
+
+  /// Forget the notes the tracks play on an endpoint that was removed
+  pub fn forget_endpoint(&mut self, endpoint: Endpoint) {
+    for track in self.tracks.iter_mut() {
+      track.forget_endpoint(endpoint);
+    }
+  }
+
+  /// Add the sound of the instrument tracks to the output
+  pub fn process_audio(
+    &mut self,
+    audio_output: &mut AudioOutput,
+    frames: usize,
+    sample_rate: SampleRate,
+  ) {
     for track in self.tracks.iter_mut() {
-      // let clips = track.clips_in_range(start_ticks, end_ticks);
-      match &track.media {
-        TrackMedia::Midi(_midi_track) => {
-          // prepare buffer for midi_track.sink
-        }
-        TrackMedia::Audio(_audio_track) => {}
-        TrackMedia::Instrument(_instrument_track) => {}
-      }
+      track.process_audio(audio_output, frames, sample_rate);
     }
   }
 }
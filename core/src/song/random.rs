@@ -0,0 +1,9 @@
+/// Next number of a splitmix64 sequence, advancing its state.
+/// The numbers only depend on the state, so what is decided from them plays the same way every time.
+pub fn splitmix64(state: &mut u64) -> u64 {
+  *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+  let mut value = *state;
+  value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  value ^ (value >> 31)
+}
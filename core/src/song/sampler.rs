@@ -0,0 +1,361 @@
+use std::sync::{Arc, RwLock};
+
+use crate::audio::AudioOutput;
+use crate::midi::buffer::EventIo;
+use crate::midi::io::MidiOutput;
+use crate::midi::types::{U4, U7};
+use crate::midi::Message;
+use crate::song::{clips::ClipId, source::audio::AudioDataSource};
+use crate::time::{ClockTime, SampleRate};
+
+const MAX_SCHEDULED_EVENTS: usize = 256;
+const MAX_VOICES: usize = 32;
+
+/// Range of keys that play a sample of the audio source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleZone {
+  pub low_key: U7,
+  pub high_key: U7,
+  pub sample: ClipId,
+}
+
+impl SampleZone {
+  pub fn new(low_key: U7, high_key: U7, sample: ClipId) -> SampleZone {
+    SampleZone {
+      low_key,
+      high_key,
+      sample,
+    }
+  }
+
+  pub fn contains(&self, key: U7) -> bool {
+    self.low_key <= key && key <= self.high_key
+  }
+}
+
+/// Instrument of the sampler, played by the notes of a channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplerInstrument {
+  pub name: String,
+  pub channel: U4,
+  pub zones: Vec<SampleZone>,
+}
+
+impl SamplerInstrument {
+  pub fn new<T>(name: T, channel: U4) -> SamplerInstrument
+  where
+    T: Into<String>,
+  {
+    SamplerInstrument {
+      name: name.into(),
+      channel,
+      zones: Vec::new(),
+    }
+  }
+
+  /// The first zone with the key
+  pub fn zone_for_key(&self, key: U7) -> Option<&SampleZone> {
+    self.zones.iter().find(|zone| zone.contains(key))
+  }
+}
+
+/// Sample playing for a note, until it is released or the sample ends
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+  channel: U4,
+  key: U7,
+  sample: ClipId,
+  gain: f32,
+  /// Frames of the sample for every frame of the output
+  step: f64,
+  position: f64,
+  /// Frames of the output before the voice starts
+  delay: usize,
+  /// Frames of the output before the voice stops, once the note is released
+  release: Option<usize>,
+}
+
+/// Instrument that plays the samples of an audio source for the notes of its channels.
+/// The notes are pushed to it as MIDI events and sound when the audio is rendered.
+pub struct Sampler {
+  source: Arc<RwLock<AudioDataSource>>,
+  instruments: Vec<SamplerInstrument>,
+  scheduled: Vec<EventIo>,
+  voices: Vec<Voice>,
+}
+
+impl Sampler {
+  pub fn new(source: Arc<RwLock<AudioDataSource>>) -> Sampler {
+    Sampler {
+      source,
+      instruments: Vec::new(),
+      scheduled: Vec::with_capacity(MAX_SCHEDULED_EVENTS),
+      voices: Vec::with_capacity(MAX_VOICES),
+    }
+  }
+
+  pub fn get_source(&self) -> &Arc<RwLock<AudioDataSource>> {
+    &self.source
+  }
+
+  /// Add an instrument and return its index
+  pub fn add_instrument(&mut self, instrument: SamplerInstrument) -> usize {
+    self.instruments.push(instrument);
+    self.instruments.len() - 1
+  }
+
+  pub fn get_instruments(&self) -> &[SamplerInstrument] {
+    self.instruments.as_slice()
+  }
+
+  /// The zone that plays a key for a channel, from the first instrument of the channel that has it
+  pub fn zone_for(&self, channel: U4, key: U7) -> Option<&SampleZone> {
+    zone_for(&self.instruments, channel, key)
+  }
+
+  /// Stop all the voices and forget the notes scheduled
+  pub fn silence(&mut self) {
+    self.scheduled.clear();
+    self.voices.clear();
+  }
+
+  /// Start and release the voices of the notes scheduled for this buffer and add the sounding ones to the output.
+  /// The samples are mono and go to the first two channels.
+  pub fn render(&mut self, audio_output: &mut AudioOutput, frames: usize, sample_rate: SampleRate) {
+    let Sampler {
+      source,
+      instruments,
+      scheduled,
+      voices,
+    } = self;
+    // the samples can't be read while the source is changed, so the voices wait for the next buffer
+    let source = match source.try_read() {
+      Ok(source) => source,
+      Err(_) => return,
+    };
+
+    let buffer_time = audio_output.time;
+    for event in scheduled.drain(..) {
+      let delay = frames_until(event.timestamp, buffer_time, sample_rate);
+      match event.message {
+        Message::NoteOn {
+          channel,
+          key,
+          velocity,
+        } if velocity > 0 => {
+          let sample_id = match zone_for(instruments, channel, key) {
+            Some(zone) => zone.sample,
+            None => continue,
+          };
+          if let Some(sample) = source.get_sample(sample_id) {
+            if voices.len() == MAX_VOICES {
+              voices.remove(0);
+            }
+            voices.push(Voice {
+              channel,
+              key,
+              sample: sample_id,
+              gain: (sample.volume * f64::from(velocity) / 127.0) as f32,
+              step: sample.playback_rate(key) * f64::from(sample.sample_rate)
+                / f64::from(sample_rate),
+              position: 0.0,
+              delay,
+              release: None,
+            });
+          }
+        }
+        Message::NoteOn { channel, key, .. } | Message::NoteOff { channel, key, .. } => {
+          for voice in voices
+            .iter_mut()
+            .filter(|voice| voice.channel == channel && voice.key == key && voice.release.is_none())
+          {
+            voice.release = Some(delay);
+          }
+        }
+        _ => {}
+      }
+    }
+
+    let num_channels = audio_output.channels;
+    let frames = frames.min(audio_output.buffer.len() / num_channels.max(1));
+    for voice in voices.iter_mut() {
+      let sample = match source.get_sample(voice.sample) {
+        Some(sample) => sample,
+        None => {
+          voice.release = Some(0);
+          continue;
+        }
+      };
+      let start = voice.delay.min(frames);
+      let end = voice.release.unwrap_or(frames).min(frames);
+      for frame in start..end {
+        let mut index = voice.position as usize;
+        if let Some((loop_start, loop_length)) = sample.loop_range.filter(|(_, length)| *length > 0)
+        {
+          let loop_end = loop_start + loop_length;
+          if index >= loop_end {
+            let offset = (voice.position - loop_start as f64) % loop_length as f64;
+            voice.position = loop_start as f64 + offset;
+            index = voice.position as usize;
+          }
+        }
+        let value = match sample.data.get(index) {
+          Some(value) => *value * voice.gain,
+          None => {
+            voice.release = Some(0);
+            break;
+          }
+        };
+        for channel in 0..num_channels.min(2) {
+          audio_output.buffer[frame * num_channels + channel] += value;
+        }
+        voice.position += voice.step;
+      }
+      voice.delay -= start;
+      voice.release = voice.release.map(|release| release.saturating_sub(frames));
+    }
+
+    voices.retain(|voice| voice.release != Some(0));
+  }
+}
+
+impl MidiOutput for Sampler {
+  /// The notes are scheduled for the next render.
+  /// When there is no room, a note off takes the place of a note on, or releases its voices right away.
+  fn push(&mut self, event: EventIo) {
+    if self.scheduled.len() == MAX_SCHEDULED_EVENTS {
+      let (channel, key) = match released_note(&event.message) {
+        Some(note) => note,
+        None => return,
+      };
+      let note_on = self
+        .scheduled
+        .iter()
+        .rposition(|scheduled| started_note(&scheduled.message) == Some((channel, key)))
+        .or_else(|| {
+          self
+            .scheduled
+            .iter()
+            .rposition(|scheduled| started_note(&scheduled.message).is_some())
+        });
+      match note_on {
+        Some(index) => {
+          self.scheduled.remove(index);
+        }
+        None => {
+          for voice in self
+            .voices
+            .iter_mut()
+            .filter(|voice| voice.channel == channel && voice.key == key)
+          {
+            voice.release = Some(0);
+          }
+          return;
+        }
+      }
+    }
+    self.scheduled.push(event);
+  }
+
+  fn panic(&mut self) {
+    self.silence();
+  }
+}
+
+fn zone_for(instruments: &[SamplerInstrument], channel: U4, key: U7) -> Option<&SampleZone> {
+  instruments
+    .iter()
+    .filter(|instrument| instrument.channel == channel)
+    .find_map(|instrument| instrument.zone_for_key(key))
+}
+
+/// Channel and key of a note on with velocity
+fn started_note(message: &Message) -> Option<(U4, U7)> {
+  match *message {
+    Message::NoteOn {
+      channel,
+      key,
+      velocity,
+    } if velocity > 0 => Some((channel, key)),
+    _ => None,
+  }
+}
+
+/// Channel and key of a note off, or of a note on without velocity
+fn released_note(message: &Message) -> Option<(U4, U7)> {
+  match *message {
+    Message::NoteOn { channel, key, .. } | Message::NoteOff { channel, key, .. }
+      if started_note(message).is_none() =>
+    {
+      Some((channel, key))
+    }
+    _ => None,
+  }
+}
+
+/// Frames of the output from its start until a time, none for the past
+fn frames_until(time: ClockTime, buffer_time: ClockTime, sample_rate: SampleRate) -> usize {
+  if time > buffer_time {
+    (time - buffer_time).to_samples(sample_rate) as usize
+  } else {
+    0
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{Sampler, MAX_SCHEDULED_EVENTS};
+  use crate::audio::AudioOutput;
+  use crate::midi::buffer::{Endpoint, EventIo};
+  use crate::midi::io::MidiOutput;
+  use crate::midi::Message;
+  use crate::testing::tone_sampler;
+  use crate::time::ClockTime;
+
+  const SAMPLE_RATE: u32 = 44100;
+  const FRAMES: usize = 64;
+
+  fn note(key: u8, velocity: u8) -> EventIo {
+    let message = Message::NoteOn {
+      channel: 0,
+      key,
+      velocity,
+    };
+    EventIo::new(ClockTime::zero(), Endpoint::Default, message)
+  }
+
+  fn render(sampler: &mut Sampler) {
+    let mut buffer = vec![0.0; FRAMES * 2];
+    let mut audio_output = AudioOutput::new(ClockTime::zero(), 2, &mut buffer);
+    sampler.render(&mut audio_output, FRAMES, SAMPLE_RATE);
+  }
+
+  #[test]
+  pub fn keep_the_note_offs_when_full() {
+    let mut sampler = tone_sampler(SAMPLE_RATE);
+    sampler.push(note(60, 100));
+    render(&mut sampler);
+    assert_eq!(sampler.voices.len(), 1);
+
+    // the note off takes the place of the note on
+    for _ in 1..MAX_SCHEDULED_EVENTS {
+      sampler.push(note(61, 0));
+    }
+    sampler.push(note(62, 100));
+    sampler.push(note(60, 0));
+    assert_eq!(sampler.scheduled.len(), MAX_SCHEDULED_EVENTS);
+    render(&mut sampler);
+    assert!(sampler.voices.is_empty());
+
+    // without a note on to replace, the voice is released right away
+    sampler.push(note(60, 100));
+    render(&mut sampler);
+    for _ in 0..MAX_SCHEDULED_EVENTS {
+      sampler.push(note(61, 0));
+    }
+    sampler.push(note(60, 0));
+    render(&mut sampler);
+    assert!(sampler.voices.is_empty());
+  }
+}
@@ -0,0 +1,681 @@
+use serde_derive::Deserialize;
+
+use crate::config::{Chase as ChaseConfig, LaunchQuantize, Session as SessionConfig};
+use crate::midi::io::MidiOutput;
+use crate::song::{clips::ClipIndex, random, track::Track};
+use crate::time::{BarsTime, TicksTime};
+use crate::transport::Segment;
+
+/// Most scenes of a session, there is room for all of them and for their slots from the start
+pub const MAX_SCENES: usize = 64;
+
+/// Most loops of a clip before its follow action
+pub const MAX_FOLLOW_LOOPS: u32 = 1024;
+
+/// What a slot does after its clip played a number of loops
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum FollowAction {
+  /// Launch the next slot of the track with a clip, going back to the first one after the last one
+  Next,
+  /// Launch the previous slot of the track with a clip, going to the last one before the first one
+  Previous,
+  /// Launch any other slot of the track with a clip
+  Random,
+  Stop,
+}
+
+/// Clip of a track for a scene
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Slot {
+  pub clip: ClipIndex,
+  pub follow_action: Option<FollowAction>,
+  /// Number of loops of the clip before the follow action
+  #[serde(default = "default_follow_loops")]
+  pub follow_loops: u32,
+}
+
+fn default_follow_loops() -> u32 {
+  1
+}
+
+impl Slot {
+  pub fn new(clip: ClipIndex) -> Slot {
+    Slot {
+      clip,
+      follow_action: None,
+      follow_loops: default_follow_loops(),
+    }
+  }
+}
+
+/// Row of slots that are launched together
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Scene {
+  pub name: String,
+}
+
+impl Scene {
+  pub fn new<T>(name: T) -> Scene
+  where
+    T: Into<String>,
+  {
+    Scene { name: name.into() }
+  }
+}
+
+/// What a track does once the launch position is reached
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Launch {
+  /// Play the clip of a scene, or stop when the slot is empty
+  Slot(usize),
+  Stop,
+  /// Follow the arrangement again
+  Arrangement,
+}
+
+/// The times are the ticks played since the transport started playing, so the clips launched
+/// keep going when the loop wraps or the song is located
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct TrackState {
+  /// Scene of the slot playing and the time when its clip started
+  playing: Option<(usize, TicksTime)>,
+  /// The launch waiting for its time, which is known from the first segment played after it
+  queued: Option<(Launch, Option<TicksTime>)>,
+  /// Whether the track left the arrangement for the session
+  in_session: bool,
+  /// Time where the next segment starts, unless the transport restarted
+  next_time: Option<TicksTime>,
+}
+
+/// Grid of slots with a clip of a track for every scene, launched while playing.
+/// The slots refer to the clips of the tracks wherever they are in the arrangement,
+/// and the tracks that play from the session leave the arrangement until they go back to it.
+pub struct Session {
+  launch_quantize: LaunchQuantize,
+  scenes: Vec<Scene>,
+  slots: Vec<Vec<Option<Slot>>>,
+  states: Vec<TrackState>,
+  random: u64,
+}
+
+impl Session {
+  pub fn new(config: &SessionConfig) -> Session {
+    Session {
+      launch_quantize: config.launch_quantize,
+      scenes: Vec::with_capacity(MAX_SCENES),
+      slots: Vec::new(),
+      states: Vec::new(),
+      random: 0,
+    }
+  }
+
+  pub fn set_launch_quantize(&mut self, launch_quantize: LaunchQuantize) {
+    self.launch_quantize = launch_quantize;
+  }
+
+  pub fn get_launch_quantize(&self) -> LaunchQuantize {
+    self.launch_quantize
+  }
+
+  /// Add a scene and return its index, unless there are already [`MAX_SCENES`]
+  pub fn add_scene(&mut self, scene: Scene) -> Option<usize> {
+    if self.scenes.len() < MAX_SCENES {
+      self.scenes.push(scene);
+      Some(self.scenes.len() - 1)
+    } else {
+      None
+    }
+  }
+
+  pub fn get_scenes(&self) -> &[Scene] {
+    self.scenes.as_slice()
+  }
+
+  /// Put a clip into the slot of a track for a scene, or empty it.
+  /// The scenes from [`MAX_SCENES`] are ignored, the tracks are checked by [`Song::set_slot`](super::Song::set_slot).
+  pub fn set_slot(&mut self, track: usize, scene: usize, slot: Option<Slot>) {
+    if scene >= MAX_SCENES {
+      return;
+    }
+    if self.slots.len() <= track {
+      self
+        .slots
+        .resize_with(track + 1, || Vec::with_capacity(MAX_SCENES));
+      self.states.resize(track + 1, TrackState::default());
+    }
+    let slots = &mut self.slots[track];
+    if slots.len() <= scene {
+      slots.resize(scene + 1, None);
+    }
+    slots[scene] = slot;
+  }
+
+  pub fn get_slot(&self, track: usize, scene: usize) -> Option<&Slot> {
+    self
+      .slots
+      .get(track)
+      .and_then(|slots| slots.get(scene))
+      .and_then(|slot| slot.as_ref())
+  }
+
+  /// Launch the slot of a track at the next launch position
+  pub fn launch_slot(&mut self, track: usize, scene: usize) {
+    self.queue(track, Launch::Slot(scene));
+  }
+
+  /// Stop the clip of a track at the next launch position
+  pub fn stop_track(&mut self, track: usize) {
+    self.queue(track, Launch::Stop);
+  }
+
+  /// Launch the slots of a scene for all the tracks of the session, the ones with an empty slot stop
+  pub fn launch_scene(&mut self, scene: usize) {
+    for track in 0..self.states.len() {
+      self.queue(track, Launch::Slot(scene));
+    }
+  }
+
+  pub fn stop_all(&mut self) {
+    for track in 0..self.states.len() {
+      self.queue(track, Launch::Stop);
+    }
+  }
+
+  /// Make all the tracks follow the arrangement again at the next launch position
+  pub fn back_to_arrangement(&mut self) {
+    for track in 0..self.states.len() {
+      self.queue(track, Launch::Arrangement);
+    }
+  }
+
+  /// Scene of the slot playing on a track
+  pub fn get_playing(&self, track: usize) -> Option<usize> {
+    self
+      .states
+      .get(track)
+      .and_then(|state| state.playing)
+      .map(|(scene, _)| scene)
+  }
+
+  /// Launch of a track waiting for its position
+  pub fn get_queued(&self, track: usize) -> Option<Launch> {
+    self
+      .states
+      .get(track)
+      .and_then(|state| state.queued)
+      .map(|(launch, _)| launch)
+  }
+
+  /// Whether a track plays from the session instead of the arrangement
+  pub fn is_in_session(&self, track: usize) -> bool {
+    self.states.get(track).is_some_and(|state| state.in_session)
+  }
+
+  /// The random follow actions are decided from the seed, so they play the same way every time
+  pub fn set_seed(&mut self, seed: u64) {
+    self.random = seed;
+  }
+
+  /// Play a track over the segment, from the session or the arrangement.
+  /// The segment is split where the launches and the follow actions happen, so they are sample accurate.
+  pub fn process_track<MidiOut>(
+    &mut self,
+    track_index: usize,
+    track: &mut Track,
+    segment: &Segment,
+    chase: &ChaseConfig,
+    midi_output: &mut MidiOut,
+  ) where
+    MidiOut: MidiOutput,
+  {
+    if track_index >= self.states.len() {
+      track.process_segment(segment, chase, midi_output);
+      return;
+    }
+    let play_start = segment.play_duration;
+    let play_end = play_start + (segment.end_position - segment.start_position);
+    let song_position = |time: TicksTime| segment.start_position + (time - play_start);
+
+    let launch_time = play_start + (self.launch_position(segment) - segment.start_position);
+    let state = &mut self.states[track_index];
+    // the time played starts from zero again when the transport restarts, and so does the clip playing
+    if state
+      .next_time
+      .is_some_and(|next_time| play_start < next_time)
+    {
+      state.playing = state.playing.map(|(scene, _)| (scene, play_start));
+      if let Some((_, time)) = &mut state.queued {
+        *time = None;
+      }
+    }
+    state.next_time = Some(play_end);
+    if let Some((_, time @ None)) = &mut state.queued {
+      *time = Some(launch_time);
+    }
+
+    let mut time = play_start;
+    loop {
+      let state = self.states[track_index];
+      let queued = state.queued.and_then(|(_, at)| at).map(|at| at.max(time));
+      let follow = state
+        .playing
+        .and_then(|(scene, start)| self.follow_time(track_index, track, scene, start))
+        .map(|at| at.max(time));
+      let change = match (queued, follow) {
+        (Some(queued), Some(follow)) => Some(queued.min(follow)),
+        (queued, follow) => queued.or(follow),
+      };
+      let part_end = change
+        .filter(|change| *change < play_end)
+        .unwrap_or(play_end);
+
+      if part_end > time {
+        let part = segment.part(song_position(time), song_position(part_end));
+        match state.playing {
+          Some((scene, start)) => {
+            if let Some(slot) = self.get_slot(track_index, scene) {
+              track.process_looped_clip(slot.clip, time - start, &part, midi_output);
+            }
+          }
+          None if !state.in_session => track.process_segment(&part, chase, midi_output),
+          None => {}
+        }
+      }
+      if part_end == play_end {
+        break;
+      }
+
+      track.stop(
+        segment.master_clock_at(song_position(part_end)),
+        midi_output,
+      );
+      let launch = if queued == Some(part_end) {
+        self.states[track_index]
+          .queued
+          .take()
+          .map(|(launch, _)| launch)
+      } else {
+        state
+          .playing
+          .map(|(scene, _)| self.follow(track_index, scene))
+      };
+      let state = &mut self.states[track_index];
+      match launch {
+        Some(Launch::Slot(scene)) => {
+          let has_clip = self
+            .slots
+            .get(track_index)
+            .and_then(|slots| slots.get(scene))
+            .is_some_and(|slot| slot.is_some());
+          state.playing = Some((scene, part_end)).filter(|_| has_clip);
+          state.in_session = true;
+        }
+        Some(Launch::Stop) | None => {
+          state.playing = None;
+          state.in_session = true;
+        }
+        Some(Launch::Arrangement) => {
+          state.playing = None;
+          state.in_session = false;
+        }
+      }
+      time = part_end;
+    }
+  }
+
+  fn queue(&mut self, track: usize, launch: Launch) {
+    if let Some(state) = self.states.get_mut(track) {
+      state.queued = Some((launch, None));
+    }
+  }
+
+  /// The next beat or bar from the start of the segment, unless it is already there
+  fn launch_position(&self, segment: &Segment) -> TicksTime {
+    let length = match self.launch_quantize {
+      LaunchQuantize::None => return segment.start_position,
+      LaunchQuantize::Beat => BarsTime::new(0, 1, 0, 0).to_ticks(segment.signature),
+      LaunchQuantize::Bar => BarsTime::from_bars(1).to_ticks(segment.signature),
+    };
+    let length = u64::from(length).max(1);
+    let from_bar = u64::from(segment.start_position - segment.bar_start_position);
+    segment.bar_start_position + TicksTime::new(from_bar.div_ceil(length) * length)
+  }
+
+  /// Time when the follow action of a slot started at a time happens, if it ever does
+  fn follow_time(
+    &self,
+    track_index: usize,
+    track: &Track,
+    scene: usize,
+    start: TicksTime,
+  ) -> Option<TicksTime> {
+    let slot = self
+      .get_slot(track_index, scene)
+      .filter(|slot| slot.follow_action.is_some() && slot.follow_loops > 0)?;
+    let length = u64::from(track.get_clip(slot.clip)?.length);
+    let loops = slot.follow_loops.min(MAX_FOLLOW_LOOPS);
+    length
+      .checked_mul(u64::from(loops))
+      .and_then(|length| u64::from(start).checked_add(length))
+      .map(TicksTime::new)
+      .filter(|_| length > 0)
+  }
+
+  /// What the follow action of a slot launches, counting only the slots of the track with a clip
+  fn follow(&mut self, track_index: usize, scene: usize) -> Launch {
+    let action = self
+      .get_slot(track_index, scene)
+      .and_then(|slot| slot.follow_action);
+    let slots = &self.slots[track_index];
+    let num_scenes = slots.iter().filter(|slot| slot.is_some()).count().max(1);
+    let current = match slots.get(scene) {
+      Some(Some(_)) => slots[..scene].iter().filter(|slot| slot.is_some()).count(),
+      _ => 0,
+    };
+    let next = match action {
+      Some(FollowAction::Next) => (current + 1) % num_scenes,
+      Some(FollowAction::Previous) => (current + num_scenes - 1) % num_scenes,
+      Some(FollowAction::Random) if num_scenes > 1 => {
+        let other = (self.next_random() % (num_scenes as u64 - 1)) as usize;
+        if other < current {
+          other
+        } else {
+          other + 1
+        }
+      }
+      Some(FollowAction::Random) => current,
+      Some(FollowAction::Stop) | None => return Launch::Stop,
+    };
+    self.slots[track_index]
+      .iter()
+      .enumerate()
+      .filter(|(_, slot)| slot.is_some())
+      .nth(next)
+      .map_or(Launch::Stop, |(scene, _)| Launch::Slot(scene))
+  }
+
+  fn next_random(&mut self) -> u64 {
+    random::splitmix64(&mut self.random)
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{FollowAction, Scene, Slot, MAX_SCENES};
+  use crate::config::{Config, LaunchQuantize};
+  use crate::midi::buffer::{Endpoint, EventIo};
+  use crate::midi::Message;
+  use crate::song::clips::Clip;
+  use crate::song::source::notes::{Note, NotesClip as NotesSourceClip};
+  use crate::song::Song;
+  use crate::testing::{midi_track, VecMidiOutput};
+  use crate::time::{BarsTime, ClockTime, Signature, TicksTime};
+  use crate::transport::Transport;
+
+  const SAMPLE_RATE: u32 = 44100;
+
+  struct Player {
+    transport: Transport,
+    master_clock: ClockTime,
+    output: Vec<EventIo>,
+  }
+
+  impl Player {
+    fn new() -> Player {
+      let mut transport = Transport::new(SAMPLE_RATE);
+      transport.set_loop_enabled(false);
+      transport.play(false);
+      Player {
+        transport,
+        master_clock: ClockTime::zero(),
+        output: Vec::new(),
+      }
+    }
+
+    fn play_until(&mut self, song: &mut Song, seconds: f64) {
+      let samples = 512;
+      while self.master_clock < ClockTime::from_seconds(seconds) {
+        let mut segments = self.transport.segments_iterator(self.master_clock, samples);
+        while let Some(segment) = segments.next(&self.transport) {
+          let mut output = VecMidiOutput(Vec::new());
+          song.process_segment(&segment, &mut output);
+          self.output.extend(output.0);
+        }
+        self.transport.update_from_segments(&segments);
+        self.master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+      }
+    }
+
+    /// The keys of the notes with their start and end in seconds
+    fn notes(&self) -> Vec<(u8, bool, f64)> {
+      self
+        .output
+        .iter()
+        .filter_map(|event| match event.message {
+          Message::NoteOn { key, .. } => Some((key, true, event.timestamp)),
+          Message::NoteOff { key, .. } => Some((key, false, event.timestamp)),
+          _ => None,
+        })
+        .map(|(key, on, time)| (key, on, (time.to_seconds() * 1000.0).round() / 1000.0))
+        .collect()
+    }
+  }
+
+  fn add_clip(song: &mut Song, track: usize, bar: u16, bars: u16, keys: &[(u8, u16)]) -> usize {
+    let signature = Signature::new(4, 4);
+    let mut notes = NotesSourceClip::new();
+    for (key, note_bar) in keys.iter() {
+      notes.add_note(Note::new(
+        *key,
+        1.0,
+        BarsTime::from_bars(*note_bar).to_ticks(signature),
+        BarsTime::new(0, 1, 0, 0).to_ticks(signature),
+      ));
+    }
+    let clip = Clip {
+      uuid: 0,
+      name: "clip".to_string(),
+      signature,
+      start: BarsTime::from_bars(bar).to_ticks(signature),
+      length: BarsTime::from_bars(bars).to_ticks(signature),
+    };
+    song.add_notes_clip(track, clip, notes).unwrap()
+  }
+
+  fn song_with_tracks(num_tracks: usize) -> Song {
+    let mut song = Song::new("song", &Config::default());
+    for _ in 0..num_tracks {
+      let track = midi_track(Endpoint::Default, 0);
+      song.add_track(track);
+    }
+    song
+  }
+
+  #[test]
+  pub fn launch_at_the_next_bar_and_follow() {
+    let mut song = song_with_tracks(1);
+    add_clip(&mut song, 0, 0, 8, &[(48, 0), (48, 5)]);
+    let first = add_clip(&mut song, 0, 10, 1, &[(60, 0)]);
+    let second = add_clip(&mut song, 0, 11, 1, &[(62, 0)]);
+    let session = song.get_session_mut();
+    assert_eq!(session.get_launch_quantize(), LaunchQuantize::Bar);
+    let follow = |clip, action, loops| Slot {
+      follow_action: Some(action),
+      follow_loops: loops,
+      ..Slot::new(clip)
+    };
+    session.set_slot(0, 0, Some(follow(first, FollowAction::Next, 2)));
+    session.set_slot(0, 1, Some(follow(second, FollowAction::Stop, 1)));
+
+    let mut player = Player::new();
+    player.play_until(&mut song, 0.5);
+    song.get_session_mut().launch_slot(0, 0);
+    player.play_until(&mut song, 2.5);
+    assert_eq!(song.get_session().get_playing(0), Some(0));
+    player.play_until(&mut song, 9.0);
+    assert_eq!(song.get_session().get_playing(0), None);
+    assert!(song.get_session().is_in_session(0));
+    song.get_session_mut().back_to_arrangement();
+    player.play_until(&mut song, 11.0);
+    assert!(!song.get_session().is_in_session(0));
+
+    assert_eq!(
+      player.notes(),
+      vec![
+        (48, true, 0.0),
+        (48, false, 0.5),
+        (60, true, 2.0),
+        (60, false, 2.5),
+        (60, true, 4.0),
+        (60, false, 4.5),
+        (62, true, 6.0),
+        (62, false, 6.5),
+        (48, true, 10.0),
+        (48, false, 10.5),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn launch_the_scenes_at_the_next_beat() {
+    let mut song = song_with_tracks(2);
+    let first = add_clip(&mut song, 0, 10, 1, &[(60, 0)]);
+    let second = add_clip(&mut song, 1, 10, 1, &[(64, 0)]);
+    let random: Vec<usize> = [72, 74, 76]
+      .iter()
+      .map(|key| add_clip(&mut song, 0, 20, 1, &[(*key, 0)]))
+      .collect();
+    let session = song.get_session_mut();
+    session.set_launch_quantize(LaunchQuantize::Beat);
+    session.add_scene(Scene::new("intro"));
+    session.add_scene(Scene::new("verse"));
+    let intro = Slot {
+      follow_action: Some(FollowAction::Next),
+      ..Slot::new(first)
+    };
+    session.set_slot(0, 0, Some(intro));
+    session.set_slot(1, 0, Some(Slot::new(second)));
+    for (scene, clip) in random.iter().enumerate() {
+      let slot = Slot {
+        follow_action: Some(FollowAction::Random),
+        ..Slot::new(*clip)
+      };
+      session.set_slot(0, scene + 1, Some(slot));
+    }
+
+    let mut player = Player::new();
+    player.play_until(&mut song, 0.1);
+    song.get_session_mut().launch_scene(0);
+    player.play_until(&mut song, 1.2);
+    song.get_session_mut().launch_scene(1);
+    player.play_until(&mut song, 40.0);
+    assert_eq!(song.get_session().get_playing(1), None);
+
+    let notes = player.notes();
+    assert_eq!(
+      notes[0..4],
+      [
+        (60, true, 0.5),
+        (64, true, 0.5),
+        (60, false, 1.0),
+        (64, false, 1.0)
+      ]
+    );
+    // a clip starts every bar from the beat of the launch, never the same one twice in a row
+    let keys: Vec<(u8, f64)> = notes[4..]
+      .iter()
+      .filter(|(_, on, _)| *on)
+      .map(|(key, _, time)| (*key, *time))
+      .collect();
+    assert_eq!(keys.len(), 20);
+    assert_eq!(keys[0], (72, 1.5));
+    for (index, pair) in keys.windows(2).enumerate() {
+      assert_ne!(pair[0].0, pair[1].0);
+      assert_eq!(pair[1].1, 3.5 + 2.0 * index as f64);
+    }
+    assert!([60, 72, 74, 76]
+      .iter()
+      .all(|key| keys.iter().any(|(played, _)| played == key)));
+  }
+
+  #[test]
+  pub fn keep_playing_when_the_loop_wraps() {
+    let mut song = song_with_tracks(1);
+    let clip = add_clip(&mut song, 0, 10, 1, &[(60, 0)]);
+    song.get_session_mut().set_slot(0, 0, Some(Slot::new(clip)));
+
+    let mut player = Player::new();
+    player.transport.set_loop_start(BarsTime::from_bars(0));
+    player.transport.set_loop_end(BarsTime::from_bars(2));
+    player.transport.set_loop_enabled(true);
+    player.play_until(&mut song, 0.5);
+    song.get_session_mut().launch_slot(0, 0);
+    // the launch waits for the second bar, and the loop goes back to the first one after it
+    player.play_until(&mut song, 9.0);
+    assert_eq!(song.get_session().get_playing(0), Some(0));
+
+    assert_eq!(
+      player.notes(),
+      vec![
+        (60, true, 2.0),
+        (60, false, 2.5),
+        (60, true, 4.0),
+        (60, false, 4.5),
+        (60, true, 6.0),
+        (60, false, 6.5),
+        (60, true, 8.0),
+        (60, false, 8.5),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn ignore_the_scenes_and_slots_out_of_the_grid() {
+    let mut song = song_with_tracks(1);
+    let clip = add_clip(&mut song, 0, 10, 1, &[(60, 0)]);
+    song.set_slot(0, MAX_SCENES - 1, Some(Slot::new(clip)));
+    song.set_slot(0, MAX_SCENES, Some(Slot::new(clip)));
+    song.set_slot(1, 0, Some(Slot::new(clip)));
+
+    let session = song.get_session();
+    assert!(session.get_slot(0, MAX_SCENES - 1).is_some());
+    assert!(session.get_slot(0, MAX_SCENES).is_none());
+    assert!(session.get_slot(1, 0).is_none());
+    song.get_session_mut().launch_scene(0);
+    assert!(song.get_session().get_queued(1).is_none());
+
+    let session = song.get_session_mut();
+    for index in 0..MAX_SCENES {
+      assert_eq!(session.add_scene(Scene::new("scene")), Some(index));
+    }
+    assert_eq!(session.add_scene(Scene::new("scene")), None);
+  }
+
+  #[test]
+  pub fn follow_the_long_clips_without_overflow() {
+    let mut song = song_with_tracks(1);
+    let clip = Clip {
+      uuid: 0,
+      name: "long".to_string(),
+      signature: Signature::new(4, 4),
+      start: TicksTime::zero(),
+      length: TicksTime::new(u64::MAX / 2),
+    };
+    let clip = song
+      .add_notes_clip(0, clip, NotesSourceClip::new())
+      .unwrap();
+    let slot: Slot = toml::from_str(&format!("clip = {}\nfollow_action = \"Next\"", clip)).unwrap();
+    assert_eq!(slot.follow_loops, 1);
+    let slot = Slot {
+      follow_loops: 1000,
+      ..slot
+    };
+    song.set_slot(0, 0, Some(slot));
+
+    let mut player = Player::new();
+    song.get_session_mut().launch_slot(0, 0);
+    player.play_until(&mut song, 3.0);
+    assert_eq!(song.get_session().get_playing(0), Some(0));
+  }
+}
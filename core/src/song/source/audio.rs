@@ -1 +1,71 @@
-pub struct AudioDataSource;
+use std::collections::HashMap;
+
+use crate::midi::types::U7;
+use crate::song::clips::ClipId;
+use crate::time::SampleRate;
+
+/// Mono audio data, played at its sample rate for the root key
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+  pub name: String,
+  pub sample_rate: SampleRate,
+  pub data: Vec<f32>,
+  pub root_key: U7,
+  /// Tuning in semitones, added to the pitch of the root key
+  pub fine_tune: f64,
+  pub volume: f64,
+  /// Start and length of the part that repeats while the note plays
+  pub loop_range: Option<(usize, usize)>,
+}
+
+impl Sample {
+  pub fn new<T>(name: T, sample_rate: SampleRate, data: Vec<f32>) -> Sample
+  where
+    T: Into<String>,
+  {
+    Sample {
+      name: name.into(),
+      sample_rate,
+      data,
+      root_key: 60,
+      fine_tune: 0.0,
+      volume: 1.0,
+      loop_range: None,
+    }
+  }
+
+  /// Speed to play the data at for a key, 1 being its own sample rate
+  pub fn playback_rate(&self, key: U7) -> f64 {
+    let semitones = f64::from(key) - f64::from(self.root_key) + self.fine_tune;
+    2f64.powf(semitones / 12.0)
+  }
+}
+
+/// The samples of the clips and instruments, shared by the tracks playing them
+#[derive(Default)]
+pub struct AudioDataSource {
+  samples: HashMap<ClipId, Sample>,
+}
+
+impl AudioDataSource {
+  pub fn new() -> AudioDataSource {
+    AudioDataSource::default()
+  }
+
+  /// An id that no sample of the source uses
+  pub fn next_free_id(&self) -> ClipId {
+    self.samples.keys().max().map_or(1, |id| id + 1)
+  }
+
+  pub fn add_sample(&mut self, id: ClipId, sample: Sample) {
+    self.samples.insert(id, sample);
+  }
+
+  pub fn remove_sample(&mut self, id: ClipId) -> Option<Sample> {
+    self.samples.remove(&id)
+  }
+
+  pub fn get_sample(&self, id: ClipId) -> Option<&Sample> {
+    self.samples.get(&id)
+  }
+}
@@ -1,6 +1,9 @@
 use std::collections::{BTreeMap, HashMap};
 
-use crate::{song::clips::ClipId, time::TicksTime};
+use crate::{
+  song::{clips::ClipId, groove::Groove},
+  time::TicksTime,
+};
 
 type Key = u8;
 
@@ -12,6 +15,33 @@ pub struct Note {
   length: TicksTime,
 }
 
+impl Note {
+  pub fn new(key: Key, velocity: f64, start: TicksTime, length: TicksTime) -> Note {
+    Note {
+      key,
+      velocity,
+      start,
+      length,
+    }
+  }
+
+  pub fn get_key(&self) -> Key {
+    self.key
+  }
+
+  pub fn get_velocity(&self) -> f64 {
+    self.velocity
+  }
+
+  pub fn get_start(&self) -> TicksTime {
+    self.start
+  }
+
+  pub fn get_length(&self) -> TicksTime {
+    self.length
+  }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum NoteEvent {
   NoteStart {
@@ -29,14 +59,26 @@ pub enum NoteEvent {
 
 type NoteEvents = Vec<NoteEvent>;
 
+/// Changes of the sound of a channel besides the notes
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ControlEvent {
+  Controller { controller: u8, value: u8 },
+  Program { value: u8 },
+  PitchBend { value: u16 },
+}
+
 pub struct NotesClip {
   events: BTreeMap<TicksTime, NoteEvents>,
+  controls: BTreeMap<TicksTime, Vec<ControlEvent>>,
+  groove: Option<Groove>,
 }
 
 impl Default for NotesClip {
   fn default() -> Self {
     NotesClip {
       events: BTreeMap::new(),
+      controls: BTreeMap::new(),
+      groove: None,
     }
   }
 }
@@ -109,6 +151,108 @@ impl NotesClip {
       .flat_map(|(_tick, tick_events)| tick_events.iter().map(move |event| event))
   }
 
+  pub fn add_control(&mut self, position: TicksTime, event: ControlEvent) -> &mut Self {
+    self
+      .controls
+      .entry(position)
+      .and_modify(|events| events.push(event))
+      .or_insert_with(|| vec![event]);
+    self
+  }
+
+  pub fn remove_control(&mut self, position: TicksTime, event: ControlEvent) -> &mut Self {
+    if let Some(events) = self.controls.get_mut(&position) {
+      if let Some(index) = events.iter().position(|prev_event| *prev_event == event) {
+        events.remove(index);
+        if events.is_empty() {
+          self.controls.remove(&position);
+        }
+      }
+    }
+    self
+  }
+
+  /// Control events over a range of ticks, in the order they were added for the same position.
+  /// The groove is not applied to them.
+  pub fn controls_range<'a>(
+    &'a self,
+    start: TicksTime,
+    end: TicksTime,
+  ) -> impl Iterator<Item = (TicksTime, ControlEvent)> + 'a {
+    self
+      .controls
+      .range(start..end)
+      .flat_map(|(position, events)| events.iter().map(move |event| (*position, *event)))
+  }
+
+  /// The groove is applied to the events when playing, without changing the notes
+  pub fn set_groove(&mut self, groove: Option<Groove>) -> &mut Self {
+    self.groove = groove;
+    self
+  }
+
+  pub fn get_groove(&self) -> Option<&Groove> {
+    self.groove.as_ref()
+  }
+
+  /// Events to play over a range of ticks, with the groove applied to their positions and velocities
+  pub fn play_events_range<'a>(
+    &'a self,
+    start: TicksTime,
+    end: TicksTime,
+  ) -> impl Iterator<Item = (TicksTime, NoteEvent)> + 'a {
+    let (range_start, range_end) = match &self.groove {
+      Some(groove) => (start - groove.max_offset(), end + groove.max_offset()),
+      None => (start, end),
+    };
+    self
+      .events
+      .range(range_start..range_end)
+      .flat_map(move |(tick, tick_events)| {
+        tick_events
+          .iter()
+          .map(move |event| self.apply_groove(*tick, *event))
+      })
+      .filter(move |(tick, _event)| start <= *tick && *tick < end)
+  }
+
+  /// Quantize the notes to the groove, see [`Groove::quantize`]
+  pub fn quantize(&mut self, groove: &Groove, strength: f64) -> &mut Self {
+    let notes: Vec<Note> = self
+      .notes_range(TicksTime::zero(), TicksTime::new(u64::MAX))
+      .collect();
+    self.events.clear();
+    for note in notes.iter() {
+      self.add_note(groove.quantize(note, strength));
+    }
+    self
+  }
+
+  fn apply_groove(&self, tick: TicksTime, event: NoteEvent) -> (TicksTime, NoteEvent) {
+    match &self.groove {
+      Some(groove) => {
+        let event = match event {
+          NoteEvent::NoteStart { key, velocity, end } => NoteEvent::NoteStart {
+            key,
+            velocity: (velocity * groove.velocity_at(tick)).min(1.0),
+            end: groove.apply(end),
+          },
+          NoteEvent::NoteEnd {
+            key,
+            velocity,
+            start,
+          } => NoteEvent::NoteEnd {
+            key,
+            velocity: (velocity * groove.velocity_at(start)).min(1.0),
+            start: groove.apply(start),
+          },
+        };
+        (groove.apply(tick), event)
+      }
+      None => (tick, event),
+    }
+  }
+
   fn split_note_into_events(&self, note: &Note) -> (NoteEvent, NoteEvent, TicksTime) {
     let note_end = note.start + note.length;
 
@@ -150,14 +294,44 @@ impl NotesClip {
   }
 }
 
+/// The notes of the clips, shared by the tracks playing them
+#[derive(Default)]
 pub struct NotesSource {
   clips: HashMap<ClipId, NotesClip>,
 }
 
+impl NotesSource {
+  pub fn new() -> NotesSource {
+    NotesSource::default()
+  }
+
+  /// An id that no clip of the source uses
+  pub fn next_free_id(&self) -> ClipId {
+    self.clips.keys().max().map_or(1, |id| id + 1)
+  }
+
+  pub fn add_clip(&mut self, id: ClipId, clip: NotesClip) {
+    self.clips.insert(id, clip);
+  }
+
+  pub fn remove_clip(&mut self, id: ClipId) -> Option<NotesClip> {
+    self.clips.remove(&id)
+  }
+
+  pub fn get_clip(&self, id: ClipId) -> Option<&NotesClip> {
+    self.clips.get(&id)
+  }
+
+  pub fn get_clip_mut(&mut self, id: ClipId) -> Option<&mut NotesClip> {
+    self.clips.get_mut(&id)
+  }
+}
+
 #[cfg(test)]
 mod test {
 
-  use super::{BTreeMap, Note, NoteEvent, NoteEvents, NotesClip, TicksTime};
+  use super::{BTreeMap, Groove, Note, NoteEvent, NoteEvents, NotesClip, TicksTime};
+  use crate::song::groove::GrooveStep;
 
   #[test]
   /// NotesClip should add notes as events and allow repeated notes
@@ -389,4 +563,79 @@ mod test {
       .collect();
     assert_eq!(range_result, expected_events)
   }
+
+  #[test]
+  /// NotesClip should play the events with the groove applied, keeping the notes unchanged
+  pub fn notes_clip_play_events_range_with_groove() {
+    let mut clip = NotesClip::new();
+    let note1 = Note::new(36, 1.0, TicksTime::new(0), TicksTime::new(10));
+    let note2 = Note::new(38, 1.0, TicksTime::new(10), TicksTime::new(10));
+    clip.add_notes(vec![note1, note2]);
+    let groove = Groove::new(
+      TicksTime::new(10),
+      vec![GrooveStep::straight(), GrooveStep::new(4, 0.5)],
+    );
+    clip.set_groove(Some(groove));
+
+    let range_result: Vec<(TicksTime, NoteEvent)> = clip
+      .play_events_range(TicksTime::new(11), TicksTime::new(25))
+      .collect();
+    assert_eq!(
+      range_result,
+      vec![
+        (
+          TicksTime::new(14),
+          NoteEvent::NoteEnd {
+            key: 36,
+            velocity: 1.0,
+            start: TicksTime::new(0),
+          }
+        ),
+        (
+          TicksTime::new(14),
+          NoteEvent::NoteStart {
+            key: 38,
+            velocity: 0.5,
+            end: TicksTime::new(20),
+          }
+        ),
+        (
+          TicksTime::new(20),
+          NoteEvent::NoteEnd {
+            key: 38,
+            velocity: 0.5,
+            start: TicksTime::new(14),
+          }
+        ),
+      ]
+    );
+
+    let notes: Vec<Note> = clip
+      .notes_range(TicksTime::new(0), TicksTime::new(30))
+      .collect();
+    assert_eq!(notes, vec![note1, note2]);
+  }
+
+  #[test]
+  /// NotesClip should move the notes when quantizing
+  pub fn notes_clip_quantize() {
+    let mut clip = NotesClip::new();
+    clip.add_notes(vec![
+      Note::new(36, 1.0, TicksTime::new(1), TicksTime::new(5)),
+      Note::new(38, 1.0, TicksTime::new(12), TicksTime::new(5)),
+    ]);
+
+    clip.quantize(&Groove::straight(TicksTime::new(10)), 1.0);
+
+    let notes: Vec<Note> = clip
+      .notes_range(TicksTime::new(0), TicksTime::new(30))
+      .collect();
+    assert_eq!(
+      notes,
+      vec![
+        Note::new(36, 1.0, TicksTime::new(0), TicksTime::new(5)),
+        Note::new(38, 1.0, TicksTime::new(10), TicksTime::new(5)),
+      ]
+    );
+  }
 }
@@ -1,13 +1,310 @@
 use std::collections::BTreeMap;
 
+use crate::audio::AudioOutput;
+use crate::config::Chase as ChaseConfig;
+use crate::midi::buffer::Endpoint;
 use crate::song::{
-  clips::{pianoroll::NotesClip, ClipIndex},
+  clips::{
+    drumbox::DrumClip, pianoroll::NotesClip, stepper::StepsClip, tracker::TrackerClip, Clip,
+    ClipIndex,
+  },
   io::{AudioSink, NotesSource},
+  sampler::Sampler,
+  track::midi::MidiTrack,
 };
+use crate::time::{ClockTime, SampleRate, TicksTime};
+use crate::transport::Segment;
 
 pub struct InstrumentTrack {
   source: NotesSource,
   sink: AudioSink,
 
-  clips: BTreeMap<ClipIndex, NotesClip>,
+  /// Plays the clips like a MIDI track, but into the sampler
+  player: MidiTrack,
+
+  sampler: Option<Sampler>,
+}
+
+impl Default for InstrumentTrack {
+  fn default() -> Self {
+    InstrumentTrack {
+      source: NotesSource,
+      sink: AudioSink,
+      player: MidiTrack::new(Endpoint::None, 0),
+      sampler: None,
+    }
+  }
+}
+
+impl InstrumentTrack {
+  pub fn new() -> InstrumentTrack {
+    InstrumentTrack::default()
+  }
+
+  /// Notes for the clip of the track at an index
+  pub fn set_clip(&mut self, index: ClipIndex, clip: NotesClip) {
+    self.player.set_clip(index, clip);
+  }
+
+  pub fn remove_clip(&mut self, index: ClipIndex) -> Option<NotesClip> {
+    self.player.remove_clip(index)
+  }
+
+  pub fn get_clip(&self, index: ClipIndex) -> Option<&NotesClip> {
+    self.player.get_clip(index)
+  }
+
+  pub(super) fn get_clips_mut(&mut self) -> &mut BTreeMap<ClipIndex, NotesClip> {
+    self.player.get_clips_mut()
+  }
+
+  /// Steps for the clip of the track at an index
+  pub fn set_steps_clip(&mut self, index: ClipIndex, clip: StepsClip) {
+    self.player.set_steps_clip(index, clip);
+  }
+
+  pub fn remove_steps_clip(&mut self, index: ClipIndex) -> Option<StepsClip> {
+    self.player.remove_steps_clip(index)
+  }
+
+  pub fn get_steps_clip(&self, index: ClipIndex) -> Option<&StepsClip> {
+    self.player.get_steps_clip(index)
+  }
+
+  pub fn get_steps_clip_mut(&mut self, index: ClipIndex) -> Option<&mut StepsClip> {
+    self.player.get_steps_clip_mut(index)
+  }
+
+  /// Patterns for the clip of the track at an index
+  pub fn set_drum_clip(&mut self, index: ClipIndex, clip: DrumClip) {
+    self.player.set_drum_clip(index, clip);
+  }
+
+  pub fn remove_drum_clip(&mut self, index: ClipIndex) -> Option<DrumClip> {
+    self.player.remove_drum_clip(index)
+  }
+
+  pub fn get_drum_clip(&self, index: ClipIndex) -> Option<&DrumClip> {
+    self.player.get_drum_clip(index)
+  }
+
+  pub fn get_drum_clip_mut(&mut self, index: ClipIndex) -> Option<&mut DrumClip> {
+    self.player.get_drum_clip_mut(index)
+  }
+
+  /// Tracker song for the clip of the track at an index
+  pub fn set_tracker_clip(&mut self, index: ClipIndex, clip: TrackerClip) {
+    self.player.set_tracker_clip(index, clip);
+  }
+
+  pub fn remove_tracker_clip(&mut self, index: ClipIndex) -> Option<TrackerClip> {
+    self.player.remove_tracker_clip(index)
+  }
+
+  pub fn get_tracker_clip(&self, index: ClipIndex) -> Option<&TrackerClip> {
+    self.player.get_tracker_clip(index)
+  }
+
+  pub fn get_tracker_clip_mut(&mut self, index: ClipIndex) -> Option<&mut TrackerClip> {
+    self.player.get_tracker_clip_mut(index)
+  }
+
+  /// Sampler that plays the notes of the clips
+  pub fn set_sampler(&mut self, sampler: Option<Sampler>) {
+    self.sampler = sampler;
+  }
+
+  pub fn get_sampler(&self) -> Option<&Sampler> {
+    self.sampler.as_ref()
+  }
+
+  pub fn get_sampler_mut(&mut self) -> Option<&mut Sampler> {
+    self.sampler.as_mut()
+  }
+
+  /// Play the notes of the clips in the segment range with the sampler, a track without one stays silent
+  pub fn process_segment(&mut self, clips: &[Clip], segment: &Segment, chase: &ChaseConfig) {
+    if let Some(sampler) = self.sampler.as_mut() {
+      self.player.process_segment(clips, segment, chase, sampler);
+    }
+  }
+
+  /// Play one of the clips looping from where it was launched with the sampler
+  pub fn process_looped_clip(
+    &mut self,
+    index: ClipIndex,
+    clip: &Clip,
+    offset: TicksTime,
+    segment: &Segment,
+  ) {
+    if let Some(sampler) = self.sampler.as_mut() {
+      self
+        .player
+        .process_looped_clip(index, clip, offset, segment, sampler);
+    }
+  }
+
+  /// Release the notes still playing in the sampler
+  pub fn stop(&mut self, time: ClockTime) {
+    if let Some(sampler) = self.sampler.as_mut() {
+      self.player.stop(time, sampler);
+    }
+  }
+
+  /// Forget the notes still playing and silence the sampler
+  pub fn forget_notes(&mut self) {
+    self.player.forget_notes();
+    if let Some(sampler) = self.sampler.as_mut() {
+      sampler.silence();
+    }
+  }
+
+  /// Add the sound of the sampler to the output.
+  /// It has to be called for every buffer, even when stopped, so the released notes can finish.
+  pub fn process_audio(
+    &mut self,
+    audio_output: &mut AudioOutput,
+    frames: usize,
+    sample_rate: SampleRate,
+  ) {
+    if let Some(sampler) = self.sampler.as_mut() {
+      sampler.render(audio_output, frames, sample_rate);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::InstrumentTrack;
+  use crate::audio::AudioOutput;
+  use crate::color::Color;
+  use crate::config::Chase as ChaseConfig;
+  use crate::song::clips::{
+    drumbox::{Hit, Kit, KitInstrument, Pattern},
+    stepper::{step_length, Lane, Step},
+    tracker::{Cell, Tracker, TrackerClip, TrackerInstrument, TrackerPattern},
+  };
+  use crate::song::track::{Track, TrackMedia};
+  use crate::testing::{clip, tone_sampler, VecMidiOutput};
+  use crate::time::ClockTime;
+  use crate::transport::Transport;
+
+  const SAMPLE_RATE: u32 = 44100;
+
+  /// Track with a sampler that plays a constant sample for all the keys of the first channel
+  fn track() -> Track {
+    let mut instrument_track = InstrumentTrack::new();
+    instrument_track.set_sampler(Some(tone_sampler(SAMPLE_RATE)));
+    Track::new(
+      "instrument",
+      Color::new("orange".into()),
+      TrackMedia::Instrument(instrument_track),
+    )
+  }
+
+  /// The mono output of the track for the first two seconds, playing from the start
+  fn play_track(mut track: Track) -> Vec<f32> {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.play(false);
+
+    let chase = ChaseConfig::default();
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let frames = 512;
+    let mut output = Vec::new();
+    let mut master_clock = ClockTime::zero();
+    while master_clock < ClockTime::from_seconds(2.0) {
+      let mut segments = transport.segments_iterator(master_clock, frames as u32);
+      while let Some(segment) = segments.next(&transport) {
+        track.process_segment(&segment, &chase, &mut midi_output);
+      }
+      transport.update_from_segments(&segments);
+      let mut buffer = vec![0.0; frames];
+      let mut audio_output = AudioOutput::new(master_clock, 1, &mut buffer);
+      track.process_audio(&mut audio_output, frames, SAMPLE_RATE);
+      output.extend(buffer);
+      master_clock += ClockTime::from_samples(frames as u32, SAMPLE_RATE);
+    }
+
+    assert!(midi_output.0.is_empty());
+    output
+  }
+
+  /// Whether the output sounds at some seconds
+  fn sounds(output: &[f32], seconds: f64) -> bool {
+    output[(seconds * f64::from(SAMPLE_RATE)) as usize] != 0.0
+  }
+
+  #[test]
+  pub fn play_the_steps_clips() {
+    let mut lane = Lane::new(60, step_length(4, 1, 1), 4);
+    lane.steps[1] = Step::on(1.0);
+    let mut track = track();
+    assert_eq!(track.add_steps_clip(clip(0, 0, 1), vec![lane]), Some(0));
+
+    let output = play_track(track);
+    assert!(!sounds(&output, 0.49));
+    assert!(sounds(&output, 0.51));
+    assert_eq!(output[(0.6 * f64::from(SAMPLE_RATE)) as usize], 1.0);
+    assert!(sounds(&output, 0.74));
+    assert!(!sounds(&output, 0.76));
+  }
+
+  #[test]
+  pub fn play_the_drum_clips() {
+    let kit = Kit::new("kit", vec![KitInstrument::new("Tone", 60)]);
+    let mut pattern = Pattern::new("A", 4, step_length(4, 1, 1));
+    pattern.set_hit(0, 3, Some(Hit::new(1.0)));
+    let mut track = track();
+    assert_eq!(
+      track.add_drum_clip(clip(0, 0, 1), kit, vec![pattern]),
+      Some(0)
+    );
+
+    let output = play_track(track);
+    assert!(!sounds(&output, 1.49));
+    assert!(sounds(&output, 1.51));
+  }
+
+  #[test]
+  pub fn play_the_tracker_clips() {
+    let mut pattern = TrackerPattern::new("A", 16, 1);
+    pattern.set_cell(4, 0, Cell::note(60, 1));
+    let tracker = Tracker {
+      instruments: vec![TrackerInstrument::new("tone", Some(0))],
+      patterns: vec![pattern],
+      sequence: vec![0],
+      ..Tracker::new()
+    };
+    let mut track = track();
+    assert_eq!(
+      track.add_tracker_clip(TrackerClip::new(clip(0, 0, 1), tracker)),
+      Some(0)
+    );
+
+    let output = play_track(track);
+    assert!(!sounds(&output, 0.49));
+    assert!(sounds(&output, 0.51));
+  }
+
+  #[test]
+  pub fn replace_the_tracker_clips() {
+    let mut pattern = TrackerPattern::new("A", 16, 1);
+    pattern.set_cell(4, 0, Cell::note(60, 1));
+    let tracker = Tracker {
+      instruments: vec![TrackerInstrument::new("tone", Some(0))],
+      patterns: vec![pattern],
+      sequence: vec![0],
+      ..Tracker::new()
+    };
+    let mut track = track();
+    let mut tracker_clip = TrackerClip::new(clip(0, 0, 1), tracker);
+    track.add_tracker_clip(tracker_clip.clone());
+    tracker_clip.set_cell(0, 4, 0, Cell::default());
+    assert!(track.replace_tracker_clip(tracker_clip));
+
+    let output = play_track(track);
+    assert!(!sounds(&output, 0.51));
+  }
 }
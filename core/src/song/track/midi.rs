@@ -1,15 +1,998 @@
 use std::collections::BTreeMap;
 
+use crate::config::Chase as ChaseConfig;
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::io::MidiOutput;
+use crate::midi::types::{U14, U4, U7};
+use crate::midi::Message;
 use crate::song::{
-  clips::{pianoroll::NotesClip, ClipIndex},
+  clips::{
+    drumbox::DrumClip, pianoroll::NotesClip, stepper::StepsClip, tracker::TrackerClip,
+    tracker::TrackerEvent, Clip, ClipIndex,
+  },
   io::{NotesSink, NotesSource},
+  source::notes::{ControlEvent, NoteEvent},
+  track::clips_in_range,
 };
+use crate::time::{ClockTime, TicksTime};
+use crate::transport::Segment;
+
+const MAX_ACTIVE_NOTES: usize = 256;
+
+/// The note off goes to the endpoint and channel of the note on, even if the track changed them
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ActiveNote {
+  clip: ClipIndex,
+  key: U7,
+  endpoint: Endpoint,
+  channel: U4,
+}
 
 pub struct MidiTrack {
   source: NotesSource,
   sink: NotesSink,
 
+  endpoint: Endpoint,
+  channel: U4,
+
   clips: BTreeMap<ClipIndex, NotesClip>,
+  steps_clips: BTreeMap<ClipIndex, StepsClip>,
+  drum_clips: BTreeMap<ClipIndex, DrumClip>,
+  tracker_clips: BTreeMap<ClipIndex, TrackerClip>,
+
+  active_notes: Vec<ActiveNote>,
+  next_position: Option<TicksTime>,
+}
+
+impl MidiTrack {
+  pub fn new(endpoint: Endpoint, channel: U4) -> MidiTrack {
+    MidiTrack {
+      source: NotesSource,
+      sink: NotesSink,
+      endpoint,
+      channel: channel & 0x0f,
+      clips: BTreeMap::new(),
+      steps_clips: BTreeMap::new(),
+      drum_clips: BTreeMap::new(),
+      tracker_clips: BTreeMap::new(),
+      active_notes: Vec::with_capacity(MAX_ACTIVE_NOTES),
+      next_position: None,
+    }
+  }
+
+  pub fn set_endpoint(&mut self, endpoint: Endpoint) {
+    self.endpoint = endpoint;
+  }
+
+  pub fn get_endpoint(&self) -> Endpoint {
+    self.endpoint
+  }
+
+  pub fn set_channel(&mut self, channel: U4) {
+    self.channel = channel & 0x0f;
+  }
+
+  pub fn get_channel(&self) -> U4 {
+    self.channel
+  }
+
+  /// Notes for the clip of the track at an index
+  pub fn set_clip(&mut self, index: ClipIndex, clip: NotesClip) {
+    self.clips.insert(index, clip);
+  }
+
+  pub fn remove_clip(&mut self, index: ClipIndex) -> Option<NotesClip> {
+    self.clips.remove(&index)
+  }
+
+  pub fn get_clip(&self, index: ClipIndex) -> Option<&NotesClip> {
+    self.clips.get(&index)
+  }
+
+  pub(super) fn get_clips_mut(&mut self) -> &mut BTreeMap<ClipIndex, NotesClip> {
+    &mut self.clips
+  }
+
+  /// Steps for the clip of the track at an index
+  pub fn set_steps_clip(&mut self, index: ClipIndex, clip: StepsClip) {
+    self.steps_clips.insert(index, clip);
+  }
+
+  pub fn remove_steps_clip(&mut self, index: ClipIndex) -> Option<StepsClip> {
+    self.steps_clips.remove(&index)
+  }
+
+  pub fn get_steps_clip(&self, index: ClipIndex) -> Option<&StepsClip> {
+    self.steps_clips.get(&index)
+  }
+
+  pub fn get_steps_clip_mut(&mut self, index: ClipIndex) -> Option<&mut StepsClip> {
+    self.steps_clips.get_mut(&index)
+  }
+
+  /// Patterns for the clip of the track at an index
+  pub fn set_drum_clip(&mut self, index: ClipIndex, clip: DrumClip) {
+    self.drum_clips.insert(index, clip);
+  }
+
+  pub fn remove_drum_clip(&mut self, index: ClipIndex) -> Option<DrumClip> {
+    self.drum_clips.remove(&index)
+  }
+
+  pub fn get_drum_clip(&self, index: ClipIndex) -> Option<&DrumClip> {
+    self.drum_clips.get(&index)
+  }
+
+  pub fn get_drum_clip_mut(&mut self, index: ClipIndex) -> Option<&mut DrumClip> {
+    self.drum_clips.get_mut(&index)
+  }
+
+  /// Tracker song for the clip of the track at an index
+  pub fn set_tracker_clip(&mut self, index: ClipIndex, clip: TrackerClip) {
+    self.tracker_clips.insert(index, clip);
+  }
+
+  pub fn remove_tracker_clip(&mut self, index: ClipIndex) -> Option<TrackerClip> {
+    self.tracker_clips.remove(&index)
+  }
+
+  pub fn get_tracker_clip(&self, index: ClipIndex) -> Option<&TrackerClip> {
+    self.tracker_clips.get(&index)
+  }
+
+  pub fn get_tracker_clip_mut(&mut self, index: ClipIndex) -> Option<&mut TrackerClip> {
+    self.tracker_clips.get_mut(&index)
+  }
+
+  /// Send the notes and controls of the clips in the segment range.
+  /// The notes still playing are released at the end of their clip, and when the segment doesn't follow
+  /// the previous one, like when the loop wraps or the song is located, as their end would never be reached.
+  /// Then the notes and controls from before the segment are chased, except for the notes of the steps, drum and tracker clips.
+  pub fn process_segment<MidiOut>(
+    &mut self,
+    clips: &[Clip],
+    segment: &Segment,
+    chase: &ChaseConfig,
+    midi_output: &mut MidiOut,
+  ) where
+    MidiOut: MidiOutput,
+  {
+    if self.next_position != Some(segment.start_position) {
+      self.release_notes(segment.master_clock, midi_output, |_| true);
+      if chase.controls {
+        self.chase_controls(clips, segment, midi_output);
+      }
+      if chase.notes {
+        self.chase_notes(clips, segment, midi_output);
+      }
+    }
+    self.next_position = Some(segment.end_position);
+
+    for (index, clip) in clips_in_range(clips, segment.start_position, segment.end_position) {
+      let clip_end = clip.start + clip.length;
+      let start = segment.start_position.max(clip.start) - clip.start;
+      let end = segment.end_position.min(clip_end) - clip.start;
+      self.play_clip(index, clip.start + start, start, end, segment, midi_output);
+
+      if segment.start_position <= clip_end && clip_end < segment.end_position {
+        let time = segment.master_clock_at(clip_end);
+        self.release_notes(time, midi_output, |note_index| note_index == index);
+      }
+    }
+  }
+
+  /// Send the notes and controls of a clip that loops from where it was launched, like from the session,
+  /// instead of following its place in the arrangement. The offset is how much of the loops was played
+  /// before the segment, so the clip keeps going wherever the song position goes.
+  /// Its notes are released at the end of every loop, and when the segment doesn't follow the previous one.
+  pub fn process_looped_clip<MidiOut>(
+    &mut self,
+    index: ClipIndex,
+    clip: &Clip,
+    offset: TicksTime,
+    segment: &Segment,
+    midi_output: &mut MidiOut,
+  ) where
+    MidiOut: MidiOutput,
+  {
+    if self.next_position != Some(segment.start_position) {
+      self.release_notes(segment.master_clock, midi_output, |_| true);
+    }
+    self.next_position = Some(segment.end_position);
+    let length = u64::from(clip.length);
+    let from = u64::from(offset);
+    let until = from + u64::from(segment.end_position - segment.start_position);
+    if length == 0 || until == from {
+      return;
+    }
+    for number in from / length..=(until - 1) / length {
+      let loop_start = number * length;
+      let loop_end = loop_start + length;
+      let start = TicksTime::new(from.max(loop_start) - loop_start);
+      let end = TicksTime::new(until.min(loop_end) - loop_start);
+      let position = segment.start_position + TicksTime::new(from.max(loop_start) - from);
+      self.play_clip(index, position, start, end, segment, midi_output);
+
+      if loop_end < until {
+        let time =
+          segment.master_clock_at(segment.start_position + TicksTime::new(loop_end - from));
+        self.release_notes(time, midi_output, |note_index| note_index == index);
+      }
+    }
+  }
+
+  /// Send the notes and controls of a clip over a range of ticks from its start,
+  /// played from a song position
+  fn play_clip<MidiOut>(
+    &mut self,
+    index: ClipIndex,
+    range_position: TicksTime,
+    start: TicksTime,
+    end: TicksTime,
+    segment: &Segment,
+    midi_output: &mut MidiOut,
+  ) where
+    MidiOut: MidiOutput,
+  {
+    let endpoint = self.endpoint;
+    let channel = self.channel;
+    if let Some(notes_clip) = self.clips.get(&index) {
+      let notes = notes_clip.get_notes();
+      // never wait for the lock in the audio thread, the clip is skipped while it is being edited
+      if let Ok(source) = notes.get_source().try_read() {
+        if let Some(notes_source) = source.get_clip(notes.get_id()) {
+          for (position, event) in notes_source.controls_range(start, end) {
+            let time = segment.master_clock_at(range_position + position - start);
+            push_control(midi_output, endpoint, time, channel, event);
+          }
+          for (position, event) in notes_source.play_events_range(start, end) {
+            let time = segment.master_clock_at(range_position + position - start);
+            let active_notes = &mut self.active_notes;
+            play_note_event(
+              active_notes,
+              index,
+              endpoint,
+              channel,
+              event,
+              time,
+              midi_output,
+            );
+          }
+        }
+      }
+    }
+    if let Some(steps_clip) = self.steps_clips.get(&index) {
+      for (position, event) in steps_clip.play_events_range(start, end) {
+        let time = segment.master_clock_at(range_position + position - start);
+        let active_notes = &mut self.active_notes;
+        play_note_event(
+          active_notes,
+          index,
+          endpoint,
+          channel,
+          event,
+          time,
+          midi_output,
+        );
+      }
+    }
+    if let Some(drum_clip) = self.drum_clips.get(&index) {
+      for (position, instrument_channel, event) in drum_clip.play_events_range(start, end) {
+        let time = segment.master_clock_at(range_position + position - start);
+        let channel = instrument_channel.map_or(channel, |channel| channel & 0x0f);
+        let active_notes = &mut self.active_notes;
+        play_note_event(
+          active_notes,
+          index,
+          endpoint,
+          channel,
+          event,
+          time,
+          midi_output,
+        );
+      }
+    }
+    if let Some(tracker_clip) = self.tracker_clips.get(&index) {
+      for (position, instrument_channel, event) in tracker_clip.play_events_range(start, end) {
+        let time = segment.master_clock_at(range_position + position - start);
+        let channel = instrument_channel.map_or(channel, |channel| channel & 0x0f);
+        match event {
+          TrackerEvent::Note(event) => {
+            let active_notes = &mut self.active_notes;
+            play_note_event(
+              active_notes,
+              index,
+              endpoint,
+              channel,
+              event,
+              time,
+              midi_output,
+            );
+          }
+          TrackerEvent::Control(event) => {
+            push_control(midi_output, endpoint, time, channel, event);
+          }
+        }
+      }
+    }
+  }
+
+  /// Release the notes still playing
+  pub fn stop<MidiOut>(&mut self, time: ClockTime, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
+    self.release_notes(time, midi_output, |_| true);
+    self.next_position = None;
+  }
+
+  /// Forget the notes still playing, the outputs were silenced
+  pub fn forget_notes(&mut self) {
+    self.active_notes.clear();
+  }
+
+  /// Forget the notes playing on an endpoint that was removed
+  pub fn forget_endpoint(&mut self, endpoint: Endpoint) {
+    self.active_notes.retain(|note| note.endpoint != endpoint);
+  }
+
+  /// Send the last controller, program and pitch bend values of the clips before the segment
+  fn chase_controls<MidiOut>(&self, clips: &[Clip], segment: &Segment, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
+    let position = segment.start_position;
+    let mut controls = ChasedControls::new();
+    for (index, clip) in clips.iter().enumerate() {
+      if clip.start >= position {
+        continue;
+      }
+      if let Some(notes_clip) = self.clips.get(&index) {
+        let notes = notes_clip.get_notes();
+        if let Ok(source) = notes.get_source().try_read() {
+          if let Some(notes_source) = source.get_clip(notes.get_id()) {
+            let end = (position - clip.start).min(clip.length);
+            for (control_position, event) in notes_source.controls_range(TicksTime::zero(), end) {
+              controls.update(clip.start + control_position, event);
+            }
+          }
+        }
+      }
+    }
+    controls.send(
+      segment.master_clock,
+      self.endpoint,
+      self.channel,
+      midi_output,
+    );
+  }
+
+  /// Start the notes of the clips that began before the segment and didn't end yet
+  fn chase_notes<MidiOut>(&mut self, clips: &[Clip], segment: &Segment, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
+    let position = segment.start_position;
+    let endpoint = self.endpoint;
+    let channel = self.channel;
+    for (index, clip) in clips.iter().enumerate() {
+      if clip.start >= position || clip.start + clip.length <= position {
+        continue;
+      }
+      let clip_position = position - clip.start;
+      if let Some(notes_clip) = self.clips.get(&index) {
+        let notes = notes_clip.get_notes();
+        if let Ok(source) = notes.get_source().try_read() {
+          if let Some(notes_source) = source.get_clip(notes.get_id()) {
+            let notes = notes_source
+              .notes_range(clip_position, TicksTime::new(u64::MAX))
+              .filter(|note| note.get_start() < clip_position);
+            for note in notes {
+              let active_note = ActiveNote {
+                clip: index,
+                key: note.get_key(),
+                endpoint,
+                channel,
+              };
+              let velocity = velocity_to_u7(note.get_velocity()).max(1);
+              let time = segment.master_clock;
+              start_note(
+                &mut self.active_notes,
+                active_note,
+                velocity,
+                time,
+                midi_output,
+              );
+            }
+          }
+        }
+      }
+    }
+  }
+
+  fn release_notes<MidiOut, Predicate>(
+    &mut self,
+    time: ClockTime,
+    midi_output: &mut MidiOut,
+    predicate: Predicate,
+  ) where
+    MidiOut: MidiOutput,
+    Predicate: Fn(ClipIndex) -> bool,
+  {
+    self.active_notes.retain(|note| {
+      let release = predicate(note.clip);
+      if release {
+        push_note_off(midi_output, note.endpoint, time, note.channel, note.key, 0);
+      }
+      !release
+    });
+  }
 }
 
-impl MidiTrack {}
+/// Last values of the controls before a position
+struct ChasedControls {
+  controllers: [Option<(TicksTime, U7)>; 128],
+  program: Option<(TicksTime, U7)>,
+  pitch_bend: Option<(TicksTime, U14)>,
+}
+
+impl ChasedControls {
+  fn new() -> ChasedControls {
+    ChasedControls {
+      controllers: [None; 128],
+      program: None,
+      pitch_bend: None,
+    }
+  }
+
+  /// Keep the value unless there is a later one, the events at the same position replace the previous ones
+  fn update(&mut self, position: TicksTime, event: ControlEvent) {
+    fn update_value<T>(current: &mut Option<(TicksTime, T)>, position: TicksTime, value: T) {
+      if current
+        .as_ref()
+        .is_none_or(|(prev_position, _)| *prev_position <= position)
+      {
+        *current = Some((position, value));
+      }
+    }
+    match event {
+      ControlEvent::Controller { controller, value } => {
+        let controller = usize::from(controller & 0x7f);
+        update_value(&mut self.controllers[controller], position, value);
+      }
+      ControlEvent::Program { value } => update_value(&mut self.program, position, value),
+      ControlEvent::PitchBend { value } => update_value(&mut self.pitch_bend, position, value),
+    }
+  }
+
+  /// The program goes first, as changing it might reset the controllers
+  fn send<MidiOut>(
+    &self,
+    time: ClockTime,
+    endpoint: Endpoint,
+    channel: U4,
+    midi_output: &mut MidiOut,
+  ) where
+    MidiOut: MidiOutput,
+  {
+    if let Some((_, value)) = self.program {
+      push_control(
+        midi_output,
+        endpoint,
+        time,
+        channel,
+        ControlEvent::Program { value },
+      );
+    }
+    for (controller, chased) in self.controllers.iter().enumerate() {
+      if let Some((_, value)) = chased {
+        let controller = controller as U7;
+        let event = ControlEvent::Controller {
+          controller,
+          value: *value,
+        };
+        push_control(midi_output, endpoint, time, channel, event);
+      }
+    }
+    if let Some((_, value)) = self.pitch_bend {
+      push_control(
+        midi_output,
+        endpoint,
+        time,
+        channel,
+        ControlEvent::PitchBend { value },
+      );
+    }
+  }
+}
+
+/// Start a note of a clip, or end the note of the clip with the same key and channel
+fn play_note_event<MidiOut>(
+  active_notes: &mut Vec<ActiveNote>,
+  clip: ClipIndex,
+  endpoint: Endpoint,
+  channel: U4,
+  event: NoteEvent,
+  time: ClockTime,
+  midi_output: &mut MidiOut,
+) where
+  MidiOut: MidiOutput,
+{
+  match event {
+    NoteEvent::NoteStart { key, velocity, .. } => {
+      let note = ActiveNote {
+        clip,
+        key,
+        endpoint,
+        channel,
+      };
+      let velocity = velocity_to_u7(velocity).max(1);
+      start_note(active_notes, note, velocity, time, midi_output);
+    }
+    NoteEvent::NoteEnd { key, velocity, .. } => {
+      let active = active_notes
+        .iter()
+        .position(|note| note.clip == clip && note.key == key && note.channel == channel);
+      if let Some(active_index) = active {
+        let note = active_notes.swap_remove(active_index);
+        let velocity = velocity_to_u7(velocity);
+        push_note_off(
+          midi_output,
+          note.endpoint,
+          time,
+          note.channel,
+          key,
+          velocity,
+        );
+      }
+    }
+  }
+}
+
+fn start_note<MidiOut>(
+  active_notes: &mut Vec<ActiveNote>,
+  note: ActiveNote,
+  velocity: U7,
+  time: ClockTime,
+  midi_output: &mut MidiOut,
+) where
+  MidiOut: MidiOutput,
+{
+  if active_notes.len() < MAX_ACTIVE_NOTES {
+    active_notes.push(note);
+    push_note_on(
+      midi_output,
+      note.endpoint,
+      time,
+      note.channel,
+      note.key,
+      velocity,
+    );
+  }
+}
+
+fn push_control<MidiOut>(
+  midi_output: &mut MidiOut,
+  endpoint: Endpoint,
+  time: ClockTime,
+  channel: U4,
+  event: ControlEvent,
+) where
+  MidiOut: MidiOutput,
+{
+  let message = match event {
+    ControlEvent::Controller { controller, value } => Message::ControlChange {
+      channel,
+      controller: controller & 0x7f,
+      value: value & 0x7f,
+    },
+    ControlEvent::Program { value } => Message::ProgramChange {
+      channel,
+      value: value & 0x7f,
+    },
+    ControlEvent::PitchBend { value } => Message::PitchBend {
+      channel,
+      value: value & 0x3fff,
+    },
+  };
+  midi_output.push(EventIo::new(time, endpoint, message));
+}
+
+fn velocity_to_u7(velocity: f64) -> U7 {
+  (velocity.clamp(0.0, 1.0) * 127.0).round() as U7
+}
+
+fn push_note_on<MidiOut>(
+  midi_output: &mut MidiOut,
+  endpoint: Endpoint,
+  time: ClockTime,
+  channel: U4,
+  key: U7,
+  velocity: U7,
+) where
+  MidiOut: MidiOutput,
+{
+  midi_output.push(EventIo::new(
+    time,
+    endpoint,
+    Message::NoteOn {
+      channel,
+      key,
+      velocity,
+    },
+  ));
+}
+
+fn push_note_off<MidiOut>(
+  midi_output: &mut MidiOut,
+  endpoint: Endpoint,
+  time: ClockTime,
+  channel: U4,
+  key: U7,
+  velocity: U7,
+) where
+  MidiOut: MidiOutput,
+{
+  midi_output.push(EventIo::new(
+    time,
+    endpoint,
+    Message::NoteOff {
+      channel,
+      key,
+      velocity,
+    },
+  ));
+}
+
+#[cfg(test)]
+mod test {
+
+  use std::sync::{Arc, RwLock};
+
+  use super::{play_note_event, ActiveNote, MidiTrack};
+  use crate::color::Color;
+  use crate::config::Chase as ChaseConfig;
+  use crate::midi::buffer::Endpoint;
+  use crate::midi::Message;
+  use crate::song::clips::{
+    drumbox::{Hit, Kit, KitInstrument, Pattern},
+    pianoroll::{Notes, NotesClip},
+    stepper::{step_length, Lane, Step},
+  };
+  use crate::song::source::notes::{
+    ControlEvent, Note, NoteEvent, NotesClip as NotesSourceClip, NotesSource,
+  };
+  use crate::song::track::{Track, TrackMedia};
+  use crate::testing::{clip, midi_track, VecMidiOutput};
+  use crate::time::{clock, BarsTime, ClockTime, Signature, TicksTime};
+  use crate::transport::Transport;
+
+  const SAMPLE_RATE: u32 = 44100;
+
+  /// Track with a clip at the second bar
+  fn track() -> Track {
+    let signature = Signature::new(4, 4);
+    let ticks =
+      |beats: u16, sixteenths: u16| BarsTime::new(0, beats, sixteenths, 0).to_ticks(signature);
+    let mut notes = NotesSourceClip::new();
+    notes.add_notes(vec![
+      Note::new(60, 1.0, ticks(0, 0), ticks(0, 2)),
+      Note::new(64, 0.5, ticks(3, 0), ticks(2, 0)),
+    ]);
+    notes
+      .add_control(ticks(0, 0), ControlEvent::Program { value: 5 })
+      .add_control(
+        ticks(1, 0),
+        ControlEvent::Controller {
+          controller: 7,
+          value: 100,
+        },
+      )
+      .add_control(
+        ticks(3, 0),
+        ControlEvent::Controller {
+          controller: 7,
+          value: 80,
+        },
+      );
+    let mut source = NotesSource::new();
+    source.add_clip(7, notes);
+    let source = Arc::new(RwLock::new(source));
+
+    let mut midi_track = MidiTrack::new(Endpoint::Default, 2);
+    midi_track.set_clip(0, NotesClip::new(clip(7, 1, 1), Notes::new(source, 7)));
+    let mut track = Track::new(
+      "midi",
+      Color::new("red".into()),
+      TrackMedia::Midi(midi_track),
+    );
+    track.add_clip(clip(7, 1, 1));
+    track
+  }
+
+  /// Play the track from a position looping from the second bar until the loop end,
+  /// and return the messages with their time in seconds
+  fn play(
+    position: BarsTime,
+    loop_end: BarsTime,
+    chase: ChaseConfig,
+    seconds: f64,
+  ) -> Vec<(Message, f64)> {
+    play_track(track(), position, loop_end, chase, seconds)
+  }
+
+  fn play_track(
+    mut track: Track,
+    position: BarsTime,
+    loop_end: BarsTime,
+    chase: ChaseConfig,
+    seconds: f64,
+  ) -> Vec<(Message, f64)> {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_start(BarsTime::from_bars(1));
+    transport.set_loop_end(loop_end);
+    transport.set_position(position);
+    transport.play(false);
+
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    while master_clock < ClockTime::from_seconds(seconds) {
+      let mut segments = transport.segments_iterator(master_clock, samples);
+      while let Some(segment) = segments.next(&transport) {
+        track.process_segment(&segment, &chase, &mut midi_output);
+      }
+      transport.update_from_segments(&segments);
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+
+    midi_output
+      .0
+      .iter()
+      .map(|event| {
+        assert_eq!(event.endpoint, Endpoint::Default);
+        let seconds = event.timestamp.units() as f64 / clock::UNITS_PER_SECOND as f64;
+        (event.message.clone(), seconds)
+      })
+      .collect()
+  }
+
+  /// The notes as (on, key, velocity, seconds)
+  fn notes(messages: &[(Message, f64)]) -> Vec<(bool, u8, u8, f64)> {
+    messages
+      .iter()
+      .filter_map(|(message, seconds)| match *message {
+        Message::NoteOn {
+          channel,
+          key,
+          velocity,
+        } => {
+          assert_eq!(channel, 2);
+          Some((true, key, velocity, *seconds))
+        }
+        Message::NoteOff {
+          channel,
+          key,
+          velocity,
+        } => {
+          assert_eq!(channel, 2);
+          Some((false, key, velocity, *seconds))
+        }
+        _ => None,
+      })
+      .collect()
+  }
+
+  fn assert_notes(notes: &[(bool, u8, u8, f64)], expected: &[(bool, u8, u8, f64)]) {
+    assert_eq!(notes.len(), expected.len());
+    for (note, expected_note) in notes.iter().zip(expected.iter()) {
+      assert_eq!(note.0, expected_note.0);
+      assert_eq!(note.1, expected_note.1);
+      assert_eq!(note.2, expected_note.2);
+      assert!((note.3 - expected_note.3).abs() < 1e-6);
+    }
+  }
+
+  #[test]
+  pub fn notes_released_at_the_clip_end() {
+    let messages = play(
+      BarsTime::from_bars(1),
+      BarsTime::from_bars(3),
+      ChaseConfig::default(),
+      4.1,
+    );
+    assert_notes(
+      &notes(&messages),
+      &[
+        (true, 60, 127, 0.0),
+        (false, 60, 127, 0.25),
+        (true, 64, 64, 1.5),
+        (false, 64, 0, 2.0),
+        (true, 60, 127, 4.0),
+      ],
+    );
+  }
+
+  #[test]
+  pub fn notes_released_when_the_loop_wraps() {
+    let messages = play(
+      BarsTime::from_bars(1),
+      BarsTime::new(1, 3, 2, 0),
+      ChaseConfig::default(),
+      1.8,
+    );
+    assert_notes(
+      &notes(&messages),
+      &[
+        (true, 60, 127, 0.0),
+        (false, 60, 127, 0.25),
+        (true, 64, 64, 1.5),
+        (false, 64, 0, 1.75),
+        (true, 60, 127, 1.75),
+      ],
+    );
+  }
+
+  #[test]
+  pub fn chase_notes_and_controls() {
+    let chase = ChaseConfig {
+      notes: true,
+      controls: true,
+    };
+    let messages = play(
+      BarsTime::new(1, 3, 2, 0),
+      BarsTime::from_bars(3),
+      chase,
+      0.1,
+    );
+    let expected = vec![
+      (
+        Message::ProgramChange {
+          channel: 2,
+          value: 5,
+        },
+        0.0,
+      ),
+      (
+        Message::ControlChange {
+          channel: 2,
+          controller: 7,
+          value: 80,
+        },
+        0.0,
+      ),
+      (
+        Message::NoteOn {
+          channel: 2,
+          key: 64,
+          velocity: 64,
+        },
+        0.0,
+      ),
+    ];
+    assert_eq!(messages, expected);
+
+    let chase = ChaseConfig {
+      notes: false,
+      controls: false,
+    };
+    let messages = play(
+      BarsTime::new(1, 3, 2, 0),
+      BarsTime::from_bars(3),
+      chase,
+      0.1,
+    );
+    assert!(messages.is_empty());
+  }
+
+  #[test]
+  pub fn play_the_steps_clips() {
+    let mut lane = Lane::new(36, step_length(4, 1, 1), 4);
+    lane.steps[0] = Step::on(1.0);
+    lane.steps[2] = Step {
+      ratchet: 2,
+      ..Step::on(0.5)
+    };
+    let mut track = midi_track(Endpoint::Default, 2);
+    track.add_steps_clip(clip(7, 1, 1), vec![lane]);
+
+    let messages = play_track(
+      track,
+      BarsTime::from_bars(1),
+      BarsTime::from_bars(2),
+      ChaseConfig::default(),
+      2.1,
+    );
+    assert_notes(
+      &notes(&messages),
+      &[
+        (true, 36, 127, 0.0),
+        (false, 36, 127, 0.25),
+        (true, 36, 64, 1.0),
+        (false, 36, 64, 1.125),
+        (true, 36, 64, 1.25),
+        (false, 36, 64, 1.375),
+        (true, 36, 127, 2.0),
+      ],
+    );
+  }
+
+  #[test]
+  pub fn play_the_drum_clips_on_the_channels_of_the_kit() {
+    let mut snare = KitInstrument::new("Snare", 38);
+    snare.channel = Some(9);
+    let kit = Kit::new("kit", vec![KitInstrument::new("Kick", 36), snare]);
+    let mut pattern = Pattern::new("A", 4, step_length(4, 1, 1));
+    pattern.set_hit(0, 0, Some(Hit::new(1.0)));
+    pattern.set_hit(1, 1, Some(Hit::new(1.0)));
+    let mut track = midi_track(Endpoint::Default, 2);
+    track.add_drum_clip(clip(7, 1, 1), kit, vec![pattern]);
+
+    let messages = play_track(
+      track,
+      BarsTime::from_bars(1),
+      BarsTime::from_bars(2),
+      ChaseConfig::default(),
+      0.8,
+    );
+    let note_ons: Vec<(u8, u8, f64)> = messages
+      .iter()
+      .filter_map(|(message, seconds)| match *message {
+        Message::NoteOn { channel, key, .. } => Some((channel, key, *seconds)),
+        _ => None,
+      })
+      .collect();
+    assert_eq!(note_ons.len(), 2);
+    assert_eq!((note_ons[0].0, note_ons[0].1), (2, 36));
+    assert_eq!((note_ons[1].0, note_ons[1].1), (9, 38));
+    assert!((note_ons[1].2 - 0.5).abs() < 1e-6);
+  }
+
+  #[test]
+  pub fn end_the_note_of_the_same_channel() {
+    let mut active_notes = Vec::new();
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let (start, end) = (TicksTime::zero(), TicksTime::new(1));
+    let note_start = NoteEvent::NoteStart {
+      key: 38,
+      velocity: 1.0,
+      end,
+    };
+    let note_end = NoteEvent::NoteEnd {
+      key: 38,
+      velocity: 0.0,
+      start,
+    };
+    let time = ClockTime::zero();
+    for channel in [9, 10].iter() {
+      play_note_event(
+        &mut active_notes,
+        0,
+        Endpoint::Default,
+        *channel,
+        note_start,
+        time,
+        &mut midi_output,
+      );
+    }
+    play_note_event(
+      &mut active_notes,
+      0,
+      Endpoint::Default,
+      10,
+      note_end,
+      time,
+      &mut midi_output,
+    );
+
+    match midi_output.0.last().unwrap().message {
+      Message::NoteOff { channel, key, .. } => assert_eq!((channel, key), (10, 38)),
+      _ => panic!("Not a note off"),
+    }
+    let channels: Vec<u8> = active_notes
+      .iter()
+      .map(|note: &ActiveNote| note.channel)
+      .collect();
+    assert_eq!(channels, vec![9]);
+  }
+}
@@ -1,15 +1,32 @@
 pub mod audio;
 pub mod instrument;
 pub mod midi;
+pub mod record;
 
+use crate::audio::AudioOutput;
 use crate::color::Color;
 
+use crate::config::Chase as ChaseConfig;
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::io::MidiOutput;
 use crate::song::{
-  clips::Clip,
-  track::{audio::AudioTrack, instrument::InstrumentTrack, midi::MidiTrack},
+  clips::{
+    drumbox::{DrumClip, Kit, Pattern},
+    pianoroll::{Notes, NotesClip},
+    stepper::{Lane, StepsClip},
+    tracker::TrackerClip,
+    Clip, ClipIndex,
+  },
+  track::{
+    audio::AudioTrack,
+    instrument::InstrumentTrack,
+    midi::MidiTrack,
+    record::{InputQuantize, MidiRecorder},
+  },
 };
 
-use crate::time::TicksTime;
+use crate::time::{ClockTime, SampleRate, TicksTime};
+use crate::transport::Segment;
 
 pub enum TrackMedia {
   Midi(MidiTrack),
@@ -30,6 +47,8 @@ pub struct Track {
   pub media: TrackMedia,
 
   clips: Vec<Clip>,
+
+  recorder: MidiRecorder,
 }
 
 impl Track {
@@ -47,14 +66,283 @@ impl Track {
       pan: 0.0,
       media,
       clips: Vec::new(),
+      recorder: MidiRecorder::new(),
+    }
+  }
+
+  /// Add a clip and return its index
+  pub fn add_clip(&mut self, clip: Clip) -> ClipIndex {
+    self.clips.push(clip);
+    self.clips.len() - 1
+  }
+
+  pub fn get_clip(&self, index: ClipIndex) -> Option<&Clip> {
+    self.clips.get(index)
+  }
+
+  /// Whether the clips of the track can have notes
+  pub fn has_notes(&self) -> bool {
+    match self.media {
+      TrackMedia::Midi(_) | TrackMedia::Instrument(_) => true,
+      TrackMedia::Audio(_) => false,
+    }
+  }
+
+  /// Add a clip with its notes and return its index, unless the track can't have notes
+  pub fn add_notes_clip(&mut self, clip: Clip, notes: Notes) -> Option<ClipIndex> {
+    let notes_clip = NotesClip::new(clip.clone(), notes);
+    let index = self.clips.len();
+    match &mut self.media {
+      TrackMedia::Midi(midi_track) => midi_track.set_clip(index, notes_clip),
+      TrackMedia::Instrument(instrument_track) => instrument_track.set_clip(index, notes_clip),
+      TrackMedia::Audio(_audio_track) => return None,
+    }
+    Some(self.add_clip(clip))
+  }
+
+  /// Add a step sequencer clip with its lanes and return its index, unless the track can't have notes
+  pub fn add_steps_clip(&mut self, clip: Clip, lanes: Vec<Lane>) -> Option<ClipIndex> {
+    let steps_clip = StepsClip::new(clip.clone(), lanes);
+    let index = self.clips.len();
+    match &mut self.media {
+      TrackMedia::Midi(midi_track) => midi_track.set_steps_clip(index, steps_clip),
+      TrackMedia::Instrument(instrument_track) => {
+        instrument_track.set_steps_clip(index, steps_clip)
+      }
+      TrackMedia::Audio(_audio_track) => return None,
+    }
+    Some(self.add_clip(clip))
+  }
+
+  pub fn get_steps_clip_mut(&mut self, index: ClipIndex) -> Option<&mut StepsClip> {
+    match &mut self.media {
+      TrackMedia::Midi(midi_track) => midi_track.get_steps_clip_mut(index),
+      TrackMedia::Instrument(instrument_track) => instrument_track.get_steps_clip_mut(index),
+      TrackMedia::Audio(_audio_track) => None,
     }
   }
 
+  /// Add a drum machine clip with its kit and patterns and return its index, unless the track can't have notes
+  pub fn add_drum_clip(
+    &mut self,
+    clip: Clip,
+    kit: Kit,
+    patterns: Vec<Pattern>,
+  ) -> Option<ClipIndex> {
+    let drum_clip = DrumClip::new(clip.clone(), kit, patterns);
+    let index = self.clips.len();
+    match &mut self.media {
+      TrackMedia::Midi(midi_track) => midi_track.set_drum_clip(index, drum_clip),
+      TrackMedia::Instrument(instrument_track) => instrument_track.set_drum_clip(index, drum_clip),
+      TrackMedia::Audio(_audio_track) => return None,
+    }
+    Some(self.add_clip(clip))
+  }
+
+  pub fn get_drum_clip_mut(&mut self, index: ClipIndex) -> Option<&mut DrumClip> {
+    match &mut self.media {
+      TrackMedia::Midi(midi_track) => midi_track.get_drum_clip_mut(index),
+      TrackMedia::Instrument(instrument_track) => instrument_track.get_drum_clip_mut(index),
+      TrackMedia::Audio(_audio_track) => None,
+    }
+  }
+
+  /// Add a compiled tracker clip and return its index, unless the track can't have notes
+  pub fn add_tracker_clip(&mut self, tracker_clip: TrackerClip) -> Option<ClipIndex> {
+    let clip = tracker_clip.get_clip().clone();
+    let index = self.clips.len();
+    match &mut self.media {
+      TrackMedia::Midi(midi_track) => midi_track.set_tracker_clip(index, tracker_clip),
+      TrackMedia::Instrument(instrument_track) => {
+        instrument_track.set_tracker_clip(index, tracker_clip)
+      }
+      TrackMedia::Audio(_audio_track) => return None,
+    }
+    Some(self.add_clip(clip))
+  }
+
+  pub fn get_tracker_clip_mut(&mut self, index: ClipIndex) -> Option<&mut TrackerClip> {
+    match &mut self.media {
+      TrackMedia::Midi(midi_track) => midi_track.get_tracker_clip_mut(index),
+      TrackMedia::Instrument(instrument_track) => instrument_track.get_tracker_clip_mut(index),
+      TrackMedia::Audio(_audio_track) => None,
+    }
+  }
+
+  /// Swap the tracker clip with the same uuid for a compiled one, or return false when there is none
+  pub fn replace_tracker_clip(&mut self, tracker_clip: TrackerClip) -> bool {
+    let uuid = tracker_clip.get_clip().uuid;
+    for index in 0..self.clips.len() {
+      if self.clips[index].uuid == uuid {
+        if let Some(current) = self.get_tracker_clip_mut(index) {
+          *current = tracker_clip;
+          return true;
+        }
+      }
+    }
+    false
+  }
+
   pub fn clips_in_range(&self, start: TicksTime, until: TicksTime) -> impl Iterator<Item = &Clip> {
-    // TODO use an Interval Tree (http://www.davismol.net/2016/02/07/data-structures-augmented-interval-tree-to-search-for-interval-overlapping/)
-    self.clips.iter().filter(move |clip| {
-      let end = clip.start + clip.length;
-      clip.start < until && end >= start
-    })
+    clips_in_range(&self.clips, start, until).map(|(_index, clip)| clip)
+  }
+
+  /// Play the clips in the segment range, a muted track releases its notes and stays silent
+  pub fn process_segment<MidiOut>(
+    &mut self,
+    segment: &Segment,
+    chase: &ChaseConfig,
+    midi_output: &mut MidiOut,
+  ) where
+    MidiOut: MidiOutput,
+  {
+    if self.mute {
+      self.stop(segment.master_clock, midi_output);
+      return;
+    }
+    match &mut self.media {
+      TrackMedia::Midi(midi_track) => {
+        midi_track.process_segment(&self.clips, segment, chase, midi_output);
+      }
+      TrackMedia::Audio(_audio_track) => {}
+      TrackMedia::Instrument(instrument_track) => {
+        instrument_track.process_segment(&self.clips, segment, chase);
+      }
+    }
+  }
+
+  /// Play one of the clips looping from where it was launched instead of the arrangement,
+  /// with the offset into its loops at the start of the segment. A muted track stays silent.
+  pub fn process_looped_clip<MidiOut>(
+    &mut self,
+    index: ClipIndex,
+    offset: TicksTime,
+    segment: &Segment,
+    midi_output: &mut MidiOut,
+  ) where
+    MidiOut: MidiOutput,
+  {
+    if self.mute {
+      self.stop(segment.master_clock, midi_output);
+      return;
+    }
+    let clip = match self.clips.get(index) {
+      Some(clip) => clip,
+      None => return,
+    };
+    match &mut self.media {
+      TrackMedia::Midi(midi_track) => {
+        midi_track.process_looped_clip(index, clip, offset, segment, midi_output);
+      }
+      TrackMedia::Audio(_audio_track) => {}
+      TrackMedia::Instrument(instrument_track) => {
+        instrument_track.process_looped_clip(index, clip, offset, segment);
+      }
+    }
+  }
+
+  /// Release the notes still playing when the transport stops
+  pub fn stop<MidiOut>(&mut self, time: ClockTime, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
+    match &mut self.media {
+      TrackMedia::Midi(midi_track) => midi_track.stop(time, midi_output),
+      TrackMedia::Audio(_audio_track) => {}
+      TrackMedia::Instrument(instrument_track) => instrument_track.stop(time),
+    }
+  }
+
+  /// Index of the empty clip kept for the next new take
+  pub fn get_new_take(&self) -> Option<ClipIndex> {
+    self.recorder.get_new_take()
+  }
+
+  /// Keep an empty clip with its notes for the next new take, unless there is one already.
+  /// It is added with no length, and moved where the take starts when recording.
+  pub fn prepare_take(&mut self, mut clip: Clip, notes: Notes) -> Option<ClipIndex> {
+    if let Some(index) = self.recorder.get_new_take() {
+      return Some(index);
+    }
+    clip.length = TicksTime::zero();
+    let index = self.add_notes_clip(clip, notes)?;
+    self.recorder.set_new_take(Some(index));
+    Some(index)
+  }
+
+  /// Record the notes of the input events into the clips when the track is armed and the segment is recorded,
+  /// otherwise finish the recording in progress. New takes use the clip prepared before.
+  pub fn record_segment(
+    &mut self,
+    segment: &Segment,
+    events: &[EventIo],
+    quantize: Option<&InputQuantize>,
+  ) {
+    let notes_clips = match &mut self.media {
+      TrackMedia::Midi(midi_track) => midi_track.get_clips_mut(),
+      TrackMedia::Instrument(instrument_track) => instrument_track.get_clips_mut(),
+      TrackMedia::Audio(_audio_track) => return,
+    };
+    if self.rec && segment.is_recording() {
+      self.recorder.record_segment(
+        &mut self.clips,
+        notes_clips,
+        segment,
+        events,
+        quantize,
+      );
+    } else {
+      self.recorder.finish(&self.clips, notes_clips, quantize);
+    }
+  }
+
+  /// Finish the recording in progress, like when the transport stops
+  pub fn finish_recording(&mut self, quantize: Option<&InputQuantize>) {
+    let notes_clips = match &mut self.media {
+      TrackMedia::Midi(midi_track) => midi_track.get_clips_mut(),
+      TrackMedia::Instrument(instrument_track) => instrument_track.get_clips_mut(),
+      TrackMedia::Audio(_audio_track) => return,
+    };
+    self.recorder.finish(&self.clips, notes_clips, quantize);
+  }
+
+  /// Forget the notes still playing, the outputs were silenced
+  pub fn forget_notes(&mut self) {
+    match &mut self.media {
+      TrackMedia::Midi(midi_track) => midi_track.forget_notes(),
+      TrackMedia::Audio(_audio_track) => {}
+      TrackMedia::Instrument(instrument_track) => instrument_track.forget_notes(),
+    }
   }
+
+  /// Forget the notes playing on an endpoint that was removed
+  pub fn forget_endpoint(&mut self, endpoint: Endpoint) {
+    if let TrackMedia::Midi(midi_track) = &mut self.media {
+      midi_track.forget_endpoint(endpoint);
+    }
+  }
+
+  /// Add the sound of the instruments to the output, for every buffer even when stopped
+  pub fn process_audio(
+    &mut self,
+    audio_output: &mut AudioOutput,
+    frames: usize,
+    sample_rate: SampleRate,
+  ) {
+    if let TrackMedia::Instrument(instrument_track) = &mut self.media {
+      instrument_track.process_audio(audio_output, frames, sample_rate);
+    }
+  }
+}
+
+fn clips_in_range(
+  clips: &[Clip],
+  start: TicksTime,
+  until: TicksTime,
+) -> impl Iterator<Item = (ClipIndex, &Clip)> {
+  // TODO use an Interval Tree (http://www.davismol.net/2016/02/07/data-structures-augmented-interval-tree-to-search-for-interval-overlapping/)
+  clips.iter().enumerate().filter(move |(_index, clip)| {
+    let end = clip.start + clip.length;
+    clip.start < until && end >= start
+  })
 }
@@ -0,0 +1,528 @@
+use std::collections::BTreeMap;
+
+use crate::config::Record as RecordConfig;
+use crate::midi::buffer::EventIo;
+use crate::midi::types::{U4, U7};
+use crate::midi::Message;
+use crate::song::{
+  clips::{pianoroll::NotesClip, Clip, ClipIndex},
+  groove::Groove,
+  source::notes::Note,
+};
+use crate::time::{ticks::TICKS_RESOLUTION, TicksTime};
+use crate::transport::Segment;
+
+const MAX_HELD_NOTES: usize = 256;
+const MAX_RECORDED_NOTES: usize = 1024;
+
+/// Quantize applied to the notes while they are recorded
+pub struct InputQuantize {
+  groove: Groove,
+  strength: f64,
+}
+
+impl InputQuantize {
+  pub fn new(groove: Groove, strength: f64) -> InputQuantize {
+    InputQuantize { groove, strength }
+  }
+
+  /// The quantize for the grid of the configuration, if any
+  pub fn from_config(config: &RecordConfig) -> Option<InputQuantize> {
+    let grid = TICKS_RESOLUTION * 16 / u64::from(config.quantize).max(1);
+    Some(InputQuantize::new(
+      Groove::straight(TicksTime::new(grid)),
+      config.quantize_strength,
+    ))
+    .filter(|_| config.quantize > 0)
+  }
+
+  pub fn get_groove(&self) -> &Groove {
+    &self.groove
+  }
+
+  pub fn get_strength(&self) -> f64 {
+    self.strength
+  }
+}
+
+/// Note pressed on the input and not released yet, from a song position
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct HeldNote {
+  channel: U4,
+  key: U7,
+  velocity: U7,
+  start: TicksTime,
+}
+
+/// Records the notes of the MIDI input into the clips of a track.
+///
+/// The take goes into the clip with notes under the position where the recording starts,
+/// or into the empty clip prepared for a new take, moved to the start of the bar.
+/// The clip grows while recording, and the passes of a loop are stacked into the same clip.
+pub struct MidiRecorder {
+  held: Vec<HeldNote>,
+  recorded: Vec<Note>,
+  take: Option<ClipIndex>,
+  new_take: Option<ClipIndex>,
+  next_position: Option<TicksTime>,
+}
+
+impl Default for MidiRecorder {
+  fn default() -> Self {
+    MidiRecorder {
+      held: Vec::with_capacity(MAX_HELD_NOTES),
+      recorded: Vec::with_capacity(MAX_RECORDED_NOTES),
+      take: None,
+      new_take: None,
+      next_position: None,
+    }
+  }
+}
+
+impl MidiRecorder {
+  pub fn new() -> MidiRecorder {
+    MidiRecorder::default()
+  }
+
+  pub fn is_recording(&self) -> bool {
+    self.next_position.is_some()
+  }
+
+  /// Index of the clip where the notes are being recorded
+  pub fn get_take(&self) -> Option<ClipIndex> {
+    self.take
+  }
+
+  /// Index of the empty clip kept for the next new take, if it wasn't used yet
+  pub fn get_new_take(&self) -> Option<ClipIndex> {
+    self.new_take
+  }
+
+  /// Keep an empty clip with notes for the next new take, so it doesn't need to be created while recording
+  pub fn set_new_take(&mut self, index: Option<ClipIndex>) {
+    self.new_take = index;
+  }
+
+  /// Record the input events of a segment.
+  /// When the segment doesn't follow the previous one, like when the loop wraps,
+  /// the notes still held are split between both positions.
+  pub fn record_segment(
+    &mut self,
+    clips: &mut [Clip],
+    notes_clips: &mut BTreeMap<ClipIndex, NotesClip>,
+    segment: &Segment,
+    events: &[EventIo],
+    quantize: Option<&InputQuantize>,
+  ) {
+    let start_position = segment.start_position;
+    if let Some(position) = self
+      .next_position
+      .filter(|position| *position != start_position)
+    {
+      self.release_held(position);
+      for note in self.held.iter_mut() {
+        note.start = start_position;
+      }
+    }
+    self.next_position = Some(segment.end_position);
+
+    let take_covers_segment = self
+      .take
+      .and_then(|index| clips.get(index))
+      .is_some_and(|clip| {
+        clip.start <= start_position && start_position <= clip.start + clip.length
+      });
+    if !take_covers_segment {
+      self.take = self.find_take(clips, notes_clips, segment.start_position);
+      if self.take.is_none() {
+        self.take = self.use_new_take(clips, notes_clips, segment);
+      }
+    }
+    if let Some(clip) = self.take.and_then(|index| clips.get_mut(index)) {
+      if clip.start + clip.length < segment.end_position {
+        clip.length = segment.end_position - clip.start;
+      }
+    }
+
+    for event in events {
+      let position = segment.position_at(event.timestamp);
+      match event.message {
+        Message::NoteOn {
+          channel,
+          key,
+          velocity,
+        } if velocity > 0 => self.hold(HeldNote {
+          channel,
+          key,
+          velocity,
+          start: position,
+        }),
+        // a note on without velocity is a note off
+        Message::NoteOn { channel, key, .. } | Message::NoteOff { channel, key, .. } => {
+          let held = self
+            .held
+            .iter()
+            .position(|note| note.channel == channel && note.key == key);
+          if let Some(index) = held {
+            let note = self.held.swap_remove(index);
+            self.push_recorded(note, position);
+          }
+        }
+        _ => {}
+      }
+    }
+
+    self.write_recorded(clips, notes_clips, quantize);
+  }
+
+  /// End the notes still held and write what was recorded, like when the recording stops
+  pub fn finish(
+    &mut self,
+    clips: &[Clip],
+    notes_clips: &BTreeMap<ClipIndex, NotesClip>,
+    quantize: Option<&InputQuantize>,
+  ) {
+    if let Some(position) = self.next_position.take() {
+      self.release_held(position);
+      self.held.clear();
+    }
+    if self.take.is_none() && !self.recorded.is_empty() {
+      self.take = self.find_take(clips, notes_clips, self.recorded[0].get_start());
+    }
+    self.write_recorded(clips, notes_clips, quantize);
+    // the notes without a take to go into are lost
+    self.recorded.clear();
+    self.take = None;
+  }
+
+  fn hold(&mut self, note: HeldNote) {
+    if self.held.len() < MAX_HELD_NOTES {
+      self.held.push(note);
+    }
+  }
+
+  fn release_held(&mut self, position: TicksTime) {
+    for index in 0..self.held.len() {
+      let note = self.held[index];
+      self.push_recorded(note, position);
+    }
+  }
+
+  fn push_recorded(&mut self, note: HeldNote, end: TicksTime) {
+    if self.recorded.len() < MAX_RECORDED_NOTES {
+      let velocity = f64::from(note.velocity) / 127.0;
+      let length = (end - note.start).max(TicksTime::new(1));
+      self
+        .recorded
+        .push(Note::new(note.key, velocity, note.start, length));
+    }
+  }
+
+  /// Existing clip with notes under a position
+  fn find_take(
+    &self,
+    clips: &[Clip],
+    notes_clips: &BTreeMap<ClipIndex, NotesClip>,
+    position: TicksTime,
+  ) -> Option<ClipIndex> {
+    clips
+      .iter()
+      .enumerate()
+      .find(|(index, clip)| {
+        clip.start <= position
+          && position < clip.start + clip.length
+          && notes_clips.contains_key(index)
+      })
+      .map(|(index, _clip)| index)
+  }
+
+  /// Move the empty clip kept for a new take to the start of the bar.
+  /// Without one the notes are only recorded into the existing clips.
+  fn use_new_take(
+    &mut self,
+    clips: &mut [Clip],
+    notes_clips: &mut BTreeMap<ClipIndex, NotesClip>,
+    segment: &Segment,
+  ) -> Option<ClipIndex> {
+    let index = self.new_take.take()?;
+    let start = segment.bar_start_position;
+    for clip in clips
+      .get_mut(index)
+      .into_iter()
+      .chain(notes_clips.get_mut(&index).map(NotesClip::get_clip_mut))
+    {
+      clip.signature = segment.signature;
+      clip.start = start;
+      clip.length = segment.end_position - start;
+    }
+    Some(index)
+  }
+
+  /// Add the recorded notes to the clip of the take, unless its source is locked
+  fn write_recorded(
+    &mut self,
+    clips: &[Clip],
+    notes_clips: &BTreeMap<ClipIndex, NotesClip>,
+    quantize: Option<&InputQuantize>,
+  ) {
+    if self.recorded.is_empty() {
+      return;
+    }
+    let take = self
+      .take
+      .and_then(|index| Some((clips.get(index)?, notes_clips.get(&index)?)));
+    if let Some((clip, notes_clip)) = take {
+      let notes = notes_clip.get_notes();
+      let mut notes_source = match notes.get_source().try_write() {
+        Ok(notes_source) => notes_source,
+        Err(_) => return,
+      };
+      if let Some(notes_source_clip) = notes_source.get_clip_mut(notes.get_id()) {
+        for note in self.recorded.drain(..) {
+          let note = Note::new(
+            note.get_key(),
+            note.get_velocity(),
+            note.get_start() - clip.start,
+            note.get_length(),
+          );
+          let note = match quantize {
+            Some(quantize) => quantize.groove.quantize(&note, quantize.strength),
+            None => note,
+          };
+          notes_source_clip.add_note(note);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use std::sync::{Arc, RwLock};
+
+  use super::InputQuantize;
+  use crate::config::Record as RecordConfig;
+  use crate::midi::buffer::{Endpoint, EventIo};
+  use crate::midi::Message;
+  use crate::song::clips::{pianoroll::Notes, Clip};
+  use crate::song::source::notes::{Note, NotesClip as NotesSourceClip, NotesSource};
+  use crate::song::track::Track;
+  use crate::testing::midi_track;
+  use crate::time::{ticks::TICKS_RESOLUTION, BarsTime, ClockTime, Signature, TicksTime};
+  use crate::transport::Transport;
+
+  const SAMPLE_RATE: u32 = 44100;
+
+  fn note_on(key: u8, velocity: u8) -> Message {
+    Message::NoteOn {
+      channel: 0,
+      key,
+      velocity,
+    }
+  }
+
+  fn note_off(key: u8) -> Message {
+    Message::NoteOff {
+      channel: 0,
+      key,
+      velocity: 0,
+    }
+  }
+
+  fn assert_close(value: TicksTime, expected: TicksTime) {
+    let diff = u64::from(value).abs_diff(u64::from(expected));
+    assert!(
+      diff <= TICKS_RESOLUTION / 1000,
+      "{:?} is not close to {:?}",
+      value,
+      expected
+    );
+  }
+
+  fn sixteenths(value: u64) -> TicksTime {
+    TicksTime::new(value * TICKS_RESOLUTION)
+  }
+
+  fn armed_track() -> Track {
+    let mut track = midi_track(Endpoint::Default, 0);
+    track.rec = true;
+    track
+  }
+
+  fn prepare_take(track: &mut Track, source: &Arc<RwLock<NotesSource>>) {
+    let id = {
+      let mut source = source.write().unwrap();
+      let id = source.next_free_id();
+      source.add_clip(id, NotesSourceClip::new());
+      id
+    };
+    let clip = Clip {
+      uuid: id,
+      name: "Recording".to_string(),
+      signature: Signature::new(4, 4),
+      start: TicksTime::zero(),
+      length: TicksTime::zero(),
+    };
+    track.prepare_take(clip, Notes::new(source.clone(), id));
+  }
+
+  /// Record the events, with their time in seconds, into a track playing from the second bar at 120 bpm
+  fn record_track(
+    track: &mut Track,
+    loop_enabled: bool,
+    events: &[(f64, Message)],
+    seconds: f64,
+    quantize: Option<&InputQuantize>,
+  ) {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(loop_enabled);
+    transport.set_loop_start(BarsTime::from_bars(1));
+    transport.set_loop_end(BarsTime::from_bars(2));
+    transport.set_position(BarsTime::from_bars(1));
+    transport.set_recording(true);
+    transport.play(false);
+
+    let events: Vec<EventIo> = events
+      .iter()
+      .map(|(seconds, message)| {
+        EventIo::new(
+          ClockTime::from_seconds(*seconds),
+          Endpoint::Id(0),
+          message.clone(),
+        )
+      })
+      .collect();
+
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    while master_clock < ClockTime::from_seconds(seconds) {
+      let mut segments = transport.segments_iterator(master_clock, samples);
+      while let Some(segment) = segments.next(&transport) {
+        let clock_end = segment.master_clock + segment.clock_duration;
+        let received_before = |clock: ClockTime| {
+          events
+            .iter()
+            .take_while(|event| event.timestamp < clock)
+            .count()
+        };
+        let segment_events =
+          &events[received_before(segment.master_clock)..received_before(clock_end)];
+        track.record_segment(&segment, segment_events, quantize);
+      }
+      transport.update_from_segments(&segments);
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+    track.finish_recording(quantize);
+  }
+
+  fn take_notes(track: &Track, source: &Arc<RwLock<NotesSource>>) -> (Vec<Clip>, Vec<Note>) {
+    let clips: Vec<Clip> = (0..)
+      .map_while(|index| track.get_clip(index).cloned())
+      .collect();
+    let source = source.read().unwrap();
+    let mut notes: Vec<Note> = source
+      .get_clip(clips[0].uuid)
+      .unwrap()
+      .notes_range(TicksTime::zero(), TicksTime::new(u64::MAX))
+      .collect();
+    notes.sort_by_key(|note| (note.get_start(), note.get_key()));
+    (clips, notes)
+  }
+
+  /// Record the events into an armed track with a clip prepared for the take,
+  /// and return the clip of the take with its notes sorted by start
+  fn record(
+    loop_enabled: bool,
+    events: &[(f64, Message)],
+    seconds: f64,
+    quantize: Option<&InputQuantize>,
+  ) -> (Vec<Clip>, Vec<Note>) {
+    let source = Arc::new(RwLock::new(NotesSource::new()));
+    let mut track = armed_track();
+    prepare_take(&mut track, &source);
+    record_track(&mut track, loop_enabled, events, seconds, quantize);
+    take_notes(&track, &source)
+  }
+
+  #[test]
+  pub fn record_notes_into_a_new_clip() {
+    let events = [
+      (0.25, note_on(60, 127)),
+      (0.75, note_off(60)),
+      (1.0, note_on(62, 0)),
+      (1.25, note_on(64, 64)),
+    ];
+    let (clips, notes) = record(false, &events, 1.5, None);
+
+    assert_eq!(clips.len(), 1);
+    assert_eq!(clips[0].start, sixteenths(16));
+    assert!(clips[0].length >= sixteenths(12));
+    assert_eq!(notes.len(), 2);
+    assert_eq!(notes[0].get_key(), 60);
+    assert_eq!(notes[0].get_velocity(), 1.0);
+    assert_close(notes[0].get_start(), sixteenths(2));
+    assert_close(notes[0].get_length(), sixteenths(4));
+    // the note still held ends where the recording finished
+    assert_eq!(notes[1].get_key(), 64);
+    assert_close(notes[1].get_start(), sixteenths(10));
+    assert_eq!(
+      notes[1].get_start() + notes[1].get_length(),
+      clips[0].length
+    );
+  }
+
+  #[test]
+  pub fn loop_passes_stack_into_the_take() {
+    let config = RecordConfig {
+      quantize: 16,
+      ..RecordConfig::default()
+    };
+    let quantize = InputQuantize::from_config(&config);
+    // a note in each pass of the loop, slightly off the grid, and one held across the loop end
+    let events = [
+      (0.26, note_on(60, 127)),
+      (0.5, note_off(60)),
+      (1.76, note_on(62, 127)),
+      (2.24, note_off(62)),
+      (3.01, note_on(64, 127)),
+      (3.5, note_off(64)),
+    ];
+    let (clips, notes) = record(true, &events, 4.0, quantize.as_ref());
+
+    assert_eq!(clips.len(), 1);
+    assert_eq!(clips[0].length, sixteenths(16));
+    let starts: Vec<(u8, TicksTime)> = notes
+      .iter()
+      .map(|note| (note.get_key(), note.get_start()))
+      .collect();
+    assert_eq!(
+      starts,
+      vec![
+        (62, sixteenths(0)),
+        (60, sixteenths(2)),
+        (64, sixteenths(8)),
+        (62, sixteenths(14)),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn forget_the_notes_without_a_take() {
+    let source = Arc::new(RwLock::new(NotesSource::new()));
+    let mut track = armed_track();
+    let events = [(0.25, note_on(60, 127)), (0.75, note_off(60))];
+    record_track(&mut track, false, &events, 1.0, None);
+    assert!(track.get_clip(0).is_none());
+
+    prepare_take(&mut track, &source);
+    let events = [(0.25, note_on(62, 127)), (0.75, note_off(62))];
+    record_track(&mut track, false, &events, 1.0, None);
+    let (clips, notes) = take_notes(&track, &source);
+    assert_eq!(clips.len(), 1);
+    assert_eq!(clips[0].start, sixteenths(16));
+    assert_eq!(notes.len(), 1);
+    assert_eq!(notes[0].get_key(), 62);
+    assert_eq!(track.get_new_take(), None);
+  }
+}
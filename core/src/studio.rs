@@ -1,20 +1,30 @@
 use std::fmt;
 use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, RwLock};
 
 use crate::audio;
 use crate::audio::{AudioInput, AudioOutput};
-use crate::config::{Config, MidiPort};
+use crate::config::{Config, MidiPort, SyncMode};
 use crate::metronome::Metronome;
 use crate::midi;
-use crate::midi::buffer::EventIo;
+use crate::midi::active_notes::{ActiveNotes, TrackedOutput};
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::capture::{CapturedClip, CapturedPosition, CapturedSongTime, MidiCapture};
 use crate::midi::io::{MidiInput, MidiOutput};
+use crate::midi::mmc::MmcCommand;
+use crate::midi::panic;
 use crate::pool::Pool;
+use crate::song::clips::ClipIndex;
+use crate::song::import::ImportedModule;
+use crate::song::markers::{LocatorNumber, Marker};
 use crate::song::Song;
-use crate::time::{BarsTime, ClockTime};
+use crate::sync::{mmc, MidiClockMaster, MidiClockSlave, MmcMaster, MmcSlave, MtcMaster, MtcSlave};
+use crate::time::{smpte::FrameRate, BarsTime, ClockTime, SmpteTime, TicksTime};
 use crate::transport::{Segment, Transport};
 use crate::midi::Buffer;
 
 const MIDI_BUFFER_CAPACITY: usize = 256 * 1024;
+const REMOVED_ENDPOINTS_CAPACITY: usize = 64;
 
 fn fill_with_zero(s: &mut [f32]) {
   for d in s {
@@ -26,8 +36,18 @@ pub struct Studio {
   config: Config,
   transport: Transport,
   metronome: Metronome,
+  midi_clock: MidiClockMaster,
+  midi_clock_slave: MidiClockSlave,
+  mtc: MtcMaster,
+  mtc_slave: MtcSlave,
+  mmc: MmcMaster,
+  mmc_slave: MmcSlave,
   song: Song,
   midi_buffer: Vec<EventIo>,
+  midi_capture: Arc<RwLock<MidiCapture>>,
+  active_notes: ActiveNotes,
+  removed_endpoints: Vec<Endpoint>,
+  panic_requested: bool,
 }
 
 unsafe impl Send for Studio {}
@@ -37,20 +57,47 @@ impl Studio {
     let song = Song::new("untitled", &config);
 
     let sample_rate = config.audio.sample_rate;
-    let transport = Transport::new(sample_rate);
+    let mut transport = Transport::new(sample_rate);
+
+    // the offset is validated when the configuration is loaded
+    let smpte_offset = config
+      .smpte
+      .offset()
+      .unwrap_or_else(|_| SmpteTime::zero(FrameRate::from(config.smpte.frame_rate)));
+    transport.set_smpte_offset(smpte_offset);
+    transport.set_count_in_bars(config.transport.count_in_bars);
+    transport.set_pre_roll_bars(config.transport.pre_roll_bars);
 
     let metronome_config = config.metronome.clone();
-    let signature = *transport.get_signature();
-    let metronome = Metronome::new(metronome_config, signature);
+    let metronome = Metronome::new(metronome_config, sample_rate);
+
+    let midi_clock = MidiClockMaster::new(config.midi_clock.clone());
+
+    let mtc = MtcMaster::new(config.mtc.clone());
+    let mtc_slave = MtcSlave::new(&config.mtc);
+
+    let mmc = MmcMaster::new(&config.mmc);
+    let mmc_slave = MmcSlave::new(&config.mmc);
 
     let midi_buffer = Vec::with_capacity(MIDI_BUFFER_CAPACITY);
+    let midi_capture = Arc::new(RwLock::new(MidiCapture::new(&config.capture)));
 
     Studio {
       config,
       transport,
       metronome,
+      midi_clock,
+      midi_clock_slave: MidiClockSlave::new(),
+      mtc,
+      mtc_slave,
+      mmc,
+      mmc_slave,
       song,
       midi_buffer,
+      midi_capture,
+      active_notes: ActiveNotes::new(),
+      removed_endpoints: Vec::with_capacity(REMOVED_ENDPOINTS_CAPACITY),
+      panic_requested: false,
     }
   }
 
@@ -78,13 +125,217 @@ impl Studio {
     self.transport.set_loop_end(position)
   }
 
+  pub fn set_punch_enabled(&mut self, enabled: bool) {
+    self.transport.set_punch_enabled(enabled);
+  }
+
+  pub fn set_punch_in(&mut self, position: BarsTime) {
+    self.transport.set_punch_in(position);
+  }
+
+  pub fn set_punch_out(&mut self, position: BarsTime) {
+    self.transport.set_punch_out(position);
+  }
+
+  /// Follow an external clock or the internal one, the tempo measured from the MIDI clock stops overriding the song
+  pub fn set_sync_mode(&mut self, mode: SyncMode) {
+    if self.config.sync.mode != mode {
+      self.config.sync.mode = mode;
+      self.midi_clock_slave = MidiClockSlave::new();
+      self.transport.set_tempo_override(None);
+    }
+  }
+
   pub fn play(&mut self, restart: bool) -> bool {
-    self.transport.play(restart);
-    self.transport.is_playing()
+    self.transport.play_with_pre_roll(restart);
+    if restart {
+      self.send_locate();
+    }
+    let playing = self.transport.is_playing();
+    self.mmc.send(if playing {
+      MmcCommand::Play
+    } else {
+      MmcCommand::Stop
+    });
+    playing
   }
 
   pub fn stop(&mut self) {
+    let playing = self.transport.is_playing();
     self.transport.stop();
+    self.mmc.send(MmcCommand::Stop);
+    if !playing {
+      // stopping while stopped goes back to the start
+      self.send_locate();
+    }
+  }
+
+  pub fn set_position(&mut self, position: BarsTime) {
+    self.transport.set_position(position);
+    self.send_locate();
+  }
+
+  /// Release the notes sent to an output endpoint that is going away
+  pub fn remove_endpoint(&mut self, endpoint: Endpoint) {
+    if self.removed_endpoints.len() < REMOVED_ENDPOINTS_CAPACITY {
+      self.removed_endpoints.push(endpoint);
+    }
+  }
+
+  /// Silence all the outputs on the next process
+  pub fn panic(&mut self) {
+    self.panic_requested = true;
+  }
+
+  /// Input of the last minutes, like for an activity monitor.
+  /// It is shared with the controller, that turns the last phrase into a clip.
+  pub fn get_midi_capture(&self) -> &Arc<RwLock<MidiCapture>> {
+    &self.midi_capture
+  }
+
+  /// Add a clip captured from the MIDI input to the selected track, and return its index.
+  /// The tempo estimated from the notes is only set while the transport is still stopped.
+  pub fn add_captured_clip(&mut self, captured: CapturedClip) -> Option<ClipIndex> {
+    let track_index = self.song.get_selected_track()?;
+    if let Some(tempo) = captured.tempo {
+      if !self.transport.is_playing() {
+        self.transport.set_tempo(tempo);
+      }
+    }
+    self
+      .song
+      .add_notes_clip(track_index, captured.clip, captured.notes)
+  }
+
+  /// Add the track of an imported module to the song, with its tempo and the changes of its speed,
+  /// and return its index
+  pub fn import_module(&mut self, imported: ImportedModule) -> usize {
+    for (position, tempo) in imported.tempo_changes.iter() {
+      if *position == TicksTime::zero() {
+        self.transport.set_tempo(*tempo);
+      } else {
+        let position = BarsTime::from_ticks_with_map(*position, self.transport.get_signature_map());
+        self.transport.set_tempo_change(position, *tempo);
+      }
+    }
+    self.song.add_track(imported.track)
+  }
+
+  pub fn add_marker(&mut self, marker: Marker) -> usize {
+    self.song.get_markers_mut().add(marker)
+  }
+
+  pub fn remove_marker(&mut self, index: usize) -> Option<Marker> {
+    self.song.get_markers_mut().remove(index)
+  }
+
+  /// Locate to a marker by its index, returning whether it exists
+  pub fn locate_marker(&mut self, index: usize) -> bool {
+    let position = self
+      .song
+      .get_markers()
+      .get(index)
+      .map(|marker| marker.get_position());
+    if let Some(position) = position {
+      let signature_map = self.transport.get_signature_map();
+      self.set_position(BarsTime::from_ticks_with_map(position, signature_map));
+    }
+    position.is_some()
+  }
+
+  pub fn locate_next_marker(&mut self) -> bool {
+    let located = self.transport.locate_next_marker(self.song.get_markers());
+    if located {
+      self.send_locate();
+    }
+    located
+  }
+
+  pub fn locate_previous_marker(&mut self) -> bool {
+    let located = self
+      .transport
+      .locate_previous_marker(self.song.get_markers());
+    if located {
+      self.send_locate();
+    }
+    located
+  }
+
+  pub fn set_locator(&mut self, number: LocatorNumber, position: BarsTime) {
+    let position = position.to_ticks_with_map(self.transport.get_signature_map());
+    self.song.get_locators_mut().set(number, position);
+  }
+
+  /// Set the loop between two locators, returning whether both locators define a range
+  pub fn set_loop_from_locators(&mut self, first: LocatorNumber, second: LocatorNumber) -> bool {
+    match self.locators_range(first, second) {
+      Some((start, end)) => {
+        self.transport.set_loop_start(start);
+        self.transport.set_loop_end(end);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Set the punch range between two locators, returning whether both locators define a range
+  pub fn set_punch_from_locators(&mut self, first: LocatorNumber, second: LocatorNumber) -> bool {
+    match self.locators_range(first, second) {
+      Some((punch_in, punch_out)) => {
+        self.transport.set_punch_in(punch_in);
+        self.transport.set_punch_out(punch_out);
+        true
+      }
+      None => false,
+    }
+  }
+
+  fn locators_range(
+    &self,
+    first: LocatorNumber,
+    second: LocatorNumber,
+  ) -> Option<(BarsTime, BarsTime)> {
+    let signature_map = self.transport.get_signature_map();
+    self
+      .song
+      .get_locators()
+      .range(first, second)
+      .map(|(start, end)| {
+        (
+          BarsTime::from_ticks_with_map(start, signature_map),
+          BarsTime::from_ticks_with_map(end, signature_map),
+        )
+      })
+  }
+
+  fn send_locate(&mut self) {
+    let position = self.transport.get_smpte_position();
+    self.mmc.send(mmc::locate(&position));
+  }
+
+  /// Follow a MIDI Machine Control command received from a remote
+  pub fn machine_control(&mut self, command: MmcCommand) {
+    let playing = self.transport.is_playing();
+    match command {
+      MmcCommand::Play | MmcCommand::DeferredPlay if !playing => {
+        self.play(false);
+      }
+      MmcCommand::Stop | MmcCommand::Pause if playing => self.stop(),
+      MmcCommand::RecordStrobe => {
+        self.transport.set_recording(true);
+        if !playing {
+          self.play(false);
+        }
+      }
+      MmcCommand::RecordExit => self.transport.set_recording(false),
+      MmcCommand::Locate { .. } => {
+        if let Some(time) = mmc::locate_target(command) {
+          self.transport.set_smpte_position(time);
+          self.send_locate();
+        }
+      }
+      _ => {}
+    }
   }
 
   #[allow(clippy::too_many_arguments)]
@@ -101,16 +352,108 @@ impl Studio {
   {
     self.capture_midi_in(midi_input);
 
+    for index in 0..self.midi_buffer.len() {
+      if let Some(command) = self.mmc_slave.command(&self.midi_buffer[index]) {
+        self.machine_control(command);
+      }
+    }
+
+    if let Some(trigger) = self.config.midi.panic.trigger {
+      let triggered = self
+        .midi_buffer
+        .iter()
+        .any(|event| panic::is_trigger(trigger, &event.message));
+      self.panic_requested |= triggered;
+    }
+    if self.panic_requested {
+      self.panic_requested = false;
+      self.song.forget_notes();
+      self.active_notes.clear();
+      midi_output.panic();
+    }
+
+    self.mmc.flush(audio_output.time, midi_output);
+
+    for endpoint in self.removed_endpoints.drain(..) {
+      self
+        .active_notes
+        .release_endpoint(endpoint, audio_output.time, midi_output);
+      self.song.forget_endpoint(endpoint);
+    }
+
+    match self.config.sync.mode {
+      SyncMode::Internal => {}
+      SyncMode::MidiClock => self.midi_clock_slave.process_input(
+        &self.midi_buffer,
+        audio_output.time,
+        &mut self.transport,
+      ),
+      SyncMode::Mtc => {
+        self
+          .mtc_slave
+          .process_input(&self.midi_buffer, audio_output.time, &mut self.transport)
+      }
+    }
+
     if self.transport.is_playing() {
       let master_clock = audio_output.time;
 
+      // the input arrived while the previous buffer was playing, so it is placed one buffer later like the output
+      let sample_rate = *self.transport.get_sample_rate();
+      let input_latency = ClockTime::from_samples(audio_frames as u32, sample_rate);
+      for event in self.midi_buffer.iter_mut() {
+        event.timestamp += input_latency;
+      }
+
+      let smpte_offset = *self.transport.get_smpte_offset();
+
       let mut segments = self
         .transport
         .segments_iterator(master_clock, audio_frames as u32);
 
+      // the input events go to the segment playing when they were received, and the last segment takes the rest
+      let mut input_start = 0;
+
       while let Some(segment) = segments.next(&self.transport) {
-        self.metronome.process_segment(&segment, midi_output);
-        self.song.process_segment(&segment);
+        let input_end = if segments.is_finished() {
+          self.midi_buffer.len()
+        } else {
+          let clock_end = segment.master_clock + segment.clock_duration;
+          input_start
+            + self.midi_buffer[input_start..]
+              .iter()
+              .take_while(|event| event.timestamp < clock_end)
+              .count()
+        };
+        let input = &self.midi_buffer[input_start..input_end];
+        input_start = input_end;
+
+        if segment.is_count_in() {
+          if let Ok(mut midi_capture) = self.midi_capture.try_write() {
+            for event in input {
+              midi_capture.push(event, None);
+            }
+          }
+          // the song position does not move during the count-in, only the metronome counts
+          self.metronome.process_segment(&segment, midi_output);
+        } else {
+          if let Ok(mut midi_capture) = self.midi_capture.try_write() {
+            let signature_map = self.transport.get_signature_map();
+            for event in input {
+              let position = segment.position_at(event.timestamp);
+              midi_capture.push(event, Some(CapturedPosition::new(position, signature_map)));
+            }
+          }
+          self.song.record_segment(&segment, input);
+
+          self.midi_clock.process_segment(&segment, midi_output);
+          self
+            .mtc
+            .process_segment(&segment, &smpte_offset, midi_output);
+          self.metronome.process_segment(&segment, midi_output);
+          let mut song_output = TrackedOutput::new(&mut self.active_notes, midi_output);
+          self.song.process_segment(&segment, &mut song_output);
+        }
       }
 
       self.transport.update_from_segments(&segments);
@@ -126,8 +469,43 @@ impl Studio {
 //        }
 //      }
     } else {
+      if let Ok(mut midi_capture) = self.midi_capture.try_write() {
+        for event in self.midi_buffer.iter() {
+          midi_capture.push(event, None);
+        }
+      }
+      self.song.finish_recording();
+      let mut song_output = TrackedOutput::new(&mut self.active_notes, midi_output);
+      self.song.stop(audio_output.time, &mut song_output);
+      // anything the tracks didn't release
+      self
+        .active_notes
+        .release_all(audio_output.time, midi_output);
+      self.midi_clock.stop(audio_output.time, midi_output);
+      self
+        .mtc
+        .process_stopped(&self.transport, audio_output.time, midi_output);
       fill_with_zero(audio_output.buffer);
     }
+
+    let sample_rate = *self.transport.get_sample_rate();
+    self
+      .song
+      .process_audio(audio_output, audio_frames, sample_rate);
+    self.metronome.process_audio(audio_output, audio_frames);
+
+    if let Ok(mut midi_capture) = self.midi_capture.try_write() {
+      let signature_map = self.transport.get_signature_map();
+      let position = self
+        .transport
+        .get_position()
+        .to_ticks_with_map(signature_map);
+      midi_capture.set_song_time(CapturedSongTime {
+        playing: self.transport.is_playing(),
+        position: CapturedPosition::new(position, signature_map),
+        tempo: self.transport.get_tempo(),
+      });
+    }
   }
 
   fn capture_midi_in<MidiIn>(&mut self, midi_input: &mut MidiIn) where MidiIn: MidiInput {
@@ -147,3 +525,375 @@ impl fmt::Debug for Studio {
     write!(f, "Studio({:?})", self.song.get_name())
   }
 }
+
+#[cfg(test)]
+mod test {
+
+  use super::Studio;
+  use crate::audio::{AudioInput, AudioOutput};
+  use crate::color::Color;
+  use crate::config::{Config, SyncMode};
+  use crate::midi::buffer::{Endpoint, EventIo};
+  use crate::midi::io::MidiInput;
+  use crate::midi::mmc::MmcCommand;
+  use crate::midi::Message;
+  use crate::song::clips::{
+    tracker::{Cell, TrackerPattern},
+    Clip,
+  };
+  use crate::song::import::{Module, ModulePattern, MODULE_SAMPLE_RATE};
+  use crate::song::markers::Marker;
+  use crate::song::sampler::{SampleZone, SamplerInstrument};
+  use crate::song::source::audio::Sample;
+  use crate::song::source::notes::{Note, NotesClip as NotesSourceClip};
+  use crate::testing::{midi_track, NoMidiInput, VecMidiInput, VecMidiOutput};
+  use crate::time::{
+    smpte::FrameRate, ticks::TICKS_RESOLUTION, BarsTime, ClockTime, Signature, SmpteTime, Tempo,
+    TicksTime,
+  };
+
+  const AUDIO_FRAMES: usize = 512;
+
+  fn process(studio: &mut Studio, time: ClockTime) -> Vec<EventIo> {
+    process_input(studio, time, &mut NoMidiInput)
+  }
+
+  fn process_input<MidiIn>(
+    studio: &mut Studio,
+    time: ClockTime,
+    midi_input: &mut MidiIn,
+  ) -> Vec<EventIo>
+  where
+    MidiIn: MidiInput,
+  {
+    let input_buffer = [0.0; AUDIO_FRAMES * 2];
+    let mut output_buffer = [0.0; AUDIO_FRAMES * 2];
+    let audio_input = AudioInput::new(time, 2, &input_buffer);
+    let mut audio_output = AudioOutput::new(time, 2, &mut output_buffer);
+    let mut midi_output = VecMidiOutput(Vec::new());
+    studio.process(
+      AUDIO_FRAMES,
+      &audio_input,
+      &mut audio_output,
+      midi_input,
+      &mut midi_output,
+    );
+    midi_output.0
+  }
+
+  #[test]
+  pub fn machine_control() {
+    let mut studio = Studio::new(Config::default());
+
+    studio.machine_control(MmcCommand::Play);
+    studio.machine_control(MmcCommand::DeferredPlay);
+    assert!(studio.transport.is_playing());
+
+    studio.machine_control(MmcCommand::Stop);
+    studio.machine_control(MmcCommand::Stop);
+    assert!(!studio.transport.is_playing());
+
+    let time = SmpteTime::new(FrameRate::Fps25, 0, 0, 2, 0, 0).unwrap();
+    studio.machine_control(super::mmc::locate(&time));
+    assert_eq!(studio.transport.get_position(), BarsTime::new(1, 0, 0, 0));
+
+    studio.machine_control(MmcCommand::RecordStrobe);
+    assert!(studio.transport.is_playing());
+    assert!(studio.transport.is_recording());
+    studio.machine_control(MmcCommand::RecordExit);
+    assert!(studio.transport.is_playing());
+    assert!(!studio.transport.is_recording());
+  }
+
+  #[test]
+  pub fn leave_the_midi_clock_sync() {
+    let mut studio = Studio::new(Config::default());
+    studio.set_sync_mode(SyncMode::MidiClock);
+    studio
+      .transport
+      .set_tempo_override(Some(Tempo::from_bpm(100.0)));
+    studio.set_sync_mode(SyncMode::MidiClock);
+    assert_eq!(
+      studio.transport.get_tempo_override(),
+      Some(Tempo::from_bpm(100.0))
+    );
+    studio.set_sync_mode(SyncMode::Internal);
+    assert_eq!(studio.transport.get_tempo_override(), None);
+    assert_eq!(studio.transport.get_tempo(), Tempo::new(120));
+  }
+
+  #[test]
+  pub fn markers_and_locators() {
+    let mut studio = Studio::new(Config::default());
+    let signature_map = studio.transport.get_signature_map().clone();
+    let ticks = |bars: u16| BarsTime::from_bars(bars).to_ticks_with_map(&signature_map);
+    let color = Color::from_rgb(255, 0, 0);
+    studio.add_marker(Marker::new("chorus", color.clone(), ticks(8)));
+    studio.add_marker(Marker::new("verse", color, ticks(4)));
+
+    assert!(studio.locate_marker(1));
+    assert_eq!(studio.transport.get_position(), BarsTime::from_bars(8));
+    assert!(!studio.locate_marker(2));
+    assert!(studio.locate_previous_marker());
+    assert_eq!(studio.transport.get_position(), BarsTime::from_bars(4));
+    assert!(studio.locate_next_marker());
+    assert_eq!(studio.transport.get_position(), BarsTime::from_bars(8));
+    assert!(!studio.locate_next_marker());
+    assert_eq!(studio.transport.get_position(), BarsTime::from_bars(8));
+    studio.set_position(BarsTime::new(2, 1, 0, 0));
+    assert!(!studio.locate_previous_marker());
+    assert_eq!(studio.transport.get_position(), BarsTime::new(2, 1, 0, 0));
+    assert!(studio.locate_next_marker());
+    assert_eq!(studio.transport.get_position(), BarsTime::from_bars(4));
+
+    studio.set_locator(1, BarsTime::from_bars(6));
+    studio.set_locator(2, BarsTime::from_bars(2));
+    assert!(!studio.set_loop_from_locators(1, 3));
+    assert!(studio.set_loop_from_locators(1, 2));
+    assert_eq!(studio.transport.get_loop_start(), BarsTime::from_bars(2));
+    assert_eq!(studio.transport.get_loop_end(), BarsTime::from_bars(6));
+    assert!(studio.set_punch_from_locators(2, 1));
+    assert_eq!(studio.transport.get_punch_in(), BarsTime::from_bars(2));
+    assert_eq!(studio.transport.get_punch_out(), BarsTime::from_bars(6));
+    assert_eq!(studio.song.get_locators().get(1), Some(ticks(6)));
+    assert_eq!(
+      studio.remove_marker(0).map(|marker| marker.get_position()),
+      Some(ticks(4))
+    );
+  }
+
+  #[test]
+  pub fn release_the_notes_of_a_removed_endpoint() {
+    let mut studio = Studio::new(Config::default());
+    let endpoint = Endpoint::Id(3);
+    let track = midi_track(endpoint, 0);
+    let index = studio.song_mut().add_track(track);
+    let signature = Signature::new(4, 4);
+    let bar = BarsTime::from_bars(1).to_ticks(signature);
+    let mut notes = NotesSourceClip::new();
+    notes.add_note(Note::new(60, 1.0, TicksTime::zero(), bar));
+    let clip = Clip {
+      uuid: 0,
+      name: "clip".to_string(),
+      signature,
+      start: TicksTime::zero(),
+      length: bar,
+    };
+    studio.song_mut().add_notes_clip(index, clip, notes);
+    studio.play(true);
+
+    let buffer_duration = ClockTime::from_samples(AUDIO_FRAMES as u32, 44100);
+    let notes = |events: Vec<EventIo>| -> Vec<Message> {
+      events
+        .into_iter()
+        .filter(|event| event.endpoint == endpoint)
+        .map(|event| event.message)
+        .collect()
+    };
+    let events = process(&mut studio, ClockTime::zero());
+    assert_eq!(
+      notes(events),
+      vec![Message::NoteOn {
+        channel: 0,
+        key: 60,
+        velocity: 127
+      }]
+    );
+
+    studio.remove_endpoint(endpoint);
+    let events = process(&mut studio, buffer_duration);
+    assert_eq!(
+      notes(events),
+      vec![Message::NoteOff {
+        channel: 0,
+        key: 60,
+        velocity: 0
+      }]
+    );
+    let events = process(&mut studio, buffer_duration + buffer_duration);
+    assert!(notes(events).is_empty());
+
+    // the track doesn't release the note again
+    studio.stop();
+    let events = process(&mut studio, buffer_duration * 3);
+    assert!(notes(events).is_empty());
+  }
+
+  #[test]
+  pub fn record_the_input_where_it_was_played() {
+    let mut studio = Studio::new(Config::default());
+    let track = midi_track(Endpoint::Default, 0);
+    let index = studio.song_mut().add_track(track);
+    let take = Clip {
+      uuid: 0,
+      name: "Recording".to_string(),
+      signature: Signature::new(4, 4),
+      start: TicksTime::zero(),
+      length: TicksTime::zero(),
+    };
+    studio.song_mut().arm_track(index, Some(take));
+    studio.transport.set_recording(true);
+    studio.play(true);
+
+    // a short note received while a buffer was playing, and given to the next one
+    let note = |seconds: f64, velocity: u8| {
+      let message = Message::NoteOn {
+        channel: 0,
+        key: 60,
+        velocity,
+      };
+      EventIo::new(ClockTime::from_seconds(seconds), Endpoint::Id(0), message)
+    };
+    let mut received = vec![note(0.25, 127), note(0.255, 0)];
+    let buffer_duration = ClockTime::from_samples(AUDIO_FRAMES as u32, 44100);
+    let mut time = ClockTime::zero();
+    while time < ClockTime::from_seconds(0.5) {
+      let count = received
+        .iter()
+        .take_while(|event| event.timestamp < time)
+        .count();
+      let mut midi_input = VecMidiInput(received.drain(..count).collect());
+      process_input(&mut studio, time, &mut midi_input);
+      time += buffer_duration;
+    }
+    studio.stop();
+    process(&mut studio, time);
+
+    let clip = studio
+      .song()
+      .get_track(index)
+      .and_then(|track| track.get_clip(0))
+      .unwrap();
+    let source = studio.song().get_notes_source().read().unwrap();
+    let notes: Vec<Note> = source
+      .get_clip(clip.uuid)
+      .unwrap()
+      .notes_range(TicksTime::zero(), TicksTime::new(u64::MAX))
+      .collect();
+    assert_eq!(notes.len(), 1);
+    // 120 bpm, so a sixteenth lasts 125 ms
+    let ticks = |seconds: f64| (seconds / 0.125 * TICKS_RESOLUTION as f64) as u64;
+    let latency = buffer_duration.to_seconds();
+    let start = u64::from(notes[0].get_start());
+    assert!(start.abs_diff(ticks(0.25 + latency)) < TICKS_RESOLUTION / 1000);
+    let length = u64::from(notes[0].get_length());
+    assert!(length.abs_diff(ticks(0.005)) < TICKS_RESOLUTION / 1000);
+  }
+
+  #[test]
+  pub fn capture_notes_played_while_stopped() {
+    let mut studio = Studio::new(Config::default());
+    let track = midi_track(Endpoint::Default, 0);
+    let index = studio.song_mut().add_track(track);
+    studio.song_mut().select_track(Some(index));
+    studio.set_position(BarsTime::new(2, 1, 0, 0));
+    // the capture knows where the song is after a buffer
+    process(&mut studio, ClockTime::zero());
+
+    // quarters at 100 bpm after something played long before
+    let push = |seconds: f64, key: u8, velocity: u8| {
+      let message = Message::NoteOn {
+        channel: 0,
+        key,
+        velocity,
+      };
+      let event = EventIo::new(ClockTime::from_seconds(seconds), Endpoint::Id(0), message);
+      studio.midi_capture.write().unwrap().push(&event, None);
+    };
+    push(1.0, 40, 100);
+    push(1.5, 40, 0);
+    for beat in 0..4 {
+      let seconds = 30.0 + f64::from(beat) * 0.6;
+      push(seconds, 60 + beat as u8, 127);
+      push(seconds + 0.3, 60 + beat as u8, 0);
+    }
+
+    let captured = studio
+      .get_midi_capture()
+      .read()
+      .unwrap()
+      .copy_last_phrase()
+      .into_clip()
+      .unwrap();
+    let clip_index = studio.add_captured_clip(captured).unwrap();
+    assert_eq!(studio.transport.get_tempo(), Tempo::from_bpm(100.0));
+
+    let clip = studio
+      .song()
+      .get_track(index)
+      .and_then(|track| track.get_clip(clip_index))
+      .unwrap();
+    let bar = 16 * TICKS_RESOLUTION;
+    assert_eq!(clip.start, TicksTime::new(2 * bar));
+    // the last note goes into the next bar
+    assert_eq!(clip.length, TicksTime::new(2 * bar));
+
+    let source = studio.song().get_notes_source().read().unwrap();
+    let notes: Vec<Note> = source
+      .get_clip(clip.uuid)
+      .unwrap()
+      .notes_range(TicksTime::zero(), TicksTime::new(u64::MAX))
+      .collect();
+    assert_eq!(notes.len(), 4);
+    let beat = 4 * TICKS_RESOLUTION;
+    for (index, note) in notes.iter().enumerate() {
+      let start = u64::from(note.get_start());
+      let expected = (index as u64 + 1) * beat;
+      assert!(start.abs_diff(expected) < TICKS_RESOLUTION / 1000);
+      assert_eq!(note.get_key(), 60 + index as u8);
+    }
+  }
+
+  #[test]
+  pub fn play_an_imported_module() {
+    let mut studio = Studio::new(Config::default());
+    let mut instrument = SamplerInstrument::new("beep", 0);
+    instrument.zones.push(SampleZone::new(0, 119, 0));
+    let mut pattern = TrackerPattern::new("intro", 16, 4);
+    pattern.set_cell(0, 0, Cell::note(48, 1));
+    let module = Module {
+      name: "module".to_string(),
+      num_channels: 4,
+      ticks_per_line: 6,
+      bpm: 125,
+      orders: vec![0],
+      patterns: vec![ModulePattern {
+        pattern,
+        speed_changes: Vec::new(),
+      }],
+      instruments: vec![instrument],
+      samples: vec![Sample::new("beep", MODULE_SAMPLE_RATE, vec![0.5; 4096])],
+    };
+    let imported = module.import(studio.song().get_audio_source());
+    studio.import_module(imported);
+
+    let process_audio = |studio: &mut Studio, time: ClockTime| -> Vec<f32> {
+      let input_buffer = [0.0; AUDIO_FRAMES * 2];
+      let mut output_buffer = vec![0.0; AUDIO_FRAMES * 2];
+      let audio_input = AudioInput::new(time, 2, &input_buffer);
+      let mut audio_output = AudioOutput::new(time, 2, &mut output_buffer);
+      studio.process(
+        AUDIO_FRAMES,
+        &audio_input,
+        &mut audio_output,
+        &mut NoMidiInput,
+        &mut VecMidiOutput(Vec::new()),
+      );
+      output_buffer
+    };
+
+    let buffer = process_audio(&mut studio, ClockTime::zero());
+    assert!(buffer.iter().all(|sample| *sample == 0.0));
+
+    studio.play(true);
+    let buffer_duration = ClockTime::from_samples(AUDIO_FRAMES as u32, 44100);
+    let buffer = process_audio(&mut studio, buffer_duration);
+    assert!(buffer[0] > 0.0);
+    assert!(buffer.chunks(2).all(|frame| frame[0] == frame[1]));
+
+    studio.stop();
+    process_audio(&mut studio, buffer_duration * 2);
+    let buffer = process_audio(&mut studio, buffer_duration * 3);
+    assert!(buffer.iter().all(|sample| *sample == 0.0));
+  }
+}
@@ -0,0 +1,230 @@
+use crate::config::MidiClock as MidiClockConfig;
+use crate::midi;
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::io::MidiOutput;
+use crate::time::{ticks::TICKS_RESOLUTION, ClockTime, TicksTime};
+use crate::transport::Segment;
+
+/// MIDI Timing Clocks are sent 24 times per quarter note, independently of the signature
+pub const CLOCKS_PER_QUARTER: u64 = 24;
+
+pub const TICKS_PER_CLOCK: u64 = 4 * TICKS_RESOLUTION / CLOCKS_PER_QUARTER;
+
+/// The Song Position Pointer counts MIDI beats (sixteenths) with 14 bits
+const MAX_SONG_POSITION: u64 = 0x3fff;
+
+/// Sends MIDI Timing Clock, Start, Continue, Stop and Song Position Pointer
+/// so external gear can follow the transport
+pub struct MidiClockMaster {
+  enabled: bool,
+  endpoints: Vec<Endpoint>,
+  running: bool,
+  next_position: TicksTime,
+  next_clock_position: TicksTime,
+}
+
+impl MidiClockMaster {
+  pub fn new(config: MidiClockConfig) -> MidiClockMaster {
+    let endpoints = config.ports.iter().map(Endpoint::from).collect();
+
+    MidiClockMaster {
+      enabled: config.enabled,
+      endpoints,
+      running: false,
+      next_position: TicksTime::zero(),
+      next_clock_position: TicksTime::zero(),
+    }
+  }
+
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+
+  pub fn endpoints(&self) -> &[Endpoint] {
+    self.endpoints.as_slice()
+  }
+
+  pub fn process_segment<MidiOut>(&mut self, segment: &Segment, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
+    if self.enabled {
+      if !self.running {
+        if segment.start_position == TicksTime::zero() {
+          self.push(midi_output, segment.master_clock, midi::Message::Start);
+          self.next_clock_position = TicksTime::zero();
+          self.running = true;
+        } else {
+          self.resume(segment, midi_output);
+        }
+      } else if segment.start_position != self.next_position {
+        // the transport was located somewhere else or it wrapped around the loop
+        self.push(midi_output, segment.master_clock, midi::Message::Stop);
+        self.resume(segment, midi_output);
+      }
+
+      while self.next_clock_position < segment.end_position {
+        let clock_time = segment.master_clock_at(self.next_clock_position);
+        self.push(midi_output, clock_time, midi::Message::TimingClock);
+        self.next_clock_position += TicksTime::new(TICKS_PER_CLOCK);
+      }
+
+      self.next_position = segment.end_position;
+    } else {
+      self.stop(segment.master_clock, midi_output);
+    }
+  }
+
+  /// Notify the external gear that the transport stopped
+  pub fn stop<MidiOut>(&mut self, master_clock: ClockTime, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
+    if self.running {
+      self.push(midi_output, master_clock, midi::Message::Stop);
+      self.running = false;
+    }
+  }
+
+  /// Continue from the first sixteenth at or after the segment start,
+  /// which is where the next clock will be sent
+  fn resume<MidiOut>(&mut self, segment: &Segment, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
+    let position = u64::from(segment.start_position);
+    let song_position = position.div_ceil(TICKS_RESOLUTION).min(MAX_SONG_POSITION);
+    let message = midi::Message::SongPositionPointer {
+      beats: song_position as u16,
+    };
+    self.push(midi_output, segment.master_clock, message);
+    self.push(midi_output, segment.master_clock, midi::Message::Continue);
+    self.next_clock_position = TicksTime::new(song_position * TICKS_RESOLUTION);
+    self.running = true;
+  }
+
+  fn push<MidiOut>(&self, midi_output: &mut MidiOut, timestamp: ClockTime, message: midi::Message)
+  where
+    MidiOut: MidiOutput,
+  {
+    for endpoint in self.endpoints.iter() {
+      midi_output.push(EventIo::new(timestamp, *endpoint, message.clone()));
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::MidiClockMaster;
+  use crate::config::MidiClock as MidiClockConfig;
+  use crate::midi::Message;
+  use crate::testing::VecMidiOutput;
+  use crate::time::{clock, BarsTime, ClockTime};
+  use crate::transport::Transport;
+
+  const SAMPLE_RATE: u32 = 44100;
+
+  fn clock_master() -> MidiClockMaster {
+    let config = MidiClockConfig {
+      enabled: true,
+      ..MidiClockConfig::default()
+    };
+    MidiClockMaster::new(config)
+  }
+
+  fn play(
+    transport: &mut Transport,
+    clock_master: &mut MidiClockMaster,
+    seconds: f64,
+  ) -> Vec<(ClockTime, Message)> {
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    while master_clock < ClockTime::from_seconds(seconds) {
+      let mut segments = transport.segments_iterator(master_clock, samples);
+      while let Some(segment) = segments.next(transport) {
+        clock_master.process_segment(&segment, &mut midi_output);
+      }
+      transport.update_from_segments(&segments);
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+    clock_master.stop(master_clock, &mut midi_output);
+    midi_output
+      .0
+      .into_iter()
+      .map(|event| (event.timestamp, event.message))
+      .collect()
+  }
+
+  fn assert_clocks(events: &[(ClockTime, Message)], start_seconds: f64, clock_seconds: f64) {
+    for (index, (timestamp, message)) in events.iter().enumerate() {
+      assert_eq!(*message, Message::TimingClock);
+      let expected = ClockTime::from_seconds(start_seconds + index as f64 * clock_seconds);
+      assert!(timestamp.units().abs_diff(expected.units()) < clock::UNITS_PER_MILLI / 1000);
+    }
+  }
+
+  #[test]
+  pub fn clocks_at_24_ppqn() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.play(false);
+    let mut clock_master = clock_master();
+
+    let events = play(&mut transport, &mut clock_master, 1.0);
+
+    // at 120 bpm a quarter note takes half a second
+    assert_eq!(events[0], (ClockTime::zero(), Message::Start));
+    let clocks = &events[1..events.len() - 1];
+    assert!(clocks.len() >= 48);
+    assert_clocks(clocks, 0.0, 0.5 / 24.0);
+    assert_eq!(events.last().unwrap().1, Message::Stop);
+  }
+
+  #[test]
+  pub fn song_position_on_continue() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_position(BarsTime::new(1, 1, 0, 0));
+    transport.play(false);
+    let mut clock_master = clock_master();
+
+    let events = play(&mut transport, &mut clock_master, 0.5);
+
+    assert_eq!(events[0].1, Message::SongPositionPointer { beats: 20 });
+    assert_eq!(events[1].1, Message::Continue);
+    assert_clocks(&events[2..events.len() - 1], 0.0, 0.5 / 24.0);
+  }
+
+  #[test]
+  pub fn song_position_on_loop_wrap() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_start(BarsTime::new(0, 2, 0, 0));
+    transport.set_loop_end(BarsTime::new(1, 0, 0, 0));
+    transport.play(false);
+    let mut clock_master = clock_master();
+
+    let events = play(&mut transport, &mut clock_master, 2.5);
+
+    let wrap_index = events
+      .iter()
+      .position(|(_, message)| *message == Message::Stop)
+      .unwrap();
+    assert_clocks(&events[1..wrap_index], 0.0, 0.5 / 24.0);
+    assert_eq!(wrap_index, 1 + 4 * 24);
+
+    let wrap_time = ClockTime::from_seconds(2.0);
+    assert!(events[wrap_index].0.units().abs_diff(wrap_time.units()) < 1000);
+    assert_eq!(
+      events[wrap_index + 1].1,
+      Message::SongPositionPointer { beats: 8 }
+    );
+    assert_eq!(events[wrap_index + 2].1, Message::Continue);
+    assert_clocks(&events[wrap_index + 3..events.len() - 1], 2.0, 0.5 / 24.0);
+  }
+}
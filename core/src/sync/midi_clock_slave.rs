@@ -0,0 +1,389 @@
+use crate::midi::buffer::EventIo;
+use crate::midi::Message;
+use crate::sync::midi_clock::{CLOCKS_PER_QUARTER, TICKS_PER_CLOCK};
+use crate::time::{clock, ticks::TICKS_RESOLUTION, BarsTime, ClockTime, Tempo, TicksTime};
+use crate::transport::Transport;
+
+/// Number of clock intervals measured at once, a quarter note, so the jitter of every clock is averaged out
+const WINDOW_SIZE: usize = CLOCKS_PER_QUARTER as usize;
+
+/// Weight of every new measure in the smoothed clock interval
+const SMOOTHING_FACTOR: f64 = 0.1;
+
+/// Maximum relative difference between a measure and the smoothed interval to consider the tempo locked
+const LOCK_TOLERANCE: f64 = 0.01;
+
+/// Clock intervals longer than this (20 bpm) mean that the clock was interrupted
+const MAX_CLOCK_INTERVAL_SECONDS: f64 = 60.0 / (20.0 * CLOCKS_PER_QUARTER as f64);
+
+/// Minimum change of the estimated tempo, in bpm, to update the transport.
+/// Smaller differences are left to the position following, which locates the transport when it drifts.
+const TEMPO_UPDATE_THRESHOLD: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+  Stopped,
+  /// Start or Continue received, waiting for the first clock
+  Armed,
+  Running,
+}
+
+/// Drives the transport from an incoming MIDI clock.
+/// The tempo is measured over the last quarter note of clocks and smoothed with a one pole filter,
+/// and the position follows the clocks counted since Start, Continue or Song Position Pointer.
+/// The measured tempo overrides the tempo map of the transport, so the tempo of the song does not change.
+pub struct MidiClockSlave {
+  state: State,
+  clock_times: [ClockTime; WINDOW_SIZE + 1],
+  num_clock_times: usize,
+  clock_interval: f64,
+  locked: bool,
+  base_position: TicksTime,
+  running_clocks: u64,
+}
+
+impl Default for MidiClockSlave {
+  fn default() -> Self {
+    MidiClockSlave {
+      state: State::Stopped,
+      clock_times: [ClockTime::zero(); WINDOW_SIZE + 1],
+      num_clock_times: 0,
+      clock_interval: 0.0,
+      locked: false,
+      base_position: TicksTime::zero(),
+      running_clocks: 0,
+    }
+  }
+}
+
+impl MidiClockSlave {
+  pub fn new() -> MidiClockSlave {
+    MidiClockSlave::default()
+  }
+
+  pub fn is_locked(&self) -> bool {
+    self.locked
+  }
+
+  /// Estimated tempo in quarter notes per minute
+  pub fn get_tempo(&self) -> Option<Tempo> {
+    if self.clock_interval > 0.0 {
+      Some(Tempo::from_bpm(self.bpm()))
+    } else {
+      None
+    }
+  }
+
+  /// Update the transport with the MIDI messages received until the master clock
+  pub fn process_input(
+    &mut self,
+    events: &[EventIo],
+    master_clock: ClockTime,
+    transport: &mut Transport,
+  ) {
+    for event in events.iter() {
+      match event.message {
+        Message::Start => {
+          transport.set_position(BarsTime::from_bars(0));
+          self.arm(TicksTime::zero());
+        }
+        Message::Continue => {
+          let position = transport
+            .get_position()
+            .to_ticks_with_map(transport.get_signature_map());
+          self.arm(position);
+        }
+        Message::Stop => {
+          self.state = State::Stopped;
+          if transport.is_playing() {
+            transport.stop();
+          }
+          transport.set_tempo_override(None);
+        }
+        Message::SongPositionPointer { beats } if self.state != State::Running => {
+          let position = TicksTime::new(u64::from(beats) * TICKS_RESOLUTION);
+          transport.set_position(BarsTime::from_ticks_with_map(
+            position,
+            transport.get_signature_map(),
+          ));
+          self.base_position = position;
+        }
+        Message::TimingClock => self.clock(event.timestamp, transport),
+        _ => {}
+      }
+    }
+
+    if self.state == State::Running && self.locked {
+      self.follow_position(master_clock, transport);
+    }
+  }
+
+  fn arm(&mut self, position: TicksTime) {
+    self.state = State::Armed;
+    self.base_position = position;
+    self.running_clocks = 0;
+  }
+
+  fn clock(&mut self, timestamp: ClockTime, transport: &mut Transport) {
+    if self.state == State::Armed {
+      self.state = State::Running;
+      if !transport.is_playing() {
+        transport.play(false);
+      }
+    }
+    if self.state == State::Running {
+      self.running_clocks += 1;
+    }
+
+    if self.num_clock_times > 0 {
+      let last_time = self.clock_time(0);
+      if seconds_between(last_time, timestamp) > MAX_CLOCK_INTERVAL_SECONDS {
+        self.num_clock_times = 0;
+        self.locked = false;
+      }
+    }
+    self.clock_times[self.num_clock_times % self.clock_times.len()] = timestamp;
+    self.num_clock_times += 1;
+
+    if self.num_clock_times > WINDOW_SIZE {
+      let measured_interval =
+        seconds_between(self.clock_time(WINDOW_SIZE), timestamp) / WINDOW_SIZE as f64;
+      if self.clock_interval > 0.0 {
+        self.clock_interval += SMOOTHING_FACTOR * (measured_interval - self.clock_interval);
+      } else {
+        self.clock_interval = measured_interval;
+      }
+      let deviation = (measured_interval - self.clock_interval).abs() / self.clock_interval;
+      self.locked = deviation < LOCK_TOLERANCE;
+      // the clocks received while stopped don't take the tempo of the song
+      if self.state == State::Running {
+        self.update_tempo(transport);
+      }
+    }
+  }
+
+  /// Time of a past clock, where 0 is the last one received
+  fn clock_time(&self, clocks_ago: usize) -> ClockTime {
+    let len = self.clock_times.len();
+    self.clock_times[(self.num_clock_times - 1 - clocks_ago) % len]
+  }
+
+  fn bpm(&self) -> f64 {
+    60.0 / (CLOCKS_PER_QUARTER as f64 * self.clock_interval)
+  }
+
+  /// The MIDI clocks count quarter notes like the tempo override, which the transport converts for every signature
+  fn update_tempo(&self, transport: &mut Transport) {
+    let bpm = self.bpm();
+    let changed = transport
+      .get_tempo_override()
+      .is_none_or(|tempo| (bpm - tempo.get_value()).abs() >= TEMPO_UPDATE_THRESHOLD);
+    if changed {
+      transport.set_tempo_override(Some(Tempo::from_bpm(bpm)));
+    }
+  }
+
+  /// Locate the transport when it drifts more than half a clock from the position given by the clocks
+  fn follow_position(&self, master_clock: ClockTime, transport: &mut Transport) {
+    let clocks_position =
+      f64::from(self.base_position) + (self.running_clocks - 1) as f64 * TICKS_PER_CLOCK as f64;
+    let elapsed_clocks = seconds_between(self.clock_time(0), master_clock) / self.clock_interval;
+    let expected_position = clocks_position + elapsed_clocks * TICKS_PER_CLOCK as f64;
+    let signature_map = transport.get_signature_map();
+    let position = f64::from(transport.get_position().to_ticks_with_map(signature_map));
+    if (position - expected_position).abs() > TICKS_PER_CLOCK as f64 / 2.0 {
+      let expected_position = TicksTime::new(expected_position.max(0.0).round() as u64);
+      let position = BarsTime::from_ticks_with_map(expected_position, signature_map);
+      transport.set_position(position);
+    }
+  }
+}
+
+/// Signed number of seconds from one time to another
+fn seconds_between(from: ClockTime, to: ClockTime) -> f64 {
+  (to.units() as f64 - from.units() as f64) / clock::UNITS_PER_SECOND as f64
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::MidiClockSlave;
+  use crate::midi::buffer::{Endpoint, EventIo};
+  use crate::midi::Message;
+  use crate::sync::midi_clock::{CLOCKS_PER_QUARTER, TICKS_PER_CLOCK};
+  use crate::time::{ticks::TICKS_RESOLUTION, BarsTime, ClockTime, Signature, Tempo, TicksTime};
+  use crate::transport::Transport;
+
+  const SAMPLE_RATE: u32 = 44100;
+  const SAMPLES: u32 = 512;
+
+  /// Clock stream at a tempo with up to half a millisecond of deterministic jitter
+  fn clock_stream(bpm: f64, start_seconds: f64, num_clocks: usize) -> Vec<EventIo> {
+    let interval = 60.0 / (CLOCKS_PER_QUARTER as f64 * bpm);
+    let mut events = vec![EventIo::new(
+      ClockTime::from_seconds(start_seconds),
+      Endpoint::None,
+      Message::Start,
+    )];
+    for index in 0..num_clocks {
+      let jitter = ((index * 7919) % 11) as f64 - 5.0;
+      let seconds = start_seconds + index as f64 * interval + jitter * 0.000_1;
+      events.push(EventIo::new(
+        ClockTime::from_seconds(seconds),
+        Endpoint::None,
+        Message::TimingClock,
+      ));
+    }
+    events
+  }
+
+  /// Process buffers while receiving the events until the last one,
+  /// and return the number of clocks received when the tempo got locked
+  fn follow(
+    slave: &mut MidiClockSlave,
+    transport: &mut Transport,
+    events: Vec<EventIo>,
+  ) -> Option<usize> {
+    let end_time = events.last().unwrap().timestamp;
+    let mut events = events.into_iter().peekable();
+    let mut master_clock = ClockTime::zero();
+    let mut num_clocks = 0;
+    let mut locked_at = None;
+    while master_clock < end_time {
+      let mut received = Vec::new();
+      while let Some(event) = events.next_if(|event| event.timestamp < master_clock) {
+        if event.message == Message::TimingClock {
+          num_clocks += 1;
+        }
+        received.push(event);
+      }
+      slave.process_input(&received, master_clock, transport);
+      if slave.is_locked() && locked_at.is_none() {
+        locked_at = Some(num_clocks);
+      }
+      if transport.is_playing() {
+        let mut segments = transport.segments_iterator(master_clock, SAMPLES);
+        while segments.next(transport).is_some() {}
+        transport.update_from_segments(&segments);
+      }
+      master_clock += ClockTime::from_samples(SAMPLES, SAMPLE_RATE);
+    }
+    locked_at
+  }
+
+  #[test]
+  pub fn lock_to_clock_stream() {
+    let bpm = 123.45;
+    let mut slave = MidiClockSlave::new();
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+
+    let start_seconds = 0.1;
+    let num_clocks = 16 * CLOCKS_PER_QUARTER as usize;
+    let events = clock_stream(bpm, start_seconds, num_clocks);
+    let end_seconds = events.last().unwrap().timestamp.to_seconds();
+    let locked_at = follow(&mut slave, &mut transport, events);
+
+    // locked after measuring the first quarter note and one more buffer
+    assert!(locked_at.unwrap() <= 2 * CLOCKS_PER_QUARTER as usize);
+    assert!(slave.is_locked());
+    let tempo = slave.get_tempo().unwrap().get_value();
+    assert!((tempo - bpm).abs() < 0.05, "tempo {} is not {}", tempo, bpm);
+    assert!((transport.get_tempo().get_value() - bpm).abs() < 0.05);
+
+    assert!(transport.is_playing());
+    let ticks_per_second = bpm / 60.0 * 4.0 * TICKS_RESOLUTION as f64;
+    let elapsed_seconds = end_seconds - start_seconds;
+    let expected_position = elapsed_seconds * ticks_per_second;
+    let position = transport
+      .get_position()
+      .to_ticks_with_map(transport.get_signature_map());
+    let difference = (f64::from(position) - expected_position).abs();
+    assert!(difference < TICKS_PER_CLOCK as f64);
+  }
+
+  #[test]
+  pub fn override_the_tempo_of_the_song() {
+    let mut slave = MidiClockSlave::new();
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_tempo_change(BarsTime::new(1, 0, 0, 0), Tempo::new(60));
+
+    let events = clock_stream(100.0, 0.0, 12 * CLOCKS_PER_QUARTER as usize);
+    follow(&mut slave, &mut transport, events);
+
+    // the tempo change at the second bar is ignored, and the song keeps its tempo map
+    assert!(transport.get_position() > BarsTime::new(2, 0, 0, 0));
+    assert!((transport.get_tempo().get_value() - 100.0).abs() < 0.1);
+    let tempo_map = transport.get_tempo_map();
+    assert_eq!(tempo_map.changes().len(), 2);
+    assert_eq!(tempo_map.tempo_at(TicksTime::zero()), Tempo::new(120));
+
+    transport.set_tempo_override(None);
+    assert_eq!(transport.get_tempo(), Tempo::new(60));
+  }
+
+  #[test]
+  pub fn tempo_in_signature_beats() {
+    let mut slave = MidiClockSlave::new();
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_signature(Signature::new(6, 8));
+
+    transport.set_signature_change(2, Signature::new(3, 4));
+
+    let events = clock_stream(100.0, 0.0, 4 * CLOCKS_PER_QUARTER as usize);
+    follow(&mut slave, &mut transport, events);
+
+    // 100 quarter notes per minute are 200 eighths per minute
+    let tempo = transport.get_tempo().get_value();
+    assert!((tempo - 200.0).abs() < 0.1);
+    let tempo_override = transport.get_tempo_override().unwrap().get_value();
+    assert!((tempo_override - 100.0).abs() < 0.1);
+
+    // the third bar counts quarter notes
+    let events = clock_stream(100.0, 0.0, 8 * CLOCKS_PER_QUARTER as usize);
+    follow(&mut slave, &mut transport, events);
+    assert!(transport.get_position() >= BarsTime::new(2, 0, 0, 0));
+    let tempo = transport.get_tempo().get_value();
+    assert!((tempo - 100.0).abs() < 0.1);
+
+    let stop = EventIo::new(ClockTime::from_seconds(4.0), Endpoint::None, Message::Stop);
+    slave.process_input(&[stop], ClockTime::from_seconds(4.0), &mut transport);
+    assert_eq!(transport.get_tempo_override(), None);
+  }
+
+  #[test]
+  pub fn transport_messages() {
+    let mut slave = MidiClockSlave::new();
+    let mut transport = Transport::new(SAMPLE_RATE);
+    let event = |seconds: f64, message: Message| {
+      EventIo::new(ClockTime::from_seconds(seconds), Endpoint::None, message)
+    };
+
+    let events = vec![
+      event(0.0, Message::SongPositionPointer { beats: 20 }),
+      event(0.0, Message::Continue),
+    ];
+    slave.process_input(&events, ClockTime::from_seconds(0.01), &mut transport);
+    assert_eq!(transport.get_position(), BarsTime::new(1, 1, 0, 0));
+    assert!(!transport.is_playing());
+
+    let events = vec![event(0.01, Message::TimingClock)];
+    slave.process_input(&events, ClockTime::from_seconds(0.02), &mut transport);
+    assert!(transport.is_playing());
+
+    let events = vec![event(0.02, Message::Stop)];
+    slave.process_input(&events, ClockTime::from_seconds(0.03), &mut transport);
+    assert!(!transport.is_playing());
+    assert_eq!(transport.get_position(), BarsTime::new(1, 1, 0, 0));
+
+    let events = vec![
+      event(0.03, Message::Start),
+      event(0.04, Message::TimingClock),
+    ];
+    slave.process_input(&events, ClockTime::from_seconds(0.05), &mut transport);
+    assert!(transport.is_playing());
+    assert_eq!(transport.get_position(), BarsTime::new(0, 0, 0, 0));
+    assert!(slave.get_tempo().is_none());
+  }
+}
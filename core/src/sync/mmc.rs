@@ -0,0 +1,217 @@
+use crate::config::Mmc as MmcConfig;
+use crate::midi;
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::io::MidiOutput;
+use crate::midi::mmc::{MmcCommand, ALL_DEVICES};
+use crate::sync::mtc::{frame_rate, smpte_type};
+use crate::time::{ClockTime, SmpteTime};
+
+const MAX_PENDING_COMMANDS: usize = 16;
+
+pub fn locate(time: &SmpteTime) -> MmcCommand {
+  MmcCommand::Locate {
+    smpte_type: smpte_type(time.get_frame_rate()),
+    hours: time.get_hours(),
+    minutes: time.get_minutes(),
+    seconds: time.get_seconds(),
+    frames: time.get_frames(),
+    subframes: time.get_subframes(),
+  }
+}
+
+/// Timecode for a Locate command
+pub fn locate_target(command: MmcCommand) -> Option<SmpteTime> {
+  match command {
+    MmcCommand::Locate {
+      smpte_type,
+      hours,
+      minutes,
+      seconds,
+      frames,
+      subframes,
+    } => SmpteTime::new(
+      frame_rate(smpte_type),
+      hours,
+      minutes,
+      seconds,
+      frames,
+      subframes,
+    )
+    .ok(),
+    _ => None,
+  }
+}
+
+/// Sends MIDI Machine Control commands to all the devices connected to the ports.
+/// The commands are queued when the transport changes and sent with the next buffer.
+pub struct MmcMaster {
+  enabled: bool,
+  endpoints: Vec<Endpoint>,
+  pending: Vec<MmcCommand>,
+}
+
+impl MmcMaster {
+  pub fn new(config: &MmcConfig) -> MmcMaster {
+    MmcMaster {
+      enabled: config.send,
+      endpoints: config.ports.iter().map(Endpoint::from).collect(),
+      pending: Vec::with_capacity(MAX_PENDING_COMMANDS),
+    }
+  }
+
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+
+  pub fn send(&mut self, command: MmcCommand) {
+    if self.enabled && self.pending.len() < MAX_PENDING_COMMANDS {
+      self.pending.push(command);
+    }
+  }
+
+  pub fn flush<MidiOut>(&mut self, master_clock: ClockTime, midi_output: &mut MidiOut)
+  where
+    MidiOut: MidiOutput,
+  {
+    for command in self.pending.drain(..) {
+      let message = midi::Message::MachineControl {
+        device_id: ALL_DEVICES,
+        command,
+      };
+      for endpoint in self.endpoints.iter() {
+        midi_output.push(EventIo::new(master_clock, *endpoint, message.clone()));
+      }
+    }
+  }
+}
+
+/// Receives the MIDI Machine Control commands sent to this device from the input
+pub struct MmcSlave {
+  enabled: bool,
+  input: Endpoint,
+  device_id: u8,
+}
+
+impl MmcSlave {
+  pub fn new(config: &MmcConfig) -> MmcSlave {
+    MmcSlave {
+      enabled: config.receive,
+      input: Endpoint::from(&config.input),
+      device_id: config.device_id,
+    }
+  }
+
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+
+  pub fn command(&self, event: &EventIo) -> Option<MmcCommand> {
+    match event.message {
+      midi::Message::MachineControl { device_id, command }
+        if self.enabled
+          && self.input.accepts(event.endpoint)
+          && (device_id == self.device_id
+            || device_id == ALL_DEVICES
+            || self.device_id == ALL_DEVICES) =>
+      {
+        Some(command)
+      }
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{locate, locate_target, MmcMaster, MmcSlave};
+  use crate::config::Mmc as MmcConfig;
+  use crate::midi::buffer::{Endpoint, EventIo};
+  use crate::midi::mmc::MmcCommand;
+  use crate::midi::Message;
+  use crate::testing::VecMidiOutput;
+  use crate::time::{smpte::FrameRate, ClockTime, SmpteTime};
+
+  fn event(device_id: u8, command: MmcCommand) -> EventIo {
+    let message = Message::MachineControl { device_id, command };
+    EventIo::new(ClockTime::zero(), Endpoint::Id(1), message)
+  }
+
+  #[test]
+  pub fn locate_round_trip() {
+    let time = SmpteTime::new(FrameRate::Fps2997Drop, 1, 2, 3, 4, 5).unwrap();
+    assert_eq!(locate_target(locate(&time)), Some(time));
+    assert_eq!(locate_target(MmcCommand::Play), None);
+  }
+
+  #[test]
+  pub fn send_queued_commands() {
+    let config = MmcConfig {
+      send: true,
+      ..MmcConfig::default()
+    };
+    let mut mmc_master = MmcMaster::new(&config);
+    let mut midi_output = VecMidiOutput(Vec::new());
+
+    mmc_master.send(MmcCommand::Play);
+    mmc_master.send(MmcCommand::Stop);
+    mmc_master.flush(ClockTime::new(10), &mut midi_output);
+    mmc_master.flush(ClockTime::new(20), &mut midi_output);
+
+    let messages = midi_output
+      .0
+      .iter()
+      .map(|event| (event.timestamp, event.message.clone()))
+      .collect::<Vec<(ClockTime, Message)>>();
+    assert_eq!(
+      messages,
+      vec![
+        (
+          ClockTime::new(10),
+          Message::MachineControl {
+            device_id: 0x7f,
+            command: MmcCommand::Play
+          }
+        ),
+        (
+          ClockTime::new(10),
+          Message::MachineControl {
+            device_id: 0x7f,
+            command: MmcCommand::Stop
+          }
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn receive_commands_for_the_device() {
+    let config = MmcConfig {
+      receive: true,
+      device_id: 0x10,
+      ..MmcConfig::default()
+    };
+    let mmc_slave = MmcSlave::new(&config);
+
+    assert_eq!(
+      mmc_slave.command(&event(0x10, MmcCommand::Play)),
+      Some(MmcCommand::Play)
+    );
+    assert_eq!(
+      mmc_slave.command(&event(0x7f, MmcCommand::Stop)),
+      Some(MmcCommand::Stop)
+    );
+    assert_eq!(mmc_slave.command(&event(0x11, MmcCommand::Play)), None);
+
+    let clock = EventIo::new(ClockTime::zero(), Endpoint::Id(1), Message::TimingClock);
+    assert_eq!(mmc_slave.command(&clock), None);
+  }
+}
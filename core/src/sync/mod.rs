@@ -0,0 +1,11 @@
+pub mod midi_clock;
+pub mod midi_clock_slave;
+pub mod mmc;
+pub mod mtc;
+pub mod mtc_slave;
+
+pub use self::midi_clock::MidiClockMaster;
+pub use self::midi_clock_slave::MidiClockSlave;
+pub use self::mmc::{MmcMaster, MmcSlave};
+pub use self::mtc::MtcMaster;
+pub use self::mtc_slave::MtcSlave;
@@ -0,0 +1,348 @@
+use crate::config::Mtc as MtcConfig;
+use crate::midi;
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::io::MidiOutput;
+use crate::time::smpte::{FrameRate, SUBFRAMES_PER_FRAME};
+use crate::time::{ClockTime, SmpteTime, TicksTime};
+use crate::transport::{Segment, Transport};
+
+/// Quarter Frame messages are sent four times per frame
+pub const QUARTER_FRAMES_PER_FRAME: u64 = 4;
+
+/// A complete timecode takes eight Quarter Frame messages, that is two frames,
+/// so the receivers add two frames to the timecode once they received all of them
+pub const QUARTER_FRAMES_PER_TIMECODE: u64 = 8;
+
+const SUBFRAMES_PER_QUARTER_FRAME: u64 = SUBFRAMES_PER_FRAME as u64 / QUARTER_FRAMES_PER_FRAME;
+
+/// SMPTE type sent with the hours
+pub(super) fn smpte_type(frame_rate: FrameRate) -> u8 {
+  match frame_rate {
+    FrameRate::Fps24 => 0,
+    FrameRate::Fps25 => 1,
+    FrameRate::Fps2997Drop => 2,
+    FrameRate::Fps30 => 3,
+  }
+}
+
+pub(super) fn frame_rate(smpte_type: u8) -> FrameRate {
+  match smpte_type & 0x03 {
+    0 => FrameRate::Fps24,
+    1 => FrameRate::Fps25,
+    2 => FrameRate::Fps2997Drop,
+    _ => FrameRate::Fps30,
+  }
+}
+
+/// Clock time of a quarter frame counted from 00:00:00:00
+pub(super) fn quarter_frame_clock(quarter_frame: u64, frame_rate: FrameRate) -> ClockTime {
+  let frames = quarter_frame / QUARTER_FRAMES_PER_FRAME;
+  let subframes = quarter_frame % QUARTER_FRAMES_PER_FRAME * SUBFRAMES_PER_QUARTER_FRAME;
+  SmpteTime::from_frames(frames, subframes as u8, frame_rate).to_clock()
+}
+
+/// The first quarter frame at or after the clock time
+fn next_quarter_frame(clock: ClockTime, frame_rate: FrameRate) -> u64 {
+  let time = SmpteTime::from_clock(clock, frame_rate);
+  let subframes = u64::from(time.get_subframes());
+  let quarter_frame =
+    time.to_frames() * QUARTER_FRAMES_PER_FRAME + subframes / SUBFRAMES_PER_QUARTER_FRAME;
+  if quarter_frame_clock(quarter_frame, frame_rate) < clock {
+    quarter_frame + 1
+  } else {
+    quarter_frame
+  }
+}
+
+pub(super) fn full_frame(time: &SmpteTime) -> midi::Message {
+  midi::Message::MTCFullFrame {
+    smpte_type: smpte_type(time.get_frame_rate()),
+    hours: time.get_hours(),
+    minutes: time.get_minutes(),
+    seconds: time.get_seconds(),
+    frames: time.get_frames(),
+  }
+}
+
+/// The piece of the timecode sent by every Quarter Frame message
+pub(super) fn quarter_frame(time: &SmpteTime, msg_type: u8) -> midi::Message {
+  let value = match msg_type {
+    0 => time.get_frames() & 0x0f,
+    1 => time.get_frames() >> 4,
+    2 => time.get_seconds() & 0x0f,
+    3 => time.get_seconds() >> 4,
+    4 => time.get_minutes() & 0x0f,
+    5 => time.get_minutes() >> 4,
+    6 => time.get_hours() & 0x0f,
+    _ => (time.get_hours() >> 4) | (smpte_type(time.get_frame_rate()) << 1),
+  };
+  midi::Message::MTCQuarterFrame { msg_type, value }
+}
+
+/// Sends MIDI Time Code for the transport position, so external gear can chase it.
+/// Quarter Frame messages are sent while playing, and a Full Frame whenever the transport is located.
+pub struct MtcMaster {
+  enabled: bool,
+  endpoints: Vec<Endpoint>,
+  running: bool,
+  next_position: TicksTime,
+  first_quarter_frame: u64,
+  next_quarter_frame: u64,
+}
+
+impl MtcMaster {
+  pub fn new(config: MtcConfig) -> MtcMaster {
+    let endpoints = config.ports.iter().map(Endpoint::from).collect();
+
+    MtcMaster {
+      enabled: config.enabled,
+      endpoints,
+      running: false,
+      next_position: TicksTime::zero(),
+      first_quarter_frame: 0,
+      next_quarter_frame: 0,
+    }
+  }
+
+  pub fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.enabled
+  }
+
+  pub fn endpoints(&self) -> &[Endpoint] {
+    self.endpoints.as_slice()
+  }
+
+  /// Send the Quarter Frames for a segment, where the start of the song is at the offset timecode
+  pub fn process_segment<MidiOut>(
+    &mut self,
+    segment: &Segment,
+    offset: &SmpteTime,
+    midi_output: &mut MidiOut,
+  ) where
+    MidiOut: MidiOutput,
+  {
+    if !self.enabled {
+      self.running = false;
+      return;
+    }
+
+    let frame_rate = offset.get_frame_rate();
+    let offset_clock = offset.to_clock();
+    let start_clock = offset_clock + segment.clock_start_position;
+    let end_clock = offset_clock + segment.clock_end_position;
+
+    if !self.running || segment.start_position != self.next_position {
+      // started, located somewhere else or wrapped around the loop,
+      // so the receivers are located and the quarter frames start again
+      // with a complete timecode from the next frame
+      let time = SmpteTime::from_clock(start_clock, frame_rate);
+      self.push(midi_output, segment.master_clock, full_frame(&time));
+      let quarter_frame = next_quarter_frame(start_clock, frame_rate);
+      self.first_quarter_frame =
+        quarter_frame.div_ceil(QUARTER_FRAMES_PER_FRAME) * QUARTER_FRAMES_PER_FRAME;
+      self.next_quarter_frame = self.first_quarter_frame;
+      self.running = true;
+    }
+
+    loop {
+      let clock = quarter_frame_clock(self.next_quarter_frame, frame_rate);
+      if clock >= end_clock {
+        break;
+      }
+      let timestamp = if clock > start_clock {
+        segment.master_clock + (clock - start_clock)
+      } else {
+        segment.master_clock
+      };
+      // every timecode is sent along two frames, starting at the frame it refers to
+      let msg_type =
+        (self.next_quarter_frame - self.first_quarter_frame) % QUARTER_FRAMES_PER_TIMECODE;
+      let timecode_start = self.next_quarter_frame - msg_type;
+      let time = SmpteTime::from_frames(timecode_start / QUARTER_FRAMES_PER_FRAME, 0, frame_rate);
+      self.push(midi_output, timestamp, quarter_frame(&time, msg_type as u8));
+      self.next_quarter_frame += 1;
+    }
+
+    self.next_position = segment.end_position;
+  }
+
+  /// Stop sending Quarter Frames, and keep the receivers at the transport position
+  /// by sending a Full Frame whenever it is located while stopped
+  pub fn process_stopped<MidiOut>(
+    &mut self,
+    transport: &Transport,
+    master_clock: ClockTime,
+    midi_output: &mut MidiOut,
+  ) where
+    MidiOut: MidiOutput,
+  {
+    self.running = false;
+    let position = transport
+      .get_position()
+      .to_ticks_with_map(transport.get_signature_map());
+    if self.enabled && position != self.next_position {
+      let time = transport.get_smpte_position();
+      self.push(midi_output, master_clock, full_frame(&time));
+      self.next_position = position;
+    }
+  }
+
+  fn push<MidiOut>(&self, midi_output: &mut MidiOut, timestamp: ClockTime, message: midi::Message)
+  where
+    MidiOut: MidiOutput,
+  {
+    for endpoint in self.endpoints.iter() {
+      midi_output.push(EventIo::new(timestamp, *endpoint, message.clone()));
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::MtcMaster;
+  use crate::config::Mtc as MtcConfig;
+  use crate::midi::Message;
+  use crate::testing::VecMidiOutput;
+  use crate::time::{clock, smpte::FrameRate, BarsTime, ClockTime, SmpteTime};
+  use crate::transport::Transport;
+
+  const SAMPLE_RATE: u32 = 44100;
+
+  fn mtc_master() -> MtcMaster {
+    let config = MtcConfig {
+      enabled: true,
+      ..MtcConfig::default()
+    };
+    MtcMaster::new(config)
+  }
+
+  fn play(
+    transport: &mut Transport,
+    mtc_master: &mut MtcMaster,
+    seconds: f64,
+  ) -> Vec<(ClockTime, Message)> {
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    while master_clock < ClockTime::from_seconds(seconds) {
+      let offset = *transport.get_smpte_offset();
+      let mut segments = transport.segments_iterator(master_clock, samples);
+      while let Some(segment) = segments.next(transport) {
+        mtc_master.process_segment(&segment, &offset, &mut midi_output);
+      }
+      transport.update_from_segments(&segments);
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+    midi_output
+      .0
+      .into_iter()
+      .map(|event| (event.timestamp, event.message))
+      .collect()
+  }
+
+  fn full_frame(frame_rate: u8, hours: u8, minutes: u8, seconds: u8, frames: u8) -> Message {
+    Message::MTCFullFrame {
+      smpte_type: frame_rate,
+      hours,
+      minutes,
+      seconds,
+      frames,
+    }
+  }
+
+  #[test]
+  pub fn quarter_frames_at_25_fps() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_smpte_offset(SmpteTime::new(FrameRate::Fps25, 1, 0, 0, 0, 0).unwrap());
+    transport.play(false);
+    let mut mtc_master = mtc_master();
+
+    let events = play(&mut transport, &mut mtc_master, 1.0);
+
+    assert_eq!(events[0], (ClockTime::zero(), full_frame(1, 1, 0, 0, 0)));
+    let quarter_frames = &events[1..];
+    assert!(quarter_frames.len() >= 100);
+    for (index, (timestamp, message)) in quarter_frames.iter().enumerate() {
+      let expected = ClockTime::from_seconds(index as f64 * 0.01);
+      assert!(timestamp.units().abs_diff(expected.units()) < clock::UNITS_PER_MILLI / 1000);
+      match message {
+        Message::MTCQuarterFrame { msg_type, .. } => assert_eq!(*msg_type as usize, index % 8),
+        _ => panic!("Unexpected message: {:?}", message),
+      }
+    }
+
+    // 01:00:00:02 at 25 fps
+    let values = quarter_frames[8..16]
+      .iter()
+      .map(|(_, message)| match message {
+        Message::MTCQuarterFrame { value, .. } => *value,
+        _ => 0xff,
+      })
+      .collect::<Vec<u8>>();
+    assert_eq!(values, vec![2, 0, 0, 0, 0, 0, 1, 0b0010]);
+  }
+
+  #[test]
+  pub fn full_frame_on_locate() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_start(BarsTime::new(0, 2, 0, 0));
+    transport.set_loop_end(BarsTime::new(1, 0, 0, 0));
+    transport.set_position(BarsTime::new(0, 1, 0, 0));
+    transport.play(false);
+    let mut mtc_master = mtc_master();
+
+    let events = play(&mut transport, &mut mtc_master, 2.0);
+
+    // at 120 bpm the loop starts one second after the start of the song, and it wraps after another second
+    let full_frames = events
+      .iter()
+      .filter(|(_, message)| matches!(message, Message::MTCFullFrame { .. }))
+      .collect::<Vec<&(ClockTime, Message)>>();
+    assert_eq!(full_frames.len(), 2);
+    assert_eq!(
+      *full_frames[0],
+      (ClockTime::zero(), full_frame(1, 0, 0, 0, 12))
+    );
+    assert_eq!(full_frames[1].1, full_frame(1, 0, 0, 1, 0));
+    let wrap_time = ClockTime::from_seconds(1.5);
+    assert!(full_frames[1].0.units().abs_diff(wrap_time.units()) < 1000);
+
+    // the quarter frames start again with a complete timecode after the full frame
+    let wrap_index = events
+      .iter()
+      .rposition(|event| event == full_frames[1])
+      .unwrap();
+    assert_eq!(
+      events[wrap_index + 1],
+      (
+        full_frames[1].0,
+        Message::MTCQuarterFrame {
+          msg_type: 0,
+          value: 0
+        }
+      )
+    );
+  }
+
+  #[test]
+  pub fn full_frame_when_located_while_stopped() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    let mut mtc_master = mtc_master();
+    let mut midi_output = VecMidiOutput(Vec::new());
+
+    mtc_master.process_stopped(&transport, ClockTime::zero(), &mut midi_output);
+    assert!(midi_output.0.is_empty());
+
+    transport.set_position(BarsTime::new(1, 0, 0, 0));
+    mtc_master.process_stopped(&transport, ClockTime::zero(), &mut midi_output);
+    mtc_master.process_stopped(&transport, ClockTime::zero(), &mut midi_output);
+    assert_eq!(midi_output.0.len(), 1);
+    assert_eq!(midi_output.0[0].message, full_frame(1, 0, 0, 2, 0));
+  }
+}
@@ -0,0 +1,333 @@
+use crate::config::Mtc as MtcConfig;
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::Message;
+use crate::sync::mtc::{
+  frame_rate, quarter_frame_clock, QUARTER_FRAMES_PER_FRAME, QUARTER_FRAMES_PER_TIMECODE,
+};
+use crate::time::{ClockTime, SmpteTime};
+use crate::transport::Transport;
+
+/// Without Quarter Frames for this long the time code is considered stopped
+const DROPOUT_SECONDS: f64 = 0.1;
+
+/// Maximum distance in frames between the transport and the time code before locating the transport
+const MAX_DRIFT_FRAMES: u64 = 1;
+
+/// Chases the transport to an incoming MIDI Time Code.
+/// Full Frames locate the transport, and once a complete timecode has been received
+/// from the Quarter Frames the transport plays and is located whenever it drifts away.
+pub struct MtcSlave {
+  input: Endpoint,
+  values: [u8; QUARTER_FRAMES_PER_TIMECODE as usize],
+  next_msg_type: u8,
+  last_quarter_frame: Option<ClockTime>,
+  running: bool,
+}
+
+impl MtcSlave {
+  pub fn new(config: &MtcConfig) -> MtcSlave {
+    MtcSlave {
+      input: Endpoint::from(&config.input),
+      values: [0; QUARTER_FRAMES_PER_TIMECODE as usize],
+      next_msg_type: 0,
+      last_quarter_frame: None,
+      running: false,
+    }
+  }
+
+  pub fn is_running(&self) -> bool {
+    self.running
+  }
+
+  /// Update the transport with the MIDI messages received until the master clock
+  pub fn process_input(
+    &mut self,
+    events: &[EventIo],
+    master_clock: ClockTime,
+    transport: &mut Transport,
+  ) {
+    let input = self.input;
+    for event in events.iter().filter(|event| input.accepts(event.endpoint)) {
+      match event.message {
+        Message::MTCFullFrame {
+          smpte_type,
+          hours,
+          minutes,
+          seconds,
+          frames,
+        } => {
+          let frame_rate = frame_rate(smpte_type);
+          if let Ok(time) = SmpteTime::new(frame_rate, hours, minutes, seconds, frames, 0) {
+            transport.set_smpte_position(time);
+          }
+          self.next_msg_type = 0;
+        }
+        Message::MTCQuarterFrame { msg_type, value } => {
+          self.quarter_frame(msg_type, value, event.timestamp, master_clock, transport)
+        }
+        _ => {}
+      }
+    }
+
+    if let Some(timestamp) = self.last_quarter_frame {
+      let dropout = ClockTime::from_seconds(DROPOUT_SECONDS);
+      if master_clock > timestamp && master_clock - timestamp > dropout {
+        self.last_quarter_frame = None;
+        self.next_msg_type = 0;
+        if self.running {
+          self.running = false;
+          if transport.is_playing() {
+            transport.stop();
+          }
+        }
+      }
+    }
+  }
+
+  fn quarter_frame(
+    &mut self,
+    msg_type: u8,
+    value: u8,
+    timestamp: ClockTime,
+    master_clock: ClockTime,
+    transport: &mut Transport,
+  ) {
+    self.last_quarter_frame = Some(timestamp);
+
+    // the timecode is only complete when the pieces are received in order
+    if msg_type == self.next_msg_type {
+      self.values[usize::from(msg_type)] = value;
+      self.next_msg_type += 1;
+    } else if msg_type == 0 {
+      self.values[0] = value;
+      self.next_msg_type = 1;
+    } else {
+      self.next_msg_type = 0;
+    }
+
+    if u64::from(self.next_msg_type) == QUARTER_FRAMES_PER_TIMECODE {
+      self.next_msg_type = 0;
+      if let Some(time) = self.timecode() {
+        self.follow(&time, timestamp, master_clock, transport);
+      }
+    }
+  }
+
+  fn timecode(&self) -> Option<SmpteTime> {
+    let join = |low: usize| self.values[low] | (self.values[low + 1] << 4);
+    let hours = self.values[6] | ((self.values[7] & 0x01) << 4);
+    let frame_rate = frame_rate(self.values[7] >> 1);
+    SmpteTime::new(frame_rate, hours, join(4), join(2), join(0), 0).ok()
+  }
+
+  /// The last Quarter Frame is received one quarter frame before the end of the two frames it took to send the timecode,
+  /// so the time code continues from there until the master clock
+  fn follow(
+    &mut self,
+    time: &SmpteTime,
+    timestamp: ClockTime,
+    master_clock: ClockTime,
+    transport: &mut Transport,
+  ) {
+    let frame_rate = time.get_frame_rate();
+    let last_quarter_frame =
+      time.to_frames() * QUARTER_FRAMES_PER_FRAME + QUARTER_FRAMES_PER_TIMECODE - 1;
+    let mut clock = quarter_frame_clock(last_quarter_frame, frame_rate);
+    if master_clock > timestamp {
+      clock += master_clock - timestamp;
+    }
+    let time = SmpteTime::from_clock(clock, frame_rate);
+
+    if !self.running || !transport.is_playing() {
+      transport.set_smpte_position(time);
+      if !transport.is_playing() {
+        transport.play(false);
+      }
+      self.running = true;
+    } else {
+      let position_clock = transport.get_smpte_position().to_clock();
+      let max_drift = SmpteTime::from_frames(MAX_DRIFT_FRAMES, 0, frame_rate).to_clock();
+      if position_clock.units().abs_diff(clock.units()) > max_drift.units() {
+        transport.set_smpte_position(time);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::MtcSlave;
+  use crate::config::Mtc as MtcConfig;
+  use crate::midi::buffer::{Endpoint, EventIo};
+  use crate::midi::decoder::{DecodedMessage, Decoder};
+  use crate::midi::encoder::Encoder;
+  use crate::midi::Message;
+  use crate::sync::MtcMaster;
+  use crate::testing::VecMidiOutput;
+  use crate::time::{smpte::FrameRate, BarsTime, ClockTime, SmpteTime};
+  use crate::transport::Transport;
+
+  const SAMPLE_RATE: u32 = 44100;
+  const SAMPLES: u32 = 512;
+
+  /// Send the events through the MIDI encoder and decoder as a driver would do
+  fn transmit(events: Vec<EventIo>) -> Vec<EventIo> {
+    let mut received = Vec::new();
+    for event in events {
+      let mut data = vec![0u8; Encoder::data_size(&event.message)];
+      Encoder::encode(&event.message, data.as_mut_slice());
+      for decoded in Decoder::new(&data) {
+        if let DecodedMessage::Message(message) = decoded {
+          received.push(EventIo::new(event.timestamp, Endpoint::Id(0), message));
+        }
+      }
+    }
+    received
+  }
+
+  fn play(
+    transport: &mut Transport,
+    mtc_master: Option<&mut MtcMaster>,
+    master_clock: ClockTime,
+  ) -> Vec<EventIo> {
+    let mut midi_output = VecMidiOutput(Vec::new());
+    let offset = *transport.get_smpte_offset();
+    let mut segments = transport.segments_iterator(master_clock, SAMPLES);
+    if let Some(mtc_master) = mtc_master {
+      while let Some(segment) = segments.next(transport) {
+        mtc_master.process_segment(&segment, &offset, &mut midi_output);
+      }
+    } else {
+      while segments.next(transport).is_some() {}
+    }
+    transport.update_from_segments(&segments);
+    midi_output.0
+  }
+
+  fn transport(offset: SmpteTime) -> Transport {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_smpte_offset(offset);
+    transport
+  }
+
+  /// Run a transport with a MTC master and another one chasing it for some time
+  fn chase(
+    master: &mut Transport,
+    slave: &mut Transport,
+    mtc_slave: &mut MtcSlave,
+    seconds: f64,
+  ) -> ClockTime {
+    let config = MtcConfig {
+      enabled: true,
+      ..MtcConfig::default()
+    };
+    let mut mtc_master = MtcMaster::new(config);
+
+    let buffer_duration = ClockTime::from_samples(SAMPLES, SAMPLE_RATE);
+    let mut master_clock = ClockTime::zero();
+    let mut received = Vec::new();
+    while master_clock < ClockTime::from_seconds(seconds) {
+      // the events sent during a buffer are received for the next one
+      mtc_slave.process_input(&received, master_clock, slave);
+      if slave.is_playing() {
+        play(slave, None, master_clock);
+      }
+      received = transmit(play(master, Some(&mut mtc_master), master_clock));
+      master_clock += buffer_duration;
+    }
+    master_clock
+  }
+
+  #[test]
+  pub fn chase_through_encoder_and_decoder() {
+    let offset = SmpteTime::new(FrameRate::Fps25, 1, 0, 0, 0, 0).unwrap();
+    let mut master = transport(offset);
+    master.set_position(BarsTime::new(1, 0, 0, 0));
+    master.play(false);
+    let mut slave = transport(offset);
+    let mut mtc_slave = MtcSlave::new(&MtcConfig::default());
+
+    chase(&mut master, &mut slave, &mut mtc_slave, 3.0);
+
+    assert!(mtc_slave.is_running());
+    assert!(slave.is_playing());
+    let master_clock = master.get_smpte_position().to_clock();
+    let slave_clock = slave.get_smpte_position().to_clock();
+    let frame = SmpteTime::new(FrameRate::Fps25, 0, 0, 0, 1, 0)
+      .unwrap()
+      .to_clock();
+    assert!(master_clock.units().abs_diff(slave_clock.units()) <= frame.units());
+  }
+
+  #[test]
+  pub fn chase_drop_frame_with_different_offset() {
+    let master_offset = SmpteTime::new(FrameRate::Fps2997Drop, 0, 0, 58, 0, 0).unwrap();
+    let mut master = transport(master_offset);
+    master.play(false);
+    let slave_offset = SmpteTime::new(FrameRate::Fps2997Drop, 0, 0, 59, 0, 0).unwrap();
+    let mut slave = transport(slave_offset);
+    let mut mtc_slave = MtcSlave::new(&MtcConfig::default());
+
+    chase(&mut master, &mut slave, &mut mtc_slave, 3.0);
+
+    // the time code crosses a minute with dropped frames,
+    // and the slave song starts one second later than the master song
+    assert!(slave.is_playing());
+    let master_position = master.get_smpte_position();
+    let slave_position = slave.get_smpte_position();
+    assert_eq!(master_position.get_minutes(), 1);
+    let difference = master_position
+      .to_clock()
+      .units()
+      .abs_diff(slave_position.to_clock().units());
+    assert!(difference <= ClockTime::from_seconds(1.0 / 29.97).units());
+    let ticks = slave
+      .get_position()
+      .to_ticks_with_map(slave.get_signature_map());
+    let expected = ClockTime::from_seconds(2.0)
+      .to_ticks_with_map(slave.get_signature_map(), slave.get_tempo_map());
+    assert!(u64::from(ticks).abs_diff(u64::from(expected)) < u64::from(expected) / 10);
+  }
+
+  #[test]
+  pub fn full_frame_locates() {
+    let offset = SmpteTime::new(FrameRate::Fps30, 1, 0, 0, 0, 0).unwrap();
+    let mut slave = transport(offset);
+    let mut mtc_slave = MtcSlave::new(&MtcConfig::default());
+    let message = Message::MTCFullFrame {
+      smpte_type: 3,
+      hours: 1,
+      minutes: 0,
+      seconds: 2,
+      frames: 0,
+    };
+    let events = transmit(vec![EventIo::new(
+      ClockTime::zero(),
+      Endpoint::Default,
+      message,
+    )]);
+
+    mtc_slave.process_input(&events, ClockTime::zero(), &mut slave);
+
+    assert!(!slave.is_playing());
+    assert_eq!(slave.get_position(), BarsTime::new(1, 0, 0, 0));
+  }
+
+  #[test]
+  pub fn stop_when_the_time_code_stops() {
+    let offset = SmpteTime::zero(FrameRate::Fps24);
+    let mut master = transport(offset);
+    master.play(false);
+    let mut slave = transport(offset);
+    let mut mtc_slave = MtcSlave::new(&MtcConfig::default());
+
+    let master_clock = chase(&mut master, &mut slave, &mut mtc_slave, 1.0);
+    assert!(slave.is_playing());
+
+    mtc_slave.process_input(&[], master_clock + ClockTime::from_seconds(0.2), &mut slave);
+    assert!(!mtc_slave.is_running());
+    assert!(!slave.is_playing());
+  }
+}
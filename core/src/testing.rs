@@ -0,0 +1,74 @@
+use std::sync::{Arc, RwLock};
+
+use crate::color::Color;
+use crate::midi::buffer::{Endpoint, EventIo};
+use crate::midi::io::{MidiInput, MidiOutput};
+use crate::midi::types::U4;
+use crate::song::clips::{Clip, ClipId};
+use crate::song::sampler::{SampleZone, Sampler, SamplerInstrument};
+use crate::song::source::audio::{AudioDataSource, Sample};
+use crate::song::track::{midi::MidiTrack, Track, TrackMedia};
+use crate::time::{BarsTime, SampleRate, Signature};
+
+/// Output that keeps the events pushed in order
+pub struct VecMidiOutput(pub Vec<EventIo>);
+
+impl MidiOutput for VecMidiOutput {
+  fn push(&mut self, event: EventIo) {
+    self.0.push(event);
+  }
+}
+
+pub struct NoMidiInput;
+
+impl MidiInput for NoMidiInput {
+  fn pop(&mut self) -> Option<EventIo> {
+    None
+  }
+}
+
+/// Input events popped in the order they were received
+pub struct VecMidiInput(pub Vec<EventIo>);
+
+impl MidiInput for VecMidiInput {
+  fn pop(&mut self) -> Option<EventIo> {
+    if self.0.is_empty() {
+      None
+    } else {
+      Some(self.0.remove(0))
+    }
+  }
+}
+
+/// Clip in 4/4 of some bars from the start of a bar
+pub fn clip(uuid: ClipId, start_bar: u16, bars: u16) -> Clip {
+  let signature = Signature::new(4, 4);
+  Clip {
+    uuid,
+    name: "clip".to_string(),
+    signature,
+    start: BarsTime::from_bars(start_bar).to_ticks(signature),
+    length: BarsTime::from_bars(bars).to_ticks(signature),
+  }
+}
+
+/// MIDI track without clips
+pub fn midi_track(endpoint: Endpoint, channel: U4) -> Track {
+  Track::new(
+    "midi",
+    Color::new("red".into()),
+    TrackMedia::Midi(MidiTrack::new(endpoint, channel)),
+  )
+}
+
+/// Sampler that plays a constant sample of one second for all the keys of the first channel
+pub fn tone_sampler(sample_rate: SampleRate) -> Sampler {
+  let source = Arc::new(RwLock::new(AudioDataSource::new()));
+  let sample = Sample::new("tone", sample_rate, vec![1.0; sample_rate as usize]);
+  source.write().unwrap().add_sample(1, sample);
+  let mut instrument = SamplerInstrument::new("tone", 0);
+  instrument.zones.push(SampleZone::new(0, 127, 1));
+  let mut sampler = Sampler::new(source);
+  sampler.add_instrument(instrument);
+  sampler
+}
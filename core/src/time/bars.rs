@@ -1,8 +1,22 @@
 use std::fmt;
+use std::str::FromStr;
 
-use crate::time::{ticks::TICKS_RESOLUTION, Signature, TicksTime};
+use failure::Fail;
+use serde::{de::Error, Deserialize, Deserializer};
 
-#[derive(PartialEq)]
+use crate::time::{ticks::TICKS_RESOLUTION, Signature, SignatureMap, TicksTime};
+
+#[derive(Debug, Fail)]
+pub enum BarsError {
+  #[fail(display = "Invalid position format: {}", text)]
+  InvalidFormat { text: String },
+}
+
+pub type BarsResult<T> = Result<T, BarsError>;
+
+/// Position in bars, beats, sixteenths and ticks, all of them starting from 0.
+/// The comparison is only meaningful between normalised positions, like the ones given by the conversions from ticks.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct BarsTime {
   bars: u16,
   beats: u16,
@@ -37,6 +51,10 @@ impl BarsTime {
     }
   }
 
+  pub fn from_ticks_with_map(ticks_time: TicksTime, signature_map: &SignatureMap) -> BarsTime {
+    signature_map.ticks_to_bars(ticks_time)
+  }
+
   pub fn get_bars(&self) -> u16 {
     self.bars
   }
@@ -64,6 +82,106 @@ impl BarsTime {
         + u64::from(self.ticks),
     )
   }
+
+  pub fn to_ticks_with_map(&self, signature_map: &SignatureMap) -> TicksTime {
+    signature_map.bars_to_ticks(self)
+  }
+
+  /// Move forward by a duration. The bars are moved first, and then the rest of the duration
+  /// is added using the signature of the bar reached, so 1 bar always moves to the same beat of the next bar.
+  pub fn add(&self, duration: &BarsTime, signature_map: &SignatureMap) -> BarsTime {
+    let bars = BarsTime::new(
+      self.bars.saturating_add(duration.bars),
+      self.beats,
+      self.sixteenths,
+      self.ticks,
+    );
+    let position = bars.to_ticks_with_map(signature_map);
+    let signature = signature_map.signature_at_bar(bars.bars);
+    let rest =
+      BarsTime::new(0, duration.beats, duration.sixteenths, duration.ticks).to_ticks(signature);
+    BarsTime::from_ticks_with_map(position + rest, signature_map)
+  }
+
+  /// Move backward by a duration, in the opposite order than `add`. It stops at the start of the song.
+  pub fn sub(&self, duration: &BarsTime, signature_map: &SignatureMap) -> BarsTime {
+    let bars = BarsTime::new(
+      self.bars.saturating_sub(duration.bars),
+      self.beats,
+      self.sixteenths,
+      self.ticks,
+    );
+    let position = if duration.bars > self.bars {
+      TicksTime::zero()
+    } else {
+      bars.to_ticks_with_map(signature_map)
+    };
+    let signature = signature_map.signature_at(position);
+    let rest =
+      BarsTime::new(0, duration.beats, duration.sixteenths, duration.ticks).to_ticks(signature);
+    BarsTime::from_ticks_with_map(position - rest, signature_map)
+  }
+}
+
+/// Parse positions like 5.3.2.120 as shown to the user, where the bars, beats and sixteenths start from 1.
+/// The parts at the end can be omitted, so 5 is the start of the fifth bar.
+impl FromStr for BarsTime {
+  type Err = BarsError;
+
+  fn from_str(text: &str) -> BarsResult<BarsTime> {
+    let invalid_format = || BarsError::InvalidFormat {
+      text: text.to_string(),
+    };
+    let parts = text
+      .trim()
+      .split('.')
+      .map(|part| part.parse::<u32>())
+      .collect::<Result<Vec<u32>, _>>()
+      .map_err(|_| invalid_format())?;
+    if parts.len() > 4 {
+      return Err(invalid_format());
+    }
+    let one_based = |index: usize| match parts.get(index) {
+      Some(0) => Err(invalid_format()),
+      Some(value) if *value > u32::from(u16::MAX) => Err(invalid_format()),
+      Some(value) => Ok(*value as u16 - 1),
+      None => Ok(0),
+    };
+    let ticks = parts.get(3).cloned().unwrap_or(0);
+    if u64::from(ticks) >= TICKS_RESOLUTION {
+      return Err(invalid_format());
+    }
+    Ok(BarsTime::new(
+      one_based(0)?,
+      one_based(1)?,
+      one_based(2)?,
+      ticks,
+    ))
+  }
+}
+
+/// Decoded from the text of the position, like `2.1.1.0`, with the same checks as when parsed
+impl<'de> Deserialize<'de> for BarsTime {
+  fn deserialize<D>(deserializer: D) -> Result<BarsTime, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let text = String::deserialize(deserializer)?;
+    text.parse().map_err(D::Error::custom)
+  }
+}
+
+impl fmt::Display for BarsTime {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{}.{}.{}.{}",
+      u32::from(self.bars) + 1,
+      u32::from(self.beats) + 1,
+      u32::from(self.sixteenths) + 1,
+      self.ticks
+    )
+  }
 }
 
 impl fmt::Debug for BarsTime {
@@ -82,8 +200,10 @@ impl fmt::Debug for BarsTime {
 #[cfg(test)]
 mod test {
 
-  use super::BarsTime;
-  use crate::time::{ticks::TicksTime, ticks::TICKS_RESOLUTION, Signature};
+  use serde_derive::Deserialize;
+
+  use super::{BarsError, BarsTime};
+  use crate::time::{ticks::TicksTime, ticks::TICKS_RESOLUTION, Signature, SignatureMap};
 
   #[test]
   pub fn new() {
@@ -127,4 +247,129 @@ mod test {
     let ticks = time.to_ticks(signature);
     assert_eq!(u64::from(ticks), 123_456_789);
   }
+
+  #[test]
+  pub fn from_ticks_with_map() {
+    let mut signature_map = SignatureMap::new(Signature::new(4, 4));
+    signature_map.set_signature(1, Signature::new(7, 8));
+    let ticks = TicksTime::new(
+      TICKS_RESOLUTION * 16 +        // 1 bar of 4/4
+          TICKS_RESOLUTION * 14 +    // 1 bar of 7/8
+          TICKS_RESOLUTION * 2 * 5 + // 5 beats
+          TICKS_RESOLUTION     +     // 1 sixteenth
+          30, // 30 ticks
+    );
+
+    let time = BarsTime::from_ticks_with_map(ticks, &signature_map);
+    assert_eq!(time.get_bars(), 2);
+    assert_eq!(time.get_beats(), 5);
+    assert_eq!(time.get_sixteenths(), 1);
+    assert_eq!(time.get_ticks(), 30);
+  }
+
+  #[test]
+  pub fn to_ticks_with_map() {
+    let mut signature_map = SignatureMap::new(Signature::new(4, 4));
+    signature_map.set_signature(1, Signature::new(7, 8));
+    signature_map.set_signature(3, Signature::new(3, 4));
+    let ticks = TicksTime::new(123_456_789_000);
+    let time = BarsTime::from_ticks_with_map(ticks, &signature_map);
+    let ticks = time.to_ticks_with_map(&signature_map);
+    assert_eq!(u64::from(ticks), 123_456_789_000);
+  }
+
+  #[test]
+  pub fn compare() {
+    assert!(BarsTime::new(1, 0, 0, 0) > BarsTime::new(0, 3, 3, 100));
+    assert!(BarsTime::new(1, 2, 0, 0) < BarsTime::new(1, 2, 1, 0));
+    assert!(BarsTime::new(1, 2, 1, 10) < BarsTime::new(1, 2, 1, 11));
+    assert_eq!(
+      BarsTime::new(2, 0, 0, 0).max(BarsTime::new(1, 3, 0, 0)),
+      BarsTime::new(2, 0, 0, 0)
+    );
+  }
+
+  #[test]
+  pub fn add_and_sub() {
+    let mut signature_map = SignatureMap::new(Signature::new(4, 4));
+    signature_map.set_signature(2, Signature::new(7, 8));
+
+    let time = BarsTime::new(0, 3, 2, 10);
+    let duration = BarsTime::new(1, 2, 0, 0);
+    assert_eq!(
+      time.add(&duration, &signature_map),
+      BarsTime::new(2, 3, 0, 10)
+    );
+    assert_eq!(
+      BarsTime::new(2, 1, 0, 10).sub(&duration, &signature_map),
+      BarsTime::new(0, 3, 0, 10)
+    );
+
+    // the beats of the duration follow the signature of the bar reached
+    let time = BarsTime::new(1, 0, 0, 0);
+    let duration = BarsTime::new(1, 8, 0, 0);
+    assert_eq!(
+      time.add(&duration, &signature_map),
+      BarsTime::new(3, 1, 0, 0)
+    );
+
+    assert_eq!(
+      BarsTime::new(1, 0, 0, 0).sub(&BarsTime::new(2, 0, 0, 0), &signature_map),
+      BarsTime::new(0, 0, 0, 0)
+    );
+    assert_eq!(
+      BarsTime::new(0, 1, 0, 0).sub(&BarsTime::new(0, 2, 0, 0), &signature_map),
+      BarsTime::new(0, 0, 0, 0)
+    );
+  }
+
+  #[test]
+  pub fn parse() {
+    assert_eq!(
+      "5.3.2.120".parse::<BarsTime>().unwrap(),
+      BarsTime::new(4, 2, 1, 120)
+    );
+    assert_eq!("5".parse::<BarsTime>().unwrap(), BarsTime::new(4, 0, 0, 0));
+    assert_eq!(
+      " 2.4 ".parse::<BarsTime>().unwrap(),
+      BarsTime::new(1, 3, 0, 0)
+    );
+
+    let time = BarsTime::new(10, 1, 2, 100);
+    assert_eq!(time.to_string(), "11.2.3.100");
+    assert_eq!(time.to_string().parse::<BarsTime>().unwrap(), time);
+  }
+
+  #[test]
+  pub fn parse_errors() {
+    for text in [
+      "",
+      "0.1.1.0",
+      "1.1.1.1.1",
+      "1.x",
+      "1.1.1.-1",
+      "1.1.1.508032000",
+    ]
+    .iter()
+    {
+      match text.parse::<BarsTime>() {
+        Err(BarsError::InvalidFormat { .. }) => {}
+        result => panic!("Unexpected result for {:?}: {:?}", text, result),
+      }
+    }
+  }
+  #[test]
+  pub fn decode_the_text() {
+    #[derive(Deserialize)]
+    struct Locator {
+      position: BarsTime,
+    }
+    let position = |text: &str| {
+      let content = format!("position = \"{}\"", text);
+      toml::from_str::<Locator>(&content).map(|locator| locator.position)
+    };
+    assert_eq!(position("5.3.2.120").unwrap(), BarsTime::new(4, 2, 1, 120));
+    assert!(position("0.1.1.0").is_err());
+    assert!(position("1.1.1.508032000").is_err());
+  }
 }
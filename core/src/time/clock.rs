@@ -1,6 +1,6 @@
 use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign};
 
-use super::{ticks, SampleRate, Signature, Tempo, TicksTime};
+use super::{ticks, SampleRate, Signature, SignatureMap, Tempo, TempoMap, TicksTime};
 
 pub const MILLIS_PER_SECOND: u64 = 1_000;
 pub const NANOS_PER_SECOND: u64 = 1_000_000_000;
@@ -51,6 +51,13 @@ impl ClockTime {
     self.0
   }
 
+  /// Number of samples closest to the time
+  pub fn to_samples(&self, sample_rate: SampleRate) -> u64 {
+    let units = u128::from(self.0) * u128::from(sample_rate);
+    let units_per_second = u128::from(UNITS_PER_SECOND);
+    ((units + units_per_second / 2) / units_per_second) as u64
+  }
+
   pub fn to_seconds(&self) -> f64 {
     self.0 as f64 / UNITS_PER_SECOND as f64
   }
@@ -64,6 +71,10 @@ impl ClockTime {
     let ticks = u128::from(self.0) * u128::from(ticks_per_minute) / u128::from(UNITS_PER_MINUTE);
     TicksTime::new(ticks as u64)
   }
+
+  pub fn to_ticks_with_map(&self, signature_map: &SignatureMap, tempo_map: &TempoMap) -> TicksTime {
+    tempo_map.clock_to_ticks(*self, signature_map)
+  }
 }
 
 impl Add for ClockTime {
@@ -135,6 +146,7 @@ impl DivAssign<u32> for ClockTime {
 mod test {
   use super::ClockTime;
   use crate::time::clock::UNITS_PER_SECOND;
+  use crate::time::{Signature, SignatureMap, Tempo, TempoMap, TicksTime};
 
   #[test]
   pub fn clock_time_new() {
@@ -166,6 +178,26 @@ mod test {
     assert_eq!(time.units(), UNITS_PER_SECOND / 2);
   }
 
+  #[test]
+  pub fn clock_time_to_samples() {
+    let time = ClockTime::from_samples(44100 + 123, 44100);
+    assert_eq!(time.to_samples(44100), 44100 + 123);
+    assert_eq!(ClockTime::from_seconds(0.5).to_samples(48000), 24000);
+  }
+
+  #[test]
+  pub fn clock_time_to_ticks_with_map() {
+    let signature = Signature::new(4, 4);
+    let mut tempo_map = TempoMap::new(Tempo::new(120));
+    let change_position = TicksTime::per_minute(signature, Tempo::new(120)) / 2;
+    tempo_map.set_tempo(change_position, Tempo::new(60));
+    let time = ClockTime::from_seconds(60.0);
+    let signature_map = SignatureMap::new(signature);
+    let ticks = time.to_ticks_with_map(&signature_map, &tempo_map);
+    let expected = change_position + TicksTime::per_minute(signature, Tempo::new(60)) / 2;
+    assert_eq!(ticks, expected);
+  }
+
   #[test]
   pub fn clock_time_add() {
     let time1 = ClockTime::new(15);
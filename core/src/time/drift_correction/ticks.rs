@@ -1,4 +1,6 @@
-use crate::time::{BarsTime, ClockTime, SampleRate, Signature, Tempo, TicksTime};
+use crate::time::{
+  tempo_map::TempoCurve, BarsTime, ClockTime, SampleRate, Signature, Tempo, TicksTime,
+};
 
 const SECONDS_PER_MINUTE: f64 = 60.0;
 
@@ -8,6 +10,18 @@ pub struct TicksDriftCorrection {
   error_per_sample: f64,
   error_accumulated: f64,
   last_correction: f64,
+  ramp: Option<RampIntegration>,
+}
+
+/// State to integrate the ticks along a tempo ramp.
+/// The position is always computed from the total number of samples elapsed since the start of the ramp,
+/// so the rounding errors don't accumulate.
+#[derive(Debug, Clone)]
+struct RampIntegration {
+  curve: TempoCurve,
+  sample_rate: f64,
+  elapsed_samples: f64,
+  position: TicksTime,
 }
 
 impl TicksDriftCorrection {
@@ -29,9 +43,30 @@ impl TicksDriftCorrection {
       error_per_sample,
       error_accumulated: 0.0,
       last_correction: 0.0,
+      ramp: None,
     }
   }
 
+  /// Drift correction along a tempo curve, starting at a position of it
+  pub fn with_curve(
+    curve: TempoCurve,
+    position: TicksTime,
+    sample_rate: SampleRate,
+  ) -> TicksDriftCorrection {
+    let tempo = Tempo::from_bpm(curve.tempo_at(position));
+    let mut correction = TicksDriftCorrection::new(curve.get_signature(), tempo, sample_rate);
+    if !curve.is_constant() {
+      let sample_rate = f64::from(sample_rate);
+      correction.ramp = Some(RampIntegration {
+        curve,
+        sample_rate,
+        elapsed_samples: curve.seconds_at(position) * sample_rate,
+        position,
+      });
+    }
+    correction
+  }
+
   pub fn get_ticks_per_sample(&self) -> f64 {
     self.ticks_per_sample
   }
@@ -49,6 +84,10 @@ impl TicksDriftCorrection {
   }
 
   pub fn next(&mut self, samples: u32) -> TicksTime {
+    if let Some(ramp) = self.ramp.as_mut() {
+      return ramp.next(samples);
+    }
+
     let samples_ticks = self.ticks_per_sample * f64::from(samples);
     let samples_error =
       samples_ticks - samples_ticks.round() + self.error_per_sample * f64::from(samples);
@@ -65,11 +104,23 @@ impl TicksDriftCorrection {
   }
 }
 
+impl RampIntegration {
+  fn next(&mut self, samples: u32) -> TicksTime {
+    self.elapsed_samples += f64::from(samples);
+    let seconds = self.elapsed_samples / self.sample_rate;
+    let next_position = TicksTime::new(self.curve.position_at(seconds).round() as u64);
+    let ticks = next_position - self.position;
+    self.position = next_position;
+    ticks
+  }
+}
+
 #[cfg(test)]
 mod test {
 
   use super::TicksDriftCorrection;
-  use super::{Signature, Tempo, TicksTime};
+  use super::{ClockTime, Signature, Tempo, TicksTime};
+  use crate::time::{BarsTime, SignatureMap, TempoMap, TempoRamp};
 
   #[test]
   pub fn ticks_drift_correction_new() {
@@ -90,4 +141,31 @@ mod test {
     let ticks = correction.next(1000);
     assert_eq!(ticks, TicksTime::new(52_662_858));
   }
+
+  #[test]
+  pub fn ticks_drift_correction_along_ramp() {
+    let signature = Signature::new(4, 4);
+    let signature_map = SignatureMap::new(signature);
+    for ramp in &[TempoRamp::Linear, TempoRamp::Exponential] {
+      let mut tempo_map = TempoMap::new(Tempo::new(60));
+      tempo_map.set_tempo_with_ramp(TicksTime::zero(), Tempo::from_bpm(60.5), *ramp);
+      let ramp_end = BarsTime::new(300, 0, 0, 0).to_ticks(signature);
+      tempo_map.set_tempo(ramp_end, Tempo::from_bpm(180.25));
+      let curve = tempo_map.curve_at(TicksTime::zero(), &signature_map);
+      let mut correction = TicksDriftCorrection::with_curve(curve, TicksTime::zero(), 44100);
+
+      // 10 minutes of buffers of 512 samples and a last smaller one
+      let mut position = TicksTime::zero();
+      for _ in 0..(600 * 44100 / 512) {
+        position += correction.next(512);
+      }
+      position += correction.next(600 * 44100 % 512);
+
+      let expected = tempo_map.clock_to_ticks(ClockTime::from_seconds(600.0), &signature_map);
+      assert!(position < ramp_end);
+      assert!(u64::from(position).abs_diff(u64::from(expected)) <= 6);
+      let clock = tempo_map.ticks_to_clock(position, &signature_map);
+      assert!((clock.to_seconds() - 600.0).abs() < 1e-9);
+    }
+  }
 }
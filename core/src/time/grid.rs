@@ -0,0 +1,250 @@
+use crate::time::{ticks::TICKS_RESOLUTION, BarsTime, SignatureMap, TicksTime};
+
+const TICKS_PER_WHOLE_NOTE: u64 = 16 * TICKS_RESOLUTION;
+
+/// How the note value of a grid is divided
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Division {
+  Straight,
+
+  /// One and a half times the note value
+  Dotted,
+
+  /// A number of notes played in the space of another number of notes, like 3 in the space of 2 for triplets
+  Tuplet {
+    notes: u8,
+    space: u8,
+  },
+}
+
+/// Grid lines every note value, starting again at every bar so they follow the signature.
+/// The ticks resolution is chosen so the straight, dotted, triplet, quintuplet and septuplet grids
+/// down to 1/64 have an exact number of ticks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grid {
+  note_value: u8,
+  division: Division,
+}
+
+impl Grid {
+  pub fn new(note_value: u8, division: Division) -> Grid {
+    assert!(note_value > 0 && note_value <= 64 && note_value.is_power_of_two());
+    if let Division::Tuplet { notes, space } = division {
+      assert!(notes > 0 && space > 0);
+    }
+    Grid {
+      note_value,
+      division,
+    }
+  }
+
+  pub fn straight(note_value: u8) -> Grid {
+    Grid::new(note_value, Division::Straight)
+  }
+
+  pub fn dotted(note_value: u8) -> Grid {
+    Grid::new(note_value, Division::Dotted)
+  }
+
+  pub fn triplet(note_value: u8) -> Grid {
+    Grid::new(note_value, Division::Tuplet { notes: 3, space: 2 })
+  }
+
+  pub fn quintuplet(note_value: u8) -> Grid {
+    Grid::new(note_value, Division::Tuplet { notes: 5, space: 4 })
+  }
+
+  pub fn septuplet(note_value: u8) -> Grid {
+    Grid::new(note_value, Division::Tuplet { notes: 7, space: 4 })
+  }
+
+  pub fn get_note_value(&self) -> u8 {
+    self.note_value
+  }
+
+  pub fn get_division(&self) -> Division {
+    self.division
+  }
+
+  /// Distance between two grid lines
+  pub fn step(&self) -> TicksTime {
+    let note = TICKS_PER_WHOLE_NOTE / u64::from(self.note_value);
+    let ticks = match self.division {
+      Division::Straight => note,
+      Division::Dotted => note * 3 / 2,
+      Division::Tuplet { notes, space } => note * u64::from(space) / u64::from(notes),
+    };
+    TicksTime::new(ticks)
+  }
+
+  /// The last grid line at or before the position
+  pub fn floor(&self, position: TicksTime, signature_map: &SignatureMap) -> TicksTime {
+    let bar_start = signature_map.bar_start(position);
+    let offset = u64::from(position - bar_start);
+    bar_start + TicksTime::new(offset - offset % u64::from(self.step()))
+  }
+
+  /// The first grid line at or after the position, where the start of the next bar is always a grid line
+  pub fn ceil(&self, position: TicksTime, signature_map: &SignatureMap) -> TicksTime {
+    let floor = self.floor(position, signature_map);
+    if floor == position {
+      position
+    } else {
+      let bar_start = signature_map.bar_start(position);
+      let signature = signature_map.signature_at(position);
+      let bar_end = bar_start + BarsTime::from_bars(1).to_ticks(signature);
+      bar_end.min(floor + self.step())
+    }
+  }
+
+  /// The closest grid line, where the later one is taken when both are at the same distance
+  pub fn round(&self, position: TicksTime, signature_map: &SignatureMap) -> TicksTime {
+    let floor = self.floor(position, signature_map);
+    let ceil = self.ceil(position, signature_map);
+    if position - floor < ceil - position {
+      floor
+    } else {
+      ceil
+    }
+  }
+
+  /// The closest grid line when it is not further than a distance, to snap only the positions close to the grid
+  pub fn nearest(
+    &self,
+    position: TicksTime,
+    max_distance: TicksTime,
+    signature_map: &SignatureMap,
+  ) -> Option<TicksTime> {
+    let line = self.round(position, signature_map);
+    let distance = if line > position {
+      line - position
+    } else {
+      position - line
+    };
+    if distance <= max_distance {
+      Some(line)
+    } else {
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{Division, Grid};
+  use crate::time::{ticks::TICKS_RESOLUTION, Signature, SignatureMap, TicksTime};
+
+  fn sixteenths(num_sixteenths: f64) -> TicksTime {
+    TicksTime::new((num_sixteenths * TICKS_RESOLUTION as f64).round() as u64)
+  }
+
+  #[test]
+  pub fn steps() {
+    assert_eq!(Grid::straight(16).step(), sixteenths(1.0));
+    assert_eq!(Grid::dotted(8).step(), sixteenths(3.0));
+    assert_eq!(
+      Grid::triplet(8).step(),
+      TicksTime::new(TICKS_RESOLUTION * 4 / 3)
+    );
+    assert_eq!(
+      Grid::quintuplet(16).step(),
+      TicksTime::new(TICKS_RESOLUTION * 4 / 5)
+    );
+    assert_eq!(
+      Grid::septuplet(16).step(),
+      TicksTime::new(TICKS_RESOLUTION * 4 / 7)
+    );
+    assert_eq!(
+      Grid::new(64, Division::Tuplet { notes: 7, space: 4 }).step(),
+      TicksTime::new(TICKS_RESOLUTION / 7)
+    );
+
+    // every step divides exactly
+    for note_value in [1u8, 2, 4, 8, 16, 32, 64].iter() {
+      for grid in [
+        Grid::straight(*note_value),
+        Grid::dotted(*note_value),
+        Grid::triplet(*note_value),
+        Grid::quintuplet(*note_value),
+        Grid::septuplet(*note_value),
+      ]
+      .iter()
+      {
+        let step = u64::from(grid.step());
+        let (notes, space) = match grid.get_division() {
+          Division::Straight => (1, 1),
+          Division::Dotted => (2, 3),
+          Division::Tuplet { notes, space } => (u64::from(notes), u64::from(space)),
+        };
+        assert_eq!(
+          step * notes * u64::from(*note_value),
+          16 * TICKS_RESOLUTION * space
+        );
+      }
+    }
+  }
+
+  #[test]
+  pub fn floor_ceil_and_round() {
+    let signature_map = SignatureMap::new(Signature::new(4, 4));
+    let grid = Grid::straight(8);
+
+    assert_eq!(grid.floor(sixteenths(3.5), &signature_map), sixteenths(2.0));
+    assert_eq!(grid.ceil(sixteenths(3.5), &signature_map), sixteenths(4.0));
+    assert_eq!(grid.round(sixteenths(3.5), &signature_map), sixteenths(4.0));
+    assert_eq!(grid.round(sixteenths(2.5), &signature_map), sixteenths(2.0));
+    assert_eq!(grid.round(sixteenths(3.0), &signature_map), sixteenths(4.0));
+    assert_eq!(grid.floor(sixteenths(4.0), &signature_map), sixteenths(4.0));
+    assert_eq!(grid.ceil(sixteenths(4.0), &signature_map), sixteenths(4.0));
+  }
+
+  #[test]
+  pub fn grid_starts_again_at_every_bar() {
+    let mut signature_map = SignatureMap::new(Signature::new(4, 4));
+    signature_map.set_signature(1, Signature::new(7, 8));
+    let grid = Grid::triplet(2);
+
+    // a bar of 4/4 has 3 half note triplets
+    let third = TicksTime::new(TICKS_RESOLUTION * 16 / 3);
+    assert_eq!(grid.floor(sixteenths(15.0), &signature_map), third + third);
+    assert_eq!(
+      grid.ceil(sixteenths(15.0), &signature_map),
+      sixteenths(16.0)
+    );
+
+    // a bar of 7/8 takes 14 sixteenths, so the last line is cut by the next bar
+    let step = grid.step();
+    let last_line = sixteenths(16.0) + step + step;
+    assert_eq!(grid.floor(sixteenths(29.5), &signature_map), last_line);
+    assert_eq!(
+      grid.ceil(sixteenths(29.5), &signature_map),
+      sixteenths(30.0)
+    );
+    assert_eq!(
+      grid.round(sixteenths(29.5), &signature_map),
+      sixteenths(30.0)
+    );
+  }
+
+  #[test]
+  pub fn nearest_within_distance() {
+    let signature_map = SignatureMap::new(Signature::new(3, 4));
+    let grid = Grid::quintuplet(16);
+    let step = grid.step();
+
+    let max_distance = sixteenths(0.1);
+    assert_eq!(
+      grid.nearest(step + sixteenths(0.05), max_distance, &signature_map),
+      Some(step)
+    );
+    assert_eq!(
+      grid.nearest(step - sixteenths(0.05), max_distance, &signature_map),
+      Some(step)
+    );
+    assert_eq!(
+      grid.nearest(step + sixteenths(0.3), max_distance, &signature_map),
+      None
+    );
+  }
+}
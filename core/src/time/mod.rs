@@ -1,14 +1,22 @@
 pub mod bars;
 pub mod clock;
 pub mod drift_correction;
+pub mod grid;
 pub mod signature;
+pub mod signature_map;
+pub mod smpte;
 pub mod tempo;
+pub mod tempo_map;
 pub mod ticks;
 
 pub use self::bars::BarsTime;
 pub use self::clock::ClockTime;
+pub use self::grid::Grid;
 pub use self::signature::Signature;
+pub use self::signature_map::SignatureMap;
+pub use self::smpte::SmpteTime;
 pub use self::tempo::Tempo;
+pub use self::tempo_map::{TempoMap, TempoRamp};
 pub use self::ticks::TicksTime;
 
 pub type SampleRate = u32;
@@ -1,4 +1,6 @@
-#[derive(Debug, Clone, Copy)]
+use serde_derive::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
 pub struct Signature {
   num_beats: u8,  // numerator
   note_value: u8, // denominator
@@ -0,0 +1,247 @@
+use crate::time::{BarsTime, Signature, TicksTime};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SignatureChange {
+  bar: u16,
+  position: TicksTime,
+  signature: Signature,
+}
+
+impl SignatureChange {
+  pub fn get_bar(&self) -> u16 {
+    self.bar
+  }
+
+  pub fn get_position(&self) -> TicksTime {
+    self.position
+  }
+
+  pub fn get_signature(&self) -> Signature {
+    self.signature
+  }
+}
+
+/// Sorted list of time signature changes along the song timeline.
+/// Signatures can only change at the start of a bar, and there is always one at the first bar.
+#[derive(Debug, Clone)]
+pub struct SignatureMap {
+  changes: Vec<SignatureChange>,
+}
+
+impl SignatureMap {
+  pub fn new(signature: Signature) -> SignatureMap {
+    SignatureMap {
+      changes: vec![SignatureChange {
+        bar: 0,
+        position: TicksTime::zero(),
+        signature,
+      }],
+    }
+  }
+
+  pub fn changes(&self) -> &[SignatureChange] {
+    self.changes.as_slice()
+  }
+
+  /// Add a signature change at the start of a bar, or replace the signature of an existing one
+  pub fn set_signature(&mut self, bar: u16, signature: Signature) {
+    match self.search_bar(bar) {
+      Ok(index) => self.changes[index].signature = signature,
+      Err(index) => self.changes.insert(
+        index,
+        SignatureChange {
+          bar,
+          position: TicksTime::zero(),
+          signature,
+        },
+      ),
+    }
+    self.update_positions();
+  }
+
+  /// Remove the signature change at a bar. The initial signature can not be removed.
+  pub fn remove_signature(&mut self, bar: u16) -> bool {
+    match self.search_bar(bar) {
+      Ok(index) if index > 0 => {
+        self.changes.remove(index);
+        self.update_positions();
+        true
+      }
+      _ => false,
+    }
+  }
+
+  pub fn signature_at(&self, position: TicksTime) -> Signature {
+    self.changes[self.index_at(position)].signature
+  }
+
+  pub fn signature_at_bar(&self, bar: u16) -> Signature {
+    self.changes[self.index_at_bar(bar)].signature
+  }
+
+  /// Position of the first signature change strictly after a position
+  pub fn next_change(&self, position: TicksTime) -> Option<TicksTime> {
+    self
+      .changes
+      .get(self.index_at(position) + 1)
+      .map(|change| change.position)
+  }
+
+  /// Position where the bar containing a position starts
+  pub fn bar_start(&self, position: TicksTime) -> TicksTime {
+    let change = &self.changes[self.index_at(position)];
+    let bar_duration = u64::from(BarsTime::from_bars(1).to_ticks(change.signature));
+    let offset = u64::from(position - change.position);
+    change.position + TicksTime::new(offset - offset % bar_duration)
+  }
+
+  pub fn ticks_to_bars(&self, ticks: TicksTime) -> BarsTime {
+    let change = &self.changes[self.index_at(ticks)];
+    let time = BarsTime::from_ticks(ticks - change.position, change.signature);
+    BarsTime::new(
+      change.bar + time.get_bars(),
+      time.get_beats(),
+      time.get_sixteenths(),
+      time.get_ticks(),
+    )
+  }
+
+  pub fn bars_to_ticks(&self, time: &BarsTime) -> TicksTime {
+    let change = &self.changes[self.index_at_bar(time.get_bars())];
+    let relative_time = BarsTime::new(
+      time.get_bars() - change.bar,
+      time.get_beats(),
+      time.get_sixteenths(),
+      time.get_ticks(),
+    );
+    change.position + relative_time.to_ticks(change.signature)
+  }
+
+  fn update_positions(&mut self) {
+    for index in 1..self.changes.len() {
+      let prev = self.changes[index - 1];
+      let num_bars = self.changes[index].bar - prev.bar;
+      self.changes[index].position =
+        prev.position + BarsTime::from_bars(num_bars).to_ticks(prev.signature);
+    }
+  }
+
+  fn search_bar(&self, bar: u16) -> Result<usize, usize> {
+    self.changes.binary_search_by(|change| change.bar.cmp(&bar))
+  }
+
+  fn index_at_bar(&self, bar: u16) -> usize {
+    // the first change is always at the first bar, so there is always a previous one
+    match self.search_bar(bar) {
+      Ok(index) => index,
+      Err(index) => index - 1,
+    }
+  }
+
+  fn index_at(&self, position: TicksTime) -> usize {
+    match self
+      .changes
+      .binary_search_by(|change| change.position.cmp(&position))
+    {
+      Ok(index) => index,
+      Err(index) => index - 1,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::SignatureMap;
+  use crate::time::{ticks::TICKS_RESOLUTION, BarsTime, Signature, TicksTime};
+
+  fn sixteenths(num_sixteenths: u64) -> TicksTime {
+    TicksTime::new(num_sixteenths * TICKS_RESOLUTION)
+  }
+
+  /// 2 bars of 4/4, then 3 bars of 7/8 and then 3/4
+  fn signature_map() -> SignatureMap {
+    let mut map = SignatureMap::new(Signature::new(4, 4));
+    map.set_signature(5, Signature::new(3, 4));
+    map.set_signature(2, Signature::new(7, 8));
+    map
+  }
+
+  #[test]
+  pub fn set_signature() {
+    let map = signature_map();
+    let changes: Vec<(u16, TicksTime)> = map
+      .changes()
+      .iter()
+      .map(|change| (change.get_bar(), change.get_position()))
+      .collect();
+    assert_eq!(
+      changes,
+      vec![
+        (0, TicksTime::zero()),
+        (2, sixteenths(2 * 16)),
+        (5, sixteenths(2 * 16 + 3 * 14)),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn remove_signature() {
+    let mut map = signature_map();
+    assert!(!map.remove_signature(0));
+    assert!(!map.remove_signature(3));
+    assert!(map.remove_signature(2));
+    assert_eq!(map.changes()[1].get_bar(), 5);
+    assert_eq!(map.changes()[1].get_position(), sixteenths(5 * 16));
+  }
+
+  #[test]
+  pub fn signature_at() {
+    let map = signature_map();
+    assert_eq!(map.signature_at(sixteenths(31)).get_num_beats(), 4);
+    assert_eq!(map.signature_at(sixteenths(32)).get_num_beats(), 7);
+    assert_eq!(map.signature_at(sixteenths(32 + 42)).get_num_beats(), 3);
+    assert_eq!(map.signature_at_bar(4).get_num_beats(), 7);
+  }
+
+  #[test]
+  pub fn next_change() {
+    let map = signature_map();
+    assert_eq!(map.next_change(TicksTime::zero()), Some(sixteenths(32)));
+    assert_eq!(map.next_change(sixteenths(32)), Some(sixteenths(32 + 42)));
+    assert_eq!(map.next_change(sixteenths(32 + 42)), None);
+  }
+
+  #[test]
+  pub fn bar_start() {
+    let map = signature_map();
+    assert_eq!(map.bar_start(sixteenths(20)), sixteenths(16));
+    assert_eq!(map.bar_start(sixteenths(32 + 15)), sixteenths(32 + 14));
+    assert_eq!(
+      map.bar_start(sixteenths(32 + 42 + 13)),
+      sixteenths(32 + 42 + 12)
+    );
+  }
+
+  #[test]
+  pub fn ticks_to_bars() {
+    let map = signature_map();
+    let ticks = sixteenths(32 + 14 + 5) + TicksTime::new(30);
+    assert_eq!(map.ticks_to_bars(ticks), BarsTime::new(3, 2, 1, 30));
+    let ticks = sixteenths(32 + 42 + 12 + 4);
+    assert_eq!(map.ticks_to_bars(ticks), BarsTime::new(6, 1, 0, 0));
+  }
+
+  #[test]
+  pub fn bars_to_ticks() {
+    let map = signature_map();
+    for ticks in &[
+      sixteenths(7) + TicksTime::new(123),
+      sixteenths(32 + 14 + 5) + TicksTime::new(30),
+      sixteenths(32 + 42 + 12 + 4),
+    ] {
+      let time = map.ticks_to_bars(*ticks);
+      assert_eq!(map.bars_to_ticks(&time), *ticks);
+    }
+  }
+}
@@ -0,0 +1,438 @@
+use std::fmt;
+
+use failure::Fail;
+
+use crate::config::SmpteFrameRate;
+use crate::time::{clock, BarsTime, ClockTime, SignatureMap, TempoMap, TicksTime};
+
+pub const SUBFRAMES_PER_FRAME: u8 = 100;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Drop frame timecode skips the frame numbers 0 and 1 at the start of every minute, except every tenth minute
+const DROP_FRAMES_PER_MINUTE: u64 = 2;
+const DROP_FRAMES_PER_10_MINUTES: u64 = 30 * 60 * 10 - 9 * DROP_FRAMES_PER_MINUTE;
+const DROP_FRAMES_PER_DROP_MINUTE: u64 = 30 * 60 - DROP_FRAMES_PER_MINUTE;
+
+#[derive(Debug, Fail)]
+pub enum SmpteError {
+  #[fail(display = "Invalid timecode format: {}", text)]
+  InvalidFormat { text: String },
+
+  #[fail(display = "Timecode out of range: {}", text)]
+  OutOfRange { text: String },
+}
+
+pub type SmpteResult<T> = Result<T, SmpteError>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameRate {
+  Fps24,
+  Fps25,
+  Fps2997Drop,
+  Fps30,
+}
+
+impl From<SmpteFrameRate> for FrameRate {
+  fn from(frame_rate: SmpteFrameRate) -> FrameRate {
+    match frame_rate {
+      SmpteFrameRate::Fps24 => FrameRate::Fps24,
+      SmpteFrameRate::Fps25 => FrameRate::Fps25,
+      SmpteFrameRate::Fps2997Drop => FrameRate::Fps2997Drop,
+      SmpteFrameRate::Fps30 => FrameRate::Fps30,
+    }
+  }
+}
+
+impl FrameRate {
+  /// Number of frames labelled in every second of timecode
+  pub fn nominal_fps(&self) -> u8 {
+    match self {
+      FrameRate::Fps24 => 24,
+      FrameRate::Fps25 => 25,
+      FrameRate::Fps2997Drop | FrameRate::Fps30 => 30,
+    }
+  }
+
+  pub fn is_drop_frame(&self) -> bool {
+    *self == FrameRate::Fps2997Drop
+  }
+
+  /// Real number of frames per second as a fraction
+  fn frames_per_second(&self) -> (u64, u64) {
+    match self {
+      FrameRate::Fps2997Drop => (30_000, 1001),
+      _ => (u64::from(self.nominal_fps()), 1),
+    }
+  }
+
+  fn frames_per_day(&self) -> u64 {
+    if self.is_drop_frame() {
+      DROP_FRAMES_PER_10_MINUTES * 6 * 24
+    } else {
+      u64::from(self.nominal_fps()) * SECONDS_PER_DAY
+    }
+  }
+}
+
+/// SMPTE timecode as hours, minutes, seconds, frames and subframes, wrapping around every 24 hours
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SmpteTime {
+  frame_rate: FrameRate,
+  hours: u8,
+  minutes: u8,
+  seconds: u8,
+  frames: u8,
+  subframes: u8,
+}
+
+impl SmpteTime {
+  pub fn new(
+    frame_rate: FrameRate,
+    hours: u8,
+    minutes: u8,
+    seconds: u8,
+    frames: u8,
+    subframes: u8,
+  ) -> SmpteResult<SmpteTime> {
+    let time = SmpteTime {
+      frame_rate,
+      hours,
+      minutes,
+      seconds,
+      frames,
+      subframes,
+    };
+    let dropped_frame = frame_rate.is_drop_frame()
+      && seconds == 0
+      && !minutes.is_multiple_of(10)
+      && u64::from(frames) < DROP_FRAMES_PER_MINUTE;
+    if hours >= 24
+      || minutes >= 60
+      || seconds >= 60
+      || frames >= frame_rate.nominal_fps()
+      || subframes >= SUBFRAMES_PER_FRAME
+      || dropped_frame
+    {
+      Err(SmpteError::OutOfRange {
+        text: time.to_string(),
+      })
+    } else {
+      Ok(time)
+    }
+  }
+
+  pub fn zero(frame_rate: FrameRate) -> SmpteTime {
+    SmpteTime::from_frames(0, 0, frame_rate)
+  }
+
+  /// Timecode for a number of frames counted from 00:00:00:00
+  pub fn from_frames(frame_number: u64, subframes: u8, frame_rate: FrameRate) -> SmpteTime {
+    let mut frame_number = frame_number % frame_rate.frames_per_day();
+    if frame_rate.is_drop_frame() {
+      let num_10_minutes = frame_number / DROP_FRAMES_PER_10_MINUTES;
+      let remaining = frame_number % DROP_FRAMES_PER_10_MINUTES;
+      let dropped_minutes = if remaining >= DROP_FRAMES_PER_MINUTE {
+        (remaining - DROP_FRAMES_PER_MINUTE) / DROP_FRAMES_PER_DROP_MINUTE
+      } else {
+        0
+      };
+      frame_number += DROP_FRAMES_PER_MINUTE * (9 * num_10_minutes + dropped_minutes);
+    }
+    let fps = u64::from(frame_rate.nominal_fps());
+    let total_seconds = frame_number / fps;
+    SmpteTime {
+      frame_rate,
+      hours: (total_seconds / 3600) as u8,
+      minutes: (total_seconds / 60 % 60) as u8,
+      seconds: (total_seconds % 60) as u8,
+      frames: (frame_number % fps) as u8,
+      subframes: subframes % SUBFRAMES_PER_FRAME,
+    }
+  }
+
+  /// Number of frames from 00:00:00:00, without counting the dropped frame numbers
+  pub fn to_frames(&self) -> u64 {
+    let total_minutes = 60 * u64::from(self.hours) + u64::from(self.minutes);
+    let total_seconds = 60 * total_minutes + u64::from(self.seconds);
+    let frames = total_seconds * u64::from(self.frame_rate.nominal_fps()) + u64::from(self.frames);
+    if self.frame_rate.is_drop_frame() {
+      frames - DROP_FRAMES_PER_MINUTE * (total_minutes - total_minutes / 10)
+    } else {
+      frames
+    }
+  }
+
+  pub fn from_clock(clock: ClockTime, frame_rate: FrameRate) -> SmpteTime {
+    let (numerator, denominator) = frame_rate.frames_per_second();
+    let subframes = u128::from(clock.units())
+      * u128::from(numerator * u64::from(SUBFRAMES_PER_FRAME))
+      / u128::from(denominator * clock::UNITS_PER_SECOND);
+    let subframes_per_frame = u128::from(SUBFRAMES_PER_FRAME);
+    SmpteTime::from_frames(
+      (subframes / subframes_per_frame) as u64,
+      (subframes % subframes_per_frame) as u8,
+      frame_rate,
+    )
+  }
+
+  /// Clock time from 00:00:00:00, rounded up so it converts back to the same timecode
+  pub fn to_clock(&self) -> ClockTime {
+    let (numerator, denominator) = self.frame_rate.frames_per_second();
+    let subframes = self.to_frames() * u64::from(SUBFRAMES_PER_FRAME) + u64::from(self.subframes);
+    let dividend = u128::from(subframes) * u128::from(denominator * clock::UNITS_PER_SECOND);
+    let divisor = u128::from(numerator * u64::from(SUBFRAMES_PER_FRAME));
+    ClockTime::new(dividend.div_ceil(divisor) as u64)
+  }
+
+  /// Timecode for a song position, where the start of the song is at the offset timecode
+  pub fn from_ticks_with_map(
+    ticks: TicksTime,
+    offset: &SmpteTime,
+    signature_map: &SignatureMap,
+    tempo_map: &TempoMap,
+  ) -> SmpteTime {
+    let clock = ticks.to_clock_with_map(signature_map, tempo_map) + offset.to_clock();
+    SmpteTime::from_clock(clock, offset.frame_rate)
+  }
+
+  /// Song position for the timecode, where the start of the song is at the offset timecode.
+  /// Timecodes before the offset are at the start of the song.
+  pub fn to_ticks_with_map(
+    &self,
+    offset: &SmpteTime,
+    signature_map: &SignatureMap,
+    tempo_map: &TempoMap,
+  ) -> TicksTime {
+    let clock = self.to_clock();
+    let offset_clock = offset.to_clock();
+    if clock > offset_clock {
+      (clock - offset_clock).to_ticks_with_map(signature_map, tempo_map)
+    } else {
+      TicksTime::zero()
+    }
+  }
+
+  pub fn to_bars_with_map(
+    &self,
+    offset: &SmpteTime,
+    signature_map: &SignatureMap,
+    tempo_map: &TempoMap,
+  ) -> BarsTime {
+    let ticks = self.to_ticks_with_map(offset, signature_map, tempo_map);
+    BarsTime::from_ticks_with_map(ticks, signature_map)
+  }
+
+  /// Parse timecodes like 01:02:03:04 or 01:02:03;04.50, where the subframes are optional
+  pub fn parse(text: &str, frame_rate: FrameRate) -> SmpteResult<SmpteTime> {
+    let invalid_format = || SmpteError::InvalidFormat {
+      text: text.to_string(),
+    };
+    let (timecode, subframes) = match text.trim().split('.').collect::<Vec<&str>>().as_slice() {
+      [timecode] => (*timecode, 0),
+      [timecode, subframes] => (*timecode, subframes.parse().map_err(|_| invalid_format())?),
+      _ => return Err(invalid_format()),
+    };
+    let fields = timecode
+      .split(&[':', ';'][..])
+      .map(|field| field.parse::<u8>())
+      .collect::<Result<Vec<u8>, _>>()
+      .map_err(|_| invalid_format())?;
+    match fields.as_slice() {
+      [hours, minutes, seconds, frames] => {
+        SmpteTime::new(frame_rate, *hours, *minutes, *seconds, *frames, subframes)
+      }
+      _ => Err(invalid_format()),
+    }
+  }
+
+  pub fn get_frame_rate(&self) -> FrameRate {
+    self.frame_rate
+  }
+
+  pub fn get_hours(&self) -> u8 {
+    self.hours
+  }
+
+  pub fn get_minutes(&self) -> u8 {
+    self.minutes
+  }
+
+  pub fn get_seconds(&self) -> u8 {
+    self.seconds
+  }
+
+  pub fn get_frames(&self) -> u8 {
+    self.frames
+  }
+
+  pub fn get_subframes(&self) -> u8 {
+    self.subframes
+  }
+}
+
+impl fmt::Display for SmpteTime {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let frames_separator = if self.frame_rate.is_drop_frame() {
+      ';'
+    } else {
+      ':'
+    };
+    write!(
+      f,
+      "{:02}:{:02}:{:02}{}{:02}.{:02}",
+      self.hours, self.minutes, self.seconds, frames_separator, self.frames, self.subframes
+    )
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{FrameRate, SmpteError, SmpteTime};
+  use crate::time::{BarsTime, ClockTime, Signature, SignatureMap, Tempo, TempoMap, TicksTime};
+
+  const FRAME_RATES: [FrameRate; 4] = [
+    FrameRate::Fps24,
+    FrameRate::Fps25,
+    FrameRate::Fps2997Drop,
+    FrameRate::Fps30,
+  ];
+
+  fn smpte(frame_rate: FrameRate, h: u8, m: u8, s: u8, f: u8, sf: u8) -> SmpteTime {
+    SmpteTime::new(frame_rate, h, m, s, f, sf).unwrap()
+  }
+
+  #[test]
+  pub fn new_out_of_range() {
+    assert!(SmpteTime::new(FrameRate::Fps25, 24, 0, 0, 0, 0).is_err());
+    assert!(SmpteTime::new(FrameRate::Fps25, 0, 60, 0, 0, 0).is_err());
+    assert!(SmpteTime::new(FrameRate::Fps25, 0, 0, 60, 0, 0).is_err());
+    assert!(SmpteTime::new(FrameRate::Fps25, 0, 0, 0, 25, 0).is_err());
+    assert!(SmpteTime::new(FrameRate::Fps24, 0, 0, 0, 0, 100).is_err());
+    assert!(SmpteTime::new(FrameRate::Fps30, 0, 1, 0, 0, 0).is_ok());
+    assert!(SmpteTime::new(FrameRate::Fps2997Drop, 0, 1, 0, 1, 0).is_err());
+    assert!(SmpteTime::new(FrameRate::Fps2997Drop, 0, 1, 0, 2, 0).is_ok());
+    assert!(SmpteTime::new(FrameRate::Fps2997Drop, 0, 10, 0, 0, 0).is_ok());
+  }
+
+  #[test]
+  pub fn drop_frame_numbers() {
+    let rate = FrameRate::Fps2997Drop;
+    assert_eq!(smpte(rate, 0, 0, 59, 29, 0).to_frames(), 1799);
+    assert_eq!(smpte(rate, 0, 1, 0, 2, 0).to_frames(), 1800);
+    assert_eq!(smpte(rate, 0, 10, 0, 0, 0).to_frames(), 17982);
+    assert_eq!(smpte(rate, 1, 0, 0, 0, 0).to_frames(), 107_892);
+    assert_eq!(
+      SmpteTime::from_frames(1800, 0, rate),
+      smpte(rate, 0, 1, 0, 2, 0)
+    );
+    assert_eq!(
+      SmpteTime::from_frames(17982, 0, rate),
+      smpte(rate, 0, 10, 0, 0, 0)
+    );
+    assert_eq!(
+      SmpteTime::from_frames(17981, 0, rate),
+      smpte(rate, 0, 9, 59, 29, 0)
+    );
+  }
+
+  #[test]
+  pub fn frames_round_trip() {
+    for frame_rate in FRAME_RATES.iter() {
+      for frame_number in (0..200_000).step_by(7) {
+        let time = SmpteTime::from_frames(frame_number, 0, *frame_rate);
+        assert_eq!(time.to_frames(), frame_number);
+      }
+    }
+  }
+
+  #[test]
+  pub fn from_frames_wraps_around_a_day() {
+    let time = SmpteTime::from_frames(25 * 24 * 3600 + 3, 0, FrameRate::Fps25);
+    assert_eq!(time, smpte(FrameRate::Fps25, 0, 0, 0, 3, 0));
+  }
+
+  #[test]
+  pub fn to_clock() {
+    let time = smpte(FrameRate::Fps25, 1, 0, 0, 0, 0);
+    assert_eq!(time.to_clock(), ClockTime::from_seconds(3600.0));
+    let time = smpte(FrameRate::Fps24, 0, 0, 1, 12, 50);
+    // 1.520833333... seconds rounded up to the next clock unit
+    assert_eq!(time.to_clock(), ClockTime::new(1_520_833_334));
+    let time = smpte(FrameRate::Fps2997Drop, 1, 0, 0, 0, 0);
+    assert_eq!(time.to_clock(), ClockTime::from_seconds(3599.9964));
+  }
+
+  #[test]
+  pub fn clock_round_trip() {
+    for frame_rate in FRAME_RATES.iter() {
+      for frame_number in (0..200_000).step_by(13) {
+        let time = SmpteTime::from_frames(frame_number, (frame_number % 100) as u8, *frame_rate);
+        assert_eq!(SmpteTime::from_clock(time.to_clock(), *frame_rate), time);
+      }
+    }
+  }
+
+  #[test]
+  pub fn display() {
+    let time = smpte(FrameRate::Fps25, 1, 2, 3, 4, 5);
+    assert_eq!(time.to_string(), "01:02:03:04.05");
+    let time = smpte(FrameRate::Fps2997Drop, 10, 20, 30, 12, 0);
+    assert_eq!(time.to_string(), "10:20:30;12.00");
+  }
+
+  #[test]
+  pub fn parse() {
+    let time = SmpteTime::parse("01:02:03:04", FrameRate::Fps25).unwrap();
+    assert_eq!(time, smpte(FrameRate::Fps25, 1, 2, 3, 4, 0));
+    let time = SmpteTime::parse("10:20:30;12.34", FrameRate::Fps2997Drop).unwrap();
+    assert_eq!(time, smpte(FrameRate::Fps2997Drop, 10, 20, 30, 12, 34));
+    let text = time.to_string();
+    assert_eq!(
+      SmpteTime::parse(&text, FrameRate::Fps2997Drop).unwrap(),
+      time
+    );
+  }
+
+  #[test]
+  pub fn parse_errors() {
+    match SmpteTime::parse("01:02:03", FrameRate::Fps25) {
+      Err(SmpteError::InvalidFormat { .. }) => {}
+      result => panic!("Unexpected result: {:?}", result),
+    }
+    match SmpteTime::parse("01:02:xx:04", FrameRate::Fps25) {
+      Err(SmpteError::InvalidFormat { .. }) => {}
+      result => panic!("Unexpected result: {:?}", result),
+    }
+    match SmpteTime::parse("01:02:03:27", FrameRate::Fps25) {
+      Err(SmpteError::OutOfRange { .. }) => {}
+      result => panic!("Unexpected result: {:?}", result),
+    }
+  }
+
+  #[test]
+  pub fn ticks_with_offset() {
+    let signature_map = SignatureMap::new(Signature::new(4, 4));
+    let tempo_map = TempoMap::new(Tempo::new(120));
+    let offset = smpte(FrameRate::Fps25, 1, 0, 0, 0, 0);
+
+    let ticks = BarsTime::new(2, 0, 0, 0).to_ticks_with_map(&signature_map);
+    let time = SmpteTime::from_ticks_with_map(ticks, &offset, &signature_map, &tempo_map);
+    assert_eq!(time, smpte(FrameRate::Fps25, 1, 0, 4, 0, 0));
+    assert_eq!(
+      time.to_ticks_with_map(&offset, &signature_map, &tempo_map),
+      ticks
+    );
+    assert_eq!(
+      time.to_bars_with_map(&offset, &signature_map, &tempo_map),
+      BarsTime::new(2, 0, 0, 0)
+    );
+
+    let before_offset = smpte(FrameRate::Fps25, 0, 59, 0, 0, 0);
+    assert_eq!(
+      before_offset.to_ticks_with_map(&offset, &signature_map, &tempo_map),
+      TicksTime::zero()
+    );
+  }
+}
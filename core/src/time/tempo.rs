@@ -1,31 +1,36 @@
-#[derive(Debug, Clone, Copy)]
-pub struct Tempo(u16);
+/// Tempo resolution, a tempo is stored as an integer number of thousandths of beat per minute
+pub const TEMPO_RESOLUTION: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tempo(u32);
 
 impl Tempo {
   pub fn new(value: u16) -> Tempo {
+    Tempo(u32::from(value) * TEMPO_RESOLUTION)
+  }
+
+  /// Tempo from thousandths of beat per minute
+  pub fn from_millis(value: u32) -> Tempo {
     Tempo(value)
   }
 
-  pub fn get_value(&self) -> u16 {
-    self.0
+  pub fn from_bpm(bpm: f64) -> Tempo {
+    Tempo((bpm * f64::from(TEMPO_RESOLUTION)).round() as u32)
   }
-}
 
-impl From<Tempo> for f64 {
-  fn from(item: Tempo) -> Self {
-    f64::from(item.0)
+  /// Beats per minute
+  pub fn get_value(&self) -> f64 {
+    f64::from(self.0) / f64::from(TEMPO_RESOLUTION)
   }
-}
 
-impl From<Tempo> for u64 {
-  fn from(item: Tempo) -> Self {
-    u64::from(item.0)
+  pub fn get_millis(&self) -> u32 {
+    self.0
   }
 }
 
-impl From<Tempo> for u16 {
+impl From<Tempo> for f64 {
   fn from(item: Tempo) -> Self {
-    item.0
+    item.get_value()
   }
 }
 
@@ -37,6 +42,19 @@ mod test {
   #[test]
   pub fn tempo_new() {
     let tempo = Tempo::new(120);
-    assert_eq!(tempo.get_value(), 120);
+    assert_eq!(tempo.get_value(), 120.0);
+    assert_eq!(tempo.get_millis(), 120_000);
+  }
+
+  #[test]
+  pub fn tempo_from_bpm() {
+    assert_eq!(Tempo::from_bpm(92.5).get_millis(), 92_500);
+    assert_eq!(Tempo::from_bpm(128.03).get_value(), 128.03);
+    assert_eq!(Tempo::from_bpm(120.0), Tempo::new(120));
+  }
+
+  #[test]
+  pub fn tempo_from_millis() {
+    assert_eq!(Tempo::from_millis(128_030), Tempo::from_bpm(128.03));
   }
 }
@@ -0,0 +1,527 @@
+use std::cmp::min;
+
+use crate::time::{clock, BarsTime, ClockTime, Signature, SignatureMap, Tempo, TicksTime};
+
+const SECONDS_PER_MINUTE: f64 = 60.0;
+
+/// How the tempo moves from a tempo change to the next one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TempoRamp {
+  /// Keep the tempo until the next change
+  Step,
+  /// Change the tempo linearly with the song position
+  Linear,
+  /// Change the tempo by the same ratio for every tick, which sounds even across the ramp
+  Exponential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempoChange {
+  position: TicksTime,
+  tempo: Tempo,
+  ramp: TempoRamp,
+}
+
+impl TempoChange {
+  pub fn new(position: TicksTime, tempo: Tempo) -> TempoChange {
+    TempoChange::with_ramp(position, tempo, TempoRamp::Step)
+  }
+
+  pub fn with_ramp(position: TicksTime, tempo: Tempo, ramp: TempoRamp) -> TempoChange {
+    TempoChange {
+      position,
+      tempo,
+      ramp,
+    }
+  }
+
+  pub fn get_position(&self) -> TicksTime {
+    self.position
+  }
+
+  pub fn get_tempo(&self) -> Tempo {
+    self.tempo
+  }
+
+  pub fn get_ramp(&self) -> TempoRamp {
+    self.ramp
+  }
+}
+
+/// Tempo along a region of the song where the signature doesn't change and the tempo
+/// is either constant or following a single ramp. A ramp starts at its tempo change and
+/// reaches the tempo of the next change at its position, the tempo stays constant after it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempoCurve {
+  signature: Signature,
+  position: TicksTime,
+  length: TicksTime,
+  start_tempo: Tempo,
+  end_tempo: Tempo,
+  ramp: TempoRamp,
+}
+
+impl TempoCurve {
+  pub fn constant(signature: Signature, tempo: Tempo) -> TempoCurve {
+    TempoCurve {
+      signature,
+      position: TicksTime::zero(),
+      length: TicksTime::zero(),
+      start_tempo: tempo,
+      end_tempo: tempo,
+      ramp: TempoRamp::Step,
+    }
+  }
+
+  pub fn get_signature(&self) -> Signature {
+    self.signature
+  }
+
+  pub fn is_constant(&self) -> bool {
+    self.ramp == TempoRamp::Step || self.length == TicksTime::zero()
+  }
+
+  /// Tempo at a position in beats per minute
+  pub fn tempo_at(&self, position: TicksTime) -> f64 {
+    self.bpm_at(f64::from(position - self.position))
+  }
+
+  /// Clock time elapsed when moving between two positions
+  pub fn ticks_to_clock(&self, start: TicksTime, end: TicksTime) -> ClockTime {
+    if self.is_constant() {
+      (end - start).to_clock(self.signature, self.start_tempo)
+    } else {
+      let seconds = self.seconds_at(end) - self.seconds_at(start);
+      ClockTime::from_seconds(seconds.max(0.0))
+    }
+  }
+
+  /// Position reached after moving from a position during some clock time
+  pub fn clock_to_ticks(&self, start: TicksTime, clock: ClockTime) -> TicksTime {
+    if self.is_constant() {
+      start + clock.to_ticks(self.signature, self.start_tempo)
+    } else {
+      let seconds = self.seconds_at(start) + clock.units() as f64 / clock::UNITS_PER_SECOND as f64;
+      TicksTime::new(self.position_at(seconds).round() as u64)
+    }
+  }
+
+  /// Seconds elapsed from the start of the curve until a position
+  pub fn seconds_at(&self, position: TicksTime) -> f64 {
+    self.seconds_at_offset(f64::from(position - self.position))
+  }
+
+  /// Position, in ticks with decimals, reached after some seconds from the start of the curve
+  pub fn position_at(&self, seconds: f64) -> f64 {
+    f64::from(self.position) + self.offset_at_seconds(seconds)
+  }
+
+  fn ticks_per_second(&self, bpm: f64) -> f64 {
+    let ticks_per_beat = f64::from(BarsTime::new(0, 1, 0, 0).to_ticks(self.signature));
+    ticks_per_beat * bpm / SECONDS_PER_MINUTE
+  }
+
+  fn bpm_at(&self, offset: f64) -> f64 {
+    let (start_bpm, end_bpm, length) = self.ramp_params();
+    if self.is_constant() || offset >= length {
+      end_bpm
+    } else {
+      match self.ramp {
+        TempoRamp::Linear => start_bpm + (end_bpm - start_bpm) * offset / length,
+        _ => start_bpm * (end_bpm / start_bpm).powf(offset / length),
+      }
+    }
+  }
+
+  /// Integrate the inverse of the tempo along the ramp, which gives a closed form for both ramps
+  fn seconds_at_offset(&self, offset: f64) -> f64 {
+    let (start_bpm, end_bpm, length) = self.ramp_params();
+    if self.is_constant() || start_bpm == end_bpm {
+      offset / self.ticks_per_second(start_bpm)
+    } else if offset > length {
+      self.seconds_at_offset(length) + (offset - length) / self.ticks_per_second(end_bpm)
+    } else {
+      let k = self.ticks_per_second(1.0);
+      match self.ramp {
+        TempoRamp::Linear => {
+          let slope = (end_bpm - start_bpm) / length;
+          (self.bpm_at(offset) / start_bpm).ln() / (k * slope)
+        }
+        _ => {
+          let rate = (end_bpm / start_bpm).ln() / length;
+          -(-rate * offset).exp_m1() / (k * start_bpm * rate)
+        }
+      }
+    }
+  }
+
+  fn offset_at_seconds(&self, seconds: f64) -> f64 {
+    let (start_bpm, end_bpm, length) = self.ramp_params();
+    if self.is_constant() || start_bpm == end_bpm {
+      seconds * self.ticks_per_second(start_bpm)
+    } else {
+      let ramp_seconds = self.seconds_at_offset(length);
+      if seconds > ramp_seconds {
+        length + (seconds - ramp_seconds) * self.ticks_per_second(end_bpm)
+      } else {
+        let k = self.ticks_per_second(1.0);
+        match self.ramp {
+          TempoRamp::Linear => {
+            let slope = (end_bpm - start_bpm) / length;
+            start_bpm * (k * slope * seconds).exp_m1() / slope
+          }
+          _ => {
+            let rate = (end_bpm / start_bpm).ln() / length;
+            -(-k * start_bpm * rate * seconds).ln_1p() / rate
+          }
+        }
+      }
+    }
+  }
+
+  fn ramp_params(&self) -> (f64, f64, f64) {
+    (
+      self.start_tempo.get_value(),
+      self.end_tempo.get_value(),
+      f64::from(self.length),
+    )
+  }
+}
+
+/// Sorted list of tempo changes along the song timeline.
+/// There is always a tempo change at the start of the song, so every position has a tempo.
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+  changes: Vec<TempoChange>,
+}
+
+impl TempoMap {
+  pub fn new(tempo: Tempo) -> TempoMap {
+    TempoMap {
+      changes: vec![TempoChange::new(TicksTime::zero(), tempo)],
+    }
+  }
+
+  pub fn changes(&self) -> &[TempoChange] {
+    self.changes.as_slice()
+  }
+
+  /// Add a tempo change, or replace the tempo of an existing change at the same position
+  pub fn set_tempo(&mut self, position: TicksTime, tempo: Tempo) {
+    self.set_tempo_with_ramp(position, tempo, TempoRamp::Step);
+  }
+
+  /// Add a tempo change that ramps towards the tempo of the next change
+  pub fn set_tempo_with_ramp(&mut self, position: TicksTime, tempo: Tempo, ramp: TempoRamp) {
+    let change = TempoChange::with_ramp(position, tempo, ramp);
+    match self.search(position) {
+      Ok(index) => self.changes[index] = change,
+      Err(index) => self.changes.insert(index, change),
+    }
+  }
+
+  /// Remove the tempo change at a position. The initial tempo can not be removed.
+  pub fn remove_tempo(&mut self, position: TicksTime) -> bool {
+    match self.search(position) {
+      Ok(index) if index > 0 => {
+        self.changes.remove(index);
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Tempo of the last change at or before a position, ignoring ramps
+  pub fn tempo_at(&self, position: TicksTime) -> Tempo {
+    self.changes[self.index_at(position)].tempo
+  }
+
+  /// Tempo curve for the region of constant signature and tempo ramp containing a position
+  pub fn curve_at(&self, position: TicksTime, signature_map: &SignatureMap) -> TempoCurve {
+    let index = self.index_at(position);
+    let change = self.changes[index];
+    let signature = signature_map.signature_at(position);
+    match self.changes.get(index + 1) {
+      Some(next) if change.ramp != TempoRamp::Step => TempoCurve {
+        signature,
+        position: change.position,
+        length: next.position - change.position,
+        start_tempo: change.tempo,
+        end_tempo: next.tempo,
+        ramp: change.ramp,
+      },
+      _ => TempoCurve::constant(signature, change.tempo),
+    }
+  }
+
+  /// Position of the first tempo change strictly after a position
+  pub fn next_change(&self, position: TicksTime) -> Option<TicksTime> {
+    self
+      .changes
+      .get(self.index_at(position) + 1)
+      .map(|change| change.position)
+  }
+
+  /// Clock time from the start of the song to a position, integrating across tempo and signature regions
+  pub fn ticks_to_clock(&self, ticks: TicksTime, signature_map: &SignatureMap) -> ClockTime {
+    let mut clock = ClockTime::zero();
+    let mut position = TicksTime::zero();
+    while position < ticks {
+      let region_end = self
+        .region_end(position, signature_map)
+        .map_or(ticks, |region_end| min(region_end, ticks));
+      clock += self
+        .curve_at(position, signature_map)
+        .ticks_to_clock(position, region_end);
+      position = region_end;
+    }
+    clock
+  }
+
+  /// Song position for a clock time from the start of the song, integrating across tempo and signature regions
+  pub fn clock_to_ticks(&self, clock: ClockTime, signature_map: &SignatureMap) -> TicksTime {
+    let mut remaining = clock;
+    let mut position = TicksTime::zero();
+    loop {
+      let curve = self.curve_at(position, signature_map);
+      if let Some(region_end) = self.region_end(position, signature_map) {
+        let region_clock = curve.ticks_to_clock(position, region_end);
+        if remaining >= region_clock {
+          remaining -= region_clock;
+          position = region_end;
+          continue;
+        }
+      }
+      return curve.clock_to_ticks(position, remaining);
+    }
+  }
+
+  /// End of the region starting at a position where both the tempo and the signature are constant
+  pub fn region_end(&self, position: TicksTime, signature_map: &SignatureMap) -> Option<TicksTime> {
+    match (
+      self.next_change(position),
+      signature_map.next_change(position),
+    ) {
+      (Some(tempo_end), Some(signature_end)) => Some(min(tempo_end, signature_end)),
+      (tempo_end, None) => tempo_end,
+      (None, signature_end) => signature_end,
+    }
+  }
+
+  fn search(&self, position: TicksTime) -> Result<usize, usize> {
+    self
+      .changes
+      .binary_search_by(|change| change.position.cmp(&position))
+  }
+
+  fn index_at(&self, position: TicksTime) -> usize {
+    // the first change is always at zero, so there is always a previous one
+    match self.search(position) {
+      Ok(index) => index,
+      Err(index) => index - 1,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{TempoChange, TempoMap, TempoRamp};
+  use crate::time::{clock, BarsTime, ClockTime, Signature, SignatureMap, Tempo, TicksTime};
+
+  fn beats(num_beats: u16) -> TicksTime {
+    BarsTime::new(0, num_beats, 0, 0).to_ticks(Signature::new(4, 4))
+  }
+
+  /// A ramp from 60 to 180 bpm along 1200 beats, which takes around 11 minutes
+  fn ramp_map(ramp: TempoRamp) -> TempoMap {
+    let mut map = TempoMap::new(Tempo::new(60));
+    map.set_tempo_with_ramp(TicksTime::zero(), Tempo::new(60), ramp);
+    map.set_tempo(beats(1200), Tempo::new(180));
+    map
+  }
+
+  /// Numerical integration of the seconds per beat along a number of beats with the Simpson's rule
+  fn integrate_seconds(num_beats: f64, bpm: impl Fn(f64) -> f64) -> f64 {
+    let steps = 100_000;
+    let step = num_beats / f64::from(steps);
+    let seconds_per_beat = |beat: f64| 60.0 / bpm(beat);
+    let sum: f64 = (1..steps)
+      .map(|i| {
+        let weight = if i % 2 == 0 { 2.0 } else { 4.0 };
+        weight * seconds_per_beat(f64::from(i) * step)
+      })
+      .sum();
+    (seconds_per_beat(0.0) + sum + seconds_per_beat(num_beats)) * step / 3.0
+  }
+
+  #[test]
+  pub fn new() {
+    let map = TempoMap::new(Tempo::new(120));
+    assert_eq!(
+      map.changes(),
+      &[TempoChange::new(TicksTime::zero(), Tempo::new(120))]
+    );
+  }
+
+  #[test]
+  pub fn set_tempo() {
+    let mut map = TempoMap::new(Tempo::new(120));
+    map.set_tempo(beats(8), Tempo::new(90));
+    map.set_tempo(beats(4), Tempo::new(60));
+    map.set_tempo(beats(8), Tempo::new(100));
+    assert_eq!(
+      map.changes(),
+      &[
+        TempoChange::new(TicksTime::zero(), Tempo::new(120)),
+        TempoChange::new(beats(4), Tempo::new(60)),
+        TempoChange::new(beats(8), Tempo::new(100)),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn remove_tempo() {
+    let mut map = TempoMap::new(Tempo::new(120));
+    map.set_tempo(beats(4), Tempo::new(60));
+    assert!(!map.remove_tempo(TicksTime::zero()));
+    assert!(!map.remove_tempo(beats(5)));
+    assert!(map.remove_tempo(beats(4)));
+    assert_eq!(map.changes().len(), 1);
+  }
+
+  #[test]
+  pub fn tempo_at() {
+    let mut map = TempoMap::new(Tempo::new(120));
+    map.set_tempo(beats(4), Tempo::new(60));
+    assert_eq!(map.tempo_at(TicksTime::zero()), Tempo::new(120));
+    assert_eq!(map.tempo_at(beats(4) - TicksTime::new(1)), Tempo::new(120));
+    assert_eq!(map.tempo_at(beats(4)), Tempo::new(60));
+    assert_eq!(map.tempo_at(beats(100)), Tempo::new(60));
+  }
+
+  #[test]
+  pub fn next_change() {
+    let mut map = TempoMap::new(Tempo::new(120));
+    map.set_tempo(beats(4), Tempo::new(60));
+    assert_eq!(map.next_change(TicksTime::zero()), Some(beats(4)));
+    assert_eq!(map.next_change(beats(3)), Some(beats(4)));
+    assert_eq!(map.next_change(beats(4)), None);
+  }
+
+  #[test]
+  pub fn ticks_to_clock() {
+    let signature_map = SignatureMap::new(Signature::new(4, 4));
+    let mut map = TempoMap::new(Tempo::new(120));
+    map.set_tempo(beats(4), Tempo::new(60));
+    assert_eq!(
+      map.ticks_to_clock(beats(2), &signature_map),
+      ClockTime::from_seconds(1.0)
+    );
+    assert_eq!(
+      map.ticks_to_clock(beats(4), &signature_map),
+      ClockTime::from_seconds(2.0)
+    );
+    assert_eq!(
+      map.ticks_to_clock(beats(6), &signature_map),
+      ClockTime::from_seconds(4.0)
+    );
+  }
+
+  #[test]
+  pub fn clock_to_ticks() {
+    let signature_map = SignatureMap::new(Signature::new(4, 4));
+    let mut map = TempoMap::new(Tempo::new(120));
+    map.set_tempo(beats(4), Tempo::new(60));
+    assert_eq!(
+      map.clock_to_ticks(ClockTime::from_seconds(1.0), &signature_map),
+      beats(2)
+    );
+    assert_eq!(
+      map.clock_to_ticks(ClockTime::from_seconds(2.0), &signature_map),
+      beats(4)
+    );
+    assert_eq!(
+      map.clock_to_ticks(ClockTime::new(4 * clock::UNITS_PER_SECOND), &signature_map),
+      beats(6)
+    );
+  }
+
+  #[test]
+  pub fn set_tempo_with_ramp() {
+    let map = ramp_map(TempoRamp::Linear);
+    assert_eq!(
+      map.changes(),
+      &[
+        TempoChange::with_ramp(TicksTime::zero(), Tempo::new(60), TempoRamp::Linear),
+        TempoChange::new(beats(1200), Tempo::new(180)),
+      ]
+    );
+  }
+
+  #[test]
+  pub fn curve_tempo_at() {
+    let signature_map = SignatureMap::new(Signature::new(4, 4));
+    let linear = ramp_map(TempoRamp::Linear).curve_at(beats(600), &signature_map);
+    assert!((linear.tempo_at(beats(600)) - 120.0).abs() < 1e-9);
+    assert!((linear.tempo_at(beats(1300)) - 180.0).abs() < 1e-9);
+    let exponential = ramp_map(TempoRamp::Exponential).curve_at(beats(600), &signature_map);
+    assert!((exponential.tempo_at(beats(600)) - 60.0 * 3f64.sqrt()).abs() < 1e-9);
+    let step = ramp_map(TempoRamp::Step).curve_at(beats(600), &signature_map);
+    assert!(step.is_constant());
+    assert_eq!(step.tempo_at(beats(600)), 60.0);
+  }
+
+  #[test]
+  pub fn ticks_to_clock_with_linear_ramp() {
+    let signature_map = SignatureMap::new(Signature::new(4, 4));
+    let map = ramp_map(TempoRamp::Linear);
+    for num_beats in &[1u16, 100, 600, 1200, 1300] {
+      let expected = integrate_seconds(f64::from(*num_beats), |beat| {
+        if beat < 1200.0 {
+          60.0 + 120.0 * beat / 1200.0
+        } else {
+          180.0
+        }
+      });
+      let clock = map.ticks_to_clock(beats(*num_beats), &signature_map);
+      assert!((clock.to_seconds() - expected).abs() < 1e-6);
+      // a nanosecond of clock is worth around 6 ticks at 180 bpm
+      let ticks = map.clock_to_ticks(clock, &signature_map);
+      assert!(u64::from(ticks).abs_diff(u64::from(beats(*num_beats))) <= 6);
+    }
+  }
+
+  #[test]
+  pub fn ticks_to_clock_with_exponential_ramp() {
+    let signature_map = SignatureMap::new(Signature::new(4, 4));
+    let map = ramp_map(TempoRamp::Exponential);
+    for num_beats in &[1u16, 100, 600, 1200, 1300] {
+      let expected = integrate_seconds(f64::from(*num_beats), |beat| {
+        60.0 * 3f64.powf(beat.min(1200.0) / 1200.0)
+      });
+      let clock = map.ticks_to_clock(beats(*num_beats), &signature_map);
+      assert!((clock.to_seconds() - expected).abs() < 1e-6);
+      // a nanosecond of clock is worth around 6 ticks at 180 bpm
+      let ticks = map.clock_to_ticks(clock, &signature_map);
+      assert!(u64::from(ticks).abs_diff(u64::from(beats(*num_beats))) <= 6);
+    }
+  }
+
+  #[test]
+  pub fn ticks_to_clock_with_signature_changes() {
+    let mut signature_map = SignatureMap::new(Signature::new(4, 4));
+    signature_map.set_signature(1, Signature::new(7, 8));
+    let map = TempoMap::new(Tempo::new(120));
+    // 4 quarters at 120 bpm and then 7 eighths at 120 bpm
+    let position = BarsTime::new(2, 0, 0, 0).to_ticks_with_map(&signature_map);
+    assert_eq!(
+      map.ticks_to_clock(position, &signature_map),
+      ClockTime::from_seconds(5.5)
+    );
+    assert_eq!(
+      map.clock_to_ticks(ClockTime::from_seconds(5.5), &signature_map),
+      position
+    );
+  }
+}
@@ -3,13 +3,23 @@ use std::{
   ops::{Add, AddAssign, Div, Mul, Sub, SubAssign},
 };
 
-use crate::time::{clock, ClockTime, Signature, Tempo};
+use serde::{Deserialize, Deserializer};
+
+use crate::time::{clock, tempo, ClockTime, Signature, SignatureMap, Tempo, TempoMap};
 
 pub const TICKS_RESOLUTION: u64 = 508_032_000; // 2^10 * 3^4 * 5^3 * 7^2
 
 #[derive(Debug, Eq, Copy, Clone)]
 pub struct TicksTime(u64);
 
+/// Decode a number of ticks, for the fields of the decoded structs
+pub fn deserialize_ticks<'de, D>(deserializer: D) -> Result<TicksTime, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  u64::deserialize(deserializer).map(TicksTime::new)
+}
+
 impl TicksTime {
   pub fn new(ticks: u64) -> TicksTime {
     TicksTime(ticks)
@@ -21,7 +31,9 @@ impl TicksTime {
 
   pub fn per_minute(signature: Signature, tempo: Tempo) -> TicksTime {
     let ticks_per_beat = TICKS_RESOLUTION * 16 / u64::from(signature.get_note_value());
-    TicksTime::new(ticks_per_beat * u64::from(tempo))
+    let ticks = u128::from(ticks_per_beat) * u128::from(tempo.get_millis())
+      / u128::from(tempo::TEMPO_RESOLUTION);
+    TicksTime::new(ticks as u64)
   }
 
   pub fn to_clock(&self, signature: Signature, tempo: Tempo) -> ClockTime {
@@ -30,6 +42,10 @@ impl TicksTime {
       u128::from(self.0) * u128::from(clock::UNITS_PER_MINUTE) / u128::from(ticks_per_minute);
     ClockTime::new(clock_units as u64)
   }
+
+  pub fn to_clock_with_map(&self, signature_map: &SignatureMap, tempo_map: &TempoMap) -> ClockTime {
+    tempo_map.ticks_to_clock(*self, signature_map)
+  }
 }
 
 impl Ord for TicksTime {
@@ -112,7 +128,7 @@ impl From<TicksTime> for u64 {
 #[cfg(test)]
 mod test {
 
-  use super::{clock, Signature, Tempo, TicksTime};
+  use super::{clock, Signature, SignatureMap, Tempo, TempoMap, TicksTime};
   use std::cmp::Ordering;
 
   #[test]
@@ -135,6 +151,13 @@ mod test {
     assert_eq!(ticks.0, 243_855_360_000);
   }
 
+  #[test]
+  pub fn per_minute_fractional_tempo() {
+    let signature = Signature::new(4, 4);
+    let ticks = TicksTime::per_minute(signature, Tempo::from_bpm(92.5));
+    assert_eq!(ticks.0, 187_971_840_000);
+  }
+
   #[test]
   pub fn to_clock() {
     let signature = Signature::new(4, 4);
@@ -144,6 +167,18 @@ mod test {
     assert_eq!(time.units() / clock::UNITS_PER_MINUTE, 1);
   }
 
+  #[test]
+  pub fn to_clock_with_map() {
+    let signature = Signature::new(4, 4);
+    let mut tempo_map = TempoMap::new(Tempo::new(120));
+    let change_position = TicksTime::per_minute(signature, Tempo::new(120));
+    tempo_map.set_tempo(change_position, Tempo::new(60));
+    let ticks = change_position + TicksTime::per_minute(signature, Tempo::new(60));
+    let signature_map = SignatureMap::new(signature);
+    let time = ticks.to_clock_with_map(&signature_map, &tempo_map);
+    assert_eq!(time.units() / clock::UNITS_PER_MINUTE, 2);
+  }
+
   #[test]
   pub fn ord_cmp() {
     let time1 = TicksTime::new(1234);
@@ -1,20 +1,54 @@
+use crate::song::markers::Markers;
 use crate::time::{
-  drift_correction::ClockDriftCorrection, drift_correction::TicksDriftCorrection, BarsTime,
-  ClockTime, SampleRate, Signature, Tempo, TicksTime,
+  drift_correction::ClockDriftCorrection, drift_correction::TicksDriftCorrection, smpte::FrameRate,
+  tempo_map::TempoCurve, BarsTime, ClockTime, SampleRate, Signature, SignatureMap, SmpteTime,
+  Tempo, TempoMap, TempoRamp, TicksTime,
 };
 
 const DEFAULT_TEMPO: u16 = 120;
 const DEFAULT_SIGNATURE_NUM_BEATS: u8 = 4;
 const DEFAULT_SIGNATURE_NOTE_VALUE: u8 = 4;
 
-pub struct Transport {
-  sample_rate: SampleRate,
+/// Segments played before the song or the recording starts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PreRoll {
+  /// The metronome counts some bars while the song position stays still.
+  /// The positions of these segments go from the start of the count-in and not from the start of the song.
+  CountIn,
+
+  /// The song plays some bars before the position where the recording starts
+  Song,
+}
+
+/// Progress of a count-in, which keeps the signature and the tempo of the position where the song starts
+#[derive(Debug, Clone, Copy)]
+struct CountIn {
+  position: TicksTime,
+  duration: TicksTime,
   signature: Signature,
   tempo: Tempo,
+}
+
+impl CountIn {
+  fn tempo_curve(&self) -> TempoCurve {
+    TempoCurve::constant(self.signature, self.tempo)
+  }
+}
+
+pub struct Transport {
+  sample_rate: SampleRate,
+  signature_map: SignatureMap,
+  tempo_map: TempoMap,
+  /// Constant tempo in quarter notes per minute that replaces the tempo map while following an external clock,
+  /// with the tempo map where it is converted to the beats of every signature
+  tempo_override: Option<(Tempo, TempoMap)>,
+  smpte_offset: SmpteTime,
 
   playing: bool,
+  recording: bool,
 
   next_play_duration: TicksTime,
+  next_clock_play_duration: ClockTime,
 
   start_position: TicksTime,
   current_position: TicksTime,
@@ -24,6 +58,16 @@ pub struct Transport {
   loop_enabled: bool,
   loop_start: TicksTime,
   loop_end: TicksTime,
+
+  punch_enabled: bool,
+  punch_in: TicksTime,
+  punch_out: TicksTime,
+
+  count_in_bars: u16,
+  pre_roll_bars: u16,
+  count_in: Option<CountIn>,
+  pre_roll_end: Option<TicksTime>,
+  pre_roll_return: TicksTime,
 }
 
 impl Transport {
@@ -32,12 +76,16 @@ impl Transport {
     let tempo = Tempo::new(DEFAULT_TEMPO);
     let mut transport = Transport {
       sample_rate,
-      signature,
-      tempo,
+      signature_map: SignatureMap::new(signature),
+      tempo_map: TempoMap::new(tempo),
+      tempo_override: None,
+      smpte_offset: SmpteTime::zero(FrameRate::Fps25),
 
       playing: false,
+      recording: false,
 
       next_play_duration: TicksTime::zero(),
+      next_clock_play_duration: ClockTime::zero(),
 
       start_position: TicksTime::zero(),
       current_position: TicksTime::zero(),
@@ -47,6 +95,16 @@ impl Transport {
       loop_enabled: true,
       loop_start: TicksTime::zero(),
       loop_end: TicksTime::zero(),
+
+      punch_enabled: false,
+      punch_in: TicksTime::zero(),
+      punch_out: TicksTime::zero(),
+
+      count_in_bars: 0,
+      pre_roll_bars: 0,
+      count_in: None,
+      pre_roll_end: None,
+      pre_roll_return: TicksTime::zero(),
     };
     transport.update_timing_constants();
     transport
@@ -61,20 +119,134 @@ impl Transport {
     &self.sample_rate
   }
 
+  /// Set the signature at the start of the song
   pub fn set_signature(&mut self, signature: Signature) {
-    self.signature = signature;
+    self.signature_map.set_signature(0, signature);
+    self.update_timing_constants();
   }
 
-  pub fn get_signature(&self) -> &Signature {
-    &self.signature
+  /// Get the signature at the current position
+  pub fn get_signature(&self) -> Signature {
+    self.signature_map.signature_at(self.current_position)
   }
 
+  pub fn set_signature_change(&mut self, bar: u16, signature: Signature) {
+    self.signature_map.set_signature(bar, signature);
+    self.update_timing_constants();
+  }
+
+  pub fn remove_signature_change(&mut self, bar: u16) -> bool {
+    let removed = self.signature_map.remove_signature(bar);
+    self.update_timing_constants();
+    removed
+  }
+
+  pub fn set_signature_map(&mut self, signature_map: SignatureMap) {
+    self.signature_map = signature_map;
+    self.update_timing_constants();
+  }
+
+  pub fn get_signature_map(&self) -> &SignatureMap {
+    &self.signature_map
+  }
+
+  /// Set the tempo at the start of the song
   pub fn set_tempo(&mut self, tempo: Tempo) {
-    self.tempo = tempo;
+    self.tempo_map.set_tempo(TicksTime::zero(), tempo);
+    self.update_timing_constants();
+  }
+
+  /// Get the tempo at the current position, following tempo ramps
+  pub fn get_tempo(&self) -> Tempo {
+    let curve = self.tempo_curve_at(self.current_position);
+    Tempo::from_bpm(curve.tempo_at(self.current_position))
+  }
+
+  pub fn set_tempo_change(&mut self, position: BarsTime, tempo: Tempo) {
+    self.set_tempo_ramp(position, tempo, TempoRamp::Step);
+  }
+
+  /// Set a tempo change that ramps towards the tempo of the next change
+  pub fn set_tempo_ramp(&mut self, position: BarsTime, tempo: Tempo, ramp: TempoRamp) {
+    let position = position.to_ticks_with_map(&self.signature_map);
+    self.tempo_map.set_tempo_with_ramp(position, tempo, ramp);
+    self.update_timing_constants();
+  }
+
+  pub fn remove_tempo_change(&mut self, position: BarsTime) -> bool {
+    let position = position.to_ticks_with_map(&self.signature_map);
+    let removed = self.tempo_map.remove_tempo(position);
+    self.update_timing_constants();
+    removed
+  }
+
+  pub fn set_tempo_map(&mut self, tempo_map: TempoMap) {
+    self.tempo_map = tempo_map;
+    self.update_timing_constants();
   }
 
-  pub fn get_tempo(&self) -> &Tempo {
-    &self.tempo
+  pub fn get_tempo_map(&self) -> &TempoMap {
+    &self.tempo_map
+  }
+
+  /// Play at a constant tempo in quarter notes per minute instead of the tempo map, without changing the tempo of the song.
+  /// The beats of every signature follow it, like the eighths of a 6/8 going twice as fast.
+  /// It is meant to be updated often, so it only rebuilds the drift correction.
+  pub fn set_tempo_override(&mut self, tempo: Option<Tempo>) {
+    let signature_map = &self.signature_map;
+    match (&mut self.tempo_override, tempo) {
+      (Some((quarter_tempo, tempo_map)), Some(tempo)) => {
+        *quarter_tempo = tempo;
+        set_quarter_tempo(tempo_map, signature_map, tempo);
+      }
+      (tempo_override, tempo) => {
+        *tempo_override = tempo.map(|tempo| (tempo, quarter_tempo_map(signature_map, tempo)));
+      }
+    }
+    self.update_drift_correction();
+  }
+
+  /// The tempo override in quarter notes per minute
+  pub fn get_tempo_override(&self) -> Option<Tempo> {
+    self
+      .tempo_override
+      .as_ref()
+      .map(|(tempo, _tempo_map)| *tempo)
+  }
+
+  /// The tempo map that times the playback, which is the one of the song unless there is a tempo override
+  fn timing_tempo_map(&self) -> &TempoMap {
+    self
+      .tempo_override
+      .as_ref()
+      .map_or(&self.tempo_map, |(_tempo, tempo_map)| tempo_map)
+  }
+
+  /// Set the timecode at the start of the song, its frame rate is used for the timecode positions
+  pub fn set_smpte_offset(&mut self, offset: SmpteTime) {
+    self.smpte_offset = offset;
+  }
+
+  pub fn get_smpte_offset(&self) -> &SmpteTime {
+    &self.smpte_offset
+  }
+
+  pub fn set_smpte_position(&mut self, position: SmpteTime) {
+    let position = position.to_bars_with_map(
+      &self.smpte_offset,
+      &self.signature_map,
+      self.timing_tempo_map(),
+    );
+    self.set_position(position);
+  }
+
+  pub fn get_smpte_position(&self) -> SmpteTime {
+    SmpteTime::from_ticks_with_map(
+      self.current_position,
+      &self.smpte_offset,
+      &self.signature_map,
+      self.timing_tempo_map(),
+    )
   }
 
   pub fn is_playing(&self) -> bool {
@@ -83,32 +255,151 @@ impl Transport {
 
   pub fn play(&mut self, restart: bool) -> bool {
     self.playing = !self.playing;
+    if !self.playing {
+      self.cancel_pre_roll();
+    }
     if restart {
       self.reset_position();
     }
     self.playing
   }
 
+  /// Like `play`, but counting in and rolling the song before the recording position when they are enabled
+  pub fn play_with_pre_roll(&mut self, restart: bool) -> bool {
+    let playing = self.play(restart);
+    if playing {
+      self.start_pre_roll();
+    }
+    playing
+  }
+
   pub fn stop(&mut self) {
+    self.cancel_pre_roll();
     if !self.playing {
       self.reset_position();
     }
     self.playing = false;
   }
 
+  pub fn set_recording(&mut self, recording: bool) {
+    self.recording = recording;
+  }
+
+  pub fn is_recording(&self) -> bool {
+    self.recording
+  }
+
+  /// Number of bars counted by the metronome before playing, where 0 disables the count-in
+  pub fn set_count_in_bars(&mut self, bars: u16) {
+    self.count_in_bars = bars;
+  }
+
+  pub fn get_count_in_bars(&self) -> u16 {
+    self.count_in_bars
+  }
+
+  /// Number of bars played before the position where the recording starts, where 0 disables the pre-roll
+  pub fn set_pre_roll_bars(&mut self, bars: u16) {
+    self.pre_roll_bars = bars;
+  }
+
+  pub fn get_pre_roll_bars(&self) -> u16 {
+    self.pre_roll_bars
+  }
+
+  pub fn is_counting_in(&self) -> bool {
+    self.count_in.is_some()
+  }
+
+  pub fn is_pre_rolling(&self) -> bool {
+    self.pre_roll_end.is_some()
+  }
+
+  /// The pre-roll starts before the punch-in when the punch range is enabled, or before the position otherwise
+  fn start_pre_roll(&mut self) {
+    if self.recording && self.pre_roll_bars > 0 {
+      let record_position = match self.punch_range() {
+        Some((punch_in, _)) => punch_in,
+        None => self.current_position,
+      };
+      let position = BarsTime::from_ticks_with_map(record_position, &self.signature_map)
+        .sub(
+          &BarsTime::from_bars(self.pre_roll_bars),
+          &self.signature_map,
+        )
+        .to_ticks_with_map(&self.signature_map);
+      if position < record_position {
+        self.pre_roll_return = self.current_position;
+        self.current_position = position;
+        self.next_position = position;
+        self.pre_roll_end = Some(record_position);
+      }
+    }
+    if self.count_in_bars > 0 {
+      let signature = self.signature_map.signature_at(self.current_position);
+      self.count_in = Some(CountIn {
+        position: TicksTime::zero(),
+        duration: BarsTime::from_bars(self.count_in_bars).to_ticks(signature),
+        signature,
+        tempo: self.get_tempo(),
+      });
+    }
+    self.update_drift_correction();
+  }
+
+  fn cancel_pre_roll(&mut self) {
+    if self.count_in.is_some() {
+      self.count_in = None;
+      self.update_drift_correction();
+    }
+    // stopping before the pre-roll ends goes back to where the recording was started from
+    if self.pre_roll_end.take().is_some() {
+      self.current_position = self.pre_roll_return;
+      self.next_position = self.pre_roll_return;
+      self.update_drift_correction();
+    }
+  }
+
   fn reset_position(&mut self) {
     self.next_play_duration = TicksTime::zero();
+    self.next_clock_play_duration = ClockTime::zero();
     self.current_position = self.start_position;
     self.next_position = self.current_position;
+    self.update_drift_correction();
   }
 
   pub fn set_position(&mut self, position: BarsTime) {
-    self.current_position = position.to_ticks(self.signature);
+    self.current_position = position.to_ticks_with_map(&self.signature_map);
     self.next_position = self.current_position;
+    self.pre_roll_end = None;
+    self.update_drift_correction();
   }
 
   pub fn get_position(&self) -> BarsTime {
-    BarsTime::from_ticks(self.current_position, self.signature)
+    BarsTime::from_ticks_with_map(self.current_position, &self.signature_map)
+  }
+
+  /// Locate to the first marker after the position, returning whether there was any
+  pub fn locate_next_marker(&mut self, markers: &Markers) -> bool {
+    let position = markers
+      .next_after(self.current_position)
+      .map(|marker| marker.get_position());
+    self.locate_marker_position(position)
+  }
+
+  /// Locate to the last marker before the position, returning whether there was any
+  pub fn locate_previous_marker(&mut self, markers: &Markers) -> bool {
+    let position = markers
+      .previous_before(self.current_position)
+      .map(|marker| marker.get_position());
+    self.locate_marker_position(position)
+  }
+
+  fn locate_marker_position(&mut self, position: Option<TicksTime>) -> bool {
+    if let Some(position) = position {
+      self.set_position(BarsTime::from_ticks_with_map(position, &self.signature_map));
+    }
+    position.is_some()
   }
 
   pub fn set_loop_enabled(&mut self, enabled: bool) {
@@ -120,19 +411,61 @@ impl Transport {
   }
 
   pub fn set_loop_start(&mut self, position: BarsTime) {
-    self.loop_start = position.to_ticks(self.signature);
+    self.loop_start = position.to_ticks_with_map(&self.signature_map);
   }
 
   pub fn get_loop_start(&self) -> BarsTime {
-    BarsTime::from_ticks(self.loop_start, self.signature)
+    BarsTime::from_ticks_with_map(self.loop_start, &self.signature_map)
   }
 
   pub fn set_loop_end(&mut self, position: BarsTime) {
-    self.loop_end = position.to_ticks(self.signature);
+    self.loop_end = position.to_ticks_with_map(&self.signature_map);
   }
 
   pub fn get_loop_end(&self) -> BarsTime {
-    BarsTime::from_ticks(self.loop_end, self.signature)
+    BarsTime::from_ticks_with_map(self.loop_end, &self.signature_map)
+  }
+
+  /// Recording only captures the segments between the punch-in and the punch-out when the punch range is enabled.
+  /// The punch range is independent of the loop: every pass of the loop records the part of the loop inside
+  /// the punch range, and the punch points outside of the loop are never reached while looping.
+  pub fn set_punch_enabled(&mut self, enabled: bool) {
+    self.punch_enabled = enabled;
+  }
+
+  pub fn is_punch_enabled(&self) -> bool {
+    self.punch_enabled
+  }
+
+  pub fn set_punch_in(&mut self, position: BarsTime) {
+    self.punch_in = position.to_ticks_with_map(&self.signature_map);
+  }
+
+  pub fn get_punch_in(&self) -> BarsTime {
+    BarsTime::from_ticks_with_map(self.punch_in, &self.signature_map)
+  }
+
+  pub fn set_punch_out(&mut self, position: BarsTime) {
+    self.punch_out = position.to_ticks_with_map(&self.signature_map);
+  }
+
+  pub fn get_punch_out(&self) -> BarsTime {
+    BarsTime::from_ticks_with_map(self.punch_out, &self.signature_map)
+  }
+
+  /// Punch-in and punch-out positions when the punch range is enabled and not empty
+  fn punch_range(&self) -> Option<(TicksTime, TicksTime)> {
+    if self.punch_enabled && self.punch_in < self.punch_out {
+      Some((self.punch_in, self.punch_out))
+    } else {
+      None
+    }
+  }
+
+  fn inside_punch(&self, position: TicksTime) -> bool {
+    self
+      .punch_range()
+      .is_some_and(|(punch_in, punch_out)| punch_in <= position && position < punch_out)
   }
 
   pub(super) fn segments_iterator(
@@ -145,35 +478,137 @@ impl Transport {
       self,
       master_clock,
       self.next_play_duration,
+      self.next_clock_play_duration,
       self.next_position,
+      self.count_in,
+      self.pre_roll_end,
       &self.time_drift_correction,
     )
   }
 
   pub(super) fn update_from_segments(&mut self, segments: &SegmentsIterator) {
     self.next_play_duration = segments.next_play_duration;
+    self.next_clock_play_duration = segments.next_clock_play_duration;
     self.current_position = segments.next_position;
     self.next_position = segments.next_position;
+    self.count_in = segments.count_in;
+    self.pre_roll_end = segments.pre_roll_end;
     self.time_drift_correction = segments.time_drift_correction.clone();
     // println!("))))))))> {:#?}", self.time_drift_correction);
   }
 
   ///! Update timing constants that change sporadically (ex. changes on sample rate, tempo, signature, ...)
   fn update_timing_constants(&mut self) {
-    self.time_drift_correction =
-      TicksDriftCorrection::new(self.signature, self.tempo, self.sample_rate);
+    if let Some((tempo, tempo_map)) = self.tempo_override.as_mut() {
+      *tempo_map = quarter_tempo_map(&self.signature_map, *tempo);
+    }
+    self.update_drift_correction();
     println!(
       "Ticks error per sample = {:?} ticks",
       self.time_drift_correction.get_error_per_sample()
     );
   }
 
+  /// The drift correction depends on the tempo and the signature,
+  /// so it needs to be rebuilt for the timing region of the next position, or for the count-in
+  fn update_drift_correction(&mut self) {
+    self.time_drift_correction = match self.count_in {
+      Some(count_in) => TicksDriftCorrection::with_curve(
+        count_in.tempo_curve(),
+        count_in.position,
+        self.sample_rate,
+      ),
+      None => self.drift_correction_at(self.next_position),
+    };
+  }
+
+  fn drift_correction_at(&self, position: TicksTime) -> TicksDriftCorrection {
+    TicksDriftCorrection::with_curve(self.tempo_curve_at(position), position, self.sample_rate)
+  }
+
+  fn tempo_curve_at(&self, position: TicksTime) -> TempoCurve {
+    self
+      .timing_tempo_map()
+      .curve_at(position, &self.signature_map)
+  }
+
+  /// Whether two positions move at the same constant rate, or along the same tempo ramp
+  fn same_timing(&self, position1: TicksTime, position2: TicksTime) -> bool {
+    let curve1 = self.tempo_curve_at(position1);
+    let curve2 = self.tempo_curve_at(position2);
+    if curve1.is_constant() && curve2.is_constant() {
+      let signature1 = self.signature_map.signature_at(position1);
+      let signature2 = self.signature_map.signature_at(position2);
+      let tempo1 = self.timing_tempo_map().tempo_at(position1);
+      let tempo2 = self.timing_tempo_map().tempo_at(position2);
+      TicksTime::per_minute(signature1, tempo1) == TicksTime::per_minute(signature2, tempo2)
+    } else {
+      curve1 == curve2
+    }
+  }
+
+  /// Position of the next change of tempo or signature after a position
+  fn next_timing_change(&self, position: TicksTime) -> Option<TicksTime> {
+    self
+      .timing_tempo_map()
+      .region_end(position, &self.signature_map)
+  }
+
+  /// Convert a duration past a boundary, computed with the timing at one position,
+  /// into the equivalent duration with the timing at another one
+  fn retime_duration(
+    &self,
+    duration: TicksTime,
+    from: TicksTime,
+    boundary: TicksTime,
+    to: TicksTime,
+  ) -> TicksTime {
+    let from_curve = self.tempo_curve_at(from);
+    let to_curve = self.tempo_curve_at(to);
+    if from_curve.is_constant() && to_curve.is_constant() {
+      let from_ticks_per_minute = TicksTime::per_minute(
+        from_curve.get_signature(),
+        self.timing_tempo_map().tempo_at(from),
+      );
+      let to_ticks_per_minute = TicksTime::per_minute(
+        to_curve.get_signature(),
+        self.timing_tempo_map().tempo_at(to),
+      );
+      if from_ticks_per_minute == to_ticks_per_minute {
+        duration
+      } else {
+        let ticks = u128::from(u64::from(duration)) * u128::from(u64::from(to_ticks_per_minute))
+          / u128::from(u64::from(from_ticks_per_minute));
+        TicksTime::new(ticks as u64)
+      }
+    } else {
+      let clock = from_curve.ticks_to_clock(boundary, boundary + duration);
+      to_curve.clock_to_ticks(to, clock) - to
+    }
+  }
+
   ///! Determine whether or not not to move the song position to the start of the loop
   fn crossing_loop_end(&self, prev_ticks: TicksTime, next_position: TicksTime) -> bool {
     self.loop_enabled && prev_ticks < self.loop_end && self.loop_end <= next_position
   }
 }
 
+/// Tempo map with a change at every signature, for a tempo in quarter notes per minute
+fn quarter_tempo_map(signature_map: &SignatureMap, tempo: Tempo) -> TempoMap {
+  let mut tempo_map = TempoMap::new(tempo);
+  set_quarter_tempo(&mut tempo_map, signature_map, tempo);
+  tempo_map
+}
+
+/// Set the tempo at every signature change, with the quarter notes converted to the beats of the signature
+fn set_quarter_tempo(tempo_map: &mut TempoMap, signature_map: &SignatureMap, tempo: Tempo) {
+  for change in signature_map.changes() {
+    let note_value = f64::from(change.get_signature().get_note_value());
+    let bpm = tempo.get_value() * note_value / 4.0;
+    tempo_map.set_tempo(change.get_position(), Tempo::from_bpm(bpm));
+  }
+}
+
 pub struct SegmentsIterator {
   master_clock: ClockTime,
   next_master_clock: ClockTime,
@@ -181,20 +616,30 @@ pub struct SegmentsIterator {
   play_duration: TicksTime,
   next_play_duration: TicksTime,
 
+  clock_play_duration: ClockTime,
+  next_clock_play_duration: ClockTime,
+
   current_position: TicksTime,
   next_position: TicksTime,
 
+  count_in: Option<CountIn>,
+  pre_roll_end: Option<TicksTime>,
+
   remaining_duration: TicksTime,
   time_drift_correction: TicksDriftCorrection,
 }
 
 impl SegmentsIterator {
+  #[allow(clippy::too_many_arguments)]
   fn new(
     samples: u32,
     _transport: &Transport,
     next_master_clock: ClockTime,
     next_play_duration: TicksTime,
+    next_clock_play_duration: ClockTime,
     next_position: TicksTime,
+    count_in: Option<CountIn>,
+    pre_roll_end: Option<TicksTime>,
     time_drift_correction: &TicksDriftCorrection,
   ) -> SegmentsIterator {
     let mut time_drift_correction = time_drift_correction.clone();
@@ -216,70 +661,185 @@ impl SegmentsIterator {
       next_master_clock,
       play_duration: next_play_duration,
       next_play_duration,
+      clock_play_duration: next_clock_play_duration,
+      next_clock_play_duration,
       current_position: next_position,
       next_position,
+      count_in,
+      pre_roll_end,
       remaining_duration,
       time_drift_correction,
     }
   }
 
+  /// Whether the last segment of the buffer was already returned
+  pub fn is_finished(&self) -> bool {
+    self.remaining_duration == TicksTime::zero()
+  }
+
   pub fn next(&mut self, transport: &Transport) -> Option<Segment> {
     self.master_clock = self.next_master_clock;
     self.play_duration = self.next_play_duration;
+    self.clock_play_duration = self.next_clock_play_duration;
     self.current_position = self.next_position;
 
     if self.remaining_duration > TicksTime::zero() {
+      if let Some(count_in) = self.count_in {
+        return Some(self.next_count_in_segment(transport, count_in));
+      }
+
       let end_position = self.current_position + self.remaining_duration;
 
-      if transport.crossing_loop_end(self.current_position, end_position) {
-        self.remaining_duration = end_position - transport.loop_end;
-        let end_position = transport.loop_end;
+      let timing_change = transport
+        .next_timing_change(self.current_position)
+        .filter(|change_position| *change_position <= end_position);
+
+      // the segments end at the pre-roll end and at the punch points, unless the timing changes or the loop wraps before
+      let current_position = self.current_position;
+      let punch_points = transport
+        .punch_range()
+        .map(|(punch_in, punch_out)| [punch_in, punch_out]);
+      let boundary = self
+        .pre_roll_end
+        .iter()
+        .chain(punch_points.iter().flatten())
+        .cloned()
+        .filter(|position| {
+          current_position < *position
+            && *position < end_position
+            && timing_change.is_none_or(|change_position| *position < change_position)
+            && !transport.crossing_loop_end(current_position, *position)
+        })
+        .min();
+
+      if let Some(boundary) = boundary {
+        self.next_position = boundary;
+        self.remaining_duration = end_position - boundary;
+        Some(self.next_segment(transport, boundary))
+      } else if transport.crossing_loop_end(self.current_position, end_position)
+        && timing_change
+          .filter(|change_position| *change_position < transport.loop_end)
+          .is_none()
+      {
         self.next_position = transport.loop_start;
-        let segment_duration = end_position - self.current_position;
-        self.next_play_duration = self.play_duration + segment_duration;
-        let segment = Segment::new(
-          transport.sample_rate,
-          transport.signature,
-          transport.tempo,
-          self.master_clock,
+        self.remaining_duration = transport.retime_duration(
+          end_position - transport.loop_end,
           self.current_position,
-          end_position,
-          segment_duration,
-          self.play_duration,
+          transport.loop_end,
+          self.next_position,
         );
-        self.next_master_clock = self.master_clock + segment.clock_duration;
-        Some(segment)
+        Some(self.next_segment(transport, transport.loop_end))
+      } else if let Some(change_position) = timing_change {
+        self.next_position = change_position;
+        self.remaining_duration = transport.retime_duration(
+          end_position - change_position,
+          self.current_position,
+          change_position,
+          self.next_position,
+        );
+        Some(self.next_segment(transport, change_position))
       } else {
         self.next_position = end_position;
-        let segment_duration = self.remaining_duration;
         self.remaining_duration = TicksTime::zero();
-        self.next_play_duration = self.play_duration + segment_duration;
-        let segment = Segment::new(
-          transport.sample_rate,
-          transport.signature,
-          transport.tempo,
-          self.master_clock,
-          self.current_position,
-          end_position,
-          segment_duration,
-          self.play_duration,
-        );
-        self.next_master_clock = self.master_clock + segment.clock_duration;
-        Some(segment)
+        Some(self.next_segment(transport, end_position))
       }
     } else {
       None
     }
   }
+
+  /// The count-in takes as much of the buffer as it needs, and the rest is played with the timing of the song
+  fn next_count_in_segment(&mut self, transport: &Transport, count_in: CountIn) -> Segment {
+    let tempo_curve = count_in.tempo_curve();
+    let start_position = count_in.position;
+    let buffer_end_position = start_position + self.remaining_duration;
+    let end_position = buffer_end_position.min(count_in.duration);
+    let segment = Segment::count_in(
+      transport.sample_rate,
+      self.master_clock,
+      start_position,
+      end_position,
+      tempo_curve,
+      self.play_duration,
+      self.clock_play_duration,
+    );
+    self.next_master_clock = self.master_clock + segment.clock_duration;
+
+    if end_position < count_in.duration {
+      self.count_in = Some(CountIn {
+        position: end_position,
+        ..count_in
+      });
+      self.remaining_duration = TicksTime::zero();
+    } else {
+      self.count_in = None;
+      let clock = tempo_curve.ticks_to_clock(end_position, buffer_end_position);
+      let curve = transport.tempo_curve_at(self.next_position);
+      self.remaining_duration =
+        curve.clock_to_ticks(self.next_position, clock) - self.next_position;
+      self.time_drift_correction = TicksDriftCorrection::with_curve(
+        curve,
+        self.next_position + self.remaining_duration,
+        transport.sample_rate,
+      );
+    }
+    segment
+  }
+
+  fn next_segment(&mut self, transport: &Transport, end_position: TicksTime) -> Segment {
+    let segment_duration = end_position - self.current_position;
+    self.next_play_duration = self.play_duration + segment_duration;
+    let mut segment = Segment::new(
+      transport.sample_rate,
+      self.master_clock,
+      self.current_position,
+      end_position,
+      segment_duration,
+      self.play_duration,
+      self.clock_play_duration,
+      &transport.signature_map,
+      transport.timing_tempo_map(),
+    );
+    self.next_master_clock = self.master_clock + segment.clock_duration;
+    self.next_clock_play_duration = self.clock_play_duration + segment.clock_duration;
+    let wrapped = self.next_position != end_position;
+    if let Some(pre_roll_end) = self.pre_roll_end {
+      if self.current_position < pre_roll_end {
+        segment.pre_roll = Some(PreRoll::Song);
+      }
+      if wrapped || pre_roll_end <= end_position {
+        self.pre_roll_end = None;
+      }
+    }
+    segment.inside_punch = transport.inside_punch(self.current_position);
+    segment.recording = transport.recording
+      && segment.pre_roll.is_none()
+      && (segment.inside_punch || transport.punch_range().is_none());
+    let next_curve = transport.tempo_curve_at(self.next_position);
+    if !transport.same_timing(self.current_position, self.next_position)
+      || (wrapped && !next_curve.is_constant())
+    {
+      // the drift correction continues from where the remaining duration of the buffer ends
+      self.time_drift_correction = TicksDriftCorrection::with_curve(
+        next_curve,
+        self.next_position + self.remaining_duration,
+        transport.sample_rate,
+      );
+    }
+    segment
+  }
 }
 
 pub struct Segment {
   pub(super) sample_rate: SampleRate,
   pub(super) signature: Signature,
   pub(super) tempo: Tempo,
+  pub(super) tempo_curve: TempoCurve,
 
   pub(super) master_clock: ClockTime,
 
+  pub(super) bar_start_position: TicksTime,
+
   pub(super) start_position: TicksTime,
   pub(super) end_position: TicksTime,
   pub(super) duration: TicksTime,
@@ -290,33 +850,740 @@ pub struct Segment {
 
   pub(super) play_duration: TicksTime,
   pub(super) clock_play_duration: ClockTime,
+
+  pub(super) pre_roll: Option<PreRoll>,
+  pub(super) inside_punch: bool,
+  pub(super) recording: bool,
 }
 
 impl Segment {
   #[allow(clippy::too_many_arguments)]
   pub fn new(
     sample_rate: SampleRate,
-    signature: Signature,
-    tempo: Tempo,
     master_clock: ClockTime,
     start_position: TicksTime,
     end_position: TicksTime,
     duration: TicksTime,
     play_duration: TicksTime,
+    clock_play_duration: ClockTime,
+    signature_map: &SignatureMap,
+    tempo_map: &TempoMap,
   ) -> Segment {
+    let signature = signature_map.signature_at(start_position);
+    let tempo_curve = tempo_map.curve_at(start_position, signature_map);
     Segment {
       sample_rate,
       signature,
-      tempo,
+      tempo: Tempo::from_bpm(tempo_curve.tempo_at(start_position)),
+      tempo_curve,
       master_clock,
+      bar_start_position: signature_map.bar_start(start_position),
       start_position,
       end_position,
       duration,
       play_duration,
-      clock_start_position: start_position.to_clock(signature, tempo),
-      clock_end_position: end_position.to_clock(signature, tempo),
-      clock_duration: duration.to_clock(signature, tempo),
-      clock_play_duration: play_duration.to_clock(signature, tempo),
+      clock_start_position: start_position.to_clock_with_map(signature_map, tempo_map),
+      clock_end_position: end_position.to_clock_with_map(signature_map, tempo_map),
+      clock_duration: tempo_curve.ticks_to_clock(start_position, end_position),
+      clock_play_duration,
+      pre_roll: None,
+      inside_punch: false,
+      recording: false,
+    }
+  }
+
+  /// Segment of a count-in, where the positions go from the start of the count-in
+  pub fn count_in(
+    sample_rate: SampleRate,
+    master_clock: ClockTime,
+    start_position: TicksTime,
+    end_position: TicksTime,
+    tempo_curve: TempoCurve,
+    play_duration: TicksTime,
+    clock_play_duration: ClockTime,
+  ) -> Segment {
+    let signature = tempo_curve.get_signature();
+    let bar_duration = u64::from(BarsTime::from_bars(1).to_ticks(signature));
+    let bar_start_position = u64::from(start_position) / bar_duration * bar_duration;
+    Segment {
+      sample_rate,
+      signature,
+      tempo: Tempo::from_bpm(tempo_curve.tempo_at(start_position)),
+      tempo_curve,
+      master_clock,
+      bar_start_position: TicksTime::new(bar_start_position),
+      start_position,
+      end_position,
+      duration: end_position - start_position,
+      play_duration,
+      clock_start_position: tempo_curve.ticks_to_clock(TicksTime::zero(), start_position),
+      clock_end_position: tempo_curve.ticks_to_clock(TicksTime::zero(), end_position),
+      clock_duration: tempo_curve.ticks_to_clock(start_position, end_position),
+      clock_play_duration,
+      pre_roll: Some(PreRoll::CountIn),
+      inside_punch: false,
+      recording: false,
+    }
+  }
+
+  /// Part of the segment between two positions, for what changes in the middle of it
+  pub fn part(&self, start: TicksTime, end: TicksTime) -> Segment {
+    let start = start.clamp(self.start_position, self.end_position);
+    let end = end.clamp(start, self.end_position);
+    let bar_duration = u64::from(BarsTime::from_bars(1).to_ticks(self.signature));
+    let bars = u64::from(start - self.bar_start_position) / bar_duration.max(1);
+    let clock_offset = self.tempo_curve.ticks_to_clock(self.start_position, start);
+    let clock_start_position = self.clock_start_position + clock_offset;
+    let clock_duration = self.tempo_curve.ticks_to_clock(start, end);
+    Segment {
+      tempo: Tempo::from_bpm(self.tempo_curve.tempo_at(start)),
+      master_clock: self.master_clock_at(start),
+      bar_start_position: self.bar_start_position + TicksTime::new(bars * bar_duration),
+      start_position: start,
+      end_position: end,
+      duration: end - start,
+      clock_start_position,
+      clock_end_position: clock_start_position + clock_duration,
+      clock_duration,
+      play_duration: self.play_duration + (start - self.start_position),
+      clock_play_duration: self.clock_play_duration + clock_offset,
+      ..*self
+    }
+  }
+
+  /// Whether the segment is played before the song or the recording starts
+  pub fn is_pre_roll(&self) -> bool {
+    self.pre_roll.is_some()
+  }
+
+  /// Whether the segment belongs to a count-in, so the song position does not advance
+  pub fn is_count_in(&self) -> bool {
+    self.pre_roll == Some(PreRoll::CountIn)
+  }
+
+  /// Whether the segment is between the punch-in and the punch-out of an enabled punch range
+  pub fn is_inside_punch(&self) -> bool {
+    self.inside_punch
+  }
+
+  /// Whether the segment has to be recorded, which happens while recording
+  /// outside of the pre-roll and inside the punch range when it is enabled
+  pub fn is_recording(&self) -> bool {
+    self.recording
+  }
+
+  /// Master clock time for a song position within the segment
+  pub fn master_clock_at(&self, position: TicksTime) -> ClockTime {
+    self.master_clock
+      + self
+        .tempo_curve
+        .ticks_to_clock(self.start_position, position)
+  }
+
+  /// Song position for a master clock time, the times before the segment are placed at its start
+  /// and the ones after it at its end
+  pub fn position_at(&self, clock: ClockTime) -> TicksTime {
+    if clock <= self.master_clock {
+      self.start_position
+    } else {
+      self
+        .tempo_curve
+        .clock_to_ticks(self.start_position, clock - self.master_clock)
+        .min(self.end_position)
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+
+  use super::{PreRoll, Segment, Transport};
+  use crate::color::Color;
+  use crate::song::markers::{Marker, Markers};
+  use crate::time::{
+    clock, smpte::FrameRate, ticks::TICKS_RESOLUTION, BarsTime, ClockTime, Signature, SmpteTime,
+    Tempo, TempoRamp, TicksTime,
+  };
+
+  const SAMPLE_RATE: u32 = 44100;
+
+  fn next_segments(
+    transport: &mut Transport,
+    master_clock: ClockTime,
+    samples: u32,
+  ) -> Vec<Segment> {
+    let mut segments = transport.segments_iterator(master_clock, samples);
+    let mut result = Vec::new();
+    while let Some(segment) = segments.next(transport) {
+      result.push(segment);
+    }
+    transport.update_from_segments(&segments);
+    result
+  }
+
+  fn assert_close(value: u64, expected: u64, tolerance: u64) {
+    let diff = value.abs_diff(expected);
+    assert!(
+      diff <= tolerance,
+      "{} is not within {} of {}",
+      value,
+      tolerance,
+      expected
+    );
+  }
+
+  #[test]
+  pub fn part_of_a_segment() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_position(BarsTime::new(0, 3, 3, 0));
+
+    // half a second covers four sixteenths at 120 bpm, the last three in the next bar
+    let segments = next_segments(&mut transport, ClockTime::zero(), SAMPLE_RATE / 2);
+    assert_eq!(segments.len(), 1);
+    let segment = &segments[0];
+    let bar = BarsTime::new(1, 0, 0, 0).to_ticks_with_map(&transport.signature_map);
+    let part = segment.part(bar, bar + TicksTime::new(TICKS_RESOLUTION));
+
+    assert_eq!(part.start_position, bar);
+    assert_eq!(part.bar_start_position, bar);
+    assert_eq!(part.duration, TicksTime::new(TICKS_RESOLUTION));
+    assert_eq!(
+      part.master_clock,
+      segment.master_clock + ClockTime::from_seconds(0.125)
+    );
+    assert_eq!(part.clock_start_position, ClockTime::from_seconds(2.0));
+    assert_eq!(part.clock_duration, ClockTime::from_seconds(0.125));
+    assert_eq!(
+      part.play_duration,
+      segment.play_duration + TicksTime::new(TICKS_RESOLUTION)
+    );
+    assert_eq!(
+      part.clock_play_duration,
+      segment.clock_play_duration + ClockTime::from_seconds(0.125)
+    );
+    assert_eq!(
+      part.master_clock_at(bar + TicksTime::new(TICKS_RESOLUTION / 2)),
+      segment.master_clock_at(bar + TicksTime::new(TICKS_RESOLUTION / 2))
+    );
+  }
+
+  #[test]
+  pub fn segments_split_at_tempo_change() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_tempo_change(BarsTime::new(1, 0, 0, 0), Tempo::new(60));
+    transport.set_position(BarsTime::new(0, 3, 3, 0));
+
+    // a quarter of a second covers the last sixteenth at 120 bpm and half a sixteenth at 60 bpm
+    let segments = next_segments(&mut transport, ClockTime::zero(), SAMPLE_RATE / 4);
+    assert_eq!(segments.len(), 2);
+
+    let change_position = BarsTime::new(1, 0, 0, 0).to_ticks_with_map(&transport.signature_map);
+
+    assert_eq!(segments[0].end_position, change_position);
+    assert_eq!(segments[0].tempo, Tempo::new(120));
+    assert_eq!(segments[0].duration, TicksTime::new(TICKS_RESOLUTION));
+    assert_eq!(
+      segments[0].clock_start_position,
+      ClockTime::from_seconds(1.875)
+    );
+    assert_eq!(segments[0].clock_end_position, ClockTime::from_seconds(2.0));
+
+    assert_eq!(segments[1].start_position, change_position);
+    assert_eq!(segments[1].tempo, Tempo::new(60));
+    assert_eq!(
+      segments[1].clock_start_position,
+      ClockTime::from_seconds(2.0)
+    );
+    assert_eq!(
+      segments[1].master_clock,
+      segments[0].master_clock + segments[0].clock_duration
+    );
+    assert_close(
+      u64::from(segments[1].duration),
+      TICKS_RESOLUTION / 2,
+      TICKS_RESOLUTION / 100_000,
+    );
+
+    let clock_duration = segments[0].clock_duration + segments[1].clock_duration;
+    assert_close(
+      clock_duration.units(),
+      ClockTime::from_seconds(0.25).units(),
+      clock::UNITS_PER_MILLI / 1000,
+    );
+  }
+
+  #[test]
+  pub fn segments_clock_at_tempo_changes() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_tempo_change(BarsTime::new(1, 0, 0, 0), Tempo::new(60));
+    transport.set_tempo_change(BarsTime::new(2, 0, 0, 0), Tempo::new(240));
+    transport.set_tempo_change(BarsTime::new(3, 0, 0, 0), Tempo::new(120));
+    let signature_map = transport.signature_map.clone();
+
+    // bar 1 after 4 beats at 120 bpm, bar 2 after 4 beats more at 60 bpm, and bar 3 after 4 beats at 240 bpm
+    let mut expected_changes = vec![
+      (
+        BarsTime::new(1, 0, 0, 0).to_ticks_with_map(&signature_map),
+        2.0,
+      ),
+      (
+        BarsTime::new(2, 0, 0, 0).to_ticks_with_map(&signature_map),
+        6.0,
+      ),
+      (
+        BarsTime::new(3, 0, 0, 0).to_ticks_with_map(&signature_map),
+        7.0,
+      ),
+    ];
+    expected_changes.reverse();
+
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    while !expected_changes.is_empty() {
+      for segment in next_segments(&mut transport, master_clock, samples) {
+        if let Some((change_position, seconds)) = expected_changes.last().cloned() {
+          assert!(segment.start_position <= change_position);
+          if segment.start_position == change_position {
+            assert_close(
+              segment.master_clock.units(),
+              ClockTime::from_seconds(seconds).units(),
+              clock::UNITS_PER_MILLI / 1000,
+            );
+            expected_changes.pop();
+          }
+        }
+      }
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+  }
+
+  #[test]
+  pub fn segments_loop_wrap_into_another_tempo() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_tempo_change(BarsTime::new(1, 0, 0, 0), Tempo::new(60));
+    transport.set_loop_start(BarsTime::new(0, 0, 0, 0));
+    transport.set_loop_end(BarsTime::new(2, 0, 0, 0));
+    let half_sixteenth = (TICKS_RESOLUTION / 2) as u32;
+    transport.set_position(BarsTime::new(1, 3, 3, half_sixteenth));
+
+    // half a sixteenth at 60 bpm takes 0.125 seconds, and the remaining 0.125 seconds are a sixteenth at 120 bpm
+    let segments = next_segments(&mut transport, ClockTime::zero(), SAMPLE_RATE / 4);
+    assert_eq!(segments.len(), 2);
+
+    assert_eq!(
+      segments[0].end_position,
+      BarsTime::new(2, 0, 0, 0).to_ticks_with_map(&transport.signature_map)
+    );
+    assert_eq!(segments[0].tempo, Tempo::new(60));
+    assert_eq!(segments[0].clock_duration, ClockTime::from_seconds(0.125));
+
+    assert_eq!(segments[1].start_position, TicksTime::zero());
+    assert_eq!(segments[1].tempo, Tempo::new(120));
+    assert_eq!(segments[1].master_clock, ClockTime::from_seconds(0.125));
+    assert_close(
+      u64::from(segments[1].duration),
+      TICKS_RESOLUTION,
+      TICKS_RESOLUTION / 100_000,
+    );
+  }
+
+  #[test]
+  pub fn segment_master_clock_at() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    let segments = next_segments(&mut transport, ClockTime::from_seconds(1.0), SAMPLE_RATE);
+    assert_eq!(segments.len(), 1);
+    let position = BarsTime::new(0, 1, 0, 0).to_ticks_with_map(&transport.signature_map);
+    assert_eq!(
+      segments[0].master_clock_at(position),
+      ClockTime::from_seconds(1.5)
+    );
+  }
+
+  #[test]
+  pub fn segments_split_at_signature_change() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_signature_change(1, Signature::new(7, 8));
+    transport.set_position(BarsTime::new(0, 3, 3, 0));
+
+    // a quarter of a second covers the last sixteenth of 4/4 and half a sixteenth of 7/8, as eighths take 0.5 seconds
+    let segments = next_segments(&mut transport, ClockTime::zero(), SAMPLE_RATE / 4);
+    assert_eq!(segments.len(), 2);
+
+    let change_position = BarsTime::new(1, 0, 0, 0).to_ticks_with_map(&transport.signature_map);
+
+    assert_eq!(segments[0].end_position, change_position);
+    assert_eq!(segments[0].signature, Signature::new(4, 4));
+    assert_eq!(segments[0].bar_start_position, TicksTime::zero());
+
+    assert_eq!(segments[1].start_position, change_position);
+    assert_eq!(segments[1].signature, Signature::new(7, 8));
+    assert_eq!(segments[1].bar_start_position, change_position);
+    assert_eq!(
+      segments[1].clock_start_position,
+      ClockTime::from_seconds(2.0)
+    );
+    assert_close(
+      u64::from(segments[1].duration),
+      TICKS_RESOLUTION / 2,
+      TICKS_RESOLUTION / 100_000,
+    );
+  }
+
+  #[test]
+  pub fn loop_positions_with_signature_changes() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_signature_change(1, Signature::new(7, 8));
+    transport.set_signature_change(2, Signature::new(3, 4));
+    transport.set_loop_start(BarsTime::new(1, 3, 0, 0));
+    transport.set_loop_end(BarsTime::new(3, 0, 0, 0));
+
+    // 16 sixteenths of 4/4, 3 eighths of 7/8, and then 14 sixteenths of 7/8 and 12 sixteenths of 3/4
+    assert_eq!(
+      transport.loop_start,
+      TicksTime::new((16 + 3 * 2) * TICKS_RESOLUTION)
+    );
+    assert_eq!(
+      transport.loop_end,
+      TicksTime::new((16 + 14 + 12) * TICKS_RESOLUTION)
+    );
+    assert_eq!(transport.get_loop_start(), BarsTime::new(1, 3, 0, 0));
+    assert_eq!(transport.get_loop_end(), BarsTime::new(3, 0, 0, 0));
+  }
+
+  #[test]
+  pub fn segments_follow_tempo_ramps() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_tempo_ramp(BarsTime::new(0, 0, 0, 0), Tempo::new(90), TempoRamp::Linear);
+    transport.set_tempo_ramp(
+      BarsTime::new(50, 0, 0, 0),
+      Tempo::from_bpm(150.5),
+      TempoRamp::Exponential,
+    );
+    transport.set_tempo_change(BarsTime::new(100, 0, 0, 0), Tempo::from_bpm(92.5));
+    transport.play(false);
+
+    // 10 minutes of buffers of 512 samples crossing both ramps
+    let mut master_clock = ClockTime::zero();
+    let num_buffers = 600 * u64::from(SAMPLE_RATE) / 512;
+    for _ in 0..num_buffers {
+      let segments = next_segments(&mut transport, master_clock, 512);
+      let last = segments.last().unwrap();
+      master_clock = last.master_clock + last.clock_duration;
+    }
+
+    let seconds = (num_buffers * 512) as f64 / f64::from(SAMPLE_RATE);
+    let expected_position = transport.get_tempo_map().clock_to_ticks(
+      ClockTime::from_seconds(seconds),
+      transport.get_signature_map(),
+    );
+    assert!(transport.get_position().get_bars() > 100);
+    assert_close(
+      u64::from(transport.current_position),
+      u64::from(expected_position),
+      TICKS_RESOLUTION / 1000,
+    );
+    // the clock duration of every segment is truncated to the clock units, as with a constant tempo
+    assert_close(
+      master_clock.units(),
+      ClockTime::from_seconds(seconds).units(),
+      num_buffers,
+    );
+    assert_eq!(transport.get_tempo(), Tempo::from_bpm(92.5));
+  }
+
+  #[test]
+  pub fn smpte_position_with_offset() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    let offset = SmpteTime::new(FrameRate::Fps25, 1, 0, 0, 0, 0).unwrap();
+    transport.set_smpte_offset(offset);
+    transport.set_position(BarsTime::new(1, 2, 0, 0));
+    let position = transport.get_smpte_position();
+    assert_eq!(
+      position,
+      SmpteTime::new(FrameRate::Fps25, 1, 0, 3, 0, 0).unwrap()
+    );
+
+    let position = SmpteTime::new(FrameRate::Fps25, 1, 0, 4, 0, 0).unwrap();
+    transport.set_smpte_position(position);
+    assert_eq!(transport.get_position(), BarsTime::new(2, 0, 0, 0));
+  }
+
+  #[test]
+  pub fn count_in_before_playing() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_count_in_bars(1);
+    transport.play_with_pre_roll(false);
+    assert!(transport.is_counting_in());
+
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    let mut count_in_duration = ClockTime::zero();
+    let mut song_start = None;
+    while master_clock < ClockTime::from_seconds(3.0) {
+      for segment in next_segments(&mut transport, master_clock, samples) {
+        if segment.is_count_in() {
+          assert!(song_start.is_none());
+          assert_eq!(segment.pre_roll, Some(PreRoll::CountIn));
+          assert_eq!(segment.play_duration, TicksTime::zero());
+          count_in_duration += segment.clock_duration;
+        } else if song_start.is_none() {
+          assert_eq!(segment.start_position, TicksTime::zero());
+          song_start = Some(segment.master_clock);
+        }
+      }
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+
+    // a bar of 4/4 at 120 bpm takes 2 seconds, and the song plays the last second
+    assert!(!transport.is_counting_in());
+    let tolerance = clock::UNITS_PER_MILLI / 1000;
+    let two_seconds = ClockTime::from_seconds(2.0).units();
+    assert_close(count_in_duration.units(), two_seconds, tolerance);
+    assert_close(song_start.unwrap().units(), two_seconds, tolerance);
+    let position = u64::from(
+      transport
+        .get_position()
+        .to_ticks_with_map(&transport.signature_map),
+    );
+    let expected_position = (master_clock - ClockTime::from_seconds(2.0))
+      .to_ticks_with_map(&transport.signature_map, &transport.tempo_map);
+    assert_close(
+      position,
+      u64::from(expected_position),
+      TICKS_RESOLUTION / 1000,
+    );
+  }
+
+  #[test]
+  pub fn pre_roll_before_recording() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_pre_roll_bars(1);
+    transport.set_position(BarsTime::new(2, 2, 0, 0));
+
+    // without recording it plays from the position
+    transport.play_with_pre_roll(false);
+    assert!(!transport.is_pre_rolling());
+    transport.stop();
+
+    transport.set_recording(true);
+    transport.play_with_pre_roll(false);
+    assert!(transport.is_pre_rolling());
+    assert_eq!(transport.get_position(), BarsTime::new(1, 2, 0, 0));
+
+    let record_position = BarsTime::new(2, 2, 0, 0).to_ticks_with_map(&transport.signature_map);
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    let mut segments = Vec::new();
+    while master_clock < ClockTime::from_seconds(2.5) {
+      segments.extend(next_segments(&mut transport, master_clock, samples));
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+
+    assert!(!transport.is_pre_rolling());
+    assert!(segments
+      .iter()
+      .any(|segment| segment.end_position == record_position));
+    for segment in segments.iter() {
+      if segment.end_position <= record_position {
+        assert_eq!(segment.pre_roll, Some(PreRoll::Song));
+        assert!(!segment.is_count_in());
+      } else {
+        assert!(segment.start_position >= record_position);
+        assert!(!segment.is_pre_roll());
+      }
+    }
+  }
+
+  #[test]
+  pub fn stop_cancels_count_in_and_pre_roll() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_count_in_bars(2);
+    transport.set_pre_roll_bars(2);
+    transport.set_recording(true);
+    transport.set_position(BarsTime::new(4, 0, 0, 0));
+    transport.play_with_pre_roll(false);
+    assert!(transport.is_counting_in());
+    assert!(transport.is_pre_rolling());
+    assert_eq!(transport.get_position(), BarsTime::new(2, 0, 0, 0));
+
+    transport.stop();
+    assert!(!transport.is_counting_in());
+    assert!(!transport.is_pre_rolling());
+    assert_eq!(transport.get_position(), BarsTime::new(4, 0, 0, 0));
+    let segments = next_segments(&mut transport, ClockTime::zero(), 512);
+    assert!(segments.iter().all(|segment| !segment.is_pre_roll()));
+  }
+
+  #[test]
+  pub fn stop_during_the_pre_roll_goes_back_to_the_start() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_pre_roll_bars(1);
+    transport.set_punch_in(BarsTime::new(6, 0, 0, 0));
+    transport.set_punch_out(BarsTime::new(8, 0, 0, 0));
+    transport.set_punch_enabled(true);
+    transport.set_recording(true);
+    transport.set_position(BarsTime::new(3, 0, 0, 0));
+    transport.play_with_pre_roll(false);
+    assert_eq!(transport.get_position(), BarsTime::new(5, 0, 0, 0));
+
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    for _ in 0..10 {
+      next_segments(&mut transport, master_clock, samples);
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+    assert!(transport.is_pre_rolling());
+    assert!(transport.get_position() > BarsTime::new(5, 0, 0, 0));
+
+    // toggling play stops the same way
+    transport.play(false);
+    assert!(!transport.is_playing());
+    assert!(!transport.is_pre_rolling());
+    assert_eq!(transport.get_position(), BarsTime::new(3, 0, 0, 0));
+    let segments = next_segments(&mut transport, master_clock, samples);
+    assert!(segments.iter().all(|segment| !segment.is_pre_roll()));
+  }
+
+  /// Play until a position, returning the positions of the segments and whether they were inside the punch range
+  fn punch_segments(
+    transport: &mut Transport,
+    until: TicksTime,
+  ) -> Vec<(TicksTime, TicksTime, bool)> {
+    let mut result = Vec::new();
+    let samples = 512;
+    let mut master_clock = ClockTime::zero();
+    while master_clock < until.to_clock_with_map(&transport.signature_map, &transport.tempo_map) {
+      for segment in next_segments(transport, master_clock, samples) {
+        assert_eq!(segment.is_recording(), segment.is_inside_punch());
+        result.push((
+          segment.start_position,
+          segment.end_position,
+          segment.is_inside_punch(),
+        ));
+      }
+      master_clock += ClockTime::from_samples(samples, SAMPLE_RATE);
+    }
+    result
+  }
+
+  #[test]
+  pub fn segments_split_at_punch_points() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_recording(true);
+    transport.set_punch_enabled(true);
+    transport.set_punch_in(BarsTime::new(1, 0, 0, 0));
+    transport.set_punch_out(BarsTime::new(2, 1, 0, 0));
+    let punch_in = BarsTime::new(1, 0, 0, 0).to_ticks_with_map(&transport.signature_map);
+    let punch_out = BarsTime::new(2, 1, 0, 0).to_ticks_with_map(&transport.signature_map);
+
+    let segments = punch_segments(&mut transport, punch_out + punch_in);
+
+    assert!(segments.iter().any(|(_, end, _)| *end == punch_in));
+    assert!(segments.iter().any(|(_, end, _)| *end == punch_out));
+    for (start, end, inside_punch) in segments {
+      assert_eq!(inside_punch, punch_in <= start && end <= punch_out);
+      assert!(inside_punch || end <= punch_in || start >= punch_out);
     }
   }
+
+  #[test]
+  pub fn punch_range_on_every_loop_pass() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_start(BarsTime::new(0, 0, 0, 0));
+    transport.set_loop_end(BarsTime::new(1, 0, 0, 0));
+    transport.set_recording(true);
+    transport.set_punch_enabled(true);
+    transport.set_punch_in(BarsTime::new(0, 2, 0, 0));
+    transport.set_punch_out(BarsTime::new(3, 0, 0, 0));
+    let punch_in = BarsTime::new(0, 2, 0, 0).to_ticks_with_map(&transport.signature_map);
+    let loop_end = BarsTime::new(1, 0, 0, 0).to_ticks_with_map(&transport.signature_map);
+
+    // three passes of the loop, where the punch-out after the loop end is never reached
+    let segments = punch_segments(&mut transport, loop_end + loop_end + loop_end);
+
+    let punch_ins = segments
+      .iter()
+      .filter(|(_, end, _)| *end == punch_in)
+      .count();
+    let wraps = segments
+      .iter()
+      .filter(|(_, end, _)| *end == loop_end)
+      .count();
+    assert_eq!(punch_ins, 3);
+    assert_eq!(wraps, 3);
+    for (start, end, inside_punch) in segments {
+      assert!(end <= loop_end);
+      assert_eq!(inside_punch, start >= punch_in);
+    }
+  }
+
+  #[test]
+  pub fn recording_without_punch_range() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_punch_in(BarsTime::new(1, 0, 0, 0));
+    transport.set_punch_out(BarsTime::new(2, 0, 0, 0));
+
+    let segments = next_segments(&mut transport, ClockTime::zero(), 512);
+    assert!(segments.iter().all(|segment| !segment.is_recording()));
+
+    transport.set_recording(true);
+    let segments = next_segments(&mut transport, ClockTime::zero(), 512);
+    assert!(segments
+      .iter()
+      .all(|segment| segment.is_recording() && !segment.is_inside_punch()));
+  }
+
+  #[test]
+  pub fn pre_roll_before_punch_in() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    transport.set_loop_enabled(false);
+    transport.set_pre_roll_bars(1);
+    transport.set_recording(true);
+    transport.set_punch_enabled(true);
+    transport.set_punch_in(BarsTime::new(3, 0, 0, 0));
+    transport.set_punch_out(BarsTime::new(4, 0, 0, 0));
+
+    transport.play_with_pre_roll(false);
+    assert_eq!(transport.get_position(), BarsTime::new(2, 0, 0, 0));
+
+    let segments = next_segments(&mut transport, ClockTime::zero(), 512);
+    assert!(segments
+      .iter()
+      .all(|segment| segment.is_pre_roll() && !segment.is_recording()));
+  }
+
+  #[test]
+  pub fn locate_markers() {
+    let mut transport = Transport::new(SAMPLE_RATE);
+    let mut markers = Markers::new();
+    for (name, bar) in [("intro", 0), ("verse", 4), ("chorus", 12)].iter() {
+      let position = BarsTime::from_bars(*bar).to_ticks_with_map(&transport.signature_map);
+      markers.add(Marker::new(*name, Color::from_rgb(0, 0, 0), position));
+    }
+
+    transport.set_position(BarsTime::new(2, 1, 0, 0));
+    assert!(transport.locate_next_marker(&markers));
+    assert_eq!(transport.get_position(), BarsTime::from_bars(4));
+    assert!(transport.locate_next_marker(&markers));
+    assert_eq!(transport.get_position(), BarsTime::from_bars(12));
+    assert!(!transport.locate_next_marker(&markers));
+    assert_eq!(transport.get_position(), BarsTime::from_bars(12));
+
+    assert!(transport.locate_previous_marker(&markers));
+    assert_eq!(transport.get_position(), BarsTime::from_bars(4));
+    assert!(transport.locate_previous_marker(&markers));
+    assert!(!transport.locate_previous_marker(&markers));
+    assert_eq!(transport.get_position(), BarsTime::from_bars(0));
+  }
 }